@@ -1,8 +1,9 @@
 use bench::client::*;
 use structopt::StructOpt;
 
-#[async_std::main]
-async fn main() {
-    let options = Options::from_args();
-    client_main(options).await.expect("running client");
+fn main() {
+    bench::block_on(async {
+        let options = Options::from_args();
+        client_main(options).await.expect("running client");
+    })
 }
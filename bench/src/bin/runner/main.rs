@@ -2,8 +2,9 @@ use bench::*;
 use runner::*;
 use structopt::StructOpt;
 
-#[async_std::main]
-async fn main() {
-    let options = Options::from_args();
-    println!("{:?}", runner_main(options).await);
+fn main() {
+    bench::block_on(async {
+        let options = Options::from_args();
+        println!("{:?}", runner_main(options).await);
+    })
 }
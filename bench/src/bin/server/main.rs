@@ -1,9 +1,10 @@
 use bench::server::*;
 use structopt::StructOpt;
 
-#[async_std::main]
-async fn main() {
-    let options = Options::from_args();
+fn main() {
+    bench::block_on(async {
+        let options = Options::from_args();
 
-    server_main(options).await.expect("running server");
+        server_main(options).await.expect("running server");
+    })
 }
@@ -8,9 +8,16 @@ use futures::{
     stream::{select, SelectAll, StreamExt},
 };
 
+use ::miknet::{ClockConfig, ClockSync, SnapshotSender};
 use serde::Serialize;
 use std::str::FromStr;
-use std::{collections::HashMap, iter::FromIterator, time::Instant};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    iter::FromIterator,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
 
 #[derive(Clone, Copy, Debug, Serialize)]
@@ -19,13 +26,81 @@ pub struct TripReport {
     index: u64,
     round_trip: f64,
     send_time: f64,
+    /// The delay from the client to the server, by the client's estimate of
+    /// the server's clock.
+    upstream: f64,
+    /// The delay from the server to the client.
+    downstream: f64,
 }
 
 #[derive(Clone)]
 pub struct Summary {
     pub mean_ms: f64,
     pub deviation_ms: f64,
+    pub mean_upstream_ms: f64,
+    pub mean_downstream_ms: f64,
     pub trip_reports: Vec<TripReport>,
+    /// Bytes the client handed to the connection to send.
+    pub payload_bytes_sent: u64,
+    /// Datagrams the client handed to the connection to send.
+    pub datagrams_sent: u64,
+    /// The client connection's stats at the end of the run.
+    pub stats: ConnectionStats,
+    /// How long the run took.
+    pub duration: Duration,
+    /// The CPU time the process spent during the run, which includes the
+    /// server's if it runs in the same process.
+    pub cpu: Duration,
+    /// The socket syscalls miknet made during the run, for miknet protocols.
+    pub syscalls: Option<u64>,
+    /// Bytes of the whole snapshots the client's snapshot transfers
+    /// replicated.
+    pub snapshot_bytes: u64,
+    /// Bytes the client's snapshot transfers encoded those snapshots in.
+    pub snapshot_bytes_sent: u64,
+}
+
+impl Summary {
+    /// Bytes the client's connection sent per payload byte.
+    pub fn overhead(&self) -> Option<f64> {
+        self.stats
+            .bytes_sent
+            .map(|bytes| bytes as f64 / self.payload_bytes_sent as f64)
+    }
+
+    /// Bytes on the wire the client's connection sent per datagram.
+    pub fn bytes_per_datagram(&self) -> Option<f64> {
+        self.stats
+            .bytes_sent
+            .map(|bytes| bytes as f64 / self.datagrams_sent as f64)
+    }
+
+    /// Packets the client's connection sent per second.
+    pub fn packets_per_second(&self) -> Option<f64> {
+        self.stats
+            .packets_sent
+            .map(|packets| packets as f64 / self.duration.as_secs_f64())
+    }
+
+    /// Microseconds of CPU time the process spent per datagram.
+    pub fn cpu_per_datagram_us(&self) -> f64 {
+        self.cpu.as_secs_f64() * 1e6 / self.datagrams_sent as f64
+    }
+
+    /// Socket syscalls per datagram.
+    pub fn syscalls_per_datagram(&self) -> Option<f64> {
+        self.syscalls
+            .map(|syscalls| syscalls as f64 / self.datagrams_sent as f64)
+    }
+
+    /// The fraction of snapshot bytes that delta encoding saved, for runs
+    /// with snapshot transfers.
+    pub fn snapshot_savings(&self) -> Option<f64> {
+        match self.snapshot_bytes {
+            0 => None,
+            bytes => Some(1.0 - self.snapshot_bytes_sent as f64 / bytes as f64),
+        }
+    }
 }
 
 impl FromIterator<Summary> for Summary {
@@ -33,29 +108,48 @@ impl FromIterator<Summary> for Summary {
     where
         T: IntoIterator<Item = Summary>,
     {
-        let (trip_reports, count, mean_sum, deviation_sum) =
+        let (trip_reports, count, mean_sum, deviation_sum, one_way_sums) =
             iter.into_iter().fold(
-                (vec![], 0, 0.0, 0.0),
+                (vec![], 0, 0.0, 0.0, (0.0, 0.0)),
                 |(
                     mut trip_reports,
                     mut count,
                     mut mean_sum,
                     mut deviation_sum,
+                    (mut upstream_sum, mut downstream_sum),
                 ),
                  mut result| {
                     mean_sum += result.mean_ms;
                     deviation_sum += result.deviation_ms;
+                    upstream_sum += result.mean_upstream_ms;
+                    downstream_sum += result.mean_downstream_ms;
                     count += 1;
                     trip_reports.append(&mut result.trip_reports);
 
-                    (trip_reports, count, mean_sum, deviation_sum)
+                    (
+                        trip_reports,
+                        count,
+                        mean_sum,
+                        deviation_sum,
+                        (upstream_sum, downstream_sum),
+                    )
                 },
             );
 
         Summary {
             mean_ms: mean_sum / count as f64,
             deviation_ms: deviation_sum / count as f64,
+            mean_upstream_ms: one_way_sums.0 / count as f64,
+            mean_downstream_ms: one_way_sums.1 / count as f64,
             trip_reports,
+            payload_bytes_sent: 0,
+            datagrams_sent: 0,
+            stats: ConnectionStats::default(),
+            duration: Duration::default(),
+            cpu: Duration::default(),
+            syscalls: None,
+            snapshot_bytes: 0,
+            snapshot_bytes_sent: 0,
         }
     }
 }
@@ -65,6 +159,16 @@ impl std::fmt::Debug for Summary {
         f.debug_struct("Summary")
             .field("Mean", &self.mean_ms)
             .field("Deviation", &self.deviation_ms)
+            .field("Upstream", &self.mean_upstream_ms)
+            .field("Downstream", &self.mean_downstream_ms)
+            .field("Overhead", &self.overhead())
+            .field("Bytes/datagram", &self.bytes_per_datagram())
+            .field("Packets/s", &self.packets_per_second())
+            .field("Retransmissions", &self.stats.retransmissions)
+            .field("Recovered", &self.stats.recovered)
+            .field("CPU us/datagram", &self.cpu_per_datagram_us())
+            .field("Syscalls/datagram", &self.syscalls_per_datagram())
+            .field("Snapshot savings", &self.snapshot_savings())
             .finish()
     }
 }
@@ -83,7 +187,18 @@ impl From<Vec<TripReport>> for Summary {
         Summary {
             mean_ms: mean,
             deviation_ms: deviation,
+            mean_upstream_ms: src.iter().map(|r| r.upstream).sum::<f64>() / n,
+            mean_downstream_ms: src.iter().map(|r| r.downstream).sum::<f64>()
+                / n,
             trip_reports: src,
+            payload_bytes_sent: 0,
+            datagrams_sent: 0,
+            stats: ConnectionStats::default(),
+            duration: Duration::default(),
+            cpu: Duration::default(),
+            syscalls: None,
+            snapshot_bytes: 0,
+            snapshot_bytes_sent: 0,
         }
     }
 }
@@ -95,6 +210,9 @@ struct TransferTracker {
     total_expected: usize,
     live: HashMap<u64, Instant>,
     returned: Vec<TripReport>,
+    /// When each returned datagram was sent, returned by the server, by its
+    /// clock, and received.
+    stamps: Vec<(Instant, Duration, Instant)>,
 }
 
 impl TransferTracker {
@@ -102,18 +220,40 @@ impl TransferTracker {
         self.live.insert(id, Instant::now());
     }
 
-    fn track_return(&mut self, id: u64) {
-        let now = Instant::now();
-        if let Some(sent_time) = self.live.remove(&id) {
-            let round_trip = now.duration_since(sent_time);
-            self.returned.push(TripReport {
-                stream_id: self.stream_id,
-                index: id,
-                send_time: (now.duration_since(self.epoch) - round_trip)
-                    .as_secs_f64()
-                    * 1e3,
-                round_trip: round_trip.as_secs_f64() * 1e3,
-            });
+    /// Tracks a returned datagram.
+    fn track_return(&mut self, id: u64, server_time: Duration, now: Instant) {
+        let sent_time = match self.live.remove(&id) {
+            Some(sent_time) => sent_time,
+            None => return,
+        };
+        let round_trip = now.duration_since(sent_time);
+        self.returned.push(TripReport {
+            stream_id: self.stream_id,
+            index: id,
+            send_time: (now.duration_since(self.epoch) - round_trip)
+                .as_secs_f64()
+                * 1e3,
+            round_trip: round_trip.as_secs_f64() * 1e3,
+            upstream: 0.0,
+            downstream: 0.0,
+        });
+        self.stamps.push((sent_time, server_time, now));
+    }
+
+    /// Splits the round trips of returned datagrams into the delays each way,
+    /// by the estimate of the server's clock at the end of the run.
+    fn split_round_trips(&mut self, clock: &ClockSync) {
+        let ms = |time: Duration| time.as_secs_f64() * 1e3;
+        for (report, (sent, server_time, received)) in
+            self.returned.iter_mut().zip(&self.stamps)
+        {
+            if let (Some(at_send), Some(at_receive)) = (
+                clock.server_time_estimate(*sent),
+                clock.server_time_estimate(*received),
+            ) {
+                report.upstream = ms(*server_time) - ms(at_send.time);
+                report.downstream = ms(at_receive.time) - ms(*server_time);
+            }
         }
     }
 
@@ -122,6 +262,9 @@ impl TransferTracker {
     }
 }
 
+/// How often the client exchanges timestamps with the server's clock.
+const CLOCK_HERTZ: u32 = 10;
+
 async fn run(
     options: Options,
     client: impl Connection + Unpin,
@@ -129,12 +272,17 @@ async fn run(
     enum Input {
         Transfer(TransferCmd),
         Wire(Result<Datagram>),
+        Clock,
     }
 
     let (mut client_sink, client_stream) = client.split();
     let returned_datagrams = client_stream.map(Input::Wire);
 
+    let protocol = options.protocol;
+    let clock_delivery_mode = protocol.clock_delivery_mode();
     let epoch = Instant::now();
+    let cpu = cpu_time();
+    let syscalls = ::miknet::batch::syscalls();
     let mut tracking = options
         .transfers
         .iter()
@@ -148,48 +296,115 @@ async fn run(
                         total_expected,
                         live: HashMap::new(),
                         returned: vec![],
+                        stamps: vec![],
                     },
                 )
             })
         })
         .collect::<HashMap<StreamId, TransferTracker>>();
+    // Delta transfers encode against the newest snapshot the server echoed,
+    // which the client knows it has.
+    let snapshots = options
+        .transfers
+        .iter()
+        .filter(|tx| tx.payload == Payload::SnapshotDeltas)
+        .map(|tx| (tx.stream_id, Arc::new(Mutex::new(SnapshotSender::new()))))
+        .collect::<HashMap<_, _>>();
     let transfers: SelectAll<_> = options
         .transfers
         .into_iter()
-        .map(Transfer::stream)
+        .map(|tx| {
+            let snapshots = snapshots.get(&tx.stream_id).cloned();
+            tx.stream(snapshots)
+        })
         .collect();
 
-    let mut input_stream =
-        select(transfers.map(Input::Transfer), returned_datagrams);
-
+    // Clock exchanges are polled on their own stream, so they are not held
+    // up behind the transfers.
+    let clock_ticks = futures::stream::iter(clock_delivery_mode)
+        .flat_map(|_| ticker(CLOCK_HERTZ))
+        .map(|_| Input::Clock);
+    let mut input_stream = select(
+        select(transfers.map(Input::Transfer), clock_ticks),
+        returned_datagrams,
+    );
+
+    let mut payload_bytes_sent = 0;
+    let mut datagrams_sent = 0;
+    let mut snapshot_bytes = 0;
+    let mut snapshot_bytes_sent = 0;
+    let mut clock = ClockSync::new(
+        epoch,
+        ClockConfig {
+            interval: Duration::from_secs(1) / CLOCK_HERTZ,
+            samples: 64,
+            ..ClockConfig::default()
+        },
+    );
     loop {
         let input = input_stream.next().await.unwrap();
         match input {
             Input::Wire(returned_datagram) => {
                 let returned_datagram: Datagram =
                     returned_datagram.expect("datagram");
+                if clock_exchange(&returned_datagram).is_some() {
+                    clock.handle_response(
+                        Instant::now(),
+                        &returned_datagram.data,
+                    )?;
+                    continue;
+                }
                 let stream = returned_datagram
                     .stream_position
                     .expect("stream position")
                     .stream_id;
                 let benchmark_datagram =
                     bincode::deserialize::<BenchmarkDatagram>(
-                        returned_datagram.data.as_slice(),
+                        &returned_datagram.data,
                     )?;
 
+                let now = Instant::now();
+                let server_time =
+                    Duration::from_micros(benchmark_datagram.server_time_us);
                 if let Some(tracker) = tracking.get_mut(&stream) {
-                    tracker.track_return(benchmark_datagram.id);
+                    tracker.track_return(
+                        benchmark_datagram.id,
+                        server_time,
+                        now,
+                    );
+                }
+                if let Some(sender) = snapshots.get(&stream) {
+                    sender
+                        .lock()
+                        .expect("snapshot sender")
+                        .acknowledge(benchmark_datagram.id as u32);
                 }
 
                 if tracking.values().all(TransferTracker::done) {
-                    return Ok(tracking
-                        .into_iter()
-                        .map(|(_, tracker)| tracker.returned)
-                        .map(Summary::from)
-                        .collect());
+                    break;
+                }
+            }
+            Input::Clock => {
+                let request = clock.poll_request(Instant::now());
+                if let (Some(request), Some(delivery_mode)) =
+                    (request, clock_delivery_mode)
+                {
+                    client_sink
+                        .send(SendCmd {
+                            delivery_mode,
+                            data: request,
+                            ..SendCmd::default()
+                        })
+                        .await?;
                 }
             }
             Input::Transfer(transfer_cmd) => {
+                payload_bytes_sent += transfer_cmd.send_cmd.data.len() as u64;
+                datagrams_sent += 1;
+                if let Some((whole, sent)) = transfer_cmd.snapshot {
+                    snapshot_bytes += whole;
+                    snapshot_bytes_sent += sent;
+                }
                 client_sink.send(transfer_cmd.send_cmd).await?;
                 if let Some((cumulative_tracking, cmd_tracking)) =
                     transfer_cmd.tracking.and_then(|cmd_tracking| {
@@ -203,6 +418,33 @@ async fn run(
             }
         }
     }
+
+    let (_, returned_datagrams) = input_stream.into_inner();
+    let client = returned_datagrams
+        .into_inner()
+        .reunite(client_sink)
+        .expect("halves of the client connection");
+
+    let mut summary: Summary = tracking
+        .into_iter()
+        .map(|(_, mut tracker)| {
+            tracker.split_round_trips(&clock);
+            tracker.returned
+        })
+        .map(Summary::from)
+        .collect();
+    summary.payload_bytes_sent = payload_bytes_sent;
+    summary.datagrams_sent = datagrams_sent;
+    summary.stats = client.stats();
+    summary.duration = epoch.elapsed();
+    summary.cpu = cpu_time() - cpu;
+    summary.snapshot_bytes = snapshot_bytes;
+    summary.snapshot_bytes_sent = snapshot_bytes_sent;
+    if protocol.is_miknet() {
+        summary.syscalls = Some(::miknet::batch::syscalls() - syscalls);
+    }
+
+    Ok(summary)
 }
 
 #[derive(Clone, Debug, StructOpt)]
@@ -211,7 +453,8 @@ pub struct Options {
     #[structopt(short = "a", default_value = "127.0.0.1:33333")]
     pub address: SocketAddr,
     /// Periodic transfers, specified in terms of
-    /// `stream_id:size:hertz:[return_count]`.
+    /// `stream_id:size:hertz:[return_count]:[payload]`, where the payload is
+    /// `zeros`, `random`, `entities`, `snapshots` or `snapshot-deltas`.
     #[structopt(short = "b", long)]
     pub transfers: Vec<Transfer>,
     #[structopt(subcommand)]
@@ -221,6 +464,9 @@ pub struct Options {
 struct TransferCmd {
     send_cmd: SendCmd,
     tracking: Option<TransferMessageTracking>,
+    /// The bytes of the whole snapshot the datagram replicates, and the bytes
+    /// it was encoded in, for snapshot transfers.
+    snapshot: Option<(u64, u64)>,
 }
 
 /// Tracking information for a transfer message on the wire.
@@ -235,61 +481,191 @@ pub struct Transfer {
     pub size: usize,
     pub hertz: u32,
     pub return_count: Option<usize>,
+    pub payload: Payload,
+}
+
+/// What a transfer's datagrams hold.
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum Payload {
+    /// Zeroes, which compress to almost nothing.
+    Zeros,
+    /// Random bytes, which do not compress.
+    Random,
+    /// Records of entity state, like a game sends, which compress well with
+    /// `ENTITY_DICTIONARY`.
+    Entities,
+    /// Whole snapshots of a world of entities, a few of which move every tick.
+    Snapshots,
+    /// Snapshots of the same world, delta encoded against the newest one the
+    /// server echoed, with `miknet::SnapshotSender`.
+    SnapshotDeltas,
+}
+
+/// The length of an entity's record in a `World`: its id, then its position.
+const ENTITY_LEN: usize = 16;
+
+/// A simulated world of entities, which snapshot transfers replicate.
+struct World {
+    state: Vec<u8>,
+}
+
+impl World {
+    fn new(size: usize) -> Self {
+        let mut state = vec![0; size];
+        for (entity, record) in state.chunks_mut(ENTITY_LEN).enumerate() {
+            let id = (entity as u32).to_le_bytes();
+            let len = record.len().min(id.len());
+            record[..len].copy_from_slice(&id[..len]);
+        }
+        Self { state }
+    }
+
+    /// Moves about a tenth of the entities, and returns a snapshot of the
+    /// world.
+    fn step(&mut self) -> Vec<u8> {
+        for record in self.state.chunks_exact_mut(ENTITY_LEN) {
+            if rand::random::<u8>() >= 26 {
+                continue;
+            }
+            for axis in record[4..].chunks_exact_mut(4) {
+                let position = i32::from_le_bytes(axis.try_into().unwrap())
+                    + i32::from(rand::random::<i8>());
+                axis.copy_from_slice(&position.to_le_bytes());
+            }
+        }
+        self.state.clone()
+    }
+}
+
+impl Payload {
+    fn generate(self, size: usize, world: &mut World) -> Vec<u8> {
+        match self {
+            Payload::Zeros => vec![0; size],
+            Payload::Random => (0..size).map(|_| rand::random()).collect(),
+            Payload::Entities => {
+                let mut data = vec![];
+                while data.len() < size {
+                    let record = format!(
+                        "entity={} x={:.2} y={:.2} z={:.2} hp={};",
+                        rand::random::<u8>(),
+                        rand::random::<f32>() * 100.0,
+                        rand::random::<f32>() * 100.0,
+                        rand::random::<f32>() * 10.0,
+                        100 - rand::random::<u8>() % 4 * 25,
+                    );
+                    data.extend(record.into_bytes());
+                }
+                data.truncate(size);
+                data
+            }
+            Payload::Snapshots | Payload::SnapshotDeltas => world.step(),
+        }
+    }
+}
+
+impl FromStr for Payload {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        match src {
+            "zeros" => Ok(Payload::Zeros),
+            "random" => Ok(Payload::Random),
+            "entities" => Ok(Payload::Entities),
+            "snapshots" => Ok(Payload::Snapshots),
+            "snapshot-deltas" => Ok(Payload::SnapshotDeltas),
+            _ => Err(anyhow::anyhow!("unknown payload {:?}", src)),
+        }
+    }
 }
 
 impl Transfer {
-    fn stream(self) -> impl Stream<Item = TransferCmd> {
+    /// Returns the transfer's datagrams. Delta transfers encode their
+    /// snapshots with `snapshots`, and must be returned so the sender learns
+    /// which the server has.
+    fn stream(
+        self,
+        snapshots: Option<Arc<Mutex<SnapshotSender>>>,
+    ) -> impl Stream<Item = TransferCmd> {
         let ticker = ticker(self.hertz);
         let mut id = 0;
+        let mut world = World::new(self.size);
         ticker.map(move |_| {
             id += 1;
+            let mut data = self.payload.generate(self.size, &mut world);
+            let mut snapshot = None;
+            if matches!(
+                self.payload,
+                Payload::Snapshots | Payload::SnapshotDeltas
+            ) {
+                let whole = data.len() as u64;
+                if let Some(sender) = &snapshots {
+                    data = sender
+                        .lock()
+                        .expect("snapshot sender")
+                        .encode(id as u32, data.into())
+                        .to_vec();
+                }
+                snapshot = Some((whole, data.len() as u64));
+            }
             match self.return_count {
                 Some(_) => TransferCmd {
-                    send_cmd: self.send_cmd(id),
+                    send_cmd: self.send_cmd(id, data),
                     tracking: Some(TransferMessageTracking {
                         stream_id: self.stream_id,
                         id,
                     }),
+                    snapshot,
                 },
                 None => TransferCmd {
-                    send_cmd: self.send_cmd(ID_DO_NOT_RETURN),
+                    send_cmd: self.send_cmd(ID_DO_NOT_RETURN, data),
                     tracking: None,
+                    snapshot,
                 },
             }
         })
     }
 
-    fn send_cmd(&self, id: u64) -> SendCmd {
+    fn send_cmd(&self, id: u64, data: Vec<u8>) -> SendCmd {
         let delivery_mode = DeliveryMode::ReliableOrdered(self.stream_id);
         SendCmd {
             delivery_mode,
             data: bincode::serialize(&BenchmarkDatagram {
+                server_time_us: 0,
                 id,
                 delivery_mode,
-                data: vec![0; self.size],
+                data,
             })
-            .expect("to serialize bulk transfer"),
+            .expect("to serialize bulk transfer")
+            .into(),
             ..SendCmd::default()
         }
     }
 }
 
 impl FromStr for Transfer {
-    type Err = std::num::ParseIntError;
+    type Err = anyhow::Error;
     fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
         let args: Vec<&str> = src.split(":").collect();
 
-        let stream_id = args[0].parse::<u8>()?;
+        let stream_id = args[0].parse::<u32>()?;
         let size = args[1].parse::<usize>()?;
         let hertz = args[2].parse::<u32>()?;
-        let return_count =
-            args.get(3).map(|a| a.parse::<usize>()).transpose()?;
+        let return_count = args
+            .get(3)
+            .filter(|a| !a.is_empty())
+            .map(|a| a.parse::<usize>())
+            .transpose()?;
+        let payload = args
+            .get(4)
+            .map(|a| a.parse::<Payload>())
+            .transpose()?
+            .unwrap_or(Payload::Zeros);
 
         Ok(Self {
             stream_id: StreamId(stream_id),
             size,
             hertz,
             return_count,
+            payload,
         })
     }
 }
@@ -327,7 +703,7 @@ pub async fn client_main(options: Options) -> Result<Summary> {
             .await
         }
         Protocol::Enet => {
-            run(options, enet::EnetConnection::connect(address).await).await
+            run(options, enet::EnetConnection::connect(address).await?).await
         }
         Protocol::Kcp => {
             run(
@@ -347,5 +723,50 @@ pub async fn client_main(options: Options) -> Result<Summary> {
             )
             .await
         }
+        Protocol::Miknet => {
+            run(
+                options,
+                miknet::connect(miknet::MiknetMode::Immediate, address)
+                    .await
+                    .expect("Connecting to miknet server"),
+            )
+            .await
+        }
+        Protocol::MiknetCoalesced => {
+            run(
+                options,
+                miknet::connect(miknet::MiknetMode::Coalesced, address)
+                    .await
+                    .expect("Connecting to miknet server"),
+            )
+            .await
+        }
+        Protocol::MiknetFec => {
+            run(
+                options,
+                miknet::connect(miknet::MiknetMode::Fec, address)
+                    .await
+                    .expect("Connecting to miknet server"),
+            )
+            .await
+        }
+        Protocol::MiknetCompressed => {
+            run(
+                options,
+                miknet::connect(miknet::MiknetMode::Compressed, address)
+                    .await
+                    .expect("Connecting to miknet server"),
+            )
+            .await
+        }
+        Protocol::MiknetUnbatched => {
+            run(
+                options,
+                miknet::connect(miknet::MiknetMode::Unbatched, address)
+                    .await
+                    .expect("Connecting to miknet server"),
+            )
+            .await
+        }
     }
 }
@@ -1,31 +1,108 @@
+//! ENet adapter for benchmarking.
+//!
+//! ENet's disconnect notification carries only a 32 bit value, so connections
+//! close with the reason's code, and the reason's message is not delivered.
+//!
+//! ENet resets its per peer counters every few seconds, to measure loss and
+//! throttle bandwidth, so connection stats keep running totals of them. ENet
+//! does not count the packets it receives from each peer.
+
 use async_std::net::*;
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
+use futures::future::FusedFuture;
 use futures::prelude::*;
-use futures::stream::FusedStream;
+use futures::ready;
+use futures::stream::{FusedStream, LocalBoxStream};
 use nhanh::*;
+use std::cmp;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::c_void;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::{link::Link, Protocol, Unsupported};
+use nhanh::events::ServerEvents;
 
 pub const MAX_CHANNELS: u64 = 256;
 
+/// ENet reports packet loss as a fraction of this (`ENET_PEER_PACKET_LOSS_SCALE`).
+const PACKET_LOSS_SCALE: f32 = 65536.0;
+
+/// ENet throttles a peer's window by a fraction of this
+/// (`ENET_PEER_PACKET_THROTTLE_SCALE`).
+const PACKET_THROTTLE_SCALE: u64 = 32;
+
 #[allow(warnings)]
 mod enet {
     include!(concat!(env!("OUT_DIR"), "/enet.rs"));
 }
 
+#[derive(Debug)]
+enum PeerEvent {
+    Datagram(Datagram),
+    Closed(CloseReason),
+}
+
 #[derive(Debug)]
 struct NewPeer {
     peer: u64,
-    peer_event_stream: mpsc::UnboundedReceiver<Datagram>,
+    peer_event_stream: mpsc::UnboundedReceiver<PeerEvent>,
+    link: Arc<Mutex<Link>>,
 }
 
-struct EnetCmd {
-    peer: u64,
-    channel: u8,
-    data: Vec<u8>,
+/// What the service loop keeps for each connected peer.
+struct PeerState {
+    peer_event_sink: mpsc::UnboundedSender<PeerEvent>,
+    link: Arc<Mutex<Link>>,
+    counters: PeerCounters,
+}
+
+/// A total of one of ENet's per peer counters. Sampled every service, it
+/// misses only what ENet counted between the last sample and a reset.
+#[derive(Debug, Default, Clone, Copy)]
+struct Counter {
+    last: u32,
+    total: u64,
+}
+
+impl Counter {
+    fn sample(&mut self, value: u32) -> u64 {
+        let counted = if value >= self.last {
+            value - self.last
+        } else {
+            value
+        };
+        self.last = value;
+        self.total += counted as u64;
+        self.total
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PeerCounters {
+    /// The reliable commands ENet sent, which every benchmark datagram, or
+    /// fragment of one, is.
+    packets_sent: Counter,
+    bytes_sent: Counter,
+    bytes_received: Counter,
+}
+
+enum EnetCmd {
+    Send {
+        peer: u64,
+        channel: u8,
+        delivery_mode: DeliveryMode,
+        data: Bytes,
+    },
+    Disconnect {
+        peer: u64,
+        code: u32,
+        linger: bool,
+        done: oneshot::Sender<()>,
+    },
 }
 
 pub struct EnetServer {
@@ -34,56 +111,74 @@ pub struct EnetServer {
     marker: Arc<()>,
     new_peer_stream: mpsc::UnboundedReceiver<NewPeer>,
     command_sink: mpsc::Sender<EnetCmd>,
+    events: ServerEvents,
 }
 
+/// Converts an address for ENet, which only speaks IPv4.
 async fn socket_addr_to_enet_addr(
     addr: impl ToSocketAddrs,
-) -> enet::ENetAddress {
-    let socket_address = addr
-        .to_socket_addrs()
-        .await
-        .expect("addresses")
-        .next()
-        .expect("address");
+) -> Result<enet::ENetAddress> {
+    let socket_address = addr.to_socket_addrs().await?.next().expect("address");
+    let host = match socket_address.ip() {
+        IpAddr::V4(v4) => u32::from_le_bytes(v4.octets()),
+        IpAddr::V6(_) => {
+            return Err(Unsupported {
+                protocol: Protocol::Enet,
+                feature: "IPv6",
+            }
+            .into())
+        }
+    };
 
     #[allow(deprecated)]
     unsafe {
         let mut address: enet::ENetAddress = std::mem::uninitialized();
-        address.host = match socket_address.ip() {
-            IpAddr::V4(v4) => u32::from_le_bytes(v4.octets()),
-            _ => panic!("Enet does not support ipv6"),
-        };
+        address.host = host;
         address.port = socket_address.port();
-        address
+        Ok(address)
     }
 }
 
+fn enet_addr_to_socket_addr(address: enet::ENetAddress) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::from(address.host.to_le_bytes()), address.port))
+}
+
 impl EnetServer {
-    pub async fn bind(address: impl ToSocketAddrs) -> Self {
-        let address = socket_addr_to_enet_addr(address).await;
+    pub async fn bind(address: impl ToSocketAddrs) -> Result<Self> {
+        let address = socket_addr_to_enet_addr(address).await?;
 
         let (command_sink, command_stream) = mpsc::channel(0);
         let (new_peer_sink, new_peer_stream) = mpsc::unbounded();
 
         let marker = Arc::new(());
+        let events = ServerEvents::default();
 
         std::thread::spawn(enet_service_loop(
             marker.clone(),
             command_stream,
             new_peer_sink,
+            events.clone(),
             HostType::Server,
             address,
         ));
 
-        Self {
+        Ok(Self {
             marker,
             new_peer_stream,
             command_sink,
-        }
+            events,
+        })
     }
 }
 
-impl Server<EnetConnection> for EnetServer {}
+impl Server<EnetConnection> for EnetServer {
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ServerEvent> {
+        self.events.subscribe(thresholds)
+    }
+}
 
 impl FusedStream for EnetServer {
     fn is_terminated(&self) -> bool {
@@ -109,6 +204,9 @@ impl Stream for EnetServer {
             peer: new_peer.peer,
             command_sink: self.command_sink.clone(),
             peer_event_stream: new_peer.peer_event_stream,
+            closing: None,
+            close_reason: None,
+            link: new_peer.link,
         })))
     }
 }
@@ -119,12 +217,16 @@ pub struct EnetConnection {
     marker: Arc<()>,
     peer: u64,
     command_sink: mpsc::Sender<EnetCmd>,
-    peer_event_stream: mpsc::UnboundedReceiver<Datagram>,
+    peer_event_stream: mpsc::UnboundedReceiver<PeerEvent>,
+    /// Resolves when the peer has disconnected after a local close.
+    closing: Option<oneshot::Receiver<()>>,
+    close_reason: Option<CloseReason>,
+    link: Arc<Mutex<Link>>,
 }
 
 impl EnetConnection {
-    pub async fn connect(server_addr: impl ToSocketAddrs) -> Self {
-        let address = socket_addr_to_enet_addr(server_addr).await;
+    pub async fn connect(server_addr: impl ToSocketAddrs) -> Result<Self> {
+        let address = socket_addr_to_enet_addr(server_addr).await?;
 
         let (command_sink, command_stream) = mpsc::channel(0);
         let (new_peer_sink, mut new_peer_stream) = mpsc::unbounded();
@@ -135,25 +237,118 @@ impl EnetConnection {
             marker.clone(),
             command_stream,
             new_peer_sink,
+            ServerEvents::default(),
             HostType::Client,
             address,
         ));
 
         let peer = new_peer_stream.next().await.expect("connection to server");
-        Self {
+        Ok(Self {
             marker,
             peer: peer.peer,
             command_sink,
             peer_event_stream: peer.peer_event_stream,
-        }
+            closing: None,
+            close_reason: None,
+            link: peer.link,
+        })
+    }
+
+    /// Waits for room in the send buffer, which the service loop releases as
+    /// ENet frees acknowledged packets.
+    fn poll_ready_inner(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        delivery_mode: Option<DeliveryMode>,
+    ) -> Poll<Result<()>> {
+        ready!(self
+            .link
+            .lock()
+            .expect("link")
+            .send_buffer
+            .poll_ready(ctx, delivery_mode));
+        Pin::new(&mut self.command_sink)
+            .poll_ready(ctx)
+            .map_err(Into::into)
     }
 }
 
-impl Connection for EnetConnection {}
+impl Connection for EnetConnection {
+    fn poll_close_with(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        close: &Close,
+    ) -> Poll<Result<()>> {
+        if self.closing.is_none() {
+            ready!(Pin::new(&mut self.command_sink).poll_ready(ctx))?;
+            let (done, closing) = oneshot::channel();
+            let peer = self.peer;
+            Pin::new(&mut self.command_sink).start_send(
+                EnetCmd::Disconnect {
+                    peer,
+                    code: close.reason.code,
+                    linger: close.linger,
+                    done,
+                },
+            )?;
+            self.closing = Some(closing);
+            self.link
+                .lock()
+                .expect("link")
+                .events
+                .report(ConnectionEvent::Disconnecting);
+        }
+
+        match self.closing.as_mut() {
+            Some(closing) if !closing.is_terminated() => {
+                // The service loop only drops `done` when it exits, at which
+                // point the peer is gone anyway.
+                Pin::new(closing).poll(ctx).map(|_| Ok(()))
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_ready_for(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        delivery_mode: DeliveryMode,
+    ) -> Poll<Result<()>> {
+        self.poll_ready_inner(ctx, Some(delivery_mode))
+    }
+
+    fn set_send_limits(&mut self, limits: SendLimits) {
+        self.link
+            .lock()
+            .expect("link")
+            .send_buffer
+            .set_limits(limits);
+    }
+
+    fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ConnectionEvent> {
+        self.link.lock().expect("link").events.subscribe(thresholds)
+    }
+
+    /// ENet does not count retransmissions or queued packets.
+    fn stats(&self) -> ConnectionStats {
+        self.link.lock().expect("link").stats.clone()
+    }
+
+    fn bandwidth(&self) -> BandwidthEstimate {
+        self.link.lock().expect("link").bandwidth()
+    }
+}
 
 impl FusedStream for EnetConnection {
     fn is_terminated(&self) -> bool {
-        self.peer_event_stream.is_terminated()
+        self.close_reason.is_some() || self.peer_event_stream.is_terminated()
     }
 }
 
@@ -163,33 +358,49 @@ impl Stream for EnetConnection {
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
     ) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.peer_event_stream)
-            .poll_next(ctx)
-            .map(|d| d.map(Ok))
+        if self.close_reason.is_some() {
+            return Poll::Ready(None);
+        }
+
+        match ready!(Pin::new(&mut self.peer_event_stream).poll_next(ctx)) {
+            Some(PeerEvent::Datagram(datagram)) => {
+                Poll::Ready(Some(Ok(datagram)))
+            }
+            Some(PeerEvent::Closed(reason)) => {
+                self.close_reason = Some(reason);
+                Poll::Ready(None)
+            }
+            None => Poll::Ready(None),
+        }
     }
 }
 
 impl Sink<SendCmd> for EnetConnection {
     type Error = Box<dyn std::error::Error>;
-    fn poll_ready(
-        mut self: Pin<&mut Self>,
-        ctx: &mut Context,
-    ) -> Poll<Result<()>> {
-        Pin::new(&mut self.command_sink)
-            .poll_ready(ctx)
-            .map_err(Into::into)
+    fn poll_ready(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        self.poll_ready_inner(ctx, None)
     }
     fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
         let channel = match item.delivery_mode {
-            DeliveryMode::ReliableOrdered(StreamId(channel)) => channel as u8,
+            DeliveryMode::ReliableOrdered(StreamId(channel)) => {
+                u8::try_from(channel).map_err(|_| {
+                    format!("ENet supports at most {} channels", MAX_CHANNELS)
+                })?
+            }
             _ => panic!("benchmark only supports reliable ordered datagrams"),
         };
 
+        self.link
+            .lock()
+            .expect("link")
+            .send_buffer
+            .push(item.delivery_mode, item.data.len());
         let peer = self.peer;
         Pin::new(&mut self.command_sink)
-            .start_send(EnetCmd {
+            .start_send(EnetCmd::Send {
                 peer,
                 channel,
+                delivery_mode: item.delivery_mode,
                 data: item.data,
             })
             .map_err(Into::into)
@@ -202,13 +413,8 @@ impl Sink<SendCmd> for EnetConnection {
             .poll_flush(ctx)
             .map_err(Into::into)
     }
-    fn poll_close(
-        mut self: Pin<&mut Self>,
-        ctx: &mut Context,
-    ) -> Poll<Result<()>> {
-        Pin::new(&mut self.command_sink)
-            .poll_close(ctx)
-            .map_err(Into::into)
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        self.poll_close_with(ctx, &Close::default())
     }
 }
 
@@ -250,25 +456,138 @@ impl HostType {
     }
 }
 
-fn enet_service_command(command: EnetCmd) {
-    let packet = unsafe {
-        enet::enet_packet_create(
-            command.data.as_ptr() as *const c_void,
-            command.data.len() as u64,
-            enet::_ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE,
-        )
-    };
+fn peer_stats(
+    peer: &enet::ENetPeer,
+    counters: &mut PeerCounters,
+) -> ConnectionStats {
+    // ENet limits reliable data in transit to its window, scaled by its
+    // throttle, but always allows one packet.
+    let throttled_window = peer.windowSize as u64 * peer.packetThrottle as u64
+        / PACKET_THROTTLE_SCALE;
+
+    ConnectionStats {
+        packets_sent: Some(counters.packets_sent.sample(peer.packetsSent)),
+        bytes_sent: Some(counters.bytes_sent.sample(peer.outgoingDataTotal)),
+        bytes_received: Some(
+            counters.bytes_received.sample(peer.incomingDataTotal),
+        ),
+        loss: Some(peer.packetLoss as f32 / PACKET_LOSS_SCALE),
+        smoothed_rtt: Some(Duration::from_millis(peer.roundTripTime as u64)),
+        rtt_variance: Some(Duration::from_millis(
+            peer.roundTripTimeVariance as u64,
+        )),
+        bytes_in_flight: Some(peer.reliableDataInTransit as u64),
+        congestion_window: Some(cmp::max(throttled_window, peer.mtu as u64)),
+        ..ConnectionStats::default()
+    }
+}
 
-    unsafe {
-        let peer = command.peer as *mut enet::ENetPeer;
-        enet::enet_peer_send(peer, command.channel, packet)
-    };
+/// A packet's place in its connection's send buffer, which it gives up when
+/// ENet frees it. ENet frees reliable packets once they are acknowledged.
+struct SentPacket {
+    link: Arc<Mutex<Link>>,
+    delivery_mode: DeliveryMode,
+    len: usize,
+}
+
+/// A packet ENet received, which is the application's to destroy. Datagrams
+/// share its data rather than copy it, and destroy it once dropped.
+struct ReceivedPacket(*mut enet::ENetPacket);
+
+// ENet is done with the packets it hands over, so any thread may destroy them.
+unsafe impl Send for ReceivedPacket {}
+
+impl AsRef<[u8]> for ReceivedPacket {
+    fn as_ref(&self) -> &[u8] {
+        let packet = unsafe { &*self.0 };
+        if packet.data.is_null() {
+            return &[];
+        }
+        unsafe {
+            std::slice::from_raw_parts(packet.data, packet.dataLength as usize)
+        }
+    }
+}
+
+impl Drop for ReceivedPacket {
+    fn drop(&mut self) {
+        unsafe { enet::enet_packet_destroy(self.0) };
+    }
+}
+
+unsafe extern "C" fn release_sent_packet(packet: *mut enet::ENetPacket) {
+    let sent = Box::from_raw((*packet).userData as *mut SentPacket);
+    sent.link
+        .lock()
+        .expect("link")
+        .send_buffer
+        .release(sent.delivery_mode, sent.len);
+}
+
+fn enet_service_command(
+    command: EnetCmd,
+    peers: &HashMap<*mut enet::ENetPeer, PeerState>,
+    disconnecting: &mut HashMap<*mut enet::ENetPeer, oneshot::Sender<()>>,
+) {
+    match command {
+        EnetCmd::Send {
+            peer,
+            channel,
+            delivery_mode,
+            data,
+        } => {
+            let peer = peer as *mut enet::ENetPeer;
+            let state = match peers.get(&peer) {
+                Some(state) => state,
+                None => return,
+            };
+
+            let packet = unsafe {
+                enet::enet_packet_create(
+                    data.as_ptr() as *const c_void,
+                    data.len() as u64,
+                    enet::_ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE,
+                )
+            };
+
+            unsafe {
+                (*packet).userData = Box::into_raw(Box::new(SentPacket {
+                    link: state.link.clone(),
+                    delivery_mode,
+                    len: data.len(),
+                })) as *mut c_void;
+                (*packet).freeCallback = Some(release_sent_packet);
+
+                // ENet only takes the packet if the send succeeds.
+                if enet::enet_peer_send(peer, channel, packet) < 0 {
+                    enet::enet_packet_destroy(packet);
+                }
+            };
+        }
+        EnetCmd::Disconnect {
+            peer,
+            code,
+            linger,
+            done,
+        } => {
+            let peer = peer as *mut enet::ENetPeer;
+            unsafe {
+                if linger {
+                    enet::enet_peer_disconnect_later(peer, code)
+                } else {
+                    enet::enet_peer_disconnect(peer, code)
+                }
+            };
+            disconnecting.insert(peer, done);
+        }
+    }
 }
 
 fn enet_service_loop(
     marker: Arc<()>,
     mut command_stream: mpsc::Receiver<EnetCmd>,
     new_peer_sink: mpsc::UnboundedSender<NewPeer>,
+    server_events: ServerEvents,
     host_type: HostType,
     server_addr: enet::ENetAddress,
 ) -> impl FnOnce() {
@@ -276,8 +595,9 @@ fn enet_service_loop(
         assert_eq!(unsafe { enet::enet_initialize() }, 0);
 
         let host = host_type.create(server_addr);
-        let mut total_sent = 0;
-        let mut peers = HashMap::new();
+        let mut total_sent: u32 = 0;
+        let mut peers: HashMap<*mut enet::ENetPeer, PeerState> = HashMap::new();
+        let mut disconnecting = HashMap::new();
         loop {
             if Arc::strong_count(&marker) == 1 {
                 return;
@@ -288,7 +608,14 @@ fn enet_service_loop(
                 Some(command) => command,
                 None => return,
             } {
-                enet_service_command(command);
+                enet_service_command(command, &peers, &mut disconnecting);
+            }
+
+            for (peer, state) in &mut peers {
+                let mut link = state.link.lock().expect("link");
+                link.stats =
+                    peer_stats(unsafe { &**peer }, &mut state.counters);
+                link.sample();
             }
 
             #[allow(deprecated)]
@@ -306,47 +633,86 @@ fn enet_service_loop(
                     enet::_ENetEventType_ENET_EVENT_TYPE_CONNECT => {
                         let (peer_event_sink, peer_event_stream) =
                             mpsc::unbounded();
+                        let peer_addr = enet_addr_to_socket_addr(unsafe {
+                            (*event.peer).address
+                        });
+                        let mut counters = PeerCounters::default();
+                        let mut link = Link::connected(peer_stats(
+                            unsafe { &*event.peer },
+                            &mut counters,
+                        ));
+                        server_events.attach(peer_addr, &mut link.events);
+                        let link = Arc::new(Mutex::new(link));
+                        let state = PeerState {
+                            peer_event_sink,
+                            link: link.clone(),
+                            counters,
+                        };
                         assert!(
-                            peers.insert(event.peer, peer_event_sink).is_none(),
+                            peers.insert(event.peer, state).is_none(),
                             "Peer already connected."
                         );
                         new_peer_sink
                             .unbounded_send(NewPeer {
                                 peer: event.peer as u64,
                                 peer_event_stream,
+                                link,
                             })
                             .expect("sending new peer event");
                     }
                     enet::_ENetEventType_ENET_EVENT_TYPE_DISCONNECT => {
-                        peers.remove(&event.peer);
+                        let state = match peers.remove(&event.peer) {
+                            Some(state) => state,
+                            None => continue,
+                        };
+                        let events =
+                            &mut state.link.lock().expect("link").events;
+                        match disconnecting.remove(&event.peer) {
+                            Some(done) => {
+                                events.report(ConnectionEvent::Disconnected(
+                                    None,
+                                ));
+                                let _ = done.send(());
+                            }
+                            None => {
+                                let reason = CloseReason {
+                                    code: event.data,
+                                    message: String::new(),
+                                };
+                                events.report(ConnectionEvent::Disconnected(
+                                    Some(reason.clone()),
+                                ));
+                                let _ = state
+                                    .peer_event_sink
+                                    .unbounded_send(PeerEvent::Closed(reason));
+                            }
+                        }
                     }
                     enet::_ENetEventType_ENET_EVENT_TYPE_RECEIVE => {
-                        let sink =
-                            peers.get_mut(&event.peer).expect("peer sink");
-                        let data: &'static [u8] = unsafe {
-                            let packet = &mut *event.packet;
-                            std::slice::from_raw_parts(
-                                packet.data,
-                                packet.dataLength as usize,
-                            )
-                        };
+                        let sink = &peers
+                            .get_mut(&event.peer)
+                            .expect("peer sink")
+                            .peer_event_sink;
+                        let packet = ReceivedPacket(event.packet);
                         if sink
-                            .unbounded_send(Datagram {
-                                data: data.to_vec(),
+                            .unbounded_send(PeerEvent::Datagram(Datagram {
+                                data: Bytes::from_owner(packet),
                                 stream_position: Some(StreamPosition {
-                                    stream_id: StreamId(event.channelID),
+                                    stream_id: StreamId(event.channelID.into()),
                                     index: StreamIndex::Ordinal(total_sent),
                                 }),
-                            })
+                            }))
                             .is_err()
                         {
                             drop(sink);
                             peers.remove(&event.peer);
                         }
 
-                        total_sent += 1;
+                        total_sent = total_sent.wrapping_add(1);
                     }
-                    e => println!("other event type: {:?}", e),
+                    // ENet has no other events, and only reports none when
+                    // there was nothing to service.
+                    _ => {}
                 }
             }
         }
@@ -9,20 +9,30 @@
 //!
 //! The server's socket sends the conv back to the client to establish
 //! connection.
+//!
+//! The TCP connection used for establishment stays open, and carries the close
+//! reason when either endpoint closes.
 
-use crate::{tcp, Result, *};
+use crate::{link::Link, tcp, Result, *};
 use async_std::net::*;
 use bincode::*;
+use bytes::BytesMut;
 use futures::{
-    channel::mpsc,
+    channel::{mpsc, oneshot},
+    future::FusedFuture,
     prelude::*,
+    ready,
     stream::{Fuse, FusedStream, LocalBoxStream, StreamExt},
 };
+use nhanh::events::ServerEvents;
 
+use std::cmp;
+use std::collections::VecDeque;
 use std::ffi::c_void;
 use std::os::raw::c_int;
 
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
 use std::task::{Context, Poll};
 use std::time::*;
@@ -51,9 +61,17 @@ impl KcpMode {
 
 pub struct KcpServer {
     peers: Fuse<LocalBoxStream<'static, Result<KcpConnection>>>,
+    events: ServerEvents,
 }
 
-impl Server<KcpConnection> for KcpServer {}
+impl Server<KcpConnection> for KcpServer {
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ServerEvent> {
+        self.events.subscribe(thresholds)
+    }
+}
 
 impl KcpServer {
     pub async fn bind(
@@ -61,9 +79,12 @@ impl KcpServer {
         address: impl ToSocketAddrs + Clone + 'static,
     ) -> Result<Self> {
         let tcp = tcp::TcpServer::bind(address.clone()).await?;
+        let events = ServerEvents::default();
 
+        let server_events = events.clone();
         let peers = tcp.then(move |tcp_connection| {
             let address = address.clone();
+            let server_events = server_events.clone();
             async move {
                 let mut tcp_connection = tcp_connection?;
 
@@ -72,7 +93,7 @@ impl KcpServer {
 
                 tcp_connection
                     .send(SendCmd {
-                        data: serialize(&port)?,
+                        data: serialize(&port)?.into(),
                         delivery_mode: DeliveryMode::ReliableOrdered(StreamId(
                             0,
                         )),
@@ -84,24 +105,30 @@ impl KcpServer {
                     .next()
                     .await
                     .expect("Confirmation of port")?;
-                let client_port: u16 =
-                    deserialize(client_port.data.as_slice())?;
+                let client_port: u16 = deserialize(&client_port.data)?;
 
                 let mut client_addr = tcp_connection.peer_addr();
                 client_addr.set_port(client_port);
 
-                Ok(KcpConnection::from_socket(
+                let connection = KcpConnection::from_socket(
                     mode,
                     tcp_connection,
                     udp,
                     client_addr,
                 )
-                .await)
+                .await;
+                server_events.attach(
+                    client_addr,
+                    &mut connection.link.lock().expect("link").events,
+                );
+
+                Ok(connection)
             }
         });
 
         Ok(Self {
             peers: peers.boxed_local().fuse(),
+            events,
         })
     }
 }
@@ -122,12 +149,24 @@ impl FusedStream for KcpServer {
     }
 }
 
+enum Command {
+    Send(SendCmd),
+    Close {
+        linger: bool,
+        done: oneshot::Sender<()>,
+    },
+}
+
 pub struct KcpConnection {
-    #[allow(unused)]
     tcp_connection: tcp::TcpConnection,
     receiver: mpsc::Receiver<Datagram>,
-    sender:
-        Pin<Box<dyn Sink<SendCmd, Error = Box<dyn std::error::Error>> + Unpin>>,
+    command_sink: mpsc::Sender<Command>,
+    /// Tells the driver to stop once the remote endpoint has closed.
+    remote_closed: Option<oneshot::Sender<()>>,
+    /// Resolves when the driver has stopped after a local close.
+    closing: Option<oneshot::Receiver<()>>,
+    close_reason: Option<CloseReason>,
+    link: Arc<Mutex<Link>>,
 }
 
 impl KcpConnection {
@@ -139,15 +178,14 @@ impl KcpConnection {
 
         let port =
             tcp_connection.next().await.expect("udp port from server")?;
-        let port: u16 = deserialize(port.data.as_slice())?;
+        let port: u16 = deserialize(&port.data)?;
         let _udp_addr = server.set_port(port);
 
-        let udp = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
-            .await?;
+        let udp = UdpSocket::bind(unspecified_address(server)).await?;
         let our_port = udp.local_addr()?.port();
         tcp_connection
             .send(SendCmd {
-                data: serialize(&our_port)?,
+                data: serialize(&our_port)?.into(),
                 delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
                 ..SendCmd::default()
             })
@@ -156,6 +194,24 @@ impl KcpConnection {
         Ok(Self::from_socket(mode, tcp_connection, udp, server).await)
     }
 
+    /// Waits for room in the send buffer, which the driver releases as KCP's
+    /// segments are acknowledged.
+    fn poll_ready_inner(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        delivery_mode: Option<DeliveryMode>,
+    ) -> Poll<Result<()>> {
+        ready!(self
+            .link
+            .lock()
+            .expect("link")
+            .send_buffer
+            .poll_ready(ctx, delivery_mode));
+        Pin::new(&mut self.command_sink)
+            .poll_ready(ctx)
+            .map_err(Into::into)
+    }
+
     async fn from_socket(
         mode: KcpMode,
         tcp_connection: tcp::TcpConnection,
@@ -164,16 +220,36 @@ impl KcpConnection {
     ) -> Self {
         let (command_sink, command_stream) = mpsc::channel(100);
         let (datagram_sink, datagram_stream) = mpsc::channel(100);
+        let (remote_closed, remote_closed_stream) = oneshot::channel();
+        let link = Arc::new(Mutex::new(Link::connected(ConnectionStats {
+            packets_sent: Some(0),
+            packets_received: Some(0),
+            bytes_sent: Some(0),
+            bytes_received: Some(0),
+            ..ConnectionStats::default()
+        })));
 
         async_std::task::spawn(
-            Self::driver(mode, socket, peer, command_stream, datagram_sink)
-                .map(drop),
+            Self::driver(
+                mode,
+                socket,
+                peer,
+                command_stream,
+                datagram_sink,
+                remote_closed_stream,
+                link.clone(),
+            )
+            .map(drop),
         );
 
         Self {
             tcp_connection,
             receiver: datagram_stream,
-            sender: Pin::new(Box::new(command_sink.sink_err_into())),
+            command_sink,
+            remote_closed: Some(remote_closed),
+            closing: None,
+            close_reason: None,
+            link,
         }
     }
 
@@ -181,8 +257,10 @@ impl KcpConnection {
         mode: KcpMode,
         socket: UdpSocket,
         peer: SocketAddr,
-        mut command_stream: mpsc::Receiver<SendCmd>,
+        mut command_stream: mpsc::Receiver<Command>,
         datagram_sink: mpsc::Sender<Datagram>,
+        mut remote_closed: oneshot::Receiver<()>,
+        link: Arc<Mutex<Link>>,
     ) -> Result<()> {
         socket.connect(peer).await?;
 
@@ -193,7 +271,12 @@ impl KcpConnection {
                 };
                 match async_std::task::block_on(socket.send(data)) {
                     Err(_) => -1,
-                    Ok(v) => v as i32,
+                    Ok(v) => {
+                        let stats = &mut link.lock().expect("link").stats;
+                        count(&mut stats.packets_sent, 1);
+                        count(&mut stats.bytes_sent, v as u64);
+                        v as i32
+                    }
                 }
             };
         let cb = {
@@ -223,6 +306,10 @@ impl KcpConnection {
             datagram_sink,
             cb,
             sequence_number: 0,
+            link: link.clone(),
+            congestion_window: 0,
+            segments_sent: 0,
+            unacknowledged: VecDeque::new(),
         };
 
         let mut buffer = [0u8; 65535];
@@ -234,20 +321,17 @@ impl KcpConnection {
 
             futures::select! {
                 read_result = socket.recv(&mut buffer).fuse() => {
-                    {
-                        let len = read_result?;
-                        let data = buffer.as_ptr() as *const i8;
-                        let code = unsafe {
-                            kcp::ikcp_input(cb.0, data, len as i64)
-                        };
-                        if code < 0 {
-                            panic!("kcp panic; input error: {:?}", code);
-                        }
-                    }
-
+                    input(cb, &buffer[..read_result?], &link);
+                    servicer.service().await;
+                }
+                _ = remote_closed => {
+                    // Surface anything the remote endpoint delivered before
+                    // closing.
                     servicer.service().await;
+                    return Ok(());
                 }
-                send_cmd = command_stream.select_next_some() => unsafe {
+                command = command_stream.select_next_some() => match command {
+                    Command::Send(send_cmd) => unsafe {
                             match send_cmd.delivery_mode {
                                 DeliveryMode::ReliableOrdered(StreamId(0)) => {},
                                 _ => panic!("KCP only supports a single reliable channel"),
@@ -265,7 +349,26 @@ impl KcpConnection {
                                 }
                             }
 
+                            servicer.sent(send_cmd.delivery_mode, send_cmd.data.len());
+
                             servicer.service().await;
+                    },
+                    Command::Close { linger, done } => {
+                        while linger && unsafe { kcp::ikcp_waitsnd(cb.0) } > 0 {
+                            futures::select! {
+                                read_result = socket.recv(&mut buffer).fuse() => {
+                                    input(cb, &buffer[..read_result?], &link);
+                                    servicer.service().await;
+                                }
+                                _ = service_ticker.select_next_some() => {
+                                    servicer.service().await
+                                }
+                            }
+                        }
+
+                        let _ = done.send(());
+                        return Ok(());
+                    }
                 },
                 _ = service_ticker.select_next_some() => servicer.service().await,
             }
@@ -273,6 +376,21 @@ impl KcpConnection {
     }
 }
 
+fn input(cb: Cb, data: &[u8], link: &Mutex<Link>) {
+    {
+        let stats = &mut link.lock().expect("link").stats;
+        count(&mut stats.packets_received, 1);
+        count(&mut stats.bytes_received, data.len() as u64);
+    }
+
+    let code = unsafe {
+        kcp::ikcp_input(cb.0, data.as_ptr() as *const i8, data.len() as i64)
+    };
+    if code < 0 {
+        panic!("kcp panic; input error: {:?}", code);
+    }
+}
+
 #[derive(Copy, Clone)]
 struct Cb(*mut kcp::ikcpcb);
 
@@ -283,6 +401,14 @@ struct KcpServicer {
     datagram_sink: mpsc::Sender<Datagram>,
     cb: Cb,
     sequence_number: u32,
+    link: Arc<Mutex<Link>>,
+    congestion_window: u32,
+    /// The number of segments given to KCP so far, which is also the sequence
+    /// number of the next segment.
+    segments_sent: u32,
+    /// Datagrams given to KCP, with the sequence number after their last
+    /// segment.
+    unacknowledged: VecDeque<(u32, DeliveryMode, usize)>,
 }
 
 impl KcpServicer {
@@ -292,65 +418,205 @@ impl KcpServicer {
 
     async fn service(&mut self) {
         unsafe { kcp::ikcp_update(self.cb.0, self.current_time_ms()) };
+        self.observe();
+        self.release_acknowledged();
 
-        let mut buffer = [0; 65535];
-        #[allow(unused_assignments)]
-        let mut len = i32::default();
-
-        while {
+        // Each datagram is received into a buffer of its size, which it then
+        // owns.
+        let mut buffer = BytesMut::new();
+        loop {
+            let size = unsafe { kcp::ikcp_peeksize(self.cb.0) };
+            if size <= 0 {
+                break;
+            }
+            buffer.resize(size as usize, 0);
             let buffer_ptr = buffer.as_mut_ptr() as *mut i8;
-            len = unsafe {
-                kcp::ikcp_recv(self.cb.0, buffer_ptr, buffer.len() as i32)
-            };
-            len > 0
-        } {
+            let len = unsafe { kcp::ikcp_recv(self.cb.0, buffer_ptr, size) };
+            if len <= 0 {
+                break;
+            }
             let _ = self
                 .datagram_sink
                 .send(Datagram {
-                    data: buffer[0..(len as usize)].to_vec(),
+                    data: buffer.split_to(len as usize).freeze(),
                     stream_position: Some(StreamPosition {
                         stream_id: StreamId(0),
                         index: StreamIndex::Ordinal(self.sequence_number),
                     }),
                 })
                 .await;
-            self.sequence_number += 1;
+            self.sequence_number = self.sequence_number.wrapping_add(1);
         }
     }
 }
 
-impl Connection for KcpConnection {}
+impl KcpServicer {
+    /// Records a datagram given to KCP, which splits it into segments of at
+    /// most `mss` bytes.
+    fn sent(&mut self, delivery_mode: DeliveryMode, len: usize) {
+        let mss = unsafe { (*self.cb.0).mss } as usize;
+        let segments = cmp::max(1, (len + mss - 1) / mss);
+        self.segments_sent = self.segments_sent.wrapping_add(segments as u32);
+        self.unacknowledged
+            .push_back((self.segments_sent, delivery_mode, len));
+    }
 
-impl Sink<SendCmd> for KcpConnection {
-    type Error = Box<dyn std::error::Error>;
-    fn poll_ready(
+    /// Releases datagrams from the send buffer once all of their segments
+    /// are acknowledged.
+    fn release_acknowledged(&mut self) {
+        let una = unsafe { (*self.cb.0).snd_una };
+        let mut link = self.link.lock().expect("link");
+        while let Some(&(end, delivery_mode, len)) = self.unacknowledged.front()
+        {
+            // Sequence numbers wrap, so compare them as KCP does.
+            if (una.wrapping_sub(end) as i32) < 0 {
+                break;
+            }
+            link.send_buffer.release(delivery_mode, len);
+            self.unacknowledged.pop_front();
+        }
+    }
+
+    fn observe(&mut self) {
+        let cb: kcp::ikcpcb = unsafe { *self.cb.0 };
+        let congestion_control = cb.nocwnd == 0;
+        let srtt = match cb.rx_srtt {
+            0 => None,
+            srtt => Some(Duration::from_millis(srtt as u64)),
+        };
+
+        // Without congestion control, KCP is limited only by the send and
+        // receive windows.
+        let window = cmp::min(cb.snd_wnd, cb.rmt_wnd);
+        let window = if congestion_control {
+            cmp::min(window, cb.cwnd)
+        } else {
+            window
+        };
+
+        let mut link = self.link.lock().expect("link");
+        link.stats.retransmissions = Some(cb.xmit as u64);
+        link.stats.smoothed_rtt = srtt;
+        link.stats.rtt_variance =
+            srtt.map(|_| Duration::from_millis(cb.rx_rttval as u64));
+        link.stats.send_queue_depth = Some(cb.nsnd_que as u64);
+        link.stats.congestion_window = Some(window as u64 * cb.mss as u64);
+        link.sample();
+
+        // KCP resets its window to a single segment when a retransmission
+        // times out.
+        if congestion_control && cb.cwnd == 1 && self.congestion_window > 1 {
+            link.events
+                .report(ConnectionEvent::CongestionWindowCollapsed);
+        }
+        self.congestion_window = cb.cwnd;
+    }
+}
+
+impl Connection for KcpConnection {
+    fn poll_close_with(
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
+        close: &Close,
     ) -> Poll<Result<()>> {
-        Pin::new(&mut self.sender)
-            .poll_ready(ctx)
-            .map_err(Into::into)
+        if self.closing.is_none() {
+            let (done, closing) = oneshot::channel();
+            // If the driver has already stopped, there is nothing to linger on
+            // and dropping `done` resolves `closing`.
+            if ready!(Pin::new(&mut self.command_sink).poll_ready(ctx)).is_ok()
+            {
+                let _ = Pin::new(&mut self.command_sink).start_send(
+                    Command::Close {
+                        linger: close.linger,
+                        done,
+                    },
+                );
+            }
+            self.closing = Some(closing);
+            self.link
+                .lock()
+                .expect("link")
+                .events
+                .report(ConnectionEvent::Disconnecting);
+        }
+
+        if let Some(closing) = self.closing.as_mut() {
+            if !closing.is_terminated() {
+                let _ = ready!(Pin::new(closing).poll(ctx));
+            }
+        }
+
+        ready!(Pin::new(&mut self.tcp_connection).poll_close_with(ctx, close))?;
+        self.link
+            .lock()
+            .expect("link")
+            .events
+            .report(ConnectionEvent::Disconnected(None));
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_ready_for(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        delivery_mode: DeliveryMode,
+    ) -> Poll<Result<()>> {
+        self.poll_ready_inner(ctx, Some(delivery_mode))
+    }
+
+    fn set_send_limits(&mut self, limits: SendLimits) {
+        self.link
+            .lock()
+            .expect("link")
+            .send_buffer
+            .set_limits(limits);
+    }
+
+    fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ConnectionEvent> {
+        self.link.lock().expect("link").events.subscribe(thresholds)
+    }
+
+    /// KCP does not estimate loss, and does not count bytes in flight.
+    fn stats(&self) -> ConnectionStats {
+        self.link.lock().expect("link").stats.clone()
+    }
+
+    fn bandwidth(&self) -> BandwidthEstimate {
+        self.link.lock().expect("link").bandwidth()
+    }
+}
+
+impl Sink<SendCmd> for KcpConnection {
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        self.poll_ready_inner(ctx, None)
     }
     fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
-        Pin::new(&mut self.sender)
-            .start_send(item)
+        self.link
+            .lock()
+            .expect("link")
+            .send_buffer
+            .push(item.delivery_mode, item.data.len());
+        Pin::new(&mut self.command_sink)
+            .start_send(Command::Send(item))
             .map_err(Into::into)
     }
     fn poll_flush(
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
     ) -> Poll<Result<()>> {
-        Pin::new(&mut self.sender)
+        Pin::new(&mut self.command_sink)
             .poll_flush(ctx)
             .map_err(Into::into)
     }
-    fn poll_close(
-        mut self: Pin<&mut Self>,
-        ctx: &mut Context,
-    ) -> Poll<Result<()>> {
-        Pin::new(&mut self.sender)
-            .poll_close(ctx)
-            .map_err(Into::into)
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        self.poll_close_with(ctx, &Close::default())
     }
 }
 
@@ -360,15 +626,39 @@ impl Stream for KcpConnection {
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
     ) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.receiver)
-            .poll_next(ctx)
-            .map(|d| d.map(Ok))
+        while !self.tcp_connection.is_terminated() {
+            match Pin::new(&mut self.tcp_connection).poll_next(ctx) {
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    self.close_reason =
+                        self.tcp_connection.close_reason().cloned();
+                    if let Some(remote_closed) = self.remote_closed.take() {
+                        let _ = remote_closed.send(());
+                    }
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        match ready!(Pin::new(&mut self.receiver).poll_next(ctx)) {
+            Some(datagram) => Poll::Ready(Some(Ok(datagram))),
+            None => {
+                let reason = self.close_reason.clone();
+                self.link
+                    .lock()
+                    .expect("link")
+                    .events
+                    .report(ConnectionEvent::Disconnected(reason));
+                Poll::Ready(None)
+            }
+        }
     }
 }
 
 impl FusedStream for KcpConnection {
     fn is_terminated(&self) -> bool {
-        false
+        self.receiver.is_terminated()
     }
 }
 
@@ -1,26 +1,111 @@
 #![recursion_limit = "512"]
 
-use async_std::net::SocketAddr;
+use async_std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use nhanh::*;
 use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
 
 use structopt::StructOpt;
+use thiserror::Error;
 
 pub mod enet;
 pub mod kcp;
+pub mod link;
+pub mod miknet;
+pub mod send_buffer;
 pub mod tcp;
 
 pub mod client;
 pub mod runner;
 pub mod server;
 
-pub const ALL_PROTOCOLS: [Protocol; 3] =
-    [Protocol::Tcp, Protocol::Enet, Protocol::Kcp];
+pub const ALL_PROTOCOLS: [Protocol; 8] = [
+    Protocol::Tcp,
+    Protocol::Enet,
+    Protocol::Kcp,
+    Protocol::Miknet,
+    Protocol::MiknetCoalesced,
+    Protocol::MiknetFec,
+    Protocol::MiknetCompressed,
+    Protocol::MiknetUnbatched,
+];
+
+/// A sample of the records in `Payload::Entities` datagrams, which compressed
+/// miknet connections prime compression with.
+pub const ENTITY_DICTIONARY: &[u8] = b"entity=0 x=0.00 y=0.00 z=0.00 hp=100;\
+entity=1 x=12.50 y=-3.25 z=0.00 hp=75;entity=2 x=-7.75 y=40.00 z=1.50 hp=100;";
 
 pub const ID_DO_NOT_RETURN: u64 = u64::max_value();
 
-pub fn default_server_address() -> SocketAddr {
-    "127.0.0.1:33333".parse().unwrap()
+/// The stream clock exchanges go on for protocols without unreliable
+/// delivery, beyond the streams the scenarios use.
+pub const CLOCK_STREAM: StreamId = StreamId(128);
+
+pub fn default_server_address(ipv6: bool) -> SocketAddr {
+    let ip: IpAddr = match ipv6 {
+        true => Ipv6Addr::LOCALHOST.into(),
+        false => Ipv4Addr::LOCALHOST.into(),
+    };
+    (ip, 33333).into()
+}
+
+/// Returns the wildcard address of `peer`'s family, on any port, for a socket
+/// to reach `peer` from.
+pub fn unspecified_address(peer: SocketAddr) -> SocketAddr {
+    let ip: IpAddr = match peer {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    (ip, 0).into()
+}
+
+/// Something an adapter's protocol cannot do.
+#[derive(Debug, Error)]
+#[error("{protocol:?} does not support {feature}")]
+pub struct Unsupported {
+    pub protocol: Protocol,
+    pub feature: &'static str,
+}
+
+/// The runtime the bench runs on, chosen by the `runtime-tokio`,
+/// `runtime-smol` and `runtime-async-std` features in that order of
+/// preference. The miknet adapter drives its sessions on it. The other
+/// adapters use async-std sockets, whose reactor runs on its own thread, so
+/// they run on any of them.
+#[cfg(feature = "runtime-tokio")]
+pub type MiknetRuntime = ::miknet::runtime::TokioRuntime;
+#[cfg(all(feature = "runtime-smol", not(feature = "runtime-tokio")))]
+pub type MiknetRuntime = ::miknet::runtime::SmolRuntime;
+#[cfg(not(any(feature = "runtime-tokio", feature = "runtime-smol")))]
+pub type MiknetRuntime = ::miknet::runtime::AsyncStdRuntime;
+
+/// Runs a future to completion on `MiknetRuntime`.
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    #[cfg(feature = "runtime-tokio")]
+    return tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime")
+        .block_on(future);
+    #[cfg(all(feature = "runtime-smol", not(feature = "runtime-tokio")))]
+    return smol::block_on(future);
+    #[cfg(not(any(feature = "runtime-tokio", feature = "runtime-smol")))]
+    return async_std::task::block_on(future);
+}
+
+/// Adds `n` to a counter in `ConnectionStats`.
+pub fn count(counter: &mut Option<u64>, n: u64) {
+    *counter.get_or_insert(0) += n;
+}
+
+/// Returns the CPU time the process has spent, in user and kernel space.
+pub fn cpu_time() -> std::time::Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    let duration = |time: libc::timeval| {
+        std::time::Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+    };
+    duration(usage.ru_utime) + duration(usage.ru_stime)
 }
 
 /// Returns a stream that yields `()` `hertz` times per second.
@@ -38,11 +123,72 @@ pub enum Protocol {
     Enet,
     Kcp,
     KcpTurbo,
+    Miknet,
+    MiknetCoalesced,
+    MiknetFec,
+    MiknetCompressed,
+    MiknetUnbatched,
+}
+
+impl Protocol {
+    /// How the client exchanges timestamps with the server's clock, on a
+    /// stream of their own. KCP has only the one stream, so its round trips
+    /// are not split.
+    pub fn clock_delivery_mode(self) -> Option<DeliveryMode> {
+        match self {
+            Protocol::Kcp | Protocol::KcpTurbo => None,
+            Protocol::Tcp | Protocol::Enet => {
+                Some(DeliveryMode::ReliableOrdered(CLOCK_STREAM))
+            }
+            _ => Some(DeliveryMode::UnreliableUnordered),
+        }
+    }
+
+    pub fn is_miknet(self) -> bool {
+        match self {
+            Protocol::Tcp
+            | Protocol::Enet
+            | Protocol::Kcp
+            | Protocol::KcpTurbo => false,
+            _ => true,
+        }
+    }
+}
+
+/// Returns the delivery mode to answer a datagram in, if it is a clock
+/// exchange rather than a `BenchmarkDatagram`.
+pub fn clock_exchange(datagram: &Datagram) -> Option<DeliveryMode> {
+    match datagram.stream_position {
+        None => Some(DeliveryMode::UnreliableUnordered),
+        Some(position) if position.stream_id == CLOCK_STREAM => {
+            Some(DeliveryMode::ReliableOrdered(CLOCK_STREAM))
+        }
+        Some(_) => None,
+    }
 }
 
+/// A datagram of a transfer. `bincode` encodes integers at a fixed width, so
+/// the server time and the id lead a serialized datagram at fixed offsets, and
+/// the server echoes datagrams by patching the time in place.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BenchmarkDatagram {
-    pub delivery_mode: DeliveryMode,
+    /// The time on the server's clock when it returned the datagram, in
+    /// microseconds.
+    pub server_time_us: u64,
     pub id: u64,
+    pub delivery_mode: DeliveryMode,
     pub data: Vec<u8>,
 }
+
+impl BenchmarkDatagram {
+    /// Returns the id of a serialized datagram.
+    pub fn id_of(serialized: &[u8]) -> Option<u64> {
+        let id = serialized.get(8..16)?;
+        Some(u64::from_le_bytes(id.try_into().expect("8 bytes")))
+    }
+
+    /// Sets the server time of a serialized datagram.
+    pub fn stamp(serialized: &mut [u8], server_time_us: u64) {
+        serialized[..8].copy_from_slice(&server_time_us.to_le_bytes());
+    }
+}
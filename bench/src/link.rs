@@ -0,0 +1,43 @@
+//! Link state which adapters share between connections and the tasks or
+//! threads which drive them.
+
+use crate::send_buffer::SendBuffer;
+use nhanh::{events::*, *};
+
+#[derive(Debug)]
+pub struct Link {
+    pub events: Events,
+    pub stats: ConnectionStats,
+    pub send_buffer: SendBuffer,
+    estimator: BandwidthEstimator,
+}
+
+impl Link {
+    /// Returns the link of a connection whose handshake is complete.
+    pub fn connected(stats: ConnectionStats) -> Self {
+        Self {
+            events: Events::connected(),
+            stats,
+            send_buffer: SendBuffer::default(),
+            estimator: BandwidthEstimator::default(),
+        }
+    }
+
+    /// Returns the link of a connection whose handshake is in progress.
+    pub fn connecting(stats: ConnectionStats) -> Self {
+        Self {
+            events: Events::default(),
+            ..Self::connected(stats)
+        }
+    }
+
+    pub fn bandwidth(&self) -> BandwidthEstimate {
+        self.estimator.estimate()
+    }
+
+    /// Updates estimates from `stats`, and reports any thresholds crossed.
+    pub fn sample(&mut self) {
+        let sample = self.estimator.sample(&self.stats);
+        self.events.observe(sample);
+    }
+}
@@ -47,6 +47,7 @@ impl Scenario {
             network_config: self.network_config.clone(),
             client_options,
             start_server: true,
+            ipv6: false,
             output: None,
         };
 
@@ -101,7 +102,7 @@ impl Serialize for Comparison {
         S: Serializer,
     {
         let network_config_fields = 6;
-        let report_fields = 2;
+        let report_fields = 13;
         let summary_fields = 2;
         let total_fields = network_config_fields
             + report_fields * self.reports.len()
@@ -145,6 +146,65 @@ impl Serialize for Comparison {
                 ))),
                 &report.deviation_ms,
             )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{:?}_mean_upstream_ms", protocol))),
+                &report.mean_upstream_ms,
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{:?}_mean_downstream_ms",
+                    protocol
+                ))),
+                &report.mean_downstream_ms,
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{:?}_bytes_sent", protocol))),
+                &report.stats.bytes_sent,
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{:?}_overhead", protocol))),
+                &report.overhead(),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{:?}_bytes_per_datagram",
+                    protocol
+                ))),
+                &report.bytes_per_datagram(),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{:?}_retransmissions", protocol))),
+                &report.stats.retransmissions,
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{:?}_recovered", protocol))),
+                &report.stats.recovered,
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{:?}_packets_per_second",
+                    protocol
+                ))),
+                &report.packets_per_second(),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{:?}_cpu_us_per_datagram",
+                    protocol
+                ))),
+                &report.cpu_per_datagram_us(),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{:?}_syscalls_per_datagram",
+                    protocol
+                ))),
+                &report.syscalls_per_datagram(),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{:?}_snapshot_savings", protocol))),
+                &report.snapshot_savings(),
+            )?;
         }
 
         state.serialize_field("least_latent", &self.least_latent)?;
@@ -212,6 +272,7 @@ fn scenarios() -> Vec<Scenario> {
                     size: 200,
                     hertz: 60,
                     return_count: DEFAULT_RETURN_COUNT,
+                    payload: client::Payload::Zeros,
                 }],
             },
             network_config: runner::NetworkConfig::default(),
@@ -226,12 +287,14 @@ fn scenarios() -> Vec<Scenario> {
                         size: 200,
                         hertz: 60,
                         return_count: DEFAULT_RETURN_COUNT,
+                        payload: client::Payload::Zeros,
                     },
                     client::Transfer {
                         stream_id: StreamId(1),
                         size: 200,
                         hertz: 240,
                         return_count: None,
+                        payload: client::Payload::Zeros,
                     },
                 ],
             },
@@ -247,12 +310,14 @@ fn scenarios() -> Vec<Scenario> {
                         size: 200,
                         hertz: 60,
                         return_count: DEFAULT_RETURN_COUNT,
+                        payload: client::Payload::Zeros,
                     },
                     client::Transfer {
                         stream_id: StreamId(1),
                         size: 200,
                         hertz: 240,
                         return_count: None,
+                        payload: client::Payload::Zeros,
                     },
                 ],
             },
@@ -269,6 +334,7 @@ fn scenarios() -> Vec<Scenario> {
                     size: 200,
                     hertz: 60,
                     return_count: DEFAULT_RETURN_COUNT,
+                    payload: client::Payload::Zeros,
                 }],
             },
             network_config: runner::NetworkConfig {
@@ -276,6 +342,74 @@ fn scenarios() -> Vec<Scenario> {
                 ..Default::default()
             },
         },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "transfer_0_200B_entities_60Hz-full_bandwidth",
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    size: 200,
+                    hertz: 60,
+                    return_count: DEFAULT_RETURN_COUNT,
+                    payload: client::Payload::Entities,
+                }],
+            },
+            network_config: runner::NetworkConfig::default(),
+        },
+        // The same world's snapshots whole and in deltas, to compare the
+        // bandwidth of each.
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "transfer_0_960B_snapshots_60Hz-full_bandwidth",
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    size: 960,
+                    hertz: 60,
+                    return_count: DEFAULT_RETURN_COUNT,
+                    payload: client::Payload::Snapshots,
+                }],
+            },
+            network_config: runner::NetworkConfig::default(),
+        },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name:
+                    "transfer_0_960B_snapshot_deltas_60Hz-full_bandwidth",
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    size: 960,
+                    hertz: 60,
+                    return_count: DEFAULT_RETURN_COUNT,
+                    payload: client::Payload::SnapshotDeltas,
+                }],
+            },
+            network_config: runner::NetworkConfig::default(),
+        },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name:
+                    "transfer_0_200B_entities_60Hz-transfer_1_200B_random_240Hz-1024kbps",
+                transfers: vec![
+                    client::Transfer {
+                        stream_id: StreamId(0),
+                        size: 200,
+                        hertz: 60,
+                        return_count: DEFAULT_RETURN_COUNT,
+                        payload: client::Payload::Entities,
+                    },
+                    client::Transfer {
+                        stream_id: StreamId(1),
+                        size: 200,
+                        hertz: 240,
+                        return_count: None,
+                        payload: client::Payload::Random,
+                    },
+                ],
+            },
+            network_config: runner::NetworkConfig {
+                rate_limit_kbps: 1024,
+                ..Default::default()
+            },
+        },
     ]
 }
 
@@ -288,43 +422,44 @@ struct Options {
     output: String,
 }
 
-#[async_std::main]
-async fn main() {
-    let options = Options::from_args();
-
-    let scenarios = scenarios();
-    let scenarios = scenarios.into_iter().filter(|s| {
-        options
-            .scenario_filter
-            .as_ref()
-            .map(|pattern| {
-                s.netcode_scenario
-                    .scenario_name
-                    .matches(pattern.as_str())
-                    .next()
-                    .is_some()
-            })
-            .unwrap_or(true)
-    });
-
-    let mut simulation_data = SimulationData::default();
-
-    let mut port = 1025;
-    for scenario in scenarios {
-        let mut reports = HashMap::new();
-        for protocol in &ALL_PROTOCOLS {
-            let report = scenario.run(port, *protocol).await;
-            reports.insert(*protocol, report);
-            port += 1;
-            std::thread::sleep(std::time::Duration::from_secs(1));
+fn main() {
+    bench::block_on(async {
+        let options = Options::from_args();
+
+        let scenarios = scenarios();
+        let scenarios = scenarios.into_iter().filter(|s| {
+            options
+                .scenario_filter
+                .as_ref()
+                .map(|pattern| {
+                    s.netcode_scenario
+                        .scenario_name
+                        .matches(pattern.as_str())
+                        .next()
+                        .is_some()
+                })
+                .unwrap_or(true)
+        });
+
+        let mut simulation_data = SimulationData::default();
+
+        let mut port = 1025;
+        for scenario in scenarios {
+            let mut reports = HashMap::new();
+            for protocol in &ALL_PROTOCOLS {
+                let report = scenario.run(port, *protocol).await;
+                reports.insert(*protocol, report);
+                port += 1;
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+            let comparison =
+                Comparison::from_reports(scenario.clone(), reports.clone());
+            simulation_data.scenarios.insert(
+                scenario.netcode_scenario.scenario_name,
+                (comparison, reports),
+            );
         }
-        let comparison =
-            Comparison::from_reports(scenario.clone(), reports.clone());
-        simulation_data.scenarios.insert(
-            scenario.netcode_scenario.scenario_name,
-            (comparison, reports),
-        );
-    }
 
-    simulation_data.write_out(options.output);
+        simulation_data.write_out(options.output);
+    })
 }
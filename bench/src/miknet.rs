@@ -0,0 +1,112 @@
+//! miknet adapter for benchmarking.
+//!
+//! miknet's driver runs its sessions on a UDP socket of the `MiknetRuntime`
+//! the bench was built for, and implements the `nhanh` api itself. Each
+//! benchmarked protocol configures it differently.
+
+use crate::{Result, *};
+use ::miknet::{
+    batch::BATCH_SIZE, CompressionConfig, Config, DriverConfig, FecConfig,
+};
+use async_std::net::SocketAddr;
+use std::time::Duration;
+
+pub type MiknetConnection = ::miknet::Connection;
+pub type MiknetServer = ::miknet::Server;
+
+#[derive(Debug, Clone, Copy)]
+pub enum MiknetMode {
+    /// Datagrams are sent as soon as they are buffered.
+    Immediate,
+    /// Datagrams wait up to 5ms to share packets.
+    Coalesced,
+    /// Datagrams are sent as soon as they are buffered, with forward error
+    /// correction.
+    Fec,
+    /// Datagrams are sent as soon as they are buffered, compressed with
+    /// `ENTITY_DICTIONARY`.
+    Compressed,
+    /// Datagrams are sent as soon as they are buffered, and packets are sent
+    /// and received one per syscall.
+    Unbatched,
+}
+
+impl MiknetMode {
+    fn config(self) -> DriverConfig {
+        let (max_coalesce_delay, fec) = match self {
+            MiknetMode::Immediate
+            | MiknetMode::Compressed
+            | MiknetMode::Unbatched => (Duration::from_millis(0), None),
+            MiknetMode::Coalesced => (Duration::from_millis(5), None),
+            MiknetMode::Fec => {
+                (Duration::from_millis(0), Some(FecConfig::default()))
+            }
+        };
+        let compression = match self {
+            MiknetMode::Compressed => Some(CompressionConfig {
+                dictionary: ENTITY_DICTIONARY,
+                ..CompressionConfig::default()
+            }),
+            _ => None,
+        };
+        let session = Config {
+            max_coalesce_delay,
+            fec,
+            compression,
+            ..Config::default()
+        };
+        let batch_size = match self {
+            MiknetMode::Unbatched => 1,
+            _ => BATCH_SIZE,
+        };
+        DriverConfig {
+            session,
+            batch_size,
+            ..DriverConfig::default()
+        }
+    }
+}
+
+pub async fn connect(
+    mode: MiknetMode,
+    server: SocketAddr,
+) -> Result<MiknetConnection> {
+    MiknetConnection::connect(MiknetRuntime::default(), mode.config(), server)
+        .await
+}
+
+/// Binds a socket to `address`. The socket is dual-stack if `address` is
+/// `[::]`.
+pub fn bind(mode: MiknetMode, address: SocketAddr) -> Result<MiknetServer> {
+    Ok(MiknetServer::bind(
+        MiknetRuntime::default(),
+        mode.config(),
+        address,
+    )?)
+}
+
+/// Binds an IPv4 socket and an IPv6 only socket to `port`, for systems where
+/// sockets cannot be dual-stack.
+pub fn bind_paired(mode: MiknetMode, port: u16) -> Result<MiknetServer> {
+    Ok(MiknetServer::bind_paired(
+        MiknetRuntime::default(),
+        mode.config(),
+        port,
+    )?)
+}
+
+/// Binds `workers` sockets to `address` with `SO_REUSEPORT`, each driven by
+/// its own task.
+#[cfg(target_os = "linux")]
+pub fn bind_sharded(
+    mode: MiknetMode,
+    address: SocketAddr,
+    workers: usize,
+) -> Result<MiknetServer> {
+    Ok(MiknetServer::bind_sharded(
+        MiknetRuntime::default(),
+        mode.config(),
+        address,
+        workers,
+    )?)
+}
@@ -99,6 +99,10 @@ pub struct Options {
     /// server address.
     #[structopt(long)]
     pub start_server: bool,
+    /// Whether to benchmark over `::1`, on the port of the client's expected
+    /// server address.
+    #[structopt(long)]
+    pub ipv6: bool,
     #[structopt(long, short = "o")]
     pub output: Option<String>,
 }
@@ -111,6 +115,8 @@ async fn run(options: &Options) -> Result<client::Summary> {
     let server_options = server::Options {
         address: options.client_options.address,
         protocol: options.client_options.protocol,
+        workers: 1,
+        paired: false,
     };
 
     let run_server = async move {
@@ -130,7 +136,13 @@ async fn run(options: &Options) -> Result<client::Summary> {
     }
 }
 
-pub async fn runner_main(options: Options) -> Result<client::Summary> {
+pub async fn runner_main(mut options: Options) -> Result<client::Summary> {
+    if options.ipv6 {
+        let port = options.client_options.address.port();
+        options.client_options.address = default_server_address(true);
+        options.client_options.address.set_port(port);
+    }
+
     options.network_config.reset();
     options.network_config.apply();
 
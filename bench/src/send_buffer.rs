@@ -0,0 +1,102 @@
+//! Accounting of the datagrams adapters buffer for sending.
+
+use nhanh::*;
+use std::{
+    collections::HashMap,
+    task::{Context, Poll, Waker},
+};
+
+/// The datagrams a connection has buffered for sending, measured against its
+/// `SendLimits`.
+#[derive(Debug, Default)]
+pub struct SendBuffer {
+    limits: SendLimits,
+    bytes: usize,
+    datagrams: usize,
+    /// Bytes buffered for each reliable stream.
+    streams: HashMap<DeliveryMode, usize>,
+    waker: Option<Waker>,
+}
+
+fn reliable(delivery_mode: DeliveryMode) -> bool {
+    match delivery_mode {
+        DeliveryMode::ReliableOrdered(_)
+        | DeliveryMode::ReliableSequenced(_)
+        | DeliveryMode::ReliableUnordered => true,
+        DeliveryMode::UnreliableSequenced(_)
+        | DeliveryMode::UnreliableUnordered => false,
+    }
+}
+
+impl SendBuffer {
+    pub fn set_limits(&mut self, limits: SendLimits) {
+        self.limits = limits;
+        self.wake();
+    }
+
+    /// Returns whether a datagram can be buffered. Without a delivery mode,
+    /// only the connection's limits are checked.
+    pub fn has_room(&self, delivery_mode: Option<DeliveryMode>) -> bool {
+        if delivery_mode.map_or(false, |mode| !reliable(mode)) {
+            return true;
+        }
+
+        let connection_full = self.datagrams > 0
+            && (self.bytes >= self.limits.bytes
+                || self.datagrams >= self.limits.datagrams);
+        let stream_full = delivery_mode
+            .and_then(|mode| self.streams.get(&mode))
+            .map_or(false, |&bytes| bytes >= self.limits.stream_bytes);
+
+        !connection_full && !stream_full
+    }
+
+    /// Like `has_room`, but registers for a wake up when there is none.
+    pub fn poll_ready(
+        &mut self,
+        ctx: &mut Context,
+        delivery_mode: Option<DeliveryMode>,
+    ) -> Poll<()> {
+        if self.has_room(delivery_mode) {
+            Poll::Ready(())
+        } else {
+            self.waker = Some(ctx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    pub fn push(&mut self, delivery_mode: DeliveryMode, bytes: usize) {
+        self.bytes += bytes;
+        self.datagrams += 1;
+        if reliable(delivery_mode) {
+            *self.streams.entry(delivery_mode).or_insert(0) += bytes;
+        }
+    }
+
+    /// Releases a datagram which was sent or, if reliable, acknowledged.
+    pub fn release(&mut self, delivery_mode: DeliveryMode, bytes: usize) {
+        self.bytes -= bytes;
+        self.datagrams -= 1;
+        if let Some(stream_bytes) = self.streams.get_mut(&delivery_mode) {
+            *stream_bytes -= bytes;
+            if *stream_bytes == 0 {
+                self.streams.remove(&delivery_mode);
+            }
+        }
+        self.wake();
+    }
+
+    /// Releases all buffered datagrams.
+    pub fn clear(&mut self) {
+        self.bytes = 0;
+        self.datagrams = 0;
+        self.streams.clear();
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
@@ -2,7 +2,9 @@ use crate::*;
 
 use async_std::net::SocketAddr;
 
+use ::miknet::ClockServer;
 use futures::prelude::*;
+use std::time::Instant;
 
 use structopt::StructOpt;
 
@@ -12,17 +14,39 @@ where
 {
     let client = server.next().await.expect("client").expect("Ok(client)");
     let (mut client_sink, mut client_stream) = client.split();
+    let clock = ClockServer::new(Instant::now());
 
     while let Some(Ok(wire_datagram)) = client_stream.next().await {
+        let received = Instant::now();
+        if let Some(delivery_mode) = clock_exchange(&wire_datagram) {
+            let response =
+                clock.respond(received, Instant::now(), &wire_datagram.data)?;
+            client_sink
+                .send(SendCmd {
+                    delivery_mode,
+                    data: response,
+                    ..SendCmd::default()
+                })
+                .await?;
+            continue;
+        }
+
         let position = wire_datagram.stream_position.expect("position");
-        let stream_id = position.stream_id;
-        let benchmark_datagram: BenchmarkDatagram =
-            bincode::deserialize(&wire_datagram.data).expect("valid datagram");
-        if benchmark_datagram.id != ID_DO_NOT_RETURN {
+        let id = BenchmarkDatagram::id_of(&wire_datagram.data)
+            .expect("valid datagram");
+        if id != ID_DO_NOT_RETURN {
+            // The client splits the round trip by the server's clock.
+            let mut data = wire_datagram.data.to_vec();
+            BenchmarkDatagram::stamp(
+                &mut data,
+                clock.time(Instant::now()).as_micros() as u64,
+            );
             client_sink
                 .send(SendCmd {
-                    delivery_mode: DeliveryMode::ReliableOrdered(stream_id),
-                    data: wire_datagram.data,
+                    delivery_mode: DeliveryMode::ReliableOrdered(
+                        position.stream_id,
+                    ),
+                    data: data.into(),
                     ..SendCmd::default()
                 })
                 .await?;
@@ -37,11 +61,39 @@ pub struct Options {
     /// Address to serve the benchmark on.
     #[structopt(short = "a", default_value = "127.0.0.1:33333")]
     pub address: SocketAddr,
+    /// Sockets to serve miknet protocols on, each driven by its own task.
+    /// More than one needs Linux's `SO_REUSEPORT`.
+    #[structopt(long, default_value = "1")]
+    pub workers: usize,
+    /// Whether to serve miknet protocols on an IPv4 socket and an IPv6 only
+    /// socket rather than one dual-stack socket, when the address is `[::]`.
+    /// Paired sockets are not sharded, so this takes one worker.
+    #[structopt(long)]
+    pub paired: bool,
     /// The protocol to benchmark.
     #[structopt(subcommand)]
     pub protocol: Protocol,
 }
 
+fn bind_miknet(
+    mode: miknet::MiknetMode,
+    options: &Options,
+) -> Result<miknet::MiknetServer> {
+    if options.paired && options.workers > 1 {
+        return Err("Paired sockets cannot be sharded across workers".into());
+    }
+    if options.paired && options.address.ip().is_unspecified() {
+        return miknet::bind_paired(mode, options.address.port());
+    }
+    if options.workers == 1 {
+        return miknet::bind(mode, options.address);
+    }
+    #[cfg(target_os = "linux")]
+    return miknet::bind_sharded(mode, options.address, options.workers);
+    #[cfg(not(target_os = "linux"))]
+    return Err("Sharding a server across workers needs Linux".into());
+}
+
 pub async fn server_main(options: Options) -> Result<()> {
     match options.protocol {
         Protocol::Tcp => match tcp::TcpServer::bind(options.address).await {
@@ -49,7 +101,7 @@ pub async fn server_main(options: Options) -> Result<()> {
             Err(e) => panic!("Failed to bind server: {:?}", e),
         },
         Protocol::Enet => {
-            run(enet::EnetServer::bind(options.address).await).await
+            run(enet::EnetServer::bind(options.address).await?).await
         }
         Protocol::Kcp => {
             run(kcp::KcpServer::bind(kcp::KcpMode::Normal, options.address)
@@ -61,5 +113,20 @@ pub async fn server_main(options: Options) -> Result<()> {
                 .await?)
             .await
         }
+        Protocol::Miknet => {
+            run(bind_miknet(miknet::MiknetMode::Immediate, &options)?).await
+        }
+        Protocol::MiknetCoalesced => {
+            run(bind_miknet(miknet::MiknetMode::Coalesced, &options)?).await
+        }
+        Protocol::MiknetFec => {
+            run(bind_miknet(miknet::MiknetMode::Fec, &options)?).await
+        }
+        Protocol::MiknetCompressed => {
+            run(bind_miknet(miknet::MiknetMode::Compressed, &options)?).await
+        }
+        Protocol::MiknetUnbatched => {
+            run(bind_miknet(miknet::MiknetMode::Unbatched, &options)?).await
+        }
     }
 }
@@ -1,6 +1,7 @@
 //! TCP implementation of the nhanh API
 
-use crate::*;
+use crate::{link::Link, *};
+use nhanh::events::ServerEvents;
 
 use async_std::{
     net::*,
@@ -8,13 +9,14 @@ use async_std::{
 };
 
 use futures::{
+    ready,
     sink::SinkExt,
-    stream::{
-        self, Fuse, FusedStream, LocalBoxStream, StreamExt, TryStreamExt,
-    },
+    stream::{Fuse, FusedStream, LocalBoxStream, StreamExt, TryStreamExt},
     Sink, Stream,
 };
 
+use serde::{Deserialize, Serialize};
+
 use std::{marker::Unpin, pin::Pin};
 
 use tokio_serde::{formats::*, SymmetricallyFramed};
@@ -22,6 +24,7 @@ use tokio_util::{codec::*, compat::*};
 
 pub struct TcpServer {
     incoming: Fuse<Incoming<'static>>,
+    events: ServerEvents,
 }
 
 impl TcpServer {
@@ -31,6 +34,7 @@ impl TcpServer {
 
         Ok(Self {
             incoming: listener.incoming().fuse(),
+            events: ServerEvents::default(),
         })
     }
 }
@@ -41,7 +45,14 @@ impl FusedStream for TcpServer {
     }
 }
 
-impl Server<TcpConnection> for TcpServer {}
+impl Server<TcpConnection> for TcpServer {
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ServerEvent> {
+        self.events.subscribe(thresholds)
+    }
+}
 
 impl Stream for TcpServer {
     type Item = Result<TcpConnection>;
@@ -55,9 +66,10 @@ impl Stream for TcpServer {
                     Ok(peer_addr) => peer_addr,
                     Err(e) => return Poll::Ready(Some(Err(e.into()))),
                 };
-                Poll::Ready(Some(Ok(TcpConnection::from((
-                    tcp_stream, peer_addr,
-                )))))
+                let mut connection =
+                    TcpConnection::from((tcp_stream, peer_addr));
+                self.events.attach(peer_addr, &mut connection.link.events);
+                Poll::Ready(Some(Ok(connection)))
             }
             Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
             Poll::Ready(None) => Poll::Ready(None),
@@ -66,11 +78,27 @@ impl Stream for TcpServer {
     }
 }
 
+/// Bytes `LengthDelimitedCodec` adds to each frame.
+const LENGTH_PREFIX_BYTES: u64 = 4;
+
+/// What a `TcpConnection` writes to the wire.
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    Datagram(Datagram),
+    /// The last frame the sender will write.
+    Close(CloseReason),
+}
+
 pub struct TcpConnection {
-    receiver: LocalBoxStream<'static, Result<Datagram>>,
+    receiver: LocalBoxStream<'static, Result<Frame>>,
     sender:
-        Pin<Box<dyn Sink<SendCmd, Error = Box<dyn std::error::Error>> + Unpin>>,
+        Pin<Box<dyn Sink<Frame, Error = Box<dyn std::error::Error>> + Unpin>>,
     peer_addr: SocketAddr,
+    total_sent: u32,
+    close_sent: bool,
+    close_reason: Option<CloseReason>,
+    terminated: bool,
+    link: Link,
 }
 
 impl TcpConnection {
@@ -84,30 +112,40 @@ impl TcpConnection {
         self.peer_addr
     }
 
-    fn send_gate() -> impl FnMut(
-        SendCmd,
-    ) -> stream::Iter<
-        <Option<Result<Datagram>> as IntoIterator>::IntoIter,
-    > {
-        let mut total_sent = 0;
-        move |send_cmd: SendCmd| {
-            stream::iter(match send_cmd.delivery_mode {
-                DeliveryMode::ReliableOrdered(stream_id) => {
-                    total_sent += 1;
-                    Some(Ok(Datagram {
-                        data: send_cmd.data,
-                        stream_position: Some(StreamPosition {
-                            stream_id,
-                            index: StreamIndex::Ordinal(total_sent),
-                        }),
-                    }))
-                }
-                _ => None,
-            })
+    fn send_gate(&mut self, send_cmd: SendCmd) -> Option<Frame> {
+        match send_cmd.delivery_mode {
+            DeliveryMode::ReliableOrdered(stream_id) => {
+                self.total_sent = self.total_sent.wrapping_add(1);
+                Some(Frame::Datagram(Datagram {
+                    data: send_cmd.data,
+                    stream_position: Some(StreamPosition {
+                        stream_id,
+                        index: StreamIndex::Ordinal(self.total_sent),
+                    }),
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes buffered frames to the socket when they reach the send limits.
+    fn poll_ready_inner(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        delivery_mode: Option<DeliveryMode>,
+    ) -> Poll<Result<()>> {
+        if !self.link.send_buffer.has_room(delivery_mode) {
+            ready!(self.as_mut().poll_flush(ctx))?;
         }
+        Pin::new(&mut self.sender).poll_ready(ctx)
     }
 }
 
+/// Counts the bytes of a frame as written to the wire.
+fn frame_bytes(frame: &Frame) -> u64 {
+    bincode::serialized_size(frame).unwrap_or(0) + LENGTH_PREFIX_BYTES
+}
+
 impl From<(TcpStream, SocketAddr)> for TcpConnection {
     fn from((stream, peer_addr): (TcpStream, SocketAddr)) -> Self {
         let framer = LengthDelimitedCodec::new();
@@ -119,48 +157,110 @@ impl From<(TcpStream, SocketAddr)> for TcpConnection {
         let wire = wire.map_err(Into::into);
         let (wire_sink, wire_stream) = wire.split();
 
-        let wire_sink = wire_sink.with_flat_map(Box::new(Self::send_gate()));
-
         Self {
             receiver: wire_stream.boxed_local(),
             sender: Pin::new(Box::new(wire_sink)),
             peer_addr,
+            total_sent: 0,
+            close_sent: false,
+            close_reason: None,
+            terminated: false,
+            link: Link::connected(ConnectionStats {
+                bytes_sent: Some(0),
+                bytes_received: Some(0),
+                ..ConnectionStats::default()
+            }),
         }
     }
 }
 
-impl Connection for TcpConnection {}
-
-impl Sink<SendCmd> for TcpConnection {
-    type Error = Box<dyn std::error::Error>;
-    fn poll_ready(
+/// TCP delivers everything written before the close frame, so TCP
+/// connections always linger.
+///
+/// Data written to the socket is bounded by the kernel's send buffer, so send
+/// limits apply to frames not yet written. Unreliable datagrams are not sent.
+impl Connection for TcpConnection {
+    fn poll_close_with(
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
+        close: &Close,
     ) -> Poll<Result<()>> {
-        Pin::new(&mut self.sender)
-            .poll_ready(ctx)
-            .map_err(Into::into)
+        if !self.close_sent {
+            ready!(Pin::new(&mut self.sender).poll_ready(ctx))?;
+            let frame = Frame::Close(close.reason.clone());
+            count(&mut self.link.stats.bytes_sent, frame_bytes(&frame));
+            Pin::new(&mut self.sender).start_send(frame)?;
+            self.close_sent = true;
+            self.link.events.report(ConnectionEvent::Disconnecting);
+        }
+
+        ready!(Pin::new(&mut self.sender).poll_close(ctx))?;
+        self.link.events.report(ConnectionEvent::Disconnected(None));
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_ready_for(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        delivery_mode: DeliveryMode,
+    ) -> Poll<Result<()>> {
+        self.poll_ready_inner(ctx, Some(delivery_mode))
+    }
+
+    fn set_send_limits(&mut self, limits: SendLimits) {
+        self.link.send_buffer.set_limits(limits);
+    }
+
+    fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ConnectionEvent> {
+        self.link.events.subscribe(thresholds)
+    }
+
+    /// TCP's packets and link estimates are in the kernel, so only bytes are
+    /// counted.
+    fn stats(&self) -> ConnectionStats {
+        self.link.stats.clone()
+    }
+
+    /// TCP's congestion state is in the kernel, so there is no estimate.
+    fn bandwidth(&self) -> BandwidthEstimate {
+        self.link.bandwidth()
+    }
+}
+
+impl Sink<SendCmd> for TcpConnection {
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        self.poll_ready_inner(ctx, None)
     }
     fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
-        Pin::new(&mut self.sender)
-            .start_send(item)
-            .map_err(Into::into)
+        let delivery_mode = item.delivery_mode;
+        match self.send_gate(item) {
+            Some(frame) => {
+                let bytes = frame_bytes(&frame);
+                count(&mut self.link.stats.bytes_sent, bytes);
+                self.link.send_buffer.push(delivery_mode, bytes as usize);
+                Pin::new(&mut self.sender).start_send(frame)
+            }
+            None => Ok(()),
+        }
     }
     fn poll_flush(
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
     ) -> Poll<Result<()>> {
-        Pin::new(&mut self.sender)
-            .poll_flush(ctx)
-            .map_err(Into::into)
+        ready!(Pin::new(&mut self.sender).poll_flush(ctx))?;
+        self.link.send_buffer.clear();
+        Poll::Ready(Ok(()))
     }
-    fn poll_close(
-        mut self: Pin<&mut Self>,
-        ctx: &mut Context,
-    ) -> Poll<Result<()>> {
-        Pin::new(&mut self.sender)
-            .poll_close(ctx)
-            .map_err(Into::into)
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        self.poll_close_with(ctx, &Close::default())
     }
 }
 
@@ -170,12 +270,39 @@ impl Stream for TcpConnection {
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
     ) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.receiver).poll_next(ctx)
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+
+        let frame = ready!(Pin::new(&mut self.receiver).poll_next(ctx));
+        if let Some(Ok(frame)) = &frame {
+            count(&mut self.link.stats.bytes_received, frame_bytes(frame));
+        }
+
+        match frame {
+            Some(Ok(Frame::Datagram(datagram))) => {
+                Poll::Ready(Some(Ok(datagram)))
+            }
+            Some(Ok(Frame::Close(reason))) => {
+                self.link.events.report(ConnectionEvent::Disconnected(Some(
+                    reason.clone(),
+                )));
+                self.close_reason = Some(reason);
+                self.terminated = true;
+                Poll::Ready(None)
+            }
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            None => {
+                self.link.events.report(ConnectionEvent::Disconnected(None));
+                self.terminated = true;
+                Poll::Ready(None)
+            }
+        }
     }
 }
 
 impl FusedStream for TcpConnection {
     fn is_terminated(&self) -> bool {
-        false
+        self.terminated
     }
 }
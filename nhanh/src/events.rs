@@ -0,0 +1,268 @@
+//! Fan out of `ConnectionEvent`s for implementers.
+//!
+//! A connection's `Events` deliver its lifecycle events to every stream taken
+//! from it, and the threshold events each stream asked for as the link is
+//! sampled. A server's `ServerEvents` subscribe its streams to every
+//! connection it accepts. `BandwidthEstimator` derives the bandwidth estimate
+//! of a link from its connection's stats, for implementers whose protocol
+//! does not estimate it.
+
+use crate::{
+    BandwidthEstimate, ConnectionEvent, ConnectionStats, EventThresholds,
+    ServerEvent,
+};
+use futures::{
+    channel::mpsc,
+    stream::{LocalBoxStream, StreamExt},
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Debug)]
+enum EventSink {
+    Connection(mpsc::UnboundedSender<ConnectionEvent>),
+    Server(SocketAddr, mpsc::UnboundedSender<ServerEvent>),
+}
+
+impl EventSink {
+    /// Returns whether the subscriber is still listening.
+    fn send(&self, event: ConnectionEvent) -> bool {
+        match self {
+            EventSink::Connection(sink) => sink.unbounded_send(event).is_ok(),
+            EventSink::Server(peer_addr, sink) => sink
+                .unbounded_send(ServerEvent {
+                    peer_addr: *peer_addr,
+                    event,
+                })
+                .is_ok(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    sink: EventSink,
+    thresholds: EventThresholds,
+    rtt_above: bool,
+    loss_above: bool,
+    /// The send rate last reported to the subscriber.
+    send_rate: Option<u64>,
+}
+
+/// Measurements of a link, as far as an implementer's protocol exposes them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LinkSample {
+    pub rtt: Option<Duration>,
+    pub loss: Option<f32>,
+    pub bandwidth: BandwidthEstimate,
+}
+
+/// The event subscribers of one connection.
+#[derive(Debug, Default)]
+pub struct Events {
+    subscribers: Vec<Subscriber>,
+    connected: bool,
+    disconnected: bool,
+}
+
+impl Events {
+    /// Returns an `Events` for a connection whose handshake is complete.
+    pub fn connected() -> Self {
+        Self {
+            connected: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn subscribe(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ConnectionEvent> {
+        let (sink, stream) = mpsc::unbounded();
+        self.add(EventSink::Connection(sink), thresholds);
+        stream.boxed_local()
+    }
+
+    pub fn subscribe_server(
+        &mut self,
+        peer_addr: SocketAddr,
+        sink: mpsc::UnboundedSender<ServerEvent>,
+        thresholds: EventThresholds,
+    ) {
+        self.add(EventSink::Server(peer_addr, sink), thresholds);
+    }
+
+    fn add(&mut self, sink: EventSink, thresholds: EventThresholds) {
+        if self.disconnected {
+            return;
+        }
+
+        if self.connected && !sink.send(ConnectionEvent::Connected) {
+            return;
+        }
+
+        self.subscribers.push(Subscriber {
+            sink,
+            thresholds,
+            rtt_above: false,
+            loss_above: false,
+            send_rate: None,
+        });
+    }
+
+    /// Reports a lifecycle event. Repeated `Connected` and `Disconnected`
+    /// events are dropped.
+    pub fn report(&mut self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::Connected if self.connected => return,
+            ConnectionEvent::Connected => self.connected = true,
+            ConnectionEvent::Disconnected(_) if self.disconnected => return,
+            ConnectionEvent::Disconnected(_) => self.disconnected = true,
+            _ => {}
+        }
+
+        self.subscribers
+            .retain(|subscriber| subscriber.sink.send(event.clone()));
+
+        if self.disconnected {
+            self.subscribers.clear();
+        }
+    }
+
+    /// Reports any thresholds the sample crosses.
+    pub fn observe(&mut self, sample: LinkSample) {
+        self.subscribers
+            .retain_mut(|subscriber| subscriber.observe(sample));
+    }
+}
+
+impl Subscriber {
+    /// Returns whether the subscriber is still listening.
+    fn observe(&mut self, sample: LinkSample) -> bool {
+        if let (Some(rtt), Some(threshold)) = (sample.rtt, self.thresholds.rtt)
+        {
+            let above = rtt > threshold;
+            if above != self.rtt_above {
+                self.rtt_above = above;
+                if !self
+                    .sink
+                    .send(ConnectionEvent::RttThresholdCrossed { rtt, above })
+                {
+                    return false;
+                }
+            }
+        }
+
+        if let (Some(loss), Some(threshold)) =
+            (sample.loss, self.thresholds.loss)
+        {
+            let above = loss > threshold;
+            if above != self.loss_above {
+                self.loss_above = above;
+                if !self
+                    .sink
+                    .send(ConnectionEvent::LossThresholdCrossed { loss, above })
+                {
+                    return false;
+                }
+            }
+        }
+
+        if let (Some(send_rate), Some(threshold)) =
+            (sample.bandwidth.send_rate, self.thresholds.bandwidth_change)
+        {
+            let changed = match self.send_rate {
+                Some(reported) => {
+                    let change = send_rate as f64 - reported as f64;
+                    change.abs() > reported as f64 * threshold as f64
+                }
+                None => true,
+            };
+            if changed {
+                self.send_rate = Some(send_rate);
+                if !self.sink.send(ConnectionEvent::BandwidthEstimateChanged(
+                    sample.bandwidth,
+                )) {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// A stream of a server's events, and the thresholds it asked for.
+type ServerSubscriber = (mpsc::UnboundedSender<ServerEvent>, EventThresholds);
+
+/// A server's event subscribers, which subscribe to each connection the
+/// server accepts.
+#[derive(Clone, Default)]
+pub struct ServerEvents(Arc<Mutex<Vec<ServerSubscriber>>>);
+
+impl ServerEvents {
+    pub fn subscribe(
+        &self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ServerEvent> {
+        let (sink, stream) = mpsc::unbounded();
+        self.0
+            .lock()
+            .expect("server events")
+            .push((sink, thresholds));
+        stream.boxed_local()
+    }
+
+    /// Subscribes all of the server's subscribers to a new connection.
+    pub fn attach(&self, peer_addr: SocketAddr, events: &mut Events) {
+        let mut subscribers = self.0.lock().expect("server events");
+        subscribers.retain(|(sink, _)| !sink.is_closed());
+        for (sink, thresholds) in subscribers.iter() {
+            events.subscribe_server(peer_addr, sink.clone(), *thresholds);
+        }
+    }
+}
+
+/// Estimates the rate a link allows from its connection's stats.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BandwidthEstimator {
+    min_rtt: Option<Duration>,
+    estimate: BandwidthEstimate,
+}
+
+impl BandwidthEstimator {
+    pub fn estimate(&self) -> BandwidthEstimate {
+        self.estimate
+    }
+
+    /// Updates the estimate from `stats`, and returns the sample of the link
+    /// to observe.
+    pub fn sample(&mut self, stats: &ConnectionStats) -> LinkSample {
+        if let Some(rtt) = stats.smoothed_rtt {
+            let min_rtt = self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt));
+            self.min_rtt = Some(min_rtt);
+
+            // A full window is acknowledged once per round trip, so that is
+            // the rate the congestion controller allows.
+            let send_rate = match rtt.as_secs_f64() {
+                secs if secs > 0.0 => stats
+                    .congestion_window
+                    .map(|window| (window as f64 / secs) as u64),
+                _ => None,
+            };
+            self.estimate = BandwidthEstimate {
+                send_rate,
+                queueing_delay: Some(rtt - min_rtt),
+            };
+        }
+
+        LinkSample {
+            rtt: stats.smoothed_rtt,
+            loss: stats.loss,
+            bandwidth: self.estimate,
+        }
+    }
+}
@@ -25,6 +25,10 @@
 //! Datagrams "surface" at an endpoint with the api client learns about the
 //! datagram.
 //!
+//! Datagrams hold their bytes in a reference counted `Bytes`, so that one
+//! buffer may be sent on many connections, and implementations may slice it,
+//! without copying it.
+//!
 //! ### Stream
 //!
 //! A stream is a logical series of datagrams on a connection. Many streams
@@ -52,13 +56,68 @@
 //! Streams buffer independently. For example, one ordered stream waiting on an
 //! older datagram before surfacing new ones should have no effect on other
 //! ordered streams.
+//!
+//! ### Partial reliability
+//!
+//! A reliable datagram may become obsolete before it is delivered, such as a
+//! state update which a newer one supersedes. A `SendCmd` may bound how many
+//! times its datagram is sent again, and may carry an `Abandon` handle with
+//! which the sender gives up on it. An abandoned datagram is not sent again,
+//! and if it has not arrived, it never surfaces. Later datagrams on its stream
+//! surface without waiting for it. Implementers which cannot abandon datagrams
+//! deliver them as though they were fully reliable.
+//!
+//! ### Close
+//!
+//! Either endpoint may close a connection, giving a `CloseReason`. The closing
+//! endpoint may choose to linger, delivering all of its pending reliable
+//! datagrams before the connection closes. The other endpoint's stream of
+//! datagrams ends, and the reason is available from
+//! `Connection::close_reason`. A close reason is not an error.
+//!
+//! ### Send buffering
+//!
+//! A connection buffers the datagrams it is given to send until they are sent,
+//! and reliable datagrams until they are acknowledged. Buffers are bounded by
+//! the connection's `SendLimits`. When a reliable datagram would not fit, the
+//! sink is not ready. When an unreliable datagram would not fit, the oldest
+//! buffered unreliable datagrams are dropped to make room.
+//!
+//! ### Events
+//!
+//! Besides datagrams, a connection may report `ConnectionEvent`s about its
+//! lifecycle and the quality of its link. Event streams are optional; a
+//! connection only reports events to streams which have been taken from it.
+//!
+//! ### Calls
+//!
+//! The `rpc` module layers request and response calls on any `Connection`,
+//! with correlation ids, timeouts, cancellation and a registry of handlers
+//! for the methods an endpoint serves.
+
+pub use bytes::Bytes;
+
+pub mod events;
+pub mod rpc;
 
 use futures::{
+    future::Future,
     sink::Sink,
-    stream::{FusedStream, Stream},
+    stream::{FusedStream, LocalBoxStream, Stream},
 };
 use serde::{Deserialize, Serialize};
-use std::marker::PhantomData;
+use std::{
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -82,7 +141,7 @@ pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
     PartialEq,
     Hash,
 )]
-pub struct StreamId(pub u8);
+pub struct StreamId(pub u32);
 
 /// A position of a datagram in a stream.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -93,18 +152,52 @@ pub struct StreamPosition {
 }
 
 /// A position in a datagram stream.
+///
+/// Indices are 32 bits, and wrap from `u32::MAX` to `0` on streams which
+/// carry more datagrams than that. They are ordered by serial number
+/// arithmetic (RFC 1982), which `StreamIndex::follows` implements: an index
+/// follows those less than 2^31 behind it, counting back past the wrap.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub enum StreamIndex {
     /// The ordinal number of a datagram which arrived in a strictly
     /// ordered stream. Ordinal indices are gauranteed to count up
-    /// by steps of `1`.
+    /// by steps of `1`, wrapping to `0` after `u32::MAX`.
     Ordinal(u32),
     /// The sequence number of a datagram which arrived in a
-    /// sequenced stream. Sequential indices are gauranteed to be
-    /// larger than preceding indices.
+    /// sequenced stream. Sequential indices are gauranteed to follow
+    /// preceding indices.
     Sequence(u32),
 }
 
+impl StreamIndex {
+    /// Returns the index after this one in an ordered stream, or the smallest
+    /// sequence number which follows this one.
+    pub fn next(self) -> Self {
+        match self {
+            StreamIndex::Ordinal(index) => {
+                StreamIndex::Ordinal(index.wrapping_add(1))
+            }
+            StreamIndex::Sequence(index) => {
+                StreamIndex::Sequence(index.wrapping_add(1))
+            }
+        }
+    }
+
+    /// Returns whether this index comes after `other` in their stream.
+    /// Indices of different kinds are never ordered.
+    pub fn follows(self, other: Self) -> bool {
+        let (index, other) = match (self, other) {
+            (StreamIndex::Ordinal(index), StreamIndex::Ordinal(other))
+            | (StreamIndex::Sequence(index), StreamIndex::Sequence(other)) => {
+                (index, other)
+            }
+            _ => return false,
+        };
+        let distance = index.wrapping_sub(other);
+        distance != 0 && distance < 1 << 31
+    }
+}
+
 /// The stream on which to deliver a datagram.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub enum DeliveryMode {
@@ -118,7 +211,9 @@ pub enum DeliveryMode {
     /// Datagrams delivered in this mode are gauranteed to surface at the
     /// receiving endpoint at most once, and not after any newer datagrams.
     ///
-    /// Datagrams are guaranteed to arrive, but are not gauranteed to surface.
+    /// Datagrams are sent until they, or newer datagrams on the stream,
+    /// arrive. The newest datagram sent is gauranteed to surface, but older
+    /// ones are not.
     ReliableSequenced(StreamId),
     /// Delivers a datagram on the connection's unordered stream.
     ///
@@ -145,13 +240,18 @@ pub struct Datagram {
     /// the datagram was sent on the unordered stream, this will be `None`.
     pub stream_position: Option<StreamPosition>,
     /// The bytes the other endpoint sent.
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SendCmd {
-    pub data: Vec<u8>,
+    pub data: Bytes,
     pub delivery_mode: DeliveryMode,
+    /// The most times a reliable datagram is sent again before it is
+    /// abandoned. If this is `None`, it is sent until it is acknowledged.
+    pub max_retransmissions: Option<u32>,
+    /// A handle with which the datagram may be abandoned.
+    pub abandon: Option<Abandon>,
     #[doc(hidden)]
     pub ___non_exhaustive: PhantomData<()>,
 }
@@ -159,13 +259,217 @@ pub struct SendCmd {
 impl Default for SendCmd {
     fn default() -> Self {
         Self {
-            data: vec![],
+            data: Bytes::new(),
             delivery_mode: DeliveryMode::UnreliableUnordered,
-            ___non_exhaustive: PhantomData::default(),
+            max_retransmissions: None,
+            abandon: None,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// A handle for giving up on a datagram before it is delivered.
+///
+/// Clones share their state, so a sender keeps one clone, and sends the
+/// datagram with another.
+#[derive(Debug, Clone, Default)]
+pub struct Abandon(Arc<AtomicBool>);
+
+impl Abandon {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Abandons the datagram. If it has already been delivered, this has no
+    /// effect.
+    pub fn abandon(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the datagram was abandoned, either through a handle or
+    /// because it was sent again as many times as its `SendCmd` allowed.
+    pub fn is_abandoned(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Handles are equal when they abandon the same datagram.
+impl PartialEq for Abandon {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Abandon {}
+
+impl Hash for Abandon {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).hash(state);
+    }
+}
+
+/// The reason an endpoint gave for closing a connection.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash,
+)]
+pub struct CloseReason {
+    /// An application defined code.
+    pub code: u32,
+    /// An application defined explanation.
+    pub message: String,
+}
+
+/// How to close a connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Close {
+    /// The reason delivered to the remote endpoint.
+    pub reason: CloseReason,
+    /// Whether to deliver all pending reliable datagrams before closing. If
+    /// this is `false`, pending datagrams are discarded.
+    pub linger: bool,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for Close {
+    fn default() -> Self {
+        Self {
+            reason: CloseReason::default(),
+            linger: true,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// Limits on the datagrams a connection buffers for sending.
+///
+/// A limit is never reached by an empty buffer, so a datagram larger than a
+/// byte limit can be sent once everything before it is acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SendLimits {
+    /// A limit on the bytes of all buffered datagrams.
+    pub bytes: usize,
+    /// A limit on the number of buffered datagrams.
+    pub datagrams: usize,
+    /// A limit on the bytes buffered for each reliable stream.
+    pub stream_bytes: usize,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for SendLimits {
+    fn default() -> Self {
+        Self {
+            bytes: 1 << 20,
+            datagrams: 4096,
+            stream_bytes: 256 << 10,
+            ___non_exhaustive: PhantomData,
         }
     }
 }
 
+/// Limits on link measurements which are reported as `ConnectionEvent`s when
+/// crossed in either direction.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EventThresholds {
+    /// A limit on smoothed round trip time.
+    pub rtt: Option<Duration>,
+    /// A limit on estimated packet loss (range: [0.0-1.0]).
+    pub loss: Option<f32>,
+    /// A relative change in estimated send rate, since the last estimate
+    /// reported, which is reported. For example, `0.25` reports changes of
+    /// more than 25%.
+    pub bandwidth_change: Option<f32>,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+/// Something that happened on a connection, other than a datagram surfacing.
+///
+/// Implementers report the events their protocol can observe; not every
+/// implementer reports every event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// The connection handshake completed. If this happened before an event
+    /// stream was taken, it is the first event on that stream.
+    Connected,
+    /// The remote endpoint is now reachable at a new address.
+    PeerAddressChanged(SocketAddr),
+    /// Smoothed round trip time crossed `EventThresholds::rtt`.
+    RttThresholdCrossed {
+        rtt: Duration,
+        /// Whether round trip time is now above the threshold.
+        above: bool,
+    },
+    /// Estimated packet loss crossed `EventThresholds::loss`.
+    LossThresholdCrossed {
+        loss: f32,
+        /// Whether loss is now above the threshold.
+        above: bool,
+    },
+    /// The congestion window shrank to its minimum, usually because a
+    /// retransmission timed out.
+    CongestionWindowCollapsed,
+    /// The estimated send rate changed by more than
+    /// `EventThresholds::bandwidth_change`.
+    BandwidthEstimateChanged(BandwidthEstimate),
+    /// The local endpoint began closing the connection.
+    Disconnecting,
+    /// The connection closed. This carries the remote endpoint's reason if it
+    /// closed the connection with one.
+    Disconnected(Option<CloseReason>),
+}
+
+/// A snapshot of a connection's counters and link estimates.
+///
+/// Implementers fill the fields their protocol exposes, and leave the rest
+/// `None`. Byte counts include the implementer's own headers, but not those of
+/// the transport beneath it (UDP, TCP, IP).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConnectionStats {
+    pub packets_sent: Option<u64>,
+    pub packets_received: Option<u64>,
+    pub bytes_sent: Option<u64>,
+    pub bytes_received: Option<u64>,
+    /// Packets sent again because they were not acknowledged in time.
+    pub retransmissions: Option<u64>,
+    /// Lost packets rebuilt from forward error correction, rather than sent
+    /// again.
+    pub recovered: Option<u64>,
+    /// Estimated packet loss (range: [0.0-1.0]).
+    pub loss: Option<f32>,
+    pub smoothed_rtt: Option<Duration>,
+    pub rtt_variance: Option<Duration>,
+    /// Bytes sent but not yet acknowledged.
+    pub bytes_in_flight: Option<u64>,
+    /// Packets waiting to be sent for the first time.
+    pub send_queue_depth: Option<u64>,
+    /// The number of bytes the congestion controller allows in flight.
+    pub congestion_window: Option<u64>,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+/// An estimate of how much a connection can send, for applications which
+/// adapt what they send to the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BandwidthEstimate {
+    /// The rate, in bytes per second, at which the connection can send without
+    /// building a queue on the path.
+    pub send_rate: Option<u64>,
+    /// How long datagrams currently wait in queues on the path, beyond the
+    /// least round trip time observed.
+    pub queueing_delay: Option<Duration>,
+}
+
+/// A `ConnectionEvent` on one of a server's connections.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerEvent {
+    /// The address of the remote endpoint when the connection was accepted.
+    pub peer_addr: SocketAddr,
+    pub event: ConnectionEvent,
+}
+
 /// An api for a bound port, waiting to receive connections.
 ///
 /// The bound port is a stream of new connections. The stream will emit an
@@ -173,6 +477,12 @@ impl Default for SendCmd {
 pub trait Server<Connection: crate::Connection>:
     Stream<Item = Result<Connection>> + FusedStream
 {
+    /// Returns a stream of events on all connections the server accepts
+    /// from now on.
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ServerEvent>;
 }
 
 /// An api for communicating with the remote endpoint on a connection.
@@ -186,9 +496,123 @@ pub trait Server<Connection: crate::Connection>:
 /// If the connection closes, the stream of datagrams will end. An error will
 /// be emitted from the stream before close if the disconnection was not
 /// correct according to the implementer's protocol.
+///
+/// Closing the sink is equivalent to closing the connection with
+/// `Close::default()`.
+///
+/// The sink applies backpressure according to the connection's `SendLimits`.
+/// `poll_ready` waits until the connection's buffer has room, without regard
+/// to streams. `poll_ready_for` also waits until the buffer of a reliable
+/// stream has room, and never waits for unreliable delivery modes; the
+/// connection drops its oldest unreliable datagrams instead.
 pub trait Connection:
     Stream<Item = Result<Datagram>>
     + FusedStream
     + Sink<SendCmd, Error = Box<dyn std::error::Error>>
 {
+    /// Closes the connection, delivering `close.reason` to the remote
+    /// endpoint.
+    ///
+    /// The first poll begins the close; later polls must be given the same
+    /// `close`. This resolves once the close is complete, which includes
+    /// delivery of pending reliable datagrams if `close.linger` is set.
+    fn poll_close_with(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        close: &Close,
+    ) -> Poll<Result<()>>;
+
+    /// Attempts to prepare the sink to receive a datagram sent in
+    /// `delivery_mode`. See the trait documentation for how this differs from
+    /// `poll_ready`.
+    fn poll_ready_for(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        delivery_mode: DeliveryMode,
+    ) -> Poll<Result<()>>;
+
+    /// Sets the limits on datagrams buffered for sending. Datagrams already
+    /// buffered are kept, even if they exceed the new limits.
+    fn set_send_limits(&mut self, limits: SendLimits);
+
+    /// The reason the remote endpoint gave for closing the connection.
+    ///
+    /// This is `None` until the stream of datagrams ends, and remains `None`
+    /// if the connection ended without a close from the remote endpoint.
+    fn close_reason(&self) -> Option<&CloseReason>;
+
+    /// Returns a stream of events on the connection from now on.
+    ///
+    /// Each call returns an independent stream. The stream ends after
+    /// `ConnectionEvent::Disconnected`.
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ConnectionEvent>;
+
+    /// Returns a snapshot of the connection's counters and link estimates.
+    fn stats(&self) -> ConnectionStats;
+
+    /// Returns the connection's current bandwidth estimate. Implementers base
+    /// this on acknowledgement timing and congestion state.
+    fn bandwidth(&self) -> BandwidthEstimate;
+}
+
+/// Combinators for `Connection`s.
+pub trait ConnectionExt: Connection {
+    /// Returns a future which closes the connection. See
+    /// `Connection::poll_close_with`.
+    fn close_with(&mut self, close: Close) -> CloseWith<'_, Self>
+    where
+        Self: Unpin,
+    {
+        CloseWith {
+            connection: self,
+            close,
+        }
+    }
+
+    /// Returns a future which resolves when the connection can buffer a
+    /// datagram sent in `delivery_mode`. See `Connection::poll_ready_for`.
+    fn ready_for(&mut self, delivery_mode: DeliveryMode) -> ReadyFor<'_, Self>
+    where
+        Self: Unpin,
+    {
+        ReadyFor {
+            connection: self,
+            delivery_mode,
+        }
+    }
+}
+
+impl<C: Connection + ?Sized> ConnectionExt for C {}
+
+/// Future for `ConnectionExt::close_with`.
+#[derive(Debug)]
+pub struct CloseWith<'a, C: ?Sized> {
+    connection: &'a mut C,
+    close: Close,
+}
+
+impl<C: Connection + Unpin + ?Sized> Future for CloseWith<'_, C> {
+    type Output = Result<()>;
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.connection).poll_close_with(ctx, &this.close)
+    }
+}
+
+/// Future for `ConnectionExt::ready_for`.
+#[derive(Debug)]
+pub struct ReadyFor<'a, C: ?Sized> {
+    connection: &'a mut C,
+    delivery_mode: DeliveryMode,
+}
+
+impl<C: Connection + Unpin + ?Sized> Future for ReadyFor<'_, C> {
+    type Output = Result<()>;
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.connection).poll_ready_for(ctx, this.delivery_mode)
+    }
 }
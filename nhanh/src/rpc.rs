@@ -0,0 +1,535 @@
+//! Request and response calls over a connection.
+//!
+//! An `RpcDriver` owns a `Connection` and carries calls in both directions
+//! over it. A call is a request datagram with a correlation id, a `MethodId`
+//! and a body, sent on a reliable stream the caller chooses, and its response
+//! comes back on the same stream. `RpcClient`s make calls, which time out, and
+//! which cancel the remote handler if they are dropped before their response
+//! arrives. Requests from the remote endpoint are served by the `Handlers` the
+//! driver was given.
+//!
+//! The driver must be polled for calls to make progress, so it is spawned, or
+//! joined with the code which makes calls. It resolves once the connection
+//! ends, and calls still waiting then fail with `RpcError::Closed`. It takes
+//! every datagram on the connection, and skips those which are not calls.
+//!
+//! Messages start with a kind byte and a big endian 64 bit id. Requests follow
+//! it with a big endian 32 bit method id and the body, responses with the
+//! body, and errors with a code byte and its details. Cancels end at the id.
+
+use crate::{Connection, Datagram, DeliveryMode, Result, SendCmd, StreamIndex};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{AbortHandle, Abortable, Aborted, LocalBoxFuture},
+    prelude::*,
+    ready,
+    stream::FuturesUnordered,
+};
+use futures_timer::Delay;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const ERROR: u8 = 2;
+const CANCEL: u8 = 3;
+
+const UNKNOWN_METHOD: u8 = 0;
+const FAILED: u8 = 1;
+
+/// Identifies a method the remote endpoint handles.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct MethodId(pub u32);
+
+/// How a call is made.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallOptions {
+    /// The stream the request and its response are delivered on. This must
+    /// be `ReliableOrdered` or `ReliableUnordered`.
+    pub delivery_mode: DeliveryMode,
+    /// How long to wait for the response.
+    pub timeout: Duration,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        Self {
+            delivery_mode: DeliveryMode::ReliableUnordered,
+            timeout: Duration::from_secs(10),
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// Why a call failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    /// No response arrived before the call's timeout. The remote handler is
+    /// cancelled.
+    TimedOut,
+    /// The remote endpoint has no handler for the method.
+    UnknownMethod(MethodId),
+    /// The remote handler failed, with its error's message.
+    Failed(String),
+    /// Calls are only made on reliable ordered and unordered streams.
+    Unreliable(DeliveryMode),
+    /// The connection or its driver ended before the response arrived.
+    Closed,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::TimedOut => write!(f, "call timed out"),
+            RpcError::UnknownMethod(method) => {
+                write!(f, "remote endpoint has no method {}", method.0)
+            }
+            RpcError::Failed(message) => {
+                write!(f, "remote handler failed: {}", message)
+            }
+            RpcError::Unreliable(delivery_mode) => {
+                write!(f, "calls cannot be made on {:?}", delivery_mode)
+            }
+            RpcError::Closed => write!(f, "connection is closed"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+type Handler = Box<dyn FnMut(Bytes) -> LocalBoxFuture<'static, Result<Bytes>>>;
+
+/// The methods an endpoint serves, each with the handler which turns a
+/// request's body into its response's.
+#[derive(Default)]
+pub struct Handlers {
+    handlers: HashMap<MethodId, Handler>,
+}
+
+impl Handlers {
+    /// Registers the handler of a method, replacing any it had. A handler's
+    /// error is sent to the caller as `RpcError::Failed`.
+    pub fn register<H, F>(&mut self, method: MethodId, mut handler: H)
+    where
+        H: FnMut(Bytes) -> F + 'static,
+        F: Future<Output = Result<Bytes>> + 'static,
+    {
+        self.handlers
+            .insert(method, Box::new(move |body| handler(body).boxed_local()));
+    }
+}
+
+impl fmt::Debug for Handlers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Call {
+        id: u64,
+        method: MethodId,
+        body: Bytes,
+        delivery_mode: DeliveryMode,
+        respond: oneshot::Sender<std::result::Result<Bytes, RpcError>>,
+    },
+    Cancel {
+        id: u64,
+        delivery_mode: DeliveryMode,
+    },
+}
+
+#[derive(Debug)]
+enum Message {
+    Request {
+        id: u64,
+        method: MethodId,
+        body: Bytes,
+    },
+    Response {
+        id: u64,
+        body: Bytes,
+    },
+    Error {
+        id: u64,
+        error: RpcError,
+    },
+    Cancel {
+        id: u64,
+    },
+}
+
+impl Message {
+    fn encode(&self) -> Bytes {
+        let mut buffer = BytesMut::new();
+        match self {
+            Message::Request { id, method, body } => {
+                buffer.put_u8(REQUEST);
+                buffer.put_u64(*id);
+                buffer.put_u32(method.0);
+                buffer.put_slice(body);
+            }
+            Message::Response { id, body } => {
+                buffer.put_u8(RESPONSE);
+                buffer.put_u64(*id);
+                buffer.put_slice(body);
+            }
+            Message::Error { id, error } => {
+                buffer.put_u8(ERROR);
+                buffer.put_u64(*id);
+                match error {
+                    RpcError::UnknownMethod(method) => {
+                        buffer.put_u8(UNKNOWN_METHOD);
+                        buffer.put_u32(method.0);
+                    }
+                    RpcError::Failed(message) => {
+                        buffer.put_u8(FAILED);
+                        buffer.put_slice(message.as_bytes());
+                    }
+                    error => {
+                        buffer.put_u8(FAILED);
+                        buffer.put_slice(error.to_string().as_bytes());
+                    }
+                }
+            }
+            Message::Cancel { id } => {
+                buffer.put_u8(CANCEL);
+                buffer.put_u64(*id);
+            }
+        }
+        buffer.freeze()
+    }
+
+    /// Decodes a message, sharing the body with `data`.
+    fn decode(mut data: Bytes) -> Option<Self> {
+        if data.remaining() < 9 {
+            return None;
+        }
+        let kind = data.get_u8();
+        let id = data.get_u64();
+        match kind {
+            REQUEST if data.remaining() >= 4 => Some(Message::Request {
+                id,
+                method: MethodId(data.get_u32()),
+                body: data,
+            }),
+            RESPONSE => Some(Message::Response { id, body: data }),
+            ERROR if data.has_remaining() => {
+                let error = match data.get_u8() {
+                    UNKNOWN_METHOD if data.remaining() >= 4 => {
+                        RpcError::UnknownMethod(MethodId(data.get_u32()))
+                    }
+                    FAILED => RpcError::Failed(
+                        String::from_utf8_lossy(&data).into_owned(),
+                    ),
+                    _ => return None,
+                };
+                Some(Message::Error { id, error })
+            }
+            CANCEL => Some(Message::Cancel { id }),
+            _ => None,
+        }
+    }
+
+    fn send_cmd(&self, delivery_mode: DeliveryMode) -> SendCmd {
+        SendCmd {
+            delivery_mode,
+            data: self.encode(),
+            ..SendCmd::default()
+        }
+    }
+}
+
+/// Makes calls through an `RpcDriver`. Clones make calls through the same
+/// driver.
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    commands: mpsc::UnboundedSender<Command>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl RpcClient {
+    /// Calls a method with `CallOptions::default()`.
+    pub fn call(&self, method: MethodId, body: Bytes) -> Call {
+        self.call_with(method, body, CallOptions::default())
+    }
+
+    /// Calls a method, and returns a future for its response.
+    pub fn call_with(
+        &self,
+        method: MethodId,
+        body: Bytes,
+        options: CallOptions,
+    ) -> Call {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (respond, response) = oneshot::channel();
+        let delivery_mode = options.delivery_mode;
+        match delivery_mode {
+            DeliveryMode::ReliableOrdered(_)
+            | DeliveryMode::ReliableUnordered => {
+                // If the driver is gone, the response fails as closed.
+                let _ = self.commands.unbounded_send(Command::Call {
+                    id,
+                    method,
+                    body,
+                    delivery_mode,
+                    respond,
+                });
+            }
+            _ => {
+                let _ = respond.send(Err(RpcError::Unreliable(delivery_mode)));
+            }
+        }
+        Call {
+            id,
+            delivery_mode,
+            response,
+            timeout: Delay::new(options.timeout),
+            commands: self.commands.clone(),
+            done: false,
+        }
+    }
+}
+
+/// Future for the response to a call. Dropping it before it resolves cancels
+/// the call.
+#[derive(Debug)]
+#[must_use = "calls are cancelled when dropped"]
+pub struct Call {
+    id: u64,
+    delivery_mode: DeliveryMode,
+    response: oneshot::Receiver<std::result::Result<Bytes, RpcError>>,
+    timeout: Delay,
+    commands: mpsc::UnboundedSender<Command>,
+    done: bool,
+}
+
+impl Call {
+    fn cancel(&mut self) {
+        if !self.done {
+            self.done = true;
+            let _ = self.commands.unbounded_send(Command::Cancel {
+                id: self.id,
+                delivery_mode: self.delivery_mode,
+            });
+        }
+    }
+}
+
+impl Future for Call {
+    type Output = std::result::Result<Bytes, RpcError>;
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(response) = self.response.poll_unpin(ctx) {
+            self.done = true;
+            return Poll::Ready(response.unwrap_or(Err(RpcError::Closed)));
+        }
+        ready!(self.timeout.poll_unpin(ctx));
+        self.cancel();
+        Poll::Ready(Err(RpcError::TimedOut))
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+type Serving = LocalBoxFuture<
+    'static,
+    (
+        u64,
+        DeliveryMode,
+        std::result::Result<Result<Bytes>, Aborted>,
+    ),
+>;
+
+/// Carries calls over a connection. See the module documentation.
+#[must_use = "calls only progress while the driver is polled"]
+pub struct RpcDriver<C> {
+    connection: C,
+    commands: mpsc::UnboundedReceiver<Command>,
+    handlers: Handlers,
+    /// Calls waiting for their responses, by id.
+    pending:
+        HashMap<u64, oneshot::Sender<std::result::Result<Bytes, RpcError>>>,
+    /// Requests being handled, by the remote endpoint's id for them.
+    serving: HashMap<u64, AbortHandle>,
+    running: FuturesUnordered<Serving>,
+    outgoing: VecDeque<SendCmd>,
+}
+
+impl<C: Connection + Unpin> RpcDriver<C> {
+    /// Returns a driver which serves `handlers` on `connection`, and a client
+    /// which makes calls through it.
+    pub fn new(connection: C, handlers: Handlers) -> (Self, RpcClient) {
+        let (commands_sink, commands) = mpsc::unbounded();
+        let driver = Self {
+            connection,
+            commands,
+            handlers,
+            pending: HashMap::new(),
+            serving: HashMap::new(),
+            running: FuturesUnordered::new(),
+            outgoing: VecDeque::new(),
+        };
+        let client = RpcClient {
+            commands: commands_sink,
+            next_id: Arc::new(AtomicU64::new(0)),
+        };
+        (driver, client)
+    }
+
+    fn command(&mut self, command: Command) {
+        match command {
+            Command::Call {
+                id,
+                method,
+                body,
+                delivery_mode,
+                respond,
+            } => {
+                self.pending.insert(id, respond);
+                let request = Message::Request { id, method, body };
+                self.outgoing.push_back(request.send_cmd(delivery_mode));
+            }
+            Command::Cancel { id, delivery_mode } => {
+                if self.pending.remove(&id).is_some() {
+                    let cancel = Message::Cancel { id };
+                    self.outgoing.push_back(cancel.send_cmd(delivery_mode));
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, datagram: Datagram) {
+        let delivery_mode = match datagram.stream_position {
+            Some(position) => match position.index {
+                StreamIndex::Ordinal(_) => {
+                    DeliveryMode::ReliableOrdered(position.stream_id)
+                }
+                StreamIndex::Sequence(_) => return,
+            },
+            None => DeliveryMode::ReliableUnordered,
+        };
+        let message = match Message::decode(datagram.data) {
+            Some(message) => message,
+            None => return,
+        };
+        match message {
+            Message::Request { id, method, body } => {
+                let handler = match self.handlers.handlers.get_mut(&method) {
+                    Some(handler) => handler,
+                    None => {
+                        let error = Message::Error {
+                            id,
+                            error: RpcError::UnknownMethod(method),
+                        };
+                        self.outgoing.push_back(error.send_cmd(delivery_mode));
+                        return;
+                    }
+                };
+                let (abort, registration) = AbortHandle::new_pair();
+                let handling = Abortable::new(handler(body), registration);
+                self.serving.insert(id, abort);
+                self.running.push(
+                    async move { (id, delivery_mode, handling.await) }
+                        .boxed_local(),
+                );
+            }
+            Message::Response { id, body } => {
+                if let Some(respond) = self.pending.remove(&id) {
+                    let _ = respond.send(Ok(body));
+                }
+            }
+            Message::Error { id, error } => {
+                if let Some(respond) = self.pending.remove(&id) {
+                    let _ = respond.send(Err(error));
+                }
+            }
+            Message::Cancel { id } => {
+                if let Some(abort) = self.serving.remove(&id) {
+                    abort.abort();
+                }
+            }
+        }
+    }
+
+    /// Sends the messages waiting to be sent.
+    fn poll_send(&mut self, ctx: &mut Context) -> Poll<Result<()>> {
+        while let Some(send_cmd) = self.outgoing.front() {
+            let delivery_mode = send_cmd.delivery_mode;
+            ready!(Pin::new(&mut self.connection)
+                .poll_ready_for(ctx, delivery_mode))?;
+            let send_cmd = self.outgoing.pop_front().expect("message");
+            Pin::new(&mut self.connection).start_send(send_cmd)?;
+        }
+        Pin::new(&mut self.connection).poll_flush(ctx)
+    }
+}
+
+impl<C: Connection + Unpin> Future for RpcDriver<C> {
+    type Output = Result<()>;
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        while let Poll::Ready(Some(command)) =
+            this.commands.poll_next_unpin(ctx)
+        {
+            this.command(command);
+        }
+
+        loop {
+            match this.connection.poll_next_unpin(ctx) {
+                Poll::Ready(Some(Ok(datagram))) => this.receive(datagram),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => break,
+            }
+        }
+
+        while let Poll::Ready(Some((id, delivery_mode, result))) =
+            this.running.poll_next_unpin(ctx)
+        {
+            if this.serving.remove(&id).is_none() {
+                continue;
+            }
+            let message = match result {
+                Ok(Ok(body)) => Message::Response { id, body },
+                Ok(Err(e)) => Message::Error {
+                    id,
+                    error: RpcError::Failed(e.to_string()),
+                },
+                Err(Aborted) => continue,
+            };
+            this.outgoing.push_back(message.send_cmd(delivery_mode));
+        }
+
+        ready!(this.poll_send(ctx))?;
+        Poll::Pending
+    }
+}
+
+impl<C> fmt::Debug for RpcDriver<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RpcDriver")
+            .field("handlers", &self.handlers)
+            .field("pending", &self.pending.len())
+            .field("serving", &self.serving.len())
+            .field("outgoing", &self.outgoing.len())
+            .finish()
+    }
+}
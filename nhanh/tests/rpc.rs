@@ -0,0 +1,269 @@
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    prelude::*,
+    stream::{FusedStream, LocalBoxStream},
+};
+use futures_timer::Delay;
+use nhanh::{rpc::*, *};
+use std::{
+    cell::{Cell, RefCell},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// One end of an in-memory connection, which records the delivery modes it
+/// sends in.
+struct Pipe {
+    incoming: mpsc::UnboundedReceiver<Datagram>,
+    outgoing: mpsc::UnboundedSender<Datagram>,
+    sent: Rc<RefCell<Vec<DeliveryMode>>>,
+}
+
+fn pipes() -> (Pipe, Pipe) {
+    let (a_sink, a_stream) = mpsc::unbounded();
+    let (b_sink, b_stream) = mpsc::unbounded();
+    let pipe = |incoming, outgoing| Pipe {
+        incoming,
+        outgoing,
+        sent: Rc::default(),
+    };
+    (pipe(a_stream, b_sink), pipe(b_stream, a_sink))
+}
+
+impl Stream for Pipe {
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        self.incoming
+            .poll_next_unpin(ctx)
+            .map(|datagram| datagram.map(Ok))
+    }
+}
+
+impl FusedStream for Pipe {
+    fn is_terminated(&self) -> bool {
+        self.incoming.is_terminated()
+    }
+}
+
+impl Sink<SendCmd> for Pipe {
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, send_cmd: SendCmd) -> Result<()> {
+        self.sent.borrow_mut().push(send_cmd.delivery_mode);
+        let stream_position = match send_cmd.delivery_mode {
+            DeliveryMode::ReliableOrdered(stream_id) => Some(StreamPosition {
+                stream_id,
+                index: StreamIndex::Ordinal(0),
+            }),
+            _ => None,
+        };
+        self.outgoing.unbounded_send(Datagram {
+            stream_position,
+            data: send_cmd.data,
+        })?;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        self.outgoing.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Connection for Pipe {
+    fn poll_close_with(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        _: &Close,
+    ) -> Poll<Result<()>> {
+        self.poll_close(ctx)
+    }
+
+    fn poll_ready_for(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        _: DeliveryMode,
+    ) -> Poll<Result<()>> {
+        self.poll_ready(ctx)
+    }
+
+    fn set_send_limits(&mut self, _: SendLimits) {}
+
+    fn close_reason(&self) -> Option<&CloseReason> {
+        None
+    }
+
+    fn events(
+        &mut self,
+        _: EventThresholds,
+    ) -> LocalBoxStream<'static, ConnectionEvent> {
+        stream::empty().boxed_local()
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats::default()
+    }
+
+    fn bandwidth(&self) -> BandwidthEstimate {
+        BandwidthEstimate::default()
+    }
+}
+
+const DOUBLE: MethodId = MethodId(1);
+const FAIL: MethodId = MethodId(2);
+const HANG: MethodId = MethodId(3);
+
+/// Handlers which record whether a hanging call was dropped.
+fn handlers(dropped: Rc<Cell<bool>>) -> Handlers {
+    struct Guard(Rc<Cell<bool>>);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    let mut handlers = Handlers::default();
+    handlers.register(DOUBLE, |body: Bytes| async move {
+        Ok(body.iter().chain(body.iter()).copied().collect())
+    });
+    handlers.register(FAIL, |_| async { Err("nope".into()) });
+    handlers.register(HANG, move |_| {
+        let guard = Guard(dropped.clone());
+        async move {
+            future::pending::<()>().await;
+            drop(guard);
+            Ok(Bytes::new())
+        }
+    });
+    handlers
+}
+
+/// Runs `test` with a client whose calls are served by `handlers`, and
+/// returns the delivery modes the server sent in.
+fn run<F>(
+    handlers: Handlers,
+    test: impl FnOnce(RpcClient) -> F,
+) -> Vec<DeliveryMode>
+where
+    F: Future<Output = ()>,
+{
+    let (client_pipe, server_pipe) = pipes();
+    let sent = server_pipe.sent.clone();
+    let (server, _) = RpcDriver::new(server_pipe, handlers);
+    let (client_driver, client) =
+        RpcDriver::new(client_pipe, Handlers::default());
+    let drivers = future::join(server, client_driver);
+    futures::executor::block_on(async {
+        match future::select(drivers, test(client).boxed_local()).await {
+            Either::Left(_) => panic!("drivers ended"),
+            Either::Right(_) => {}
+        }
+    });
+    let sent = sent.borrow().clone();
+    sent
+}
+
+#[test]
+fn calls_are_answered_on_their_stream() {
+    let sent = run(handlers(Rc::default()), |client| async move {
+        let options = CallOptions {
+            delivery_mode: DeliveryMode::ReliableOrdered(StreamId(3)),
+            ..CallOptions::default()
+        };
+        let ordered =
+            client.call_with(DOUBLE, Bytes::from_static(b"ab"), options);
+        let unordered = client.call(DOUBLE, Bytes::from_static(b"cd"));
+        let (ordered, unordered) = future::join(ordered, unordered).await;
+        assert_eq!(ordered, Ok(Bytes::from_static(b"abab")));
+        assert_eq!(unordered, Ok(Bytes::from_static(b"cdcd")));
+    });
+    let mut sent = sent;
+    sent.sort_by_key(|delivery_mode| format!("{:?}", delivery_mode));
+    assert_eq!(
+        sent,
+        vec![
+            DeliveryMode::ReliableOrdered(StreamId(3)),
+            DeliveryMode::ReliableUnordered
+        ]
+    );
+}
+
+#[test]
+fn failures_reach_the_caller() {
+    run(handlers(Rc::default()), |client| async move {
+        assert_eq!(
+            client.call(FAIL, Bytes::new()).await,
+            Err(RpcError::Failed("nope".to_string()))
+        );
+        assert_eq!(
+            client.call(MethodId(9), Bytes::new()).await,
+            Err(RpcError::UnknownMethod(MethodId(9)))
+        );
+        let options = CallOptions {
+            delivery_mode: DeliveryMode::UnreliableUnordered,
+            ..CallOptions::default()
+        };
+        assert_eq!(
+            client.call_with(DOUBLE, Bytes::new(), options).await,
+            Err(RpcError::Unreliable(DeliveryMode::UnreliableUnordered))
+        );
+    });
+}
+
+#[test]
+fn timed_out_calls_cancel_their_handlers() {
+    let dropped = Rc::new(Cell::new(false));
+    run(handlers(dropped.clone()), |client| async move {
+        let options = CallOptions {
+            timeout: Duration::from_millis(50),
+            ..CallOptions::default()
+        };
+        assert_eq!(
+            client.call_with(HANG, Bytes::new(), options).await,
+            Err(RpcError::TimedOut)
+        );
+        Delay::new(Duration::from_millis(50)).await;
+    });
+    assert!(dropped.get());
+}
+
+#[test]
+fn dropped_calls_cancel_their_handlers() {
+    let dropped = Rc::new(Cell::new(false));
+    let observed = dropped.clone();
+    run(handlers(dropped), |client| async move {
+        let call = client.call(HANG, Bytes::new());
+        let timeout = Delay::new(Duration::from_millis(50));
+        match future::select(call, timeout).await {
+            Either::Left(_) => panic!("hanging call returned"),
+            Either::Right(_) => {}
+        }
+        Delay::new(Duration::from_millis(50)).await;
+        assert!(observed.get());
+    });
+}
+
+#[test]
+fn calls_fail_when_the_connection_ends() {
+    let (client_pipe, server_pipe) = pipes();
+    drop(server_pipe);
+    let (driver, client) = RpcDriver::new(client_pipe, Handlers::default());
+    let call = client.call(DOUBLE, Bytes::new());
+    let (ended, response) =
+        futures::executor::block_on(future::join(driver, call));
+    assert!(ended.is_ok());
+    assert_eq!(response, Err(RpcError::Closed));
+}
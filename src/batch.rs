@@ -0,0 +1,403 @@
+//! Batched UDP IO.
+//!
+//! A busy server spends most of its CPU time in syscalls if it sends and
+//! receives one packet per call. On Linux, sockets send and receive batches of
+//! packets with `sendmmsg` and `recvmmsg`. Where the kernel supports it,
+//! consecutive packets of one size to one destination go out as one segmented
+//! send (`UDP_SEGMENT`), and packets from one source arrive coalesced in one
+//! buffer (`UDP_GRO`), which `RecvMeta::packets` splits again. If the kernel
+//! or device cannot segment sends, batches are sent without segmentation.
+//! Elsewhere, batches are sent and received a packet per call.
+//!
+//! IPv6 sockets may exchange packets with IPv4 peers if they are dual-stack.
+//! Sources are reported as IPv4 addresses either way, and IPv4 destinations
+//! are mapped into IPv6 as the socket needs.
+
+use socket2::SockRef;
+use std::{
+    io::{self, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The most messages one call sends or receives.
+pub const BATCH_SIZE: usize = 32;
+
+/// The bytes each receive buffer needs to hold packets coalesced into it.
+pub const RECV_BUFFER_BYTES: usize = 1 << 16;
+
+/// The send and receive syscalls made through this module.
+static SYSCALLS: AtomicU64 = AtomicU64::new(0);
+
+/// A packet to send.
+#[derive(Debug, Clone, Copy)]
+pub struct Transmit<'a> {
+    pub destination: SocketAddr,
+    pub contents: &'a [u8],
+}
+
+/// Where a received buffer came from, and how to split it into packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvMeta {
+    pub source: SocketAddr,
+    /// The bytes received into the buffer.
+    pub len: usize,
+    /// The length of each packet in the buffer. The last may be shorter.
+    pub stride: usize,
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            source: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            len: 0,
+            stride: 0,
+        }
+    }
+}
+
+impl RecvMeta {
+    /// Returns the packets received into `buffer`.
+    pub fn packets<'a>(
+        &self,
+        buffer: &'a [u8],
+    ) -> impl Iterator<Item = &'a [u8]> {
+        buffer[..self.len].chunks(self.stride.max(1))
+    }
+}
+
+/// Returns the send and receive syscalls every socket in the process has made
+/// through this module.
+pub fn syscalls() -> u64 {
+    SYSCALLS.load(Ordering::Relaxed)
+}
+
+/// Returns `address` with an IPv4-mapped IPv6 address replaced by the IPv4
+/// address it maps, so that a peer has one address on any socket.
+pub fn canonical(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => (ip, address.port()).into(),
+            None => address,
+        },
+        IpAddr::V4(_) => address,
+    }
+}
+
+/// Prepares a socket for batched IO, turning on coalesced receives where the
+/// kernel supports them. Sockets so prepared must only receive with `recv`.
+pub fn configure(socket: SockRef) {
+    #[cfg(target_os = "linux")]
+    linux::configure(socket);
+    #[cfg(not(target_os = "linux"))]
+    let _ = socket;
+}
+
+/// Sends packets in order until the socket would block, and returns how many
+/// were sent. This fails if the first packet could not be sent.
+pub fn send(socket: SockRef, transmits: &[Transmit]) -> io::Result<usize> {
+    if transmits.is_empty() {
+        return Ok(0);
+    }
+
+    #[cfg(target_os = "linux")]
+    return linux::send(socket, transmits);
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        // Only Linux lets IPv6 sockets send to IPv4 addresses unmapped.
+        let ipv6 = socket.local_addr()?.is_ipv6();
+        let mut sent = 0;
+        for transmit in transmits {
+            let mut destination = transmit.destination;
+            if let (true, IpAddr::V4(ip)) = (ipv6, destination.ip()) {
+                destination = (ip.to_ipv6_mapped(), destination.port()).into();
+            }
+            SYSCALLS.fetch_add(1, Ordering::Relaxed);
+            match socket.send_to(transmit.contents, &destination.into()) {
+                Ok(_) => sent += 1,
+                Err(e) if sent == 0 => return Err(e),
+                // The next call sends the rest, or fails.
+                Err(_) => break,
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// Receives into `buffers` and returns how many were filled, with where each
+/// came from in `meta`. Each buffer should hold `RECV_BUFFER_BYTES`.
+///
+/// A blocking socket waits for the first packet, but not the rest.
+pub fn recv(
+    socket: SockRef,
+    buffers: &mut [IoSliceMut],
+    meta: &mut [RecvMeta],
+) -> io::Result<usize> {
+    let count = buffers.len().min(meta.len()).min(BATCH_SIZE);
+    if count == 0 {
+        return Ok(0);
+    }
+
+    #[cfg(target_os = "linux")]
+    return linux::recv(socket, &mut buffers[..count], &mut meta[..count]);
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        use std::mem::MaybeUninit;
+
+        SYSCALLS.fetch_add(1, Ordering::Relaxed);
+        let buffer: &mut [u8] = &mut buffers[0];
+        // Safety: initialized bytes are valid as uninitialized ones, and the
+        // socket only writes to them.
+        let uninit =
+            unsafe { &mut *(buffer as *mut [u8] as *mut [MaybeUninit<u8>]) };
+        let (len, source) = socket.recv_from(uninit)?;
+        let source = source.as_socket().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "not an IP address")
+        })?;
+        meta[0] = RecvMeta {
+            source: canonical(source),
+            len,
+            stride: len,
+        };
+        Ok(1)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{RecvMeta, Transmit, BATCH_SIZE, SYSCALLS};
+    use socket2::{SockAddr, SockAddrStorage, SockRef};
+    use std::{
+        io::{self, IoSliceMut},
+        mem,
+        ops::Range,
+        os::unix::io::AsRawFd,
+        ptr,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    /// The most packets one segmented send carries.
+    const MAX_SEGMENTS: usize = 64;
+
+    /// The most bytes one segmented send carries.
+    const MAX_SEGMENTED_BYTES: usize = 65507;
+
+    /// Room for one control message of up to 8 bytes.
+    type Control = [u64; 4];
+
+    /// Whether sends may be segmented. This is cleared once the kernel or a
+    /// device refuses a segmented send.
+    static SEGMENTATION: AtomicBool = AtomicBool::new(true);
+
+    pub fn configure(socket: SockRef) {
+        let fd = socket.as_raw_fd();
+        let on: libc::c_int = 1;
+        // Without coalesced receives, packets arrive one per buffer.
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            );
+        }
+
+        let mut size: libc::c_int = 0;
+        let mut len = mem::size_of_val(&size) as libc::socklen_t;
+        let supported = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                &mut size as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        } == 0;
+        if !supported {
+            SEGMENTATION.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Groups transmits into messages: runs of packets to one destination
+    /// which are the size of the first, except for a shorter last one, if
+    /// sends may be segmented, and single packets otherwise.
+    fn group(transmits: &[Transmit], segment: bool) -> Vec<Range<usize>> {
+        let mut groups = Vec::with_capacity(BATCH_SIZE);
+        let mut start = 0;
+        while start < transmits.len() && groups.len() < BATCH_SIZE {
+            let first = &transmits[start];
+            let size = first.contents.len();
+            let mut end = start + 1;
+            let mut bytes = size;
+            while segment && size > 0 && end < transmits.len() {
+                let next = &transmits[end];
+                if end - start == MAX_SEGMENTS
+                    || next.destination != first.destination
+                    || next.contents.len() > size
+                    || bytes + next.contents.len() > MAX_SEGMENTED_BYTES
+                {
+                    break;
+                }
+                bytes += next.contents.len();
+                end += 1;
+                if next.contents.len() < size {
+                    break;
+                }
+            }
+            groups.push(start..end);
+            start = end;
+        }
+        groups
+    }
+
+    pub fn send(socket: SockRef, transmits: &[Transmit]) -> io::Result<usize> {
+        let segment = SEGMENTATION.load(Ordering::Relaxed);
+        let groups = group(transmits, segment);
+        let end = groups.last().map_or(0, |group| group.end);
+        let mut iovecs: Vec<libc::iovec> = transmits[..end]
+            .iter()
+            .map(|transmit| libc::iovec {
+                iov_base: transmit.contents.as_ptr() as *mut libc::c_void,
+                iov_len: transmit.contents.len(),
+            })
+            .collect();
+        let addresses: Vec<SockAddr> = groups
+            .iter()
+            .map(|group| transmits[group.start].destination.into())
+            .collect();
+        let mut controls: Vec<Control> = vec![[0; 4]; groups.len()];
+
+        let mut messages = Vec::with_capacity(groups.len());
+        for (i, group) in groups.iter().enumerate() {
+            let mut header: libc::msghdr = unsafe { mem::zeroed() };
+            header.msg_name = addresses[i].as_ptr() as *mut libc::c_void;
+            header.msg_namelen = addresses[i].len();
+            header.msg_iov = iovecs[group.clone()].as_mut_ptr();
+            header.msg_iovlen = group.len() as _;
+            if group.len() > 1 {
+                let size = transmits[group.start].contents.len() as u16;
+                unsafe {
+                    header.msg_control =
+                        controls[i].as_mut_ptr() as *mut libc::c_void;
+                    header.msg_controllen =
+                        libc::CMSG_SPACE(mem::size_of_val(&size) as u32) as _;
+                    let cmsg = &mut *libc::CMSG_FIRSTHDR(&header);
+                    cmsg.cmsg_level = libc::SOL_UDP;
+                    cmsg.cmsg_type = libc::UDP_SEGMENT;
+                    cmsg.cmsg_len =
+                        libc::CMSG_LEN(mem::size_of_val(&size) as u32) as _;
+                    ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg) as *mut u16,
+                        size,
+                    );
+                }
+            }
+            messages.push(libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            });
+        }
+
+        SYSCALLS.fetch_add(1, Ordering::Relaxed);
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as _,
+                0,
+            )
+        };
+        if sent < 0 {
+            let e = io::Error::last_os_error();
+            let segmented = groups.iter().any(|group| group.len() > 1);
+            // Devices without checksum offload refuse segmented sends.
+            if segmented && e.raw_os_error() == Some(libc::EIO) {
+                SEGMENTATION.store(false, Ordering::Relaxed);
+                return send(socket, transmits);
+            }
+            return Err(e);
+        }
+        Ok(groups[..sent as usize]
+            .iter()
+            .map(|group| group.len())
+            .sum())
+    }
+
+    pub fn recv(
+        socket: SockRef,
+        buffers: &mut [IoSliceMut],
+        meta: &mut [RecvMeta],
+    ) -> io::Result<usize> {
+        let mut names: Vec<libc::sockaddr_storage> =
+            vec![unsafe { mem::zeroed() }; buffers.len()];
+        let mut controls: Vec<Control> = vec![[0; 4]; buffers.len()];
+        let mut messages = Vec::with_capacity(buffers.len());
+        for i in 0..buffers.len() {
+            let mut header: libc::msghdr = unsafe { mem::zeroed() };
+            header.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            header.msg_namelen =
+                mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            // `IoSliceMut` is guaranteed to be ABI compatible with `iovec`.
+            header.msg_iov = &mut buffers[i] as *mut _ as *mut libc::iovec;
+            header.msg_iovlen = 1;
+            header.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            header.msg_controllen = mem::size_of::<Control>() as _;
+            messages.push(libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            });
+        }
+
+        SYSCALLS.fetch_add(1, Ordering::Relaxed);
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as _,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let received = received as usize;
+        for (i, message) in messages[..received].iter().enumerate() {
+            let len = message.msg_len as usize;
+            let mut stride = len;
+            let header = &message.msg_hdr;
+            let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(header) };
+            while let Some(control) = unsafe { cmsg.as_ref() } {
+                if control.cmsg_level == libc::SOL_UDP
+                    && control.cmsg_type == libc::UDP_GRO
+                {
+                    stride = unsafe {
+                        ptr::read_unaligned(
+                            libc::CMSG_DATA(control) as *const libc::c_int
+                        )
+                    } as usize;
+                }
+                cmsg = unsafe { libc::CMSG_NXTHDR(header, control) };
+            }
+
+            let mut storage = SockAddrStorage::zeroed();
+            let source = unsafe {
+                *storage.view_as::<libc::sockaddr_storage>() = names[i];
+                SockAddr::new(storage, header.msg_namelen)
+            };
+            let source = source.as_socket().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "not an IP address")
+            })?;
+            meta[i] = RecvMeta {
+                source: super::canonical(source),
+                len,
+                stride,
+            };
+        }
+        Ok(received)
+    }
+}
@@ -0,0 +1,257 @@
+//! Estimation of a server's clock.
+//!
+//! Lag compensation, and measuring how long datagrams take each way, need a
+//! client to know what time it is on the server. A `ClockSync` exchanges
+//! timestamps with the server's `ClockServer` as NTP does: a request carries
+//! the time the client sent it, and the response the times the server
+//! received and answered it. The client then knows the round trip, and the
+//! offset of the server's clock from its own to within half of it. Requests
+//! and responses should go on an `UnreliableUnordered` stream, since a
+//! retransmitted exchange only measures the retransmission timeout.
+//!
+//! Exchanges which waited in a queue on the way give offsets far from the
+//! truth, so the client keeps only those with round trips close to the
+//! shortest of its recent ones, and estimates the offset where their bounds
+//! agree. The bounds of older exchanges widen as the clocks may have drifted
+//! apart since, and new exchanges replace them, so that the estimate follows
+//! the drift.
+
+use crate::{
+    codec::{put_varint, Reader},
+    error::DecodeError,
+};
+use nhanh::Bytes;
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+/// How fast clocks are taken to drift apart at most, in parts per million.
+/// NTP assumes 15; this allows for clocks nothing disciplines.
+const MAX_DRIFT_PPM: i64 = 100;
+
+/// How a `ClockSync` samples the server's clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockConfig {
+    /// The time between requests.
+    pub interval: Duration,
+    /// How many of the most recent exchanges the estimate is made from.
+    pub samples: usize,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            samples: 8,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// An estimate of the server's clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerTime {
+    /// The time on the server's clock.
+    pub time: Duration,
+    /// How far the server's clock may be from `time`.
+    pub error: Duration,
+}
+
+/// An exchange of timestamps, in microseconds on the client's clock.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// When the response arrived.
+    received: i64,
+    /// What to add to the client's clock to read the server's.
+    offset: i64,
+    round_trip: i64,
+}
+
+/// Estimates a server's clock from exchanges with its `ClockServer`.
+#[derive(Debug)]
+pub struct ClockSync {
+    config: ClockConfig,
+    epoch: Instant,
+    /// Recent exchanges, oldest first.
+    samples: VecDeque<Sample>,
+    next_request: Option<Instant>,
+}
+
+impl ClockSync {
+    pub fn new(now: Instant, config: ClockConfig) -> Self {
+        Self {
+            config,
+            epoch: now,
+            samples: VecDeque::new(),
+            next_request: None,
+        }
+    }
+
+    /// Returns the client's clock at `instant`, in microseconds.
+    fn micros(&self, instant: Instant) -> i64 {
+        match instant.checked_duration_since(self.epoch) {
+            Some(since) => since.as_micros() as i64,
+            None => -(self.epoch.duration_since(instant).as_micros() as i64),
+        }
+    }
+
+    /// Returns a request to send to the server, when one is due.
+    pub fn poll_request(&mut self, now: Instant) -> Option<Bytes> {
+        if self.next_request.is_some_and(|next| now < next) {
+            return None;
+        }
+        self.next_request = Some(now + self.config.interval);
+        let mut request = vec![];
+        put_varint(&mut request, self.micros(now).max(0) as u64);
+        Some(request.into())
+    }
+
+    /// Handles a response from the server's `ClockServer::respond`.
+    pub fn handle_response(
+        &mut self,
+        now: Instant,
+        response: &[u8],
+    ) -> Result<(), DecodeError> {
+        let mut reader = Reader::new(response);
+        let sent: u64 = reader.varint()?;
+        // Server times are measured in `i64` microseconds, so larger ones
+        // are refused.
+        let server_received: i64 = reader.varint_into()?;
+        let server_sent: i64 = reader.varint_into()?;
+        let server_received = Duration::from_micros(server_received as u64);
+        let server_sent = Duration::from_micros(server_sent as u64);
+        let sent = self
+            .epoch
+            .checked_add(Duration::from_micros(sent))
+            .filter(|sent| *sent <= now)
+            .ok_or(DecodeError::Invalid("clock response"))?;
+        self.sample(sent, server_received, server_sent, now);
+        Ok(())
+    }
+
+    /// Records an exchange made some other way: a message sent at `sent`,
+    /// which the server received and answered at the times on its clock
+    /// given, and whose answer arrived at `received`.
+    pub fn sample(
+        &mut self,
+        sent: Instant,
+        server_received: Duration,
+        server_sent: Duration,
+        received: Instant,
+    ) {
+        let sent = self.micros(sent);
+        let received = self.micros(received);
+        let micros =
+            |time: Duration| time.as_micros().min(i64::MAX as u128) as i64;
+        let server_received = micros(server_received);
+        let server_sent = micros(server_sent);
+        // Exchanges with absurd times saturate rather than overflow.
+        let round_trip = received
+            .saturating_sub(sent)
+            .saturating_sub(server_sent.saturating_sub(server_received))
+            .max(0);
+        let offset = server_received
+            .saturating_sub(sent)
+            .saturating_add(server_sent.saturating_sub(received))
+            / 2;
+
+        if self.samples.len() >= self.config.samples.max(1) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            received,
+            offset,
+            round_trip,
+        });
+    }
+
+    /// The shortest round trip of the recent exchanges.
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.samples
+            .iter()
+            .map(|sample| sample.round_trip)
+            .min()
+            .map(|round_trip| Duration::from_micros(round_trip as u64))
+    }
+
+    /// Returns what time it is on the server's clock at `now`, and how far
+    /// off that may be, once there is an exchange to estimate it from.
+    pub fn server_time_estimate(&self, now: Instant) -> Option<ServerTime> {
+        let now = self.micros(now);
+        let shortest = self.samples.iter().map(|s| s.round_trip).min()?;
+        // The offset is within half an exchange's round trip of the one it
+        // gives, and further for older exchanges, as clocks drift.
+        let bounds = self
+            .samples
+            .iter()
+            .filter(|sample| sample.round_trip <= shortest.saturating_mul(2))
+            .map(|sample| {
+                let age = now.saturating_sub(sample.received).max(0);
+                let error = sample.round_trip / 2
+                    + age.saturating_mul(MAX_DRIFT_PPM) / 1_000_000;
+                (
+                    sample.offset.saturating_sub(error),
+                    sample.offset.saturating_add(error),
+                )
+            });
+        let agreed = bounds.clone().fold(
+            (i64::MIN, i64::MAX),
+            |(low, high), (sample_low, sample_high)| {
+                (low.max(sample_low), high.min(sample_high))
+            },
+        );
+        // Exchanges which disagree had clocks drift faster than allowed
+        // between them, and the tightest bound is the best left.
+        let (low, high) = match agreed {
+            (low, high) if low <= high => (low, high),
+            _ => bounds.min_by_key(|(low, high)| high.saturating_sub(*low))?,
+        };
+
+        let width = high.saturating_sub(low);
+        let offset = low.saturating_add(width / 2);
+        Some(ServerTime {
+            time: Duration::from_micros(
+                now.saturating_add(offset).max(0) as u64
+            ),
+            error: Duration::from_micros((width / 2) as u64),
+        })
+    }
+}
+
+/// Answers the `ClockSync` requests of a server's clients.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockServer {
+    epoch: Instant,
+}
+
+impl ClockServer {
+    /// Creates a server whose clock reads zero at `epoch`.
+    pub fn new(epoch: Instant) -> Self {
+        Self { epoch }
+    }
+
+    /// Returns the time on the server's clock at `now`.
+    pub fn time(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.epoch)
+    }
+
+    /// Returns the response to a request which arrived at `received`, to
+    /// send at `now`.
+    pub fn respond(
+        &self,
+        received: Instant,
+        now: Instant,
+        request: &[u8],
+    ) -> Result<Bytes, DecodeError> {
+        let sent = Reader::new(request).varint()?;
+        let mut response = vec![];
+        put_varint(&mut response, sent);
+        put_varint(&mut response, self.time(received).as_micros() as u64);
+        put_varint(&mut response, self.time(now).as_micros() as u64);
+        Ok(response.into())
+    }
+}
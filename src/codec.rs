@@ -0,0 +1,80 @@
+//! Primitives of the wire format.
+
+use crate::error::DecodeError;
+use std::convert::TryFrom;
+
+pub type Decoded<T> = std::result::Result<T, DecodeError>;
+
+/// Returns the encoded length of a varint.
+pub fn varint_len(value: u64) -> usize {
+    let bits = 64 - value.leading_zeros() as usize;
+    bits.div_ceil(7).max(1)
+}
+
+/// Appends a varint: seven bits to a byte, least significant first, with the
+/// high bit set on every byte but the last.
+pub fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads the primitives of the wire format from a packet.
+#[derive(Debug)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn byte(&mut self) -> Decoded<u8> {
+        let (&byte, rest) =
+            self.bytes.split_first().ok_or(DecodeError::Truncated)?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Decoded<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(DecodeError::Truncated);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    /// Reads everything left in the packet.
+    pub fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.bytes)
+    }
+
+    pub fn varint(&mut self) -> Decoded<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                break;
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::Invalid("varint"))
+    }
+
+    /// Reads a varint which must fit in `T`.
+    pub fn varint_into<T: TryFrom<u64>>(&mut self) -> Decoded<T> {
+        T::try_from(self.varint()?).map_err(|_| DecodeError::Invalid("varint"))
+    }
+}
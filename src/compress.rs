@@ -0,0 +1,83 @@
+//! Compression of datagrams.
+//!
+//! Endpoints which agreed on compression, and configured the same dictionary,
+//! may compress the datagrams they send with LZ4, primed with the dictionary.
+//! A dictionary trained on a game's messages lets even short ones shrink.
+//! Datagrams are compressed before they are split into fragments, and flow
+//! control counts their compressed bytes. Receivers buffer them compressed,
+//! and decompress each as it surfaces, so what the windows count bounds their
+//! memory. Datagrams which would not shrink are sent as they are, as are those
+//! on streams with compression turned off.
+
+use crate::codec::{put_varint, Reader};
+use std::marker::PhantomData;
+
+/// How a connection compresses the datagrams it sends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionConfig {
+    /// The bytes compression is primed with. Endpoints only agree on
+    /// compression if they configured the same dictionary.
+    pub dictionary: &'static [u8],
+    /// Datagrams shorter than this are not compressed.
+    pub min_len: usize,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            dictionary: &[],
+            min_len: 64,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// Returns the identifier of a dictionary which endpoints compare in the
+/// handshake: its 32 bit FNV-1a hash.
+pub fn dictionary_id(dictionary: &[u8]) -> u32 {
+    dictionary.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Compresses a datagram's data, unless it is too short or would not shrink.
+/// The result is a varint of the data's length, then an LZ4 block.
+pub fn compress(config: &CompressionConfig, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < config.min_len {
+        return None;
+    }
+    let mut compressed = vec![];
+    put_varint(&mut compressed, data.len() as u64);
+    compressed
+        .extend(lz4_flex::block::compress_with_dict(data, config.dictionary));
+    if compressed.len() >= data.len() {
+        return None;
+    }
+    Some(compressed)
+}
+
+/// Decompresses a datagram's data, unless it is invalid or would be longer
+/// than `max_len` bytes.
+pub fn decompress(
+    dictionary: &[u8],
+    compressed: &[u8],
+    max_len: u64,
+) -> Option<Vec<u8>> {
+    let mut reader = Reader::new(compressed);
+    let len = reader.varint().ok()?;
+    if len > max_len {
+        return None;
+    }
+    let data = lz4_flex::block::decompress_with_dict(
+        reader.rest(),
+        len as usize,
+        dictionary,
+    )
+    .ok()?;
+    if data.len() as u64 != len {
+        return None;
+    }
+    Some(data)
+}
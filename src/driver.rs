@@ -0,0 +1,614 @@
+//! `nhanh` connections and servers driven from async tasks.
+//!
+//! Sessions do no IO, so a driver task owns a UDP socket of a `Runtime` and
+//! every session on it, and wakes connections as their sessions change. A
+//! client's driver holds its one connection; a server spawns a driver for
+//! each of its sockets, which all deliver their connections to it.
+//!
+//! Flushing a connection hands its datagrams to the session, which coalesces
+//! them for up to `Config::max_coalesce_delay`; it does not wait for them to
+//! be sent.
+//!
+//! Drivers send and receive packets in batches of up to
+//! `DriverConfig::batch_size`. Packets the socket fails to send are dropped,
+//! as the network might have dropped them, so that one unreachable peer does
+//! not end the connections of the others.
+
+use crate::{
+    batch::{self, RecvMeta, Transmit, BATCH_SIZE, RECV_BUFFER_BYTES},
+    runtime::{self, AsyncUdpSocket, Runtime},
+    session::{Config, Session},
+    Error,
+};
+use futures::{
+    channel::mpsc,
+    future,
+    prelude::*,
+    stream::{FusedStream, LocalBoxStream},
+};
+use nhanh::{
+    events::{BandwidthEstimator, Events, ServerEvents},
+    BandwidthEstimate, Bytes, Close, CloseReason, ConnectionEvent,
+    ConnectionStats, Datagram, DeliveryMode, EventThresholds, SendCmd,
+    SendLimits, ServerEvent,
+};
+use std::{
+    collections::HashMap,
+    io::{self, IoSliceMut},
+    marker::PhantomData,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+/// How long a driver sleeps when no session has a timeout.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// How a driver runs its sessions.
+#[derive(Debug, Clone, Copy)]
+pub struct DriverConfig {
+    /// The configuration of every session the driver runs.
+    pub session: Config,
+    /// The most packets sent or received per syscall.
+    pub batch_size: usize,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        Self {
+            session: Config::default(),
+            batch_size: BATCH_SIZE,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// A session, and the tasks waiting on it.
+struct Peer {
+    session: Session,
+    events: Events,
+    estimator: BandwidthEstimator,
+    /// Woken when a datagram surfaces or the session closes.
+    reader: Option<Waker>,
+    /// Woken when the send buffer has room or the session closes.
+    writer: Option<Waker>,
+    /// Woken when the session closes.
+    closer: Option<Waker>,
+}
+
+impl Peer {
+    fn new(session: Session, events: Events) -> Self {
+        Self {
+            session,
+            events,
+            estimator: BandwidthEstimator::default(),
+            reader: None,
+            writer: None,
+            closer: None,
+        }
+    }
+
+    /// Reports the session's events and link samples, and wakes the tasks
+    /// waiting on it.
+    fn sync(&mut self) {
+        while let Some(event) = self.session.poll_event() {
+            self.events.report(event);
+        }
+        let sample = self.estimator.sample(&self.session.stats());
+        self.events.observe(sample);
+
+        let closed = self.session.is_closed();
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+        if closed || self.session.has_room(None) {
+            if let Some(writer) = self.writer.take() {
+                writer.wake();
+            }
+        }
+        if closed {
+            if let Some(closer) = self.closer.take() {
+                closer.wake();
+            }
+        }
+    }
+}
+
+struct Driver<R: Runtime> {
+    runtime: R,
+    socket: R::Socket,
+    config: DriverConfig,
+    peers: HashMap<SocketAddr, Arc<Mutex<Peer>>>,
+    notify_sink: mpsc::UnboundedSender<()>,
+    notify_stream: mpsc::UnboundedReceiver<()>,
+    /// Where a server's connections are delivered. Clients accept none.
+    accepted: Option<mpsc::UnboundedSender<Connection>>,
+    events: ServerEvents,
+}
+
+impl<R: Runtime> Driver<R> {
+    async fn run(mut self) -> io::Result<()> {
+        let batch_size = self.config.batch_size.max(1);
+        let mut buffer = vec![0; batch_size * RECV_BUFFER_BYTES];
+        let mut meta = vec![RecvMeta::default(); batch_size];
+        loop {
+            let now = Instant::now();
+            let timeout = self.service(now).await;
+
+            let accepting = self
+                .accepted
+                .as_ref()
+                .is_some_and(|accepted| !accepted.is_closed());
+            if self.peers.is_empty() && !accepting {
+                return Ok(());
+            }
+
+            let wake = timeout.unwrap_or(now + IDLE_WAIT);
+            let socket = &self.socket;
+            let mut buffers: Vec<IoSliceMut> = buffer
+                .chunks_mut(RECV_BUFFER_BYTES)
+                .map(IoSliceMut::new)
+                .collect();
+            let received = futures::select! {
+                received = future::poll_fn(
+                    |ctx| socket.poll_recv(ctx, &mut buffers, &mut meta)
+                ).fuse() => match received {
+                    // A peer's port was closed, which its session times out
+                    // on.
+                    Err(e)
+                        if e.kind() == io::ErrorKind::ConnectionRefused => 0,
+                    received => received?,
+                },
+                _ = self.notify_stream.next() => {
+                    while let Some(Some(_)) =
+                        self.notify_stream.next().now_or_never() {}
+                    0
+                }
+                _ = self.runtime.sleep_until(wake).fuse() => 0,
+            };
+            drop(buffers);
+
+            for (meta, buffer) in meta[..received]
+                .iter()
+                .zip(buffer.chunks(RECV_BUFFER_BYTES))
+            {
+                for packet in meta.packets(buffer) {
+                    self.receive(packet, meta.source);
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, packet: &[u8], peer_addr: SocketAddr) {
+        let now = Instant::now();
+        if let Some(peer) = self.peers.get(&peer_addr) {
+            let packet = Bytes::copy_from_slice(packet);
+            peer.lock().expect("peer").session.handle(now, packet);
+            return;
+        }
+
+        let accepted = match self.accepted.as_ref() {
+            Some(accepted) => accepted,
+            None => return,
+        };
+        let session = match Session::accept(now, self.config.session, packet) {
+            Some(session) => session,
+            None => return,
+        };
+        // Sessions with incompatible clients only send a rejection, so the
+        // driver holds them until they close, and they are never accepted.
+        let compatible = session.is_connected();
+        let mut events = Events::connected();
+        self.events.attach(peer_addr, &mut events);
+        let peer = Arc::new(Mutex::new(Peer::new(session, events)));
+        self.peers.insert(peer_addr, peer.clone());
+        if !compatible {
+            return;
+        }
+        let _ = accepted
+            .unbounded_send(Connection::new(peer, self.notify_sink.clone()));
+    }
+
+    /// Handles timeouts and sends what each session has to send. Sessions
+    /// whose connections were dropped are closed, and then forgotten. Returns
+    /// the earliest timeout of the remaining sessions.
+    async fn service(&mut self, now: Instant) -> Option<Instant> {
+        let mut transmits = vec![];
+        let mut timeout: Option<Instant> = None;
+        self.peers.retain(|peer_addr, peer| {
+            let abandoned = Arc::strong_count(peer) == 1;
+            let mut peer = peer.lock().expect("peer");
+            if abandoned {
+                peer.session.close(now, &Close::default());
+            }
+            if peer.session.poll_timeout().is_some_and(|t| t <= now) {
+                peer.session.handle_timeout(now);
+            }
+            while let Some(packet) = peer.session.poll_transmit(now) {
+                transmits.push((packet, *peer_addr));
+            }
+            peer.sync();
+
+            if let Some(t) = peer.session.poll_timeout() {
+                timeout = Some(timeout.map_or(t, |timeout| timeout.min(t)));
+            }
+            !(abandoned && peer.session.is_closed())
+        });
+
+        let transmits: Vec<Transmit> = transmits
+            .iter()
+            .map(|(packet, peer_addr)| Transmit {
+                destination: *peer_addr,
+                contents: packet,
+            })
+            .collect();
+        let batch_size = self.config.batch_size.max(1);
+        let socket = &self.socket;
+        let mut sent = 0;
+        while sent < transmits.len() {
+            let batch =
+                &transmits[sent..transmits.len().min(sent + batch_size)];
+            match future::poll_fn(|ctx| socket.poll_send(ctx, batch)).await {
+                Ok(count) => sent += count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Sending to the first destination left failed.
+                Err(_) => {
+                    let destination = batch[0].destination;
+                    sent += batch
+                        .iter()
+                        .take_while(|transmit| {
+                            transmit.destination == destination
+                        })
+                        .count();
+                }
+            }
+        }
+
+        timeout
+    }
+}
+
+/// Accepts connections on sockets driven by tasks of a `Runtime`.
+pub struct Server {
+    accepted: mpsc::UnboundedReceiver<Connection>,
+    events: ServerEvents,
+}
+
+impl Server {
+    /// Binds a socket to `address`. The socket is dual-stack if `address` is
+    /// `[::]`.
+    pub fn bind<R: Runtime + Clone>(
+        runtime: R,
+        config: DriverConfig,
+        address: SocketAddr,
+    ) -> io::Result<Self> {
+        let socket = match address.ip() {
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                runtime::bind_dual_stack(address.port())?
+            }
+            _ => UdpSocket::bind(address)?,
+        };
+        Self::serve(runtime, config, vec![socket])
+    }
+
+    /// Binds an IPv4 socket and an IPv6 only socket to `port`, for systems
+    /// where sockets cannot be dual-stack.
+    pub fn bind_paired<R: Runtime + Clone>(
+        runtime: R,
+        config: DriverConfig,
+        port: u16,
+    ) -> io::Result<Self> {
+        let (ipv4, ipv6) = runtime::bind_pair(port)?;
+        Self::serve(runtime, config, vec![ipv4, ipv6])
+    }
+
+    /// Binds `workers` sockets to `address` with `SO_REUSEPORT`, and drives
+    /// each from its own task, so connections spread across the runtime's
+    /// threads. Each connection stays with the socket its first packet
+    /// reached.
+    #[cfg(target_os = "linux")]
+    pub fn bind_sharded<R: Runtime + Clone>(
+        runtime: R,
+        config: DriverConfig,
+        address: SocketAddr,
+        workers: usize,
+    ) -> io::Result<Self> {
+        let sockets = runtime::bind_reuse_port(address, workers)?;
+        Self::serve(runtime, config, sockets)
+    }
+
+    /// Spawns a driver for each of `sockets`, which all deliver their
+    /// connections to the one server.
+    pub fn serve<R: Runtime + Clone>(
+        runtime: R,
+        config: DriverConfig,
+        sockets: Vec<UdpSocket>,
+    ) -> io::Result<Self> {
+        let (accepted_sink, accepted) = mpsc::unbounded();
+        let events = ServerEvents::default();
+
+        for socket in sockets {
+            let socket = runtime.wrap_udp_socket(socket)?;
+            let (notify_sink, notify_stream) = mpsc::unbounded();
+            runtime.spawn(Box::pin(
+                Driver {
+                    runtime: runtime.clone(),
+                    socket,
+                    config,
+                    peers: HashMap::new(),
+                    notify_sink,
+                    notify_stream,
+                    accepted: Some(accepted_sink.clone()),
+                    events: events.clone(),
+                }
+                .run()
+                .map(drop),
+            ));
+        }
+
+        Ok(Self { accepted, events })
+    }
+}
+
+impl nhanh::Server<Connection> for Server {
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ServerEvent> {
+        self.events.subscribe(thresholds)
+    }
+}
+
+impl Stream for Server {
+    type Item = nhanh::Result<Connection>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.accepted)
+            .poll_next(ctx)
+            .map(|connection| connection.map(Ok))
+    }
+}
+
+impl FusedStream for Server {
+    fn is_terminated(&self) -> bool {
+        self.accepted.is_terminated()
+    }
+}
+
+/// A connection whose session a driver task runs.
+pub struct Connection {
+    peer: Arc<Mutex<Peer>>,
+    /// Wakes the driver after the session changes.
+    notify_sink: mpsc::UnboundedSender<()>,
+    closing: bool,
+    terminated: bool,
+    close_reason: Option<CloseReason>,
+}
+
+impl Connection {
+    fn new(
+        peer: Arc<Mutex<Peer>>,
+        notify_sink: mpsc::UnboundedSender<()>,
+    ) -> Self {
+        Self {
+            peer,
+            notify_sink,
+            closing: false,
+            terminated: false,
+            close_reason: None,
+        }
+    }
+
+    /// Opens a connection to `server` from a socket of its address family,
+    /// and resolves once the handshake completes.
+    pub async fn connect<R: Runtime + Clone>(
+        runtime: R,
+        config: DriverConfig,
+        server: SocketAddr,
+    ) -> nhanh::Result<Self> {
+        let ip: IpAddr = match server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = runtime.bind_udp((ip, 0).into())?;
+        let session = Session::connect(Instant::now(), config.session);
+        let peer = Arc::new(Mutex::new(Peer::new(session, Events::default())));
+        let (notify_sink, notify_stream) = mpsc::unbounded();
+
+        let mut peers = HashMap::new();
+        // Replies come from the canonical address.
+        peers.insert(batch::canonical(server), peer.clone());
+        let driver = Driver {
+            runtime: runtime.clone(),
+            socket,
+            config,
+            peers,
+            notify_sink: notify_sink.clone(),
+            notify_stream,
+            accepted: None,
+            events: ServerEvents::default(),
+        };
+        runtime.spawn(Box::pin(driver.run().map(drop)));
+
+        future::poll_fn(|ctx| {
+            let mut peer = peer.lock().expect("peer");
+            if peer.session.is_connected() {
+                return Poll::Ready(Ok(()));
+            }
+            if peer.session.is_closed() {
+                let error = peer.session.take_error().unwrap_or(Error::Closed);
+                return Poll::Ready(Err(error));
+            }
+            peer.writer = Some(ctx.waker().clone());
+            Poll::Pending
+        })
+        .await?;
+
+        Ok(Self::new(peer, notify_sink))
+    }
+
+    fn notify(&self) {
+        let _ = self.notify_sink.unbounded_send(());
+    }
+
+    fn poll_ready_inner(
+        &mut self,
+        ctx: &mut Context,
+        delivery_mode: Option<DeliveryMode>,
+    ) -> Poll<nhanh::Result<()>> {
+        let mut peer = self.peer.lock().expect("peer");
+        if peer.session.is_closed() {
+            return Poll::Ready(Err(Error::Closed.into()));
+        }
+        if peer.session.has_room(delivery_mode) {
+            return Poll::Ready(Ok(()));
+        }
+        peer.writer = Some(ctx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Connection {
+    /// Wakes the driver to close the session.
+    fn drop(&mut self) {
+        self.notify();
+    }
+}
+
+impl nhanh::Connection for Connection {
+    fn poll_close_with(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        close: &Close,
+    ) -> Poll<nhanh::Result<()>> {
+        let mut peer = self.peer.lock().expect("peer");
+        if !self.closing {
+            peer.session.close(Instant::now(), close);
+            drop(peer);
+            self.closing = true;
+            self.notify();
+            peer = self.peer.lock().expect("peer");
+        }
+
+        if peer.session.is_closed() {
+            return Poll::Ready(Ok(()));
+        }
+        peer.closer = Some(ctx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_ready_for(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        delivery_mode: DeliveryMode,
+    ) -> Poll<nhanh::Result<()>> {
+        self.poll_ready_inner(ctx, Some(delivery_mode))
+    }
+
+    fn set_send_limits(&mut self, limits: SendLimits) {
+        self.peer
+            .lock()
+            .expect("peer")
+            .session
+            .set_send_limits(limits);
+    }
+
+    fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    fn events(
+        &mut self,
+        thresholds: EventThresholds,
+    ) -> LocalBoxStream<'static, ConnectionEvent> {
+        self.peer.lock().expect("peer").events.subscribe(thresholds)
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.peer.lock().expect("peer").session.stats()
+    }
+
+    fn bandwidth(&self) -> BandwidthEstimate {
+        self.peer.lock().expect("peer").estimator.estimate()
+    }
+}
+
+impl Sink<SendCmd> for Connection {
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<nhanh::Result<()>> {
+        self.poll_ready_inner(ctx, None)
+    }
+    fn start_send(self: Pin<&mut Self>, item: SendCmd) -> nhanh::Result<()> {
+        self.peer
+            .lock()
+            .expect("peer")
+            .session
+            .send(Instant::now(), item)?;
+        self.notify();
+        Ok(())
+    }
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _: &mut Context,
+    ) -> Poll<nhanh::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<nhanh::Result<()>> {
+        nhanh::Connection::poll_close_with(self, ctx, &Close::default())
+    }
+}
+
+impl Stream for Connection {
+    type Item = nhanh::Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+
+        let mut peer = self.peer.lock().expect("peer");
+        if let Some(datagram) = peer.session.poll_datagram() {
+            drop(peer);
+            // Surfacing datagrams may raise the limits to advertise.
+            self.notify();
+            return Poll::Ready(Some(Ok(datagram)));
+        }
+
+        if !peer.session.is_closed() {
+            peer.reader = Some(ctx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let close_reason = peer.session.close_reason().cloned();
+        let error = peer.session.take_error();
+        drop(peer);
+        self.close_reason = close_reason;
+        match error {
+            Some(error) => Poll::Ready(Some(Err(error.into()))),
+            None => {
+                self.terminated = true;
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+impl FusedStream for Connection {
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
@@ -0,0 +1,90 @@
+//! Errors of the miknet protocol.
+
+use crate::{handshake::Features, stream::StreamKey};
+use nhanh::StreamId;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("malformed packet: {0}")]
+    Malformed(#[from] DecodeError),
+    /// The remote endpoint broke the protocol, so the connection was closed.
+    #[error("remote endpoint violated the protocol: {0}")]
+    Violation(Violation),
+    /// The remote endpoint closed the connection because this endpoint broke
+    /// the protocol.
+    #[error("remote endpoint reported a protocol violation: {0}")]
+    ViolationReported(Violation),
+    /// The endpoints cannot talk to each other.
+    #[error("remote endpoint is incompatible: {0}")]
+    Incompatible(Incompatibility),
+    #[error("remote endpoint stopped responding")]
+    TimedOut,
+    #[error("connection is closed")]
+    Closed,
+    /// The send limits have no room for another datagram until buffered ones
+    /// are acknowledged.
+    #[error("send buffer is full")]
+    Full,
+    /// A flow controlled datagram is larger than the remote endpoint will
+    /// ever buffer, or a datagram is split into more fragments than can be
+    /// numbered.
+    #[error("datagram of {len} bytes exceeds the remote limit of {max}")]
+    TooLarge { len: usize, max: u64 },
+    /// A datagram was sent on a stream beyond those the endpoints agreed to.
+    #[error("stream {stream:?} is beyond the {max} streams agreed on")]
+    StreamLimit { stream: StreamId, max: u32 },
+}
+
+/// A way in which an endpoint broke the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum Violation {
+    #[error("stream {0:?} received more bytes than its window")]
+    StreamWindowExceeded(StreamKey),
+    #[error("connection received more bytes than its window")]
+    ConnectionWindowExceeded,
+    #[error("stream {0:?} received a datagram beyond its reorder window")]
+    ReorderWindowExceeded(StreamKey),
+    #[error("stream {0:?} received a datagram without an index")]
+    MissingIndex(StreamKey),
+    #[error("received a fragment inconsistent with earlier fragments")]
+    InconsistentFragment,
+    #[error("received a packet before the handshake completed")]
+    UnexpectedPacket,
+    #[error("stream {0:?} is beyond the streams agreed on")]
+    StreamLimitExceeded(StreamKey),
+    #[error("stream {0:?} was reclaimed before its datagrams arrived")]
+    PrematureReclaim(StreamKey),
+    #[error("stream {0:?} received a datagram which does not decompress")]
+    MalformedCompression(StreamKey),
+}
+
+/// A way in which two endpoints cannot talk to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum Incompatibility {
+    /// The endpoints speak no protocol version in common. The remote endpoint
+    /// speaks versions `min` through `max`.
+    #[error("remote endpoint speaks protocol versions {min} to {max}")]
+    Version { min: u32, max: u32 },
+    /// An endpoint requires features which the other lacks.
+    #[error("connection lacks required features {0:?}")]
+    Features(Features),
+}
+
+/// A way in which a packet is malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum DecodeError {
+    #[error("packet ended early")]
+    Truncated,
+    #[error("unknown packet type {0:#04x}")]
+    UnknownPacket(u8),
+    /// A handshake is in a protocol version this endpoint does not speak.
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unknown frame type {0:#04x}")]
+    UnknownFrame(u8),
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+}
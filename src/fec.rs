@@ -0,0 +1,205 @@
+//! Forward error correction.
+//!
+//! A sender using forward error correction follows each group of consecutively
+//! numbered packets with a `Repair` frame holding the parity of their
+//! payloads. A receiver missing exactly one packet of the group rebuilds it
+//! from the parity and the others, so the loss costs no round trip to recover.
+//!
+//! Groups open with a packet carrying datagrams on a protected stream, and
+//! close when they reach their size, or half a round trip after they opened so
+//! that sparse traffic is protected too. Their size shrinks as the loss rate
+//! grows. The loss rate counts the packets receivers rebuilt, which they
+//! report in acknowledgements, so that it measures the link rather than what
+//! forward error correction left of its losses.
+
+use crate::frame::Repair;
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+/// The bytes a `Repair` packet may take beyond the packets it protects.
+/// Packets are kept this much smaller than the MTU when forward error
+/// correction is configured.
+pub const REPAIR_OVERHEAD: usize = 32;
+
+/// How many packets a receiver keeps to rebuild others from.
+const KEPT_PACKETS: usize = 64;
+
+/// How a connection sends forward error correction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FecConfig {
+    /// Below this loss rate, losses are left to retransmission.
+    pub min_loss: f32,
+    /// The fewest packets one repair protects, at high loss rates.
+    pub min_group: u32,
+    /// The most packets one repair protects, at low loss rates. Receivers
+    /// only rebuild packets in groups of up to 64.
+    pub max_group: u32,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for FecConfig {
+    fn default() -> Self {
+        Self {
+            min_loss: 0.005,
+            min_group: 2,
+            max_group: 16,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+impl FecConfig {
+    /// Returns how many packets a group holds at a loss rate, aiming for one
+    /// loss in four groups.
+    fn group_size(&self, loss: f32) -> Option<u32> {
+        if loss < self.min_loss {
+            return None;
+        }
+        let size = (1.0 / (4.0 * loss)).round() as u32;
+        let max_group = self.max_group.clamp(1, KEPT_PACKETS as u32);
+        Some(size.clamp(self.min_group.clamp(1, max_group), max_group))
+    }
+}
+
+#[derive(Debug)]
+struct Group {
+    first: u64,
+    count: u32,
+    size: u32,
+    deadline: Instant,
+    len: u64,
+    parity: Vec<u8>,
+}
+
+/// Groups sent packets, and computes their repairs.
+#[derive(Debug)]
+pub struct Encoder {
+    config: FecConfig,
+    group: Option<Group>,
+}
+
+impl Encoder {
+    pub fn new(config: FecConfig) -> Self {
+        Self {
+            config,
+            group: None,
+        }
+    }
+
+    /// Records a sent packet's payload, and returns a repair if it completes a
+    /// group. Only `protected` packets open groups.
+    pub fn on_sent(
+        &mut self,
+        now: Instant,
+        rtt: Duration,
+        loss: f32,
+        number: u64,
+        payload: &[u8],
+        protected: bool,
+    ) -> Option<Repair> {
+        if self.group.is_none() && protected {
+            self.group = self.config.group_size(loss).map(|size| Group {
+                first: number,
+                count: 0,
+                size,
+                deadline: now + rtt / 2,
+                len: 0,
+                parity: vec![],
+            });
+        }
+        let group = self.group.as_mut()?;
+        if group.first + u64::from(group.count) != number {
+            // A packet went unrecorded, so the group cannot be repaired.
+            self.group = None;
+            return None;
+        }
+        group.count += 1;
+        group.len ^= payload.len() as u64;
+        xor(&mut group.parity, payload);
+        if group.count < group.size {
+            return None;
+        }
+        self.take_repair()
+    }
+
+    /// Returns when an open group is due to close.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.group.as_ref().map(|group| group.deadline)
+    }
+
+    /// Closes the open group if it is due, and returns its repair.
+    pub fn handle_timeout(&mut self, now: Instant) -> Option<Repair> {
+        match self.group {
+            Some(ref group) if group.deadline <= now => self.take_repair(),
+            _ => None,
+        }
+    }
+
+    fn take_repair(&mut self) -> Option<Repair> {
+        self.group.take().map(|group| Repair {
+            first: group.first,
+            count: group.count,
+            len: group.len,
+            data: group.parity,
+        })
+    }
+}
+
+/// Keeps received payloads, and rebuilds missing packets from repairs.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    payloads: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Decoder {
+    pub fn on_received(&mut self, number: u64, payload: &[u8]) {
+        self.payloads.insert(number, payload.to_vec());
+        while self.payloads.len() > KEPT_PACKETS {
+            let oldest = *self.payloads.keys().next().expect("payload");
+            self.payloads.remove(&oldest);
+        }
+    }
+
+    /// Returns the number and payload of the packet a repair rebuilds, if
+    /// exactly one of its group is missing.
+    pub fn on_repair(&mut self, repair: &Repair) -> Option<(u64, Vec<u8>)> {
+        if repair.count as usize > KEPT_PACKETS {
+            return None;
+        }
+        let end = repair.first.checked_add(u64::from(repair.count))?;
+        let numbers = repair.first..end;
+        let mut missing = numbers
+            .clone()
+            .filter(|number| !self.payloads.contains_key(number));
+        let number = missing.next()?;
+        if missing.next().is_some() {
+            return None;
+        }
+
+        let mut payload = repair.data.clone();
+        let mut len = repair.len;
+        for (_, other) in self.payloads.range(numbers) {
+            len ^= other.len() as u64;
+            xor(&mut payload, other);
+        }
+        if len > payload.len() as u64 {
+            return None;
+        }
+        payload.truncate(len as usize);
+        Some((number, payload))
+    }
+}
+
+/// XORs `bytes` into `parity`, as though the shorter were padded with zeroes.
+fn xor(parity: &mut Vec<u8>, bytes: &[u8]) {
+    if parity.len() < bytes.len() {
+        parity.resize(bytes.len(), 0);
+    }
+    for (parity, byte) in parity.iter_mut().zip(bytes) {
+        *parity ^= byte;
+    }
+}
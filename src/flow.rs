@@ -0,0 +1,120 @@
+//! Flow control.
+//!
+//! Ordered streams and reliable datagrams on the unordered stream are flow
+//! controlled, because their receivers must buffer datagrams which arrive out
+//! of order. Each receiver advertises how many bytes it will buffer for each of
+//! these streams and for all of them together, and how far ahead of the oldest
+//! datagram it is waiting for the sender may send. A sender never exceeds what
+//! it was last told, so a receiver which sees more closes the connection.
+//!
+//! Sequenced streams and unreliable unordered datagrams surface as soon as they
+//! arrive, or never, so they are not flow controlled. A receiver drops them
+//! instead of buffering more than its connection limit.
+
+use std::marker::PhantomData;
+
+/// Limits on what a connection buffers for the remote endpoint. Buffered
+/// datagrams are those received but not yet surfaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReceiveLimits {
+    /// A limit on the bytes buffered for each flow controlled stream.
+    pub stream_bytes: u64,
+    /// A limit on the bytes buffered for all streams.
+    pub connection_bytes: u64,
+    /// How far past the oldest datagram a flow controlled stream is waiting
+    /// for the remote endpoint may send.
+    pub reorder_datagrams: u32,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for ReceiveLimits {
+    fn default() -> Self {
+        Self {
+            stream_bytes: 256 << 10,
+            connection_bytes: 1 << 20,
+            reorder_datagrams: 1024,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// The byte limit a receiver extends for a stream or a connection.
+#[derive(Debug)]
+pub struct ReceiveWindow {
+    window: u64,
+    buffered: u64,
+    surfaced: u64,
+    /// The most bytes the remote endpoint has been allowed to send.
+    advertised: u64,
+}
+
+impl ReceiveWindow {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            buffered: 0,
+            surfaced: 0,
+            advertised: window,
+        }
+    }
+
+    /// Buffers received bytes. Returns `false` if the remote endpoint sent
+    /// more than it was allowed.
+    ///
+    /// A sender may have sent up to `advertised` bytes, and at least
+    /// `advertised - window` of those have surfaced, so a well behaved sender
+    /// never fills more than the window.
+    #[must_use]
+    pub fn receive(&mut self, bytes: u64) -> bool {
+        self.buffered += bytes;
+        self.buffered <= self.window
+    }
+
+    pub fn surface(&mut self, bytes: u64) {
+        self.buffered -= bytes;
+        self.surfaced += bytes;
+    }
+
+    /// Returns a new limit to advertise, once the remote endpoint could use
+    /// half of the window without one.
+    pub fn update(&mut self) -> Option<u64> {
+        let max = self.surfaced + self.window;
+        if max - self.advertised >= self.window / 2 {
+            self.advertised = max;
+            Some(max)
+        } else {
+            None
+        }
+    }
+
+    pub fn advertised(&self) -> u64 {
+        self.advertised
+    }
+}
+
+/// The byte limit a sender was extended for a stream or a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct SendWindow {
+    max: u64,
+    sent: u64,
+}
+
+impl SendWindow {
+    pub fn new(max: u64) -> Self {
+        Self { max, sent: 0 }
+    }
+
+    pub fn available(&self) -> u64 {
+        self.max - self.sent
+    }
+
+    pub fn send(&mut self, bytes: u64) {
+        self.sent += bytes;
+    }
+
+    /// Limits only increase, so stale advertisements are ignored.
+    pub fn raise(&mut self, max: u64) {
+        self.max = self.max.max(max);
+    }
+}
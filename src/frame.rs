@@ -0,0 +1,703 @@
+//! Packets, and the frames they carry.
+//!
+//! The wire format is specified in `wire.md` at the root of the repository.
+
+use crate::{
+    codec::{put_varint, varint_len, Decoded, Reader},
+    error::*,
+    flow::ReceiveLimits,
+    handshake::{Capabilities, Features, MIN_VERSION, VERSION},
+    serial,
+    stream::StreamKey,
+};
+use nhanh::{Bytes, CloseReason, StreamId};
+use std::{convert::TryFrom, marker::PhantomData, time::Duration};
+
+/// The most bytes a `Packet::Data` spends outside of its frames.
+pub const DATA_OVERHEAD: usize = 9;
+
+/// The most bytes a datagram frame spends outside of its data, for datagrams
+/// smaller than 2 MiB.
+pub const DATAGRAM_OVERHEAD: usize = 22;
+
+const CONNECT: u8 = 0x01;
+const ACCEPT: u8 = 0x02;
+const REJECT: u8 = 0x03;
+/// The low three bits of a data packet's type hold the length of its number,
+/// less one.
+const DATA: u8 = 0x08;
+const DATA_MASK: u8 = 0xf8;
+
+const ACK: u8 = 0x01;
+const MAX_BYTES: u8 = 0x02;
+const MAX_STREAM_BYTES: u8 = 0x03;
+const MAX_INDEX: u8 = 0x04;
+const PING: u8 = 0x05;
+const CLOSE: u8 = 0x06;
+const ABORT: u8 = 0x07;
+const REPAIR: u8 = 0x08;
+const SKIP: u8 = 0x09;
+const RECLAIM: u8 = 0x0a;
+
+/// The high bit of a datagram frame's type is set, and the rest are flags.
+const DATAGRAM: u8 = 0x80;
+const STREAM: u8 = 0x40;
+const INDEX_MASK: u8 = 0x30;
+const INDEX_NONE: u8 = 0x00;
+const INDEX_NEXT: u8 = 0x10;
+const INDEX_SAME: u8 = 0x20;
+const INDEX_EXPLICIT: u8 = 0x30;
+const FRAGMENT: u8 = 0x08;
+const LENGTH: u8 = 0x04;
+const COMPRESSED: u8 = 0x02;
+const RESERVED: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    /// Opens a connection.
+    Connect(Hello),
+    /// Accepts a connection.
+    Accept(Hello),
+    /// Refuses a connection with an incompatible endpoint.
+    Reject(Reject),
+    Data {
+        /// Numbers count up from `0` on each endpoint, and are never reused.
+        number: u64,
+        frames: Vec<Frame>,
+    },
+}
+
+impl Packet {
+    /// Encodes the packet. Packet numbers are shortened to what the remote
+    /// endpoint needs to tell them apart, given the largest packet number it
+    /// has acknowledged.
+    pub fn encode(&self, largest_acked: Option<u64>) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Packet::Connect(hello) => {
+                buf.push(CONNECT);
+                hello.encode(&mut buf);
+            }
+            Packet::Accept(hello) => {
+                buf.push(ACCEPT);
+                hello.encode(&mut buf);
+            }
+            Packet::Reject(reject) => {
+                buf.push(REJECT);
+                put_varint(&mut buf, reject.min_version.into());
+                put_varint(&mut buf, reject.max_version.into());
+                put_varint(&mut buf, reject.missing.bits());
+            }
+            Packet::Data { number, frames } => {
+                let len = number_len(*number, largest_acked);
+                buf.push(DATA | (len - 1) as u8);
+                buf.extend_from_slice(&number.to_be_bytes()[8 - len..]);
+                let mut previous = Previous::default();
+                for (i, frame) in frames.iter().enumerate() {
+                    let last = i + 1 == frames.len();
+                    frame.encode(&mut buf, &mut previous, last);
+                }
+            }
+        }
+        buf
+    }
+
+    /// Decodes a packet, given the largest packet number received from the
+    /// remote endpoint. The data of its datagrams shares `bytes`.
+    pub fn decode(
+        bytes: &Bytes,
+        largest_received: Option<u64>,
+    ) -> Result<Self> {
+        let mut reader = Reader::new(bytes);
+        let packet = match reader.byte()? {
+            CONNECT => Packet::Connect(Hello::decode(&mut reader)?),
+            ACCEPT => Packet::Accept(Hello::decode(&mut reader)?),
+            REJECT => Packet::Reject(Reject {
+                min_version: reader.varint_into()?,
+                max_version: reader.varint_into()?,
+                missing: Features::from_bits(reader.varint()?),
+            }),
+            kind if kind & DATA_MASK == DATA => {
+                let len = usize::from(kind & !DATA_MASK) + 1;
+                let truncated = reader
+                    .bytes(len)?
+                    .iter()
+                    .fold(0, |number, &byte| number << 8 | u64::from(byte));
+                let number = expand_number(truncated, len, largest_received);
+                let frames = decode_frames(&bytes.slice_ref(reader.rest()))?;
+                Packet::Data { number, frames }
+            }
+            kind => return Err(DecodeError::UnknownPacket(kind).into()),
+        };
+        if !reader.is_empty() {
+            return Err(DecodeError::Invalid("trailing bytes").into());
+        }
+        Ok(packet)
+    }
+}
+
+/// Returns the frames of an encoded data packet, after its number.
+pub fn payload(packet: &[u8]) -> &[u8] {
+    let len = packet
+        .first()
+        .map_or(0, |kind| usize::from(kind & !DATA_MASK));
+    packet.get(len + 2..).unwrap_or(&[])
+}
+
+/// Decodes the frames of a data packet from its payload, which the data of
+/// its datagrams shares.
+pub fn decode_frames(payload: &Bytes) -> Result<Vec<Frame>> {
+    let mut reader = Reader::new(payload);
+    let mut frames = vec![];
+    let mut previous = Previous::default();
+    while !reader.is_empty() {
+        frames.push(Frame::decode(&mut reader, &mut previous, payload)?);
+    }
+    Ok(frames)
+}
+
+/// Returns how many bytes of a packet number to send, so that the remote
+/// endpoint can tell it from any other it might receive: those up to twice as
+/// far from the largest acknowledged number.
+fn number_len(number: u64, largest_acked: Option<u64>) -> usize {
+    let unacked = match largest_acked {
+        Some(largest_acked) => number.saturating_sub(largest_acked),
+        None => number.saturating_add(1),
+    };
+    let bits = 65 - unacked.leading_zeros() as usize;
+    bits.div_ceil(8).clamp(1, 8)
+}
+
+/// Recovers a packet number from its low `len` bytes, as the number closest
+/// to the one after the largest received.
+fn expand_number(truncated: u64, len: usize, largest: Option<u64>) -> u64 {
+    if len == 8 {
+        return truncated;
+    }
+    let expected = largest.map_or(0, |largest| largest + 1);
+    let window = 1u64 << (8 * len);
+    let half_window = window / 2;
+    let candidate = (expected & !(window - 1)) | truncated;
+    if candidate.saturating_add(half_window) <= expected
+        && candidate <= u64::MAX - window
+    {
+        candidate + window
+    } else if candidate > expected.saturating_add(half_window)
+        && candidate >= window
+    {
+        candidate - window
+    } else {
+        candidate
+    }
+}
+
+/// What an endpoint tells the other when the connection opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    /// In a `Connect`, the newest version the client speaks. In an `Accept`,
+    /// the version the connection speaks.
+    pub version: u32,
+    /// What the sender is able to do.
+    pub capabilities: Capabilities,
+    /// What the sender will buffer.
+    pub limits: ReceiveLimits,
+    /// The identifier of the sender's compression dictionary. See
+    /// `compress::dictionary_id`.
+    pub dictionary: u32,
+}
+
+impl Hello {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_varint(buf, self.version.into());
+        put_varint(buf, self.capabilities.features.bits());
+        put_varint(buf, self.capabilities.max_streams.into());
+        put_varint(buf, self.limits.stream_bytes);
+        put_varint(buf, self.limits.connection_bytes);
+        put_varint(buf, self.limits.reorder_datagrams.into());
+        put_varint(buf, self.dictionary.into());
+    }
+
+    /// Decodes a hello. Only the version is read from a hello in a version
+    /// this endpoint does not speak, because what follows it may differ.
+    fn decode(reader: &mut Reader) -> Decoded<Self> {
+        let version = reader.varint_into()?;
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            version,
+            capabilities: Capabilities {
+                features: Features::from_bits(reader.varint()?),
+                max_streams: reader.varint_into()?,
+                ___non_exhaustive: PhantomData,
+            },
+            limits: ReceiveLimits {
+                stream_bytes: reader.varint()?,
+                connection_bytes: reader.varint()?,
+                reorder_datagrams: reader.varint_into()?,
+                ___non_exhaustive: PhantomData,
+            },
+            dictionary: reader.varint_into()?,
+        })
+    }
+}
+
+/// Why an endpoint refused a connection. Its fields keep their encoding in
+/// every version of the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reject {
+    /// The oldest version the sender speaks.
+    pub min_version: u32,
+    /// The newest version the sender speaks.
+    pub max_version: u32,
+    /// Features the sender requires which the connection lacks.
+    pub missing: Features,
+}
+
+/// Streams are encoded as one varint: `0` for the unordered stream, and odd
+/// and even numbers for ordered and sequenced streams.
+fn stream_code(stream: StreamKey) -> u64 {
+    match stream {
+        StreamKey::Unordered => 0,
+        StreamKey::Ordered(StreamId(id)) => 1 + 2 * u64::from(id),
+        StreamKey::Sequenced(StreamId(id)) => 2 + 2 * u64::from(id),
+    }
+}
+
+fn decode_stream(reader: &mut Reader) -> Decoded<StreamKey> {
+    let code = reader.varint()?;
+    if code == 0 {
+        return Ok(StreamKey::Unordered);
+    }
+    let id = StreamId(
+        u32::try_from((code - 1) / 2)
+            .map_err(|_| DecodeError::Invalid("stream"))?,
+    );
+    Ok(if code % 2 == 1 {
+        StreamKey::Ordered(id)
+    } else {
+        StreamKey::Sequenced(id)
+    })
+}
+
+/// What a datagram frame may leave out, because the datagram frame before it
+/// in the packet said it.
+#[derive(Debug, Default)]
+struct Previous {
+    stream: Option<StreamKey>,
+    index: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Datagram(DatagramFrame),
+    Ack(Ack),
+    /// Raises the number of bytes the receiver of this frame may send on a
+    /// stream since the connection opened, or on all streams if `stream` is
+    /// `None`.
+    MaxBytes {
+        stream: Option<StreamKey>,
+        max: u64,
+    },
+    /// Raises the index below which the receiver of this frame may send on a
+    /// stream.
+    MaxIndex {
+        stream: StreamKey,
+        max: u32,
+    },
+    /// Asks for an acknowledgement.
+    Ping,
+    Close(CloseReason),
+    /// Closes the connection because the receiver of this frame broke the
+    /// protocol.
+    Abort(Violation),
+    Repair(Repair),
+    /// Tells the receiver of this frame that the datagram at `index` on a
+    /// stream was abandoned, so later datagrams surface without it. `len` is
+    /// how many of the stream's bytes it held, if any were sent, so that the
+    /// endpoints' flow control agrees.
+    Skip {
+        stream: StreamKey,
+        index: u32,
+        len: u64,
+    },
+    /// Tells the receiver of this frame that the sender dropped its state for
+    /// an idle stream, after sending the datagrams below `end`. The receiver
+    /// drops its own, and the stream starts again from index `0`.
+    Reclaim {
+        stream: StreamKey,
+        end: u32,
+    },
+}
+
+impl Frame {
+    /// Returns the most bytes the frame takes to encode. Frames take fewer
+    /// when they can leave out what the frame before them said.
+    pub fn size(&self) -> usize {
+        match self {
+            Frame::Datagram(datagram) => datagram.size(),
+            frame => {
+                let mut buf = vec![];
+                frame.encode(&mut buf, &mut Previous::default(), false);
+                buf.len()
+            }
+        }
+    }
+
+    /// Returns whether the receiver of the frame must acknowledge its packet.
+    pub fn ack_eliciting(&self) -> bool {
+        !matches!(self, Frame::Ack(_))
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, previous: &mut Previous, last: bool) {
+        match self {
+            Frame::Datagram(datagram) => datagram.encode(buf, previous, last),
+            Frame::Ack(ack) => {
+                buf.push(ACK);
+                ack.encode(buf);
+            }
+            Frame::MaxBytes { stream: None, max } => {
+                buf.push(MAX_BYTES);
+                put_varint(buf, *max);
+            }
+            Frame::MaxBytes {
+                stream: Some(stream),
+                max,
+            } => {
+                buf.push(MAX_STREAM_BYTES);
+                put_varint(buf, stream_code(*stream));
+                put_varint(buf, *max);
+            }
+            Frame::MaxIndex { stream, max } => {
+                buf.push(MAX_INDEX);
+                put_varint(buf, stream_code(*stream));
+                put_varint(buf, (*max).into());
+            }
+            Frame::Ping => buf.push(PING),
+            Frame::Close(reason) => {
+                buf.push(CLOSE);
+                put_varint(buf, reason.code.into());
+                put_varint(buf, reason.message.len() as u64);
+                buf.extend_from_slice(reason.message.as_bytes());
+            }
+            Frame::Abort(violation) => {
+                buf.push(ABORT);
+                encode_violation(buf, violation);
+            }
+            Frame::Repair(repair) => {
+                buf.push(REPAIR);
+                put_varint(buf, repair.first);
+                put_varint(buf, repair.count.into());
+                put_varint(buf, repair.len);
+                put_varint(buf, repair.data.len() as u64);
+                buf.extend_from_slice(&repair.data);
+            }
+            Frame::Skip { stream, index, len } => {
+                buf.push(SKIP);
+                put_varint(buf, stream_code(*stream));
+                put_varint(buf, (*index).into());
+                put_varint(buf, *len);
+            }
+            Frame::Reclaim { stream, end } => {
+                buf.push(RECLAIM);
+                put_varint(buf, stream_code(*stream));
+                put_varint(buf, (*end).into());
+            }
+        }
+    }
+
+    /// Decodes a frame from `reader`, which reads `payload`.
+    fn decode(
+        reader: &mut Reader,
+        previous: &mut Previous,
+        payload: &Bytes,
+    ) -> Decoded<Self> {
+        Ok(match reader.byte()? {
+            kind if kind & DATAGRAM != 0 => Frame::Datagram(
+                DatagramFrame::decode(kind, reader, previous, payload)?,
+            ),
+            ACK => Frame::Ack(Ack::decode(reader)?),
+            MAX_BYTES => Frame::MaxBytes {
+                stream: None,
+                max: reader.varint()?,
+            },
+            MAX_STREAM_BYTES => Frame::MaxBytes {
+                stream: Some(decode_stream(reader)?),
+                max: reader.varint()?,
+            },
+            MAX_INDEX => Frame::MaxIndex {
+                stream: decode_stream(reader)?,
+                max: reader.varint_into()?,
+            },
+            PING => Frame::Ping,
+            CLOSE => {
+                let code = reader.varint_into()?;
+                let len = reader.varint_into()?;
+                let message = String::from_utf8(reader.bytes(len)?.to_vec())
+                    .map_err(|_| DecodeError::Invalid("close reason"))?;
+                Frame::Close(CloseReason { code, message })
+            }
+            ABORT => Frame::Abort(decode_violation(reader)?),
+            REPAIR => Frame::Repair(Repair {
+                first: reader.varint()?,
+                count: reader.varint_into()?,
+                len: reader.varint()?,
+                data: {
+                    let len = reader.varint_into()?;
+                    reader.bytes(len)?.to_vec()
+                },
+            }),
+            SKIP => Frame::Skip {
+                stream: decode_stream(reader)?,
+                index: reader.varint_into()?,
+                len: reader.varint()?,
+            },
+            RECLAIM => Frame::Reclaim {
+                stream: decode_stream(reader)?,
+                end: reader.varint_into()?,
+            },
+            kind => return Err(DecodeError::UnknownFrame(kind)),
+        })
+    }
+}
+
+fn encode_violation(buf: &mut Vec<u8>, violation: &Violation) {
+    let (code, stream) = match *violation {
+        Violation::StreamWindowExceeded(stream) => (0, Some(stream)),
+        Violation::ConnectionWindowExceeded => (1, None),
+        Violation::ReorderWindowExceeded(stream) => (2, Some(stream)),
+        Violation::MissingIndex(stream) => (3, Some(stream)),
+        Violation::InconsistentFragment => (4, None),
+        Violation::UnexpectedPacket => (5, None),
+        Violation::StreamLimitExceeded(stream) => (6, Some(stream)),
+        Violation::PrematureReclaim(stream) => (7, Some(stream)),
+        Violation::MalformedCompression(stream) => (8, Some(stream)),
+    };
+    put_varint(buf, code);
+    if let Some(stream) = stream {
+        put_varint(buf, stream_code(stream));
+    }
+}
+
+fn decode_violation(reader: &mut Reader) -> Decoded<Violation> {
+    Ok(match reader.varint()? {
+        0 => Violation::StreamWindowExceeded(decode_stream(reader)?),
+        1 => Violation::ConnectionWindowExceeded,
+        2 => Violation::ReorderWindowExceeded(decode_stream(reader)?),
+        3 => Violation::MissingIndex(decode_stream(reader)?),
+        4 => Violation::InconsistentFragment,
+        5 => Violation::UnexpectedPacket,
+        6 => Violation::StreamLimitExceeded(decode_stream(reader)?),
+        7 => Violation::PrematureReclaim(decode_stream(reader)?),
+        8 => Violation::MalformedCompression(decode_stream(reader)?),
+        _ => return Err(DecodeError::Invalid("violation")),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatagramFrame {
+    pub stream: StreamKey,
+    /// The index of the datagram in its stream. This is present for all
+    /// datagrams except unreliable datagrams on the unordered stream.
+    pub index: Option<u32>,
+    /// This is present if the datagram was split across frames.
+    pub fragment: Option<Fragment>,
+    /// Whether the datagram's data is compressed. See the `compress` module.
+    pub compressed: bool,
+    pub data: Bytes,
+}
+
+impl DatagramFrame {
+    /// Returns the most bytes the `Frame` carrying this datagram takes to
+    /// encode.
+    pub fn size(&self) -> usize {
+        let index = self.index.map_or(0, |index| varint_len(index.into()));
+        let fragment = self.fragment.map_or(0, |fragment| {
+            varint_len(fragment.message.into())
+                + varint_len(fragment.part.into())
+                + varint_len(fragment.parts.into())
+        });
+        1 + varint_len(stream_code(self.stream))
+            + index
+            + fragment
+            + varint_len(self.data.len() as u64)
+            + self.data.len()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, previous: &mut Previous, last: bool) {
+        let mut kind = DATAGRAM;
+        if previous.stream != Some(self.stream) {
+            kind |= STREAM;
+        }
+        kind |= match (self.index, previous.index) {
+            (None, _) => INDEX_NONE,
+            (Some(index), Some(before)) if index == before => INDEX_SAME,
+            (Some(index), Some(before)) if serial::next(before) == index => {
+                INDEX_NEXT
+            }
+            (Some(_), _) => INDEX_EXPLICIT,
+        };
+        if self.fragment.is_some() {
+            kind |= FRAGMENT;
+        }
+        if self.compressed {
+            kind |= COMPRESSED;
+        }
+        if !last {
+            kind |= LENGTH;
+        }
+
+        buf.push(kind);
+        if kind & STREAM != 0 {
+            put_varint(buf, stream_code(self.stream));
+        }
+        if let (INDEX_EXPLICIT, Some(index)) = (kind & INDEX_MASK, self.index) {
+            put_varint(buf, index.into());
+        }
+        if let Some(fragment) = self.fragment {
+            put_varint(buf, fragment.message.into());
+            put_varint(buf, fragment.part.into());
+            put_varint(buf, fragment.parts.into());
+        }
+        if !last {
+            put_varint(buf, self.data.len() as u64);
+        }
+        buf.extend_from_slice(&self.data);
+
+        previous.stream = Some(self.stream);
+        previous.index = self.index;
+    }
+
+    fn decode(
+        kind: u8,
+        reader: &mut Reader,
+        previous: &mut Previous,
+        payload: &Bytes,
+    ) -> Decoded<Self> {
+        if kind & RESERVED != 0 {
+            return Err(DecodeError::Invalid("datagram flags"));
+        }
+        let stream = if kind & STREAM != 0 {
+            decode_stream(reader)?
+        } else {
+            previous.stream.ok_or(DecodeError::Invalid("stream"))?
+        };
+        let index = match kind & INDEX_MASK {
+            INDEX_NONE => None,
+            INDEX_EXPLICIT => Some(reader.varint_into()?),
+            relative => {
+                let before =
+                    previous.index.ok_or(DecodeError::Invalid("index"))?;
+                Some(if relative == INDEX_SAME {
+                    before
+                } else {
+                    serial::next(before)
+                })
+            }
+        };
+        let fragment = if kind & FRAGMENT != 0 {
+            Some(Fragment {
+                message: reader.varint_into()?,
+                part: reader.varint_into()?,
+                parts: reader.varint_into()?,
+            })
+        } else {
+            None
+        };
+        let data = if kind & LENGTH != 0 {
+            let len = reader.varint_into()?;
+            reader.bytes(len)?
+        } else {
+            reader.rest()
+        };
+
+        previous.stream = Some(stream);
+        previous.index = index;
+        Ok(Self {
+            stream,
+            index,
+            fragment,
+            compressed: kind & COMPRESSED != 0,
+            data: payload.slice_ref(data),
+        })
+    }
+}
+
+/// A part of a datagram too large for a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fragment {
+    /// Identifies the datagram among those the sender has fragmented.
+    pub message: u32,
+    pub part: u16,
+    pub parts: u16,
+}
+
+/// The parity of a group of consecutively numbered packets, from which a
+/// receiver missing one of them can rebuild it. See the `fec` module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    /// The number of the first packet in the group.
+    pub first: u64,
+    /// How many packets are in the group.
+    pub count: u32,
+    /// The XOR of the lengths of the packets' payloads.
+    pub len: u64,
+    /// The XOR of the packets' payloads, padded with zeroes to the longest.
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ack {
+    /// Inclusive ranges of received packet numbers, newest first. Ranges are
+    /// separated by at least one number which was not received.
+    pub ranges: Vec<(u64, u64)>,
+    /// How long the sender held the acknowledgement of the newest packet.
+    pub delay: Duration,
+    /// How many packets the sender has rebuilt from repairs, in all.
+    pub recovered: u64,
+}
+
+impl Ack {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let delay = u64::try_from(self.delay.as_micros()).unwrap_or(u64::MAX);
+        put_varint(buf, delay);
+        put_varint(buf, self.recovered);
+        put_varint(buf, self.ranges.len() as u64);
+        let mut newer_start = None;
+        for &(start, end) in &self.ranges {
+            match newer_start {
+                None => put_varint(buf, end),
+                Some(newer_start) => put_varint(buf, newer_start - end - 2),
+            }
+            put_varint(buf, end - start);
+            newer_start = Some(start);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Decoded<Self> {
+        let invalid = || DecodeError::Invalid("ack range");
+        let delay = Duration::from_micros(reader.varint()?);
+        let recovered = reader.varint()?;
+        let count = reader.varint()?;
+        let mut ranges = vec![];
+        let mut newer_start: Option<u64> = None;
+        for _ in 0..count {
+            let end = match newer_start {
+                None => reader.varint()?,
+                Some(newer_start) => newer_start
+                    .checked_sub(reader.varint()?)
+                    .and_then(|end| end.checked_sub(2))
+                    .ok_or_else(invalid)?,
+            };
+            let start =
+                end.checked_sub(reader.varint()?).ok_or_else(invalid)?;
+            ranges.push((start, end));
+            newer_start = Some(start);
+        }
+        Ok(Self {
+            ranges,
+            delay,
+            recovered,
+        })
+    }
+}
@@ -0,0 +1,136 @@
+//! Version and capability negotiation.
+//!
+//! A client's `Connect` carries its protocol version and the capabilities it
+//! offers, and the server's `Accept` carries the server's. Both endpoints then
+//! use what they have in common. An endpoint which speaks a version the other
+//! does not, or which lacks features the other requires, is sent a `Reject`,
+//! and the connection closes with `Error::Incompatible` on both ends.
+
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{BitAnd, BitOr},
+};
+
+/// The protocol version this endpoint speaks.
+pub const VERSION: u32 = 1;
+
+/// The oldest protocol version this endpoint speaks.
+pub const MIN_VERSION: u32 = 1;
+
+/// A set of optional protocol features.
+///
+/// Sets keep the bits of features this version does not know, so that they can
+/// be reported, but no endpoint agrees to use them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Features(u64);
+
+impl Features {
+    pub const COMPRESSION: Self = Self(1 << 1);
+    pub const FEC: Self = Self(1 << 2);
+
+    /// The features this version implements.
+    pub const SUPPORTED: Self = Self::COMPRESSION.union(Self::FEC);
+
+    const NAMES: [(Self, &'static str); 2] =
+        [(Self::COMPRESSION, "COMPRESSION"), (Self::FEC, "FEC")];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns the features in `self` which are not in `other`.
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        self.intersection(other)
+    }
+}
+
+impl fmt::Debug for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = vec![];
+        let mut unknown = *self;
+        for &(feature, name) in Self::NAMES.iter() {
+            if self.contains(feature) {
+                names.push(name.to_string());
+                unknown = unknown.difference(feature);
+            }
+        }
+        if !unknown.is_empty() {
+            names.push(format!("{:#x}", unknown.0));
+        }
+        write!(f, "Features({})", names.join(" | "))
+    }
+}
+
+/// What an endpoint is able to do on a connection. By default, endpoints
+/// offer every feature they support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    pub features: Features,
+    /// Streams of each kind are identified by ids below this.
+    pub max_streams: u32,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            features: Features::COMPRESSION | Features::FEC,
+            max_streams: 1 << 16,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Returns what both endpoints are able to do, which never includes
+    /// features this version does not implement.
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self {
+            features: self.features & Features::SUPPORTED & other.features,
+            max_streams: self.max_streams.min(other.max_streams),
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! A synchronous host for engines which run their own loop.
+//!
+//! A `Host` owns a UDP socket and the sessions of every connection on it, and
+//! is driven by calling `service` once a tick, as ENet's `enet_host_service`
+//! is. Each call handles timeouts, sends what the sessions have to send, and
+//! waits up to its timeout for packets, returning the first connect, receive
+//! or disconnect event. The async `nhanh::Connection` adapters drive the same
+//! `Session`s from a task instead.
+//!
+//! Packets are sent and received in batches, so a host with many connections
+//! makes few syscalls per packet.
+//!
+//! Servers which send the same datagram to many peers may gather them into
+//! groups, or broadcast to every peer. The datagram's data is shared by every
+//! session it is sent on, and compressed once for all of them.
+
+use crate::{
+    batch::{self, RecvMeta, Transmit, BATCH_SIZE, RECV_BUFFER_BYTES},
+    error::{Error, Result},
+    runtime,
+    session::{Config, Session, SharedDatagram},
+};
+use nhanh::{
+    Bytes, Close, CloseReason, ConnectionEvent, Datagram, DeliveryMode, SendCmd,
+};
+use socket2::SockRef;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, IoSliceMut},
+    mem,
+    net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+/// Something which happened on a host's connections.
+#[derive(Debug)]
+pub enum HostEvent {
+    /// A connection opened, whether the remote endpoint or this host opened
+    /// it.
+    Connect(SocketAddr),
+    /// A datagram surfaced from a peer.
+    Receive(SocketAddr, Datagram),
+    /// A connection closed, or a connection this host opened never did.
+    Disconnect {
+        peer: SocketAddr,
+        /// The remote endpoint's reason, if it closed the connection with
+        /// one.
+        reason: Option<CloseReason>,
+        /// Why the connection closed, if it failed.
+        error: Option<Error>,
+    },
+}
+
+/// Identifies a group of a host's peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GroupId(pub u32);
+
+#[derive(Debug)]
+struct Peer {
+    session: Session,
+    /// Whether the connection was reported open, so its close is reported.
+    /// Connections this host opens are reported closed even if they never
+    /// open.
+    reported: bool,
+    groups: HashSet<GroupId>,
+}
+
+impl Peer {
+    fn new(session: Session, reported: bool) -> Self {
+        Self {
+            session,
+            reported,
+            groups: HashSet::new(),
+        }
+    }
+}
+
+/// Connections over one UDP socket, serviced synchronously.
+#[derive(Debug)]
+pub struct Host {
+    socket: UdpSocket,
+    config: Config,
+    peers: HashMap<SocketAddr, Peer>,
+    /// Whether connections from remote endpoints are accepted.
+    accepting: bool,
+    events: VecDeque<HostEvent>,
+    /// The time of the latest call to `service`.
+    now: Instant,
+    buffer: Vec<u8>,
+    /// Packets the socket had no room for, in the order they are sent.
+    unsent: Vec<(SocketAddr, Vec<u8>)>,
+}
+
+impl Host {
+    /// Returns a host on a socket bound to `address`, which accepts
+    /// connections.
+    pub fn bind(
+        address: impl ToSocketAddrs,
+        config: Config,
+    ) -> io::Result<Self> {
+        Ok(Self::new(UdpSocket::bind(address)?, config, true))
+    }
+
+    /// Returns a host on a dual-stack socket bound to `port`, which accepts
+    /// connections over IPv4 and IPv6.
+    pub fn bind_dual_stack(port: u16, config: Config) -> io::Result<Self> {
+        Ok(Self::new(runtime::bind_dual_stack(port)?, config, true))
+    }
+
+    /// Returns a host on a socket bound to any port, which only opens
+    /// connections. The socket is dual-stack, or IPv4 only if the system has
+    /// no IPv6.
+    pub fn client(config: Config) -> io::Result<Self> {
+        let socket = runtime::bind_dual_stack(0)
+            .or_else(|_| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)))?;
+        Ok(Self::new(socket, config, false))
+    }
+
+    fn new(socket: UdpSocket, config: Config, accepting: bool) -> Self {
+        batch::configure(SockRef::from(&socket));
+        Self {
+            socket,
+            config,
+            peers: HashMap::new(),
+            accepting,
+            events: VecDeque::new(),
+            now: Instant::now(),
+            buffer: vec![0; BATCH_SIZE * RECV_BUFFER_BYTES],
+            unsent: vec![],
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Opens a connection to `address`. `HostEvent::Connect` follows once it
+    /// opens, or `HostEvent::Disconnect` if it fails. Events name the peer of
+    /// an IPv4-mapped address by its IPv4 address, and methods which take a
+    /// peer accept either.
+    pub fn connect(&mut self, address: SocketAddr) {
+        let session = Session::connect(self.now, self.config);
+        self.peers
+            .insert(batch::canonical(address), Peer::new(session, true));
+    }
+
+    /// Returns whether a datagram can be buffered for a peer without
+    /// exceeding its send limits, as `Session::has_room` does.
+    pub fn has_room(
+        &self,
+        peer: SocketAddr,
+        delivery_mode: Option<DeliveryMode>,
+    ) -> bool {
+        self.peers
+            .get(&batch::canonical(peer))
+            .is_some_and(|peer| peer.session.has_room(delivery_mode))
+    }
+
+    /// Buffers a datagram to send to a peer. Datagrams go out on the next
+    /// call to `service` or `flush`. Fails with `Error::Full` if the peer's
+    /// send limits have no room for it.
+    pub fn send(&mut self, peer: SocketAddr, send_cmd: SendCmd) -> Result<()> {
+        let now = self.now;
+        match self.peers.get_mut(&batch::canonical(peer)) {
+            Some(peer)
+                if !peer.session.has_room(Some(send_cmd.delivery_mode)) =>
+            {
+                Err(Error::Full)
+            }
+            Some(peer) => peer.session.send(now, send_cmd),
+            None => Err(Error::Closed),
+        }
+    }
+
+    /// Adds a connected peer to a group, and returns whether it is connected.
+    /// Peers leave their groups when their connections close.
+    pub fn join(&mut self, group: GroupId, peer: SocketAddr) -> bool {
+        match self.peers.get_mut(&batch::canonical(peer)) {
+            Some(peer) if peer.reported && peer.session.is_connected() => {
+                peer.groups.insert(group);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn leave(&mut self, group: GroupId, peer: SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(&batch::canonical(peer)) {
+            peer.groups.remove(&group);
+        }
+    }
+
+    /// The peers in a group.
+    pub fn members(
+        &self,
+        group: GroupId,
+    ) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers
+            .iter()
+            .filter(move |(_, peer)| peer.groups.contains(&group))
+            .map(|(&address, _)| address)
+    }
+
+    /// Buffers a datagram to send to every peer in a group, and returns the
+    /// peers it could not be sent to, with why. Datagrams go out on the next
+    /// call to `service` or `flush`.
+    pub fn send_group(
+        &mut self,
+        group: GroupId,
+        send_cmd: SendCmd,
+    ) -> Vec<(SocketAddr, Error)> {
+        self.send_shared(send_cmd, |peer| peer.groups.contains(&group))
+    }
+
+    /// Buffers a datagram to send to every connected peer, as `send_group`
+    /// does.
+    pub fn broadcast(&mut self, send_cmd: SendCmd) -> Vec<(SocketAddr, Error)> {
+        self.send_shared(send_cmd, |peer| {
+            peer.reported && peer.session.is_connected()
+        })
+    }
+
+    fn send_shared(
+        &mut self,
+        send_cmd: SendCmd,
+        filter: impl Fn(&Peer) -> bool,
+    ) -> Vec<(SocketAddr, Error)> {
+        let now = self.now;
+        let delivery_mode = send_cmd.delivery_mode;
+        let datagram = SharedDatagram::new(send_cmd);
+        let mut failures = vec![];
+        for (&address, peer) in &mut self.peers {
+            if !filter(peer) {
+                continue;
+            }
+            if !peer.session.has_room(Some(delivery_mode)) {
+                failures.push((address, Error::Full));
+                continue;
+            }
+            if let Err(e) = peer.session.send_shared(now, &datagram) {
+                failures.push((address, e));
+            }
+        }
+        failures
+    }
+
+    /// Closes the connection to a peer. `HostEvent::Disconnect` follows once
+    /// it closes.
+    pub fn disconnect(&mut self, peer: SocketAddr, close: &Close) {
+        let now = self.now;
+        if let Some(peer) = self.peers.get_mut(&batch::canonical(peer)) {
+            peer.session.close(now, close);
+        }
+    }
+
+    /// Returns the session of the connection to a peer, for its stats and
+    /// settings.
+    pub fn peer(&mut self, peer: SocketAddr) -> Option<&mut Session> {
+        self.peers
+            .get_mut(&batch::canonical(peer))
+            .map(|peer| &mut peer.session)
+    }
+
+    /// The peers with connections open or opening.
+    pub fn peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers.keys().copied()
+    }
+
+    /// Sends the datagrams buffered for every peer now, without waiting out
+    /// their coalescing delay.
+    pub fn flush(&mut self) {
+        for peer in self.peers.values_mut() {
+            peer.session.flush();
+        }
+        self.transmit(self.now);
+    }
+
+    /// Services the host's connections at `now`, and returns the next event.
+    /// If there is none, this waits up to `timeout` for packets to produce
+    /// one, and returns `None` if none did. A zero timeout never waits.
+    pub fn service(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+    ) -> io::Result<Option<HostEvent>> {
+        let deadline = now + timeout;
+        let mut now = now;
+        loop {
+            self.now = now;
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            self.handle_timeouts(now);
+            self.transmit(now);
+            self.poll_events();
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            let wake = self
+                .peers
+                .values()
+                .filter_map(|peer| peer.session.poll_timeout())
+                .fold(deadline, Instant::min);
+            let started = Instant::now();
+            self.receive(now, wake.saturating_duration_since(now))?;
+            now = (now + started.elapsed()).min(deadline);
+        }
+    }
+
+    /// Waits up to `wait` after `now` for a packet, then handles every packet
+    /// received.
+    fn receive(&mut self, now: Instant, wait: Duration) -> io::Result<()> {
+        let started = Instant::now();
+        if wait > Duration::from_secs(0) {
+            self.socket.set_nonblocking(false)?;
+            self.socket.set_read_timeout(Some(wait))?;
+            if !self.receive_batch(now, started)? {
+                return Ok(());
+            }
+        }
+
+        self.socket.set_nonblocking(true)?;
+        while self.receive_batch(now, started)? {}
+        Ok(())
+    }
+
+    /// Handles a batch of packets, and returns false if there were none.
+    fn receive_batch(
+        &mut self,
+        now: Instant,
+        started: Instant,
+    ) -> io::Result<bool> {
+        let mut meta = [RecvMeta::default(); BATCH_SIZE];
+        let mut buffers: Vec<IoSliceMut> = self
+            .buffer
+            .chunks_mut(RECV_BUFFER_BYTES)
+            .map(IoSliceMut::new)
+            .collect();
+        let received = match batch::recv(
+            SockRef::from(&self.socket),
+            &mut buffers,
+            &mut meta,
+        ) {
+            Ok(received) => received,
+            Err(e) if would_block(&e) => return Ok(false),
+            // A peer's port was closed, which its session times out on.
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                return Ok(true)
+            }
+            Err(e) => return Err(e),
+        };
+        drop(buffers);
+
+        let now = now + started.elapsed();
+        let buffer = mem::take(&mut self.buffer);
+        for (meta, buffer) in meta[..received]
+            .iter()
+            .zip(buffer.chunks(RECV_BUFFER_BYTES))
+        {
+            for packet in meta.packets(buffer) {
+                self.handle(now, packet, meta.source);
+            }
+        }
+        self.buffer = buffer;
+        Ok(true)
+    }
+
+    fn handle(&mut self, now: Instant, packet: &[u8], from: SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(&from) {
+            // The packet leaves the receive buffer, which its datagrams would
+            // otherwise keep whole, and they share the copy.
+            peer.session.handle(now, Bytes::copy_from_slice(packet));
+            return;
+        }
+        if !self.accepting {
+            return;
+        }
+        // Sessions with incompatible clients only send a rejection, so the
+        // host holds them until they close, and never reports them.
+        if let Some(session) = Session::accept(now, self.config, packet) {
+            self.peers.insert(from, Peer::new(session, false));
+        }
+    }
+
+    fn handle_timeouts(&mut self, now: Instant) {
+        for peer in self.peers.values_mut() {
+            if peer.session.poll_timeout().is_some_and(|t| t <= now) {
+                peer.session.handle_timeout(now);
+            }
+        }
+    }
+
+    /// Sends what the sessions have to send. Sessions are not asked for more
+    /// until the packets the socket had no room for are sent. A packet the
+    /// socket fails to send is dropped, as the network might have, so that
+    /// one unreachable peer does not hold up the others.
+    fn transmit(&mut self, now: Instant) {
+        if self.unsent.is_empty() {
+            for (&address, peer) in &mut self.peers {
+                while let Some(packet) = peer.session.poll_transmit(now) {
+                    self.unsent.push((address, packet));
+                }
+            }
+        }
+
+        let transmits: Vec<Transmit> = self
+            .unsent
+            .iter()
+            .map(|(destination, packet)| Transmit {
+                destination: *destination,
+                contents: packet,
+            })
+            .collect();
+        let mut sent = 0;
+        while sent < transmits.len() {
+            match batch::send(SockRef::from(&self.socket), &transmits[sent..]) {
+                Ok(count) => sent += count,
+                Err(e) if would_block(&e) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Sending to the first destination left failed. Its packets
+                // are dropped, as the network might have dropped them.
+                Err(_) => {
+                    let destination = transmits[sent].destination;
+                    sent += transmits[sent..]
+                        .iter()
+                        .take_while(|transmit| {
+                            transmit.destination == destination
+                        })
+                        .count();
+                }
+            }
+        }
+        drop(transmits);
+        self.unsent.drain(..sent);
+    }
+
+    /// Queues the events of every connection, and forgets closed ones. A
+    /// connection's datagrams follow its connect, and precede its disconnect.
+    fn poll_events(&mut self) {
+        let events = &mut self.events;
+        self.peers.retain(|&address, peer| {
+            let mut disconnect = None;
+            while let Some(event) = peer.session.poll_event() {
+                match event {
+                    ConnectionEvent::Connected => {
+                        peer.reported = true;
+                        events.push_back(HostEvent::Connect(address));
+                    }
+                    ConnectionEvent::Disconnected(reason) if peer.reported => {
+                        disconnect = Some(HostEvent::Disconnect {
+                            peer: address,
+                            reason,
+                            error: peer.session.take_error(),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            while let Some(datagram) = peer.session.poll_datagram() {
+                events.push_back(HostEvent::Receive(address, datagram));
+            }
+            events.extend(disconnect);
+            !peer.session.is_closed()
+        });
+    }
+}
+
+fn would_block(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
@@ -1 +1,72 @@
 //! miknet
+//!
+//! A reliable UDP protocol implementing the `nhanh` api.
+//!
+//! The protocol state of a connection is held by a `Session`, which performs
+//! no IO of its own. Endpoints open connections with a handshake that agrees
+//! on a protocol version and `Capabilities` and exchanges `ReceiveLimits`,
+//! then exchange numbered packets of frames. Every packet with more than
+//! acknowledgements in it is acknowledged, and reliable datagrams in lost
+//! packets are sent again.
+//!
+//! The wire format is specified in `wire.md`, and implemented by the `frame`
+//! module.
+//!
+//! Engines with a loop of their own can drive connections with a `Host`
+//! instead, which services its sessions on a UDP socket synchronously, and
+//! sends a datagram to groups of its peers at once.
+//! The `driver` module runs connections from async tasks instead, as
+//! `nhanh::Connection`s and `nhanh::Server`s. Its drivers reach sockets,
+//! timers and executors through `Runtime`, with adapters for Tokio, async-std
+//! and smol behind cargo features, which also enable the module. Both send
+//! and receive packets in batches, as the `batch` module describes.
+//!
+//! Games replicate their world with the `snapshot` module, which encodes each
+//! snapshot against the last one a client acknowledged, and plays them out
+//! on the client behind a delay, and clients estimate the server's clock
+//! with the `clock` module.
+//!
+//! ## Flow control
+//!
+//! Receivers advertise how much they will buffer for the remote endpoint, and
+//! close the connection with `Error::Violation` if it sends more. See the
+//! `flow` module.
+
+pub mod batch;
+pub mod clock;
+mod codec;
+pub mod compress;
+#[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+pub mod driver;
+mod error;
+pub mod fec;
+pub mod flow;
+pub mod frame;
+pub mod handshake;
+pub mod host;
+mod recovery;
+mod recv;
+pub mod runtime;
+mod send;
+pub mod serial;
+pub mod session;
+pub mod snapshot;
+pub mod stream;
+
+#[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+pub use self::driver::{Connection, DriverConfig, Server};
+pub use self::{
+    clock::{ClockConfig, ClockServer, ClockSync, ServerTime},
+    compress::CompressionConfig,
+    error::{DecodeError, Error, Incompatibility, Result, Violation},
+    fec::FecConfig,
+    flow::ReceiveLimits,
+    handshake::{Capabilities, Features},
+    host::{GroupId, Host, HostEvent},
+    runtime::{AsyncUdpSocket, Runtime},
+    session::{Config, Session, SharedDatagram},
+    snapshot::{
+        InterpolationBuffer, InterpolationConfig, SnapshotReceiver,
+        SnapshotSender,
+    },
+};
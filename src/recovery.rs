@@ -0,0 +1,506 @@
+//! Loss detection, round trip time estimation, and congestion control.
+//!
+//! These follow the recommendations of RFC 9002: a packet is lost once three
+//! newer packets are acknowledged, or once it is 9/8 of a round trip older than
+//! the newest acknowledged packet. When nothing is acknowledged for a probe
+//! timeout, the sender probes with an ack eliciting packet. Congestion control
+//! is NewReno, counted in bytes.
+
+use crate::{frame::Ack, frame::Frame, send::Outgoing};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How many newer packets are acknowledged before a packet is lost.
+const PACKET_THRESHOLD: u64 = 3;
+
+/// Timers never fire sooner than this after they are set.
+const GRANULARITY: Duration = Duration::from_millis(1);
+
+/// The round trip time assumed before one is measured.
+const INITIAL_RTT: Duration = Duration::from_millis(333);
+
+/// The longest an endpoint holds an acknowledgement.
+pub const MAX_ACK_DELAY: Duration = Duration::from_millis(25);
+
+/// The most ranges of packet numbers an acknowledgement carries.
+const MAX_ACK_RANGES: usize = 32;
+
+/// Consecutive probe timeouts after which the congestion window collapses.
+const PERSISTENT_CONGESTION: u32 = 3;
+
+/// Something carried by a packet which is acted on if the packet is lost or
+/// acknowledged.
+#[derive(Debug, Clone)]
+pub enum Sent {
+    Datagram(Outgoing),
+    /// A frame which is sent again, if still needed, when lost.
+    Control(Frame),
+}
+
+#[derive(Debug)]
+pub struct SentPacket {
+    pub time: Instant,
+    pub size: usize,
+    pub ack_eliciting: bool,
+    pub contents: Vec<Sent>,
+}
+
+/// Round trip time estimates.
+#[derive(Debug)]
+pub struct Rtt {
+    latest: Duration,
+    smoothed: Option<Duration>,
+    variance: Duration,
+    min: Duration,
+}
+
+impl Rtt {
+    fn new() -> Self {
+        Self {
+            latest: INITIAL_RTT,
+            smoothed: None,
+            variance: INITIAL_RTT / 2,
+            min: INITIAL_RTT,
+        }
+    }
+
+    fn update(&mut self, sample: Duration, ack_delay: Duration) {
+        self.latest = sample;
+        let smoothed = match self.smoothed {
+            Some(smoothed) => smoothed,
+            None => {
+                self.min = sample;
+                self.smoothed = Some(sample);
+                self.variance = sample / 2;
+                return;
+            }
+        };
+
+        self.min = self.min.min(sample);
+        // The remote endpoint's delay is only discounted when that leaves a
+        // plausible round trip.
+        let ack_delay = ack_delay.min(MAX_ACK_DELAY);
+        let adjusted = if sample >= self.min + ack_delay {
+            sample - ack_delay
+        } else {
+            sample
+        };
+
+        let deviation = smoothed.abs_diff(adjusted);
+        self.variance = (self.variance * 3 + deviation) / 4;
+        self.smoothed = Some((smoothed * 7 + adjusted) / 8);
+    }
+
+    pub fn smoothed(&self) -> Duration {
+        self.smoothed.unwrap_or(INITIAL_RTT)
+    }
+
+    pub fn variance(&self) -> Duration {
+        self.variance
+    }
+
+    /// How long to wait for an acknowledgement before probing.
+    pub fn pto(&self) -> Duration {
+        self.smoothed() + (self.variance * 4).max(GRANULARITY) + MAX_ACK_DELAY
+    }
+}
+
+/// Tracks packets in flight, and decides when they are lost and how many
+/// bytes may be in flight.
+#[derive(Debug)]
+pub struct Recovery {
+    mtu: usize,
+    sent: BTreeMap<u64, SentPacket>,
+    next_number: u64,
+    largest_acked: Option<u64>,
+    rtt: Rtt,
+    /// When the oldest unacknowledged packet will be lost by the time
+    /// threshold.
+    loss_time: Option<Instant>,
+    last_ack_eliciting: Option<Instant>,
+    pto_count: u32,
+    bytes_in_flight: usize,
+    congestion_window: usize,
+    slow_start_threshold: usize,
+    /// Packets sent before this time do not reduce the window again.
+    recovery_start: Option<Instant>,
+    collapsed: bool,
+    loss: f32,
+    /// The loss rate counting packets the remote endpoint rebuilt.
+    raw_loss: f32,
+    /// How many packets the remote endpoint has rebuilt.
+    recovered: u64,
+}
+
+impl Recovery {
+    pub fn new(mtu: usize) -> Self {
+        Self {
+            mtu,
+            sent: BTreeMap::new(),
+            next_number: 0,
+            largest_acked: None,
+            rtt: Rtt::new(),
+            loss_time: None,
+            last_ack_eliciting: None,
+            pto_count: 0,
+            bytes_in_flight: 0,
+            congestion_window: 10 * mtu,
+            slow_start_threshold: usize::MAX,
+            recovery_start: None,
+            collapsed: false,
+            loss: 0.0,
+            raw_loss: 0.0,
+            recovered: 0,
+        }
+    }
+
+    pub fn rtt(&self) -> &Rtt {
+        &self.rtt
+    }
+
+    /// The largest packet number the remote endpoint has acknowledged.
+    pub fn largest_acked(&self) -> Option<u64> {
+        self.largest_acked
+    }
+
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    pub fn congestion_window(&self) -> usize {
+        self.congestion_window
+    }
+
+    /// Estimated packet loss (range: [0.0-1.0]).
+    pub fn loss(&self) -> f32 {
+        self.loss
+    }
+
+    /// Estimated packet loss before forward error correction rebuilt any
+    /// packets (range: [0.0-1.0]).
+    pub fn raw_loss(&self) -> f32 {
+        self.raw_loss
+    }
+
+    /// Counts the packets the remote endpoint rebuilt, given how many it has
+    /// rebuilt in all.
+    pub fn on_recovered(&mut self, recovered: u64) {
+        // The estimate is as high as it goes long before 64 losses in a row.
+        let new = recovered.saturating_sub(self.recovered).min(64);
+        for _ in 0..new {
+            self.raw_loss = self.raw_loss * 0.875 + 0.125;
+        }
+        self.recovered = self.recovered.max(recovered);
+    }
+
+    /// Returns whether the congestion window collapsed since this was last
+    /// called.
+    pub fn take_collapsed(&mut self) -> bool {
+        std::mem::replace(&mut self.collapsed, false)
+    }
+
+    /// Returns whether the congestion controller allows sending a full packet.
+    pub fn can_send(&self) -> bool {
+        self.bytes_in_flight + self.mtu <= self.congestion_window
+    }
+
+    /// Returns whether anything sent is still waiting for an acknowledgement.
+    pub fn in_flight(&self) -> bool {
+        self.sent.values().any(|packet| packet.ack_eliciting)
+    }
+
+    pub fn next_number(&mut self) -> u64 {
+        let number = self.next_number;
+        self.next_number += 1;
+        number
+    }
+
+    pub fn on_sent(&mut self, number: u64, packet: SentPacket) {
+        if packet.ack_eliciting {
+            self.last_ack_eliciting = Some(packet.time);
+            self.bytes_in_flight += packet.size;
+        }
+        self.sent.insert(number, packet);
+    }
+
+    /// Forgets the datagrams carried by packets in flight, once the sender
+    /// has dropped them, so they are neither released nor sent again.
+    pub fn forget_datagrams(&mut self) {
+        for packet in self.sent.values_mut() {
+            packet
+                .contents
+                .retain(|sent| !matches!(sent, Sent::Datagram(_)));
+        }
+    }
+
+    /// Processes an acknowledgement, and returns the contents of the packets
+    /// it acknowledges and of the packets it reveals are lost.
+    pub fn on_ack(
+        &mut self,
+        now: Instant,
+        ack: &Ack,
+    ) -> (Vec<Sent>, Vec<Sent>) {
+        let mut acked = vec![];
+        let mut newest = None;
+        for &(start, end) in &ack.ranges {
+            let numbers: Vec<u64> =
+                self.sent.range(start..=end).map(|(&n, _)| n).collect();
+            for number in numbers {
+                let packet = self.sent.remove(&number).expect("sent packet");
+                if newest.is_none_or(|(newest, _)| number > newest) {
+                    newest = Some((number, packet.time));
+                }
+                self.on_packet_acked(&packet);
+                acked.extend(packet.contents);
+            }
+        }
+
+        let (largest, sent_time) = match newest {
+            Some(newest) => newest,
+            None => return (acked, vec![]),
+        };
+        if self.largest_acked.is_none_or(|acked| largest > acked) {
+            self.largest_acked = Some(largest);
+            if ack.ranges.first().map(|&(_, end)| end) == Some(largest) {
+                self.rtt.update(now - sent_time, ack.delay);
+            }
+        }
+        self.pto_count = 0;
+
+        let lost = self.detect_lost(now);
+        (acked, lost)
+    }
+
+    fn on_packet_acked(&mut self, packet: &SentPacket) {
+        self.loss *= 0.875;
+        self.raw_loss *= 0.875;
+        if !packet.ack_eliciting {
+            return;
+        }
+        self.bytes_in_flight -= packet.size;
+        if self
+            .recovery_start
+            .is_some_and(|start| packet.time <= start)
+        {
+            return;
+        }
+        if self.congestion_window < self.slow_start_threshold {
+            self.congestion_window += packet.size;
+        } else {
+            self.congestion_window +=
+                self.mtu * packet.size / self.congestion_window;
+        }
+    }
+
+    fn detect_lost(&mut self, now: Instant) -> Vec<Sent> {
+        self.loss_time = None;
+        let largest_acked = match self.largest_acked {
+            Some(largest_acked) => largest_acked,
+            None => return vec![],
+        };
+
+        let delay = self.rtt.latest.max(self.rtt.smoothed()) * 9 / 8;
+        let delay = delay.max(GRANULARITY);
+
+        let mut lost = vec![];
+        let mut newest_lost = None;
+        for (&number, packet) in self.sent.range(..largest_acked) {
+            if number + PACKET_THRESHOLD <= largest_acked
+                || packet.time + delay <= now
+            {
+                lost.push(number);
+                newest_lost = Some(packet.time);
+            } else {
+                let loss_time = packet.time + delay;
+                self.loss_time = Some(
+                    self.loss_time.map_or(loss_time, |t| t.min(loss_time)),
+                );
+            }
+        }
+
+        if let Some(sent_time) = newest_lost {
+            self.on_congestion(now, sent_time);
+        }
+
+        let mut contents = vec![];
+        for number in lost {
+            let packet = self.sent.remove(&number).expect("lost packet");
+            self.loss = self.loss * 0.875 + 0.125;
+            self.raw_loss = self.raw_loss * 0.875 + 0.125;
+            if packet.ack_eliciting {
+                self.bytes_in_flight -= packet.size;
+            }
+            contents.extend(packet.contents);
+        }
+        contents
+    }
+
+    /// Halves the window once per round trip in which packets are lost.
+    fn on_congestion(&mut self, now: Instant, sent_time: Instant) {
+        if self.recovery_start.is_some_and(|start| sent_time <= start) {
+            return;
+        }
+        self.recovery_start = Some(now);
+        self.congestion_window = (self.congestion_window / 2).max(2 * self.mtu);
+        self.slow_start_threshold = self.congestion_window;
+    }
+
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.loss_time.is_some() {
+            return self.loss_time;
+        }
+        if !self.in_flight() {
+            return None;
+        }
+        self.last_ack_eliciting.map(|sent| {
+            sent + self.rtt.pto() * 2u32.pow(self.pto_count.min(16))
+        })
+    }
+
+    /// Handles a timeout, and returns the contents of lost packets and whether
+    /// to send a probe.
+    pub fn on_timeout(&mut self, now: Instant) -> (Vec<Sent>, bool) {
+        if self.loss_time.is_some_and(|loss_time| loss_time <= now) {
+            return (self.detect_lost(now), false);
+        }
+        if self.poll_timeout().is_none_or(|timeout| timeout > now) {
+            return (vec![], false);
+        }
+
+        self.pto_count += 1;
+        if self.pto_count == PERSISTENT_CONGESTION {
+            self.congestion_window = 2 * self.mtu;
+            self.slow_start_threshold = self.congestion_window;
+            self.recovery_start = Some(now);
+            self.collapsed = true;
+        }
+
+        // Once probes go unacknowledged too, everything in flight is presumed
+        // lost.
+        let mut lost = vec![];
+        if self.pto_count >= 2 {
+            for (_, packet) in std::mem::take(&mut self.sent) {
+                if packet.ack_eliciting {
+                    self.bytes_in_flight -= packet.size;
+                }
+                self.loss = self.loss * 0.875 + 0.125;
+                self.raw_loss = self.raw_loss * 0.875 + 0.125;
+                lost.extend(packet.contents);
+            }
+        }
+        (lost, true)
+    }
+}
+
+/// Tracks received packet numbers, and when to acknowledge them.
+#[derive(Debug, Default)]
+pub struct Acks {
+    /// Inclusive ranges of received packet numbers, newest first.
+    ranges: VecDeque<(u64, u64)>,
+    /// When the newest packet was received.
+    newest_time: Option<Instant>,
+    /// Ack eliciting packets received since the last acknowledgement.
+    unacked: u32,
+    deadline: Option<Instant>,
+    pending: bool,
+    /// Packets rebuilt from repairs.
+    recovered: u64,
+}
+
+impl Acks {
+    /// Records a received packet. Returns `false` if it is a duplicate, or too
+    /// old to tell.
+    pub fn receive(
+        &mut self,
+        now: Instant,
+        number: u64,
+        ack_eliciting: bool,
+    ) -> bool {
+        let position =
+            self.ranges.iter().position(|&(start, _)| start <= number);
+        match position {
+            Some(i) if number <= self.ranges[i].1 => return false,
+            None if self.ranges.len() == MAX_ACK_RANGES => return false,
+            _ => {}
+        }
+
+        match position {
+            Some(i) => {
+                if self.ranges[i].1 + 1 == number {
+                    self.ranges[i].1 = number;
+                } else {
+                    self.ranges.insert(i, (number, number));
+                }
+            }
+            None => self.ranges.push_back((number, number)),
+        }
+        // Merge with the next newer range, which precedes it.
+        let i = self
+            .ranges
+            .iter()
+            .position(|&(start, end)| start <= number && number <= end)
+            .expect("received range");
+        if i > 0 && self.ranges[i - 1].0 == self.ranges[i].1 + 1 {
+            self.ranges[i - 1].0 = self.ranges[i].0;
+            self.ranges.remove(i);
+        }
+        self.ranges.truncate(MAX_ACK_RANGES);
+
+        if number == self.ranges[0].1 {
+            self.newest_time = Some(now);
+        }
+        self.pending = true;
+        if ack_eliciting {
+            self.unacked += 1;
+            if self.deadline.is_none() {
+                self.deadline = Some(now + MAX_ACK_DELAY);
+            }
+        }
+        true
+    }
+
+    /// Counts a packet rebuilt from a repair.
+    pub fn on_recovered(&mut self) {
+        self.recovered += 1;
+    }
+
+    /// How many packets were rebuilt from repairs.
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// The largest packet number received.
+    pub fn largest(&self) -> Option<u64> {
+        self.ranges.front().map(|&(_, end)| end)
+    }
+
+    /// Returns whether an acknowledgement must be sent now.
+    pub fn due(&self, now: Instant) -> bool {
+        self.unacked >= 2
+            || self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns an acknowledgement of everything received, if anything was
+    /// received since the last one.
+    pub fn frame(&mut self, now: Instant) -> Option<Ack> {
+        if !self.pending {
+            return None;
+        }
+        self.pending = false;
+        self.unacked = 0;
+        self.deadline = None;
+        Some(Ack {
+            ranges: self.ranges.iter().cloned().collect(),
+            delay: self
+                .newest_time
+                .map_or(Duration::from_secs(0), |time| now - time),
+            recovered: self.recovered,
+        })
+    }
+}
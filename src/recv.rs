@@ -0,0 +1,732 @@
+//! The receiving side of a connection's streams.
+
+use crate::{
+    compress,
+    error::Violation,
+    flow::{ReceiveLimits, ReceiveWindow},
+    frame::{DatagramFrame, Fragment, Frame},
+    serial,
+    stream::StreamKey,
+};
+use nhanh::{Bytes, Datagram, StreamIndex, StreamPosition};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// The most unreliable datagrams reassembled at once. The oldest is dropped to
+/// make room for another.
+const MAX_UNRELIABLE_PARTIALS: usize = 64;
+
+/// Reorders, reassembles, and surfaces received datagrams within the
+/// connection's `ReceiveLimits`.
+#[derive(Debug)]
+pub struct Receiver {
+    limits: ReceiveLimits,
+    streams: HashMap<StreamKey, StreamReceiver>,
+    window: ReceiveWindow,
+    partials: HashMap<u32, Partial>,
+    /// Messages of unreliable partial datagrams, oldest first.
+    unreliable_partials: VecDeque<u32>,
+    /// Bytes buffered of datagrams which are not flow controlled.
+    unregulated_bytes: u64,
+    ready: VecDeque<Surfaced>,
+    /// Streams whose limits may need to be advertised.
+    dirty: BTreeSet<StreamKey>,
+    /// Limits which were lost in transit, and must be advertised again.
+    lost: BTreeSet<Limit>,
+    /// Streams the remote endpoint reclaimed recently.
+    tombstones: HashMap<StreamKey, Tombstone>,
+    /// The dictionary compressed datagrams are decompressed with, once the
+    /// endpoints agree on compression.
+    dictionary: Option<&'static [u8]>,
+}
+
+/// A stream reclaimed in the remote endpoint's packet numbered `packet`.
+/// Datagrams on the stream in older packets were for the reclaimed state, and
+/// are ignored.
+#[derive(Debug)]
+struct Tombstone {
+    packet: u64,
+    at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Limit {
+    ConnectionBytes,
+    StreamBytes(StreamKey),
+    StreamIndex(StreamKey),
+}
+
+#[derive(Debug)]
+struct StreamReceiver {
+    window: ReceiveWindow,
+    order: Order,
+    /// The index below which the remote endpoint may send.
+    max_index: u32,
+    /// The number of the newest packet with a datagram or skip on the stream.
+    last_packet: u64,
+}
+
+#[derive(Debug)]
+enum Order {
+    Ordered {
+        next: u32,
+        /// Datagrams waiting for older ones, or `None` for those which were
+        /// skipped.
+        pending: BTreeMap<u32, Option<Payload>>,
+    },
+    Sequenced {
+        last: Option<u32>,
+    },
+    Unordered {
+        /// The oldest index not yet received.
+        floor: u32,
+        /// Indices received above `floor`.
+        received: BTreeSet<u32>,
+    },
+}
+
+/// The data of a received datagram as it arrived. Compressed data is only
+/// decompressed once it surfaces, so buffered datagrams take no more memory
+/// than the windows count.
+#[derive(Debug)]
+struct Payload {
+    data: Bytes,
+    compressed: bool,
+}
+
+#[derive(Debug)]
+struct Partial {
+    stream: StreamKey,
+    index: Option<u32>,
+    compressed: bool,
+    parts: Vec<Option<Bytes>>,
+    missing: usize,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Surfaced {
+    stream: StreamKey,
+    flow_controlled: bool,
+    /// Whether the stream was reclaimed since, so the datagram no longer
+    /// counts against its window.
+    reclaimed: bool,
+    /// The bytes of the datagram which arrived.
+    len: u64,
+    /// Whether the datagram's data is still compressed.
+    compressed: bool,
+    datagram: Datagram,
+}
+
+/// Returns whether datagrams on `stream` with `index` are flow controlled.
+fn flow_controlled(stream: StreamKey, index: Option<u32>) -> bool {
+    match stream {
+        StreamKey::Ordered(_) => true,
+        StreamKey::Sequenced(_) => false,
+        StreamKey::Unordered => index.is_some(),
+    }
+}
+
+impl StreamReceiver {
+    fn new(stream: StreamKey, limits: &ReceiveLimits, packet: u64) -> Self {
+        Self {
+            window: ReceiveWindow::new(limits.stream_bytes),
+            order: match stream {
+                StreamKey::Ordered(_) => Order::Ordered {
+                    next: 0,
+                    pending: BTreeMap::new(),
+                },
+                StreamKey::Sequenced(_) => Order::Sequenced { last: None },
+                StreamKey::Unordered => Order::Unordered {
+                    floor: 0,
+                    received: BTreeSet::new(),
+                },
+            },
+            max_index: limits.reorder_datagrams,
+            last_packet: packet,
+        }
+    }
+
+    /// Returns whether a datagram with `index` has not already surfaced, and
+    /// could.
+    fn wants(&self, index: u32) -> bool {
+        match &self.order {
+            Order::Ordered { next, pending } => {
+                serial::le(*next, index) && !pending.contains_key(&index)
+            }
+            Order::Sequenced { last } => {
+                last.is_none_or(|last| serial::lt(last, index))
+            }
+            Order::Unordered { floor, received } => {
+                serial::le(*floor, index) && !received.contains(&index)
+            }
+        }
+    }
+
+    /// Accepts a wanted datagram, and returns the datagrams which can
+    /// surface.
+    fn accept(
+        &mut self,
+        index: Option<u32>,
+        payload: Payload,
+    ) -> Vec<Surfacing> {
+        match (&mut self.order, index) {
+            (Order::Ordered { next, pending }, Some(index)) => {
+                pending.insert(index, Some(payload));
+                drain(next, pending)
+            }
+            (Order::Sequenced { last }, Some(index)) => {
+                *last = Some(index);
+                vec![(Some(StreamIndex::Sequence(index)), payload)]
+            }
+            (Order::Unordered { floor, received }, Some(index)) => {
+                received.insert(index);
+                while received.remove(floor) {
+                    *floor = serial::next(*floor);
+                }
+                vec![(None, payload)]
+            }
+            (_, None) => vec![(None, payload)],
+        }
+    }
+
+    /// Moves past an abandoned datagram the stream wants, and returns the
+    /// datagrams which can surface.
+    fn skip(&mut self, index: u32) -> Vec<Surfacing> {
+        match &mut self.order {
+            Order::Ordered { next, pending } => {
+                pending.insert(index, None);
+                drain(next, pending)
+            }
+            Order::Unordered { floor, received } => {
+                received.insert(index);
+                while received.remove(floor) {
+                    *floor = serial::next(*floor);
+                }
+                vec![]
+            }
+            Order::Sequenced { .. } => vec![],
+        }
+    }
+
+    /// Returns whether every datagram below `end` was received or skipped.
+    fn reached(&self, end: u32) -> bool {
+        match &self.order {
+            Order::Ordered { next, .. } => *next == end,
+            Order::Unordered { floor, .. } => *floor == end,
+            Order::Sequenced { .. } => true,
+        }
+    }
+
+    /// The oldest index the stream is waiting for, if it reorders.
+    fn reorder_base(&self) -> Option<u32> {
+        match &self.order {
+            Order::Ordered { next, .. } => Some(*next),
+            Order::Unordered { floor, .. } => Some(*floor),
+            Order::Sequenced { .. } => None,
+        }
+    }
+}
+
+type Surfacing = (Option<StreamIndex>, Payload);
+
+/// Removes the datagrams an ordered stream can surface from `pending`.
+fn drain(
+    next: &mut u32,
+    pending: &mut BTreeMap<u32, Option<Payload>>,
+) -> Vec<Surfacing> {
+    let mut surfacing = vec![];
+    while let Some(payload) = pending.remove(next) {
+        if let Some(payload) = payload {
+            surfacing.push((Some(StreamIndex::Ordinal(*next)), payload));
+        }
+        *next = serial::next(*next);
+    }
+    surfacing
+}
+
+impl Receiver {
+    pub fn new(limits: ReceiveLimits) -> Self {
+        Self {
+            limits,
+            streams: HashMap::new(),
+            window: ReceiveWindow::new(limits.connection_bytes),
+            partials: HashMap::new(),
+            unreliable_partials: VecDeque::new(),
+            unregulated_bytes: 0,
+            ready: VecDeque::new(),
+            dirty: BTreeSet::new(),
+            lost: BTreeSet::new(),
+            tombstones: HashMap::new(),
+            dictionary: None,
+        }
+    }
+
+    /// Accepts compressed datagrams, which are decompressed with
+    /// `dictionary`.
+    pub fn enable_compression(&mut self, dictionary: &'static [u8]) {
+        self.dictionary = Some(dictionary);
+    }
+
+    /// Returns the receiving state of a stream for a datagram or skip in the
+    /// remote endpoint's packet numbered `packet`, or `None` if the packet is
+    /// older than the stream's reclaim.
+    fn stream(
+        &mut self,
+        key: StreamKey,
+        packet: u64,
+    ) -> Option<&mut StreamReceiver> {
+        if self
+            .tombstones
+            .get(&key)
+            .is_some_and(|tombstone| packet < tombstone.packet)
+        {
+            return None;
+        }
+        let limits = self.limits;
+        let stream = self
+            .streams
+            .entry(key)
+            .or_insert_with(|| StreamReceiver::new(key, &limits, packet));
+        stream.last_packet = stream.last_packet.max(packet);
+        Some(stream)
+    }
+
+    /// Receives a datagram frame from the remote endpoint's packet numbered
+    /// `packet`.
+    pub fn receive(
+        &mut self,
+        frame: DatagramFrame,
+        packet: u64,
+    ) -> Result<(), Violation> {
+        let key = frame.stream;
+        let index = frame.index;
+        match (key, index) {
+            (StreamKey::Ordered(_), None) | (StreamKey::Sequenced(_), None) => {
+                return Err(Violation::MissingIndex(key));
+            }
+            _ => {}
+        }
+        let flow_controlled = flow_controlled(key, index);
+
+        let stream = match self.stream(key, packet) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        if let Some(index) = index {
+            if !stream.wants(index) {
+                return Ok(());
+            }
+            if flow_controlled && !serial::lt(index, stream.max_index) {
+                return Err(Violation::ReorderWindowExceeded(key));
+            }
+        }
+
+        let len = frame.data.len() as u64;
+        let data = match frame.fragment {
+            Some(fragment) => {
+                match self.reassemble(
+                    key,
+                    index,
+                    fragment,
+                    frame.compressed,
+                    frame.data,
+                    flow_controlled,
+                )? {
+                    Some(data) => data,
+                    None => return Ok(()),
+                }
+            }
+            None => {
+                if !self.buffer(key, len, flow_controlled)? {
+                    return Ok(());
+                }
+                frame.data
+            }
+        };
+
+        let len = data.len() as u64;
+        if frame.compressed && self.dictionary.is_none() {
+            return Err(Violation::MalformedCompression(key));
+        }
+
+        let stream = self.streams.get_mut(&key).expect("stream receiver");
+        // A newer datagram may have surfaced on a sequenced stream while this
+        // one was reassembled.
+        if index.is_some_and(|index| !stream.wants(index)) {
+            self.unregulated_bytes -= len;
+            return Ok(());
+        }
+        let payload = Payload {
+            data,
+            compressed: frame.compressed,
+        };
+        let surfacing = stream.accept(index, payload);
+        self.surface(key, flow_controlled, surfacing);
+        Ok(())
+    }
+
+    /// Moves past a datagram the remote endpoint abandoned. Bytes of it which
+    /// arrived, and those it reserved but never sent, count as surfaced.
+    pub fn skip(
+        &mut self,
+        key: StreamKey,
+        index: u32,
+        len: u64,
+        packet: u64,
+    ) -> Result<(), Violation> {
+        if let StreamKey::Sequenced(_) = key {
+            return Ok(());
+        }
+        let stream = match self.stream(key, packet) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        if !stream.wants(index) {
+            return Ok(());
+        }
+        if !serial::lt(index, stream.max_index) {
+            return Err(Violation::ReorderWindowExceeded(key));
+        }
+
+        let message = self
+            .partials
+            .iter()
+            .find(|(_, partial)| {
+                partial.stream == key && partial.index == Some(index)
+            })
+            .map(|(message, _)| *message);
+        let received = match message {
+            Some(message) => {
+                self.partials.remove(&message).expect("partial").bytes
+            }
+            None => 0,
+        };
+        if received > len {
+            return Err(Violation::InconsistentFragment);
+        }
+        self.buffer(key, len - received, true)?;
+        let stream = self.streams.get_mut(&key).expect("stream receiver");
+        stream.window.surface(len);
+        self.window.surface(len);
+        self.dirty.insert(key);
+
+        let surfacing = stream.skip(index);
+        self.surface(key, true, surfacing);
+        Ok(())
+    }
+
+    /// Drops the state of a stream the remote endpoint reclaimed in its packet
+    /// numbered `packet`, after sending the datagrams below `end`. Reclaims
+    /// older than the stream's newest datagram are stale, and ignored.
+    pub fn reclaim(
+        &mut self,
+        now: Instant,
+        key: StreamKey,
+        end: u32,
+        packet: u64,
+    ) -> Result<(), Violation> {
+        if let Some(stream) = self.streams.get(&key) {
+            if packet <= stream.last_packet {
+                return Ok(());
+            }
+            if !stream.reached(end) {
+                return Err(Violation::PrematureReclaim(key));
+            }
+        }
+
+        self.streams.remove(&key);
+        self.dirty.remove(&key);
+        self.lost.remove(&Limit::StreamBytes(key));
+        self.lost.remove(&Limit::StreamIndex(key));
+        let (reclaimed, partials): (HashMap<_, _>, _) = self
+            .partials
+            .drain()
+            .partition(|(_, partial)| partial.stream == key);
+        self.partials = partials;
+        // Every flow controlled datagram below `end` arrived, so only
+        // unreliable ones can be partial.
+        for (message, partial) in reclaimed {
+            self.unreliable_partials.retain(|m| *m != message);
+            self.unregulated_bytes -= partial.bytes;
+        }
+        for surfaced in &mut self.ready {
+            if surfaced.stream == key {
+                surfaced.reclaimed = true;
+            }
+        }
+        let tombstone = self
+            .tombstones
+            .entry(key)
+            .or_insert(Tombstone { packet, at: now });
+        tombstone.packet = tombstone.packet.max(packet);
+        tombstone.at = now;
+        Ok(())
+    }
+
+    /// Forgets streams reclaimed more than `idle` ago.
+    pub fn expire(&mut self, now: Instant, idle: Duration) {
+        self.tombstones
+            .retain(|_, tombstone| tombstone.at + idle > now);
+    }
+
+    fn surface(
+        &mut self,
+        key: StreamKey,
+        flow_controlled: bool,
+        surfacing: Vec<Surfacing>,
+    ) {
+        for (index, Payload { data, compressed }) in surfacing {
+            self.ready.push_back(Surfaced {
+                stream: key,
+                flow_controlled,
+                reclaimed: false,
+                len: data.len() as u64,
+                compressed,
+                datagram: Datagram {
+                    stream_position: key.stream_id().and_then(|stream_id| {
+                        index.map(|index| StreamPosition { stream_id, index })
+                    }),
+                    data,
+                },
+            });
+        }
+    }
+
+    /// Counts `len` received bytes against the limits. Returns `false` if the
+    /// bytes are not flow controlled, and there is no room for them.
+    fn buffer(
+        &mut self,
+        key: StreamKey,
+        len: u64,
+        flow_controlled: bool,
+    ) -> Result<bool, Violation> {
+        if !flow_controlled {
+            if self.unregulated_bytes + len > self.limits.connection_bytes {
+                return Ok(false);
+            }
+            self.unregulated_bytes += len;
+            return Ok(true);
+        }
+
+        let stream = self.streams.get_mut(&key).expect("stream receiver");
+        if !stream.window.receive(len) {
+            return Err(Violation::StreamWindowExceeded(key));
+        }
+        if !self.window.receive(len) {
+            return Err(Violation::ConnectionWindowExceeded);
+        }
+        Ok(true)
+    }
+
+    /// Buffers a fragment, and returns its datagram if it is complete.
+    fn reassemble(
+        &mut self,
+        key: StreamKey,
+        index: Option<u32>,
+        fragment: Fragment,
+        compressed: bool,
+        data: Bytes,
+        flow_controlled: bool,
+    ) -> Result<Option<Bytes>, Violation> {
+        if fragment.part >= fragment.parts {
+            return Err(Violation::InconsistentFragment);
+        }
+
+        if let Some(partial) = self.partials.get(&fragment.message) {
+            if partial.stream != key
+                || partial.index != index
+                || partial.compressed != compressed
+                || partial.parts.len() != fragment.parts as usize
+            {
+                return Err(Violation::InconsistentFragment);
+            }
+            if partial.parts[fragment.part as usize].is_some() {
+                return Ok(None);
+            }
+        }
+
+        let len = data.len() as u64;
+        if !flow_controlled {
+            while self.unregulated_bytes + len > self.limits.connection_bytes
+                || (!self.partials.contains_key(&fragment.message)
+                    && self.unreliable_partials.len()
+                        >= MAX_UNRELIABLE_PARTIALS)
+            {
+                if !self.drop_oldest_partial(fragment.message) {
+                    return Ok(None);
+                }
+            }
+        }
+        if !self.buffer(key, len, flow_controlled)? {
+            return Ok(None);
+        }
+
+        let unreliable_partials = &mut self.unreliable_partials;
+        let partial =
+            self.partials.entry(fragment.message).or_insert_with(|| {
+                if !flow_controlled {
+                    unreliable_partials.push_back(fragment.message);
+                }
+                Partial {
+                    stream: key,
+                    index,
+                    compressed,
+                    parts: vec![None; fragment.parts as usize],
+                    missing: fragment.parts as usize,
+                    bytes: 0,
+                }
+            });
+        partial.parts[fragment.part as usize] = Some(data);
+        partial.missing -= 1;
+        partial.bytes += len;
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+
+        let partial = self.partials.remove(&fragment.message).expect("partial");
+        self.unreliable_partials
+            .retain(|message| *message != fragment.message);
+        let mut data = Vec::with_capacity(partial.bytes as usize);
+        for part in partial.parts.into_iter().flatten() {
+            data.extend_from_slice(&part);
+        }
+        Ok(Some(data.into()))
+    }
+
+    /// Drops the oldest unreliable partial datagram other than `keep`.
+    /// Returns `false` if there is none.
+    fn drop_oldest_partial(&mut self, keep: u32) -> bool {
+        let position = match self
+            .unreliable_partials
+            .iter()
+            .position(|message| *message != keep)
+        {
+            Some(position) => position,
+            None => return false,
+        };
+        let message = self
+            .unreliable_partials
+            .remove(position)
+            .expect("partial message");
+        let partial = self.partials.remove(&message).expect("partial");
+        self.unregulated_bytes -= partial.bytes;
+        true
+    }
+
+    /// Returns the next datagram to surface, decompressed. Fails if its
+    /// compression is malformed.
+    pub fn poll_datagram(&mut self) -> Result<Option<Datagram>, Violation> {
+        let surfaced = match self.ready.pop_front() {
+            Some(surfaced) => surfaced,
+            None => return Ok(None),
+        };
+        let len = surfaced.len;
+        if surfaced.flow_controlled {
+            self.window.surface(len);
+            if !surfaced.reclaimed {
+                if let Some(stream) = self.streams.get_mut(&surfaced.stream) {
+                    stream.window.surface(len);
+                }
+                self.dirty.insert(surfaced.stream);
+            }
+        } else {
+            self.unregulated_bytes -= len;
+        }
+
+        let mut datagram = surfaced.datagram;
+        if surfaced.compressed {
+            let dictionary = self.dictionary.expect("compression enabled");
+            datagram.data = compress::decompress(
+                dictionary,
+                &datagram.data,
+                self.limits.connection_bytes,
+            )
+            .ok_or(Violation::MalformedCompression(surfaced.stream))?
+            .into();
+        }
+        Ok(Some(datagram))
+    }
+
+    /// Returns a frame advertising new limits to the remote endpoint, if any
+    /// are due.
+    pub fn poll_update(&mut self) -> Option<Frame> {
+        if let Some(limit) = self.lost.iter().next().cloned() {
+            self.lost.remove(&limit);
+            return Some(match limit {
+                Limit::ConnectionBytes => Frame::MaxBytes {
+                    stream: None,
+                    max: self.window.advertised(),
+                },
+                Limit::StreamBytes(key) => Frame::MaxBytes {
+                    stream: Some(key),
+                    max: self.streams[&key].window.advertised(),
+                },
+                Limit::StreamIndex(key) => Frame::MaxIndex {
+                    stream: key,
+                    max: self.streams[&key].max_index,
+                },
+            });
+        }
+
+        if let Some(max) = self.window.update() {
+            return Some(Frame::MaxBytes { stream: None, max });
+        }
+
+        let reorder = self.limits.reorder_datagrams;
+        while let Some(key) = self.dirty.iter().next().cloned() {
+            let stream = self.streams.get_mut(&key).expect("stream receiver");
+            if let Some(max) = stream.window.update() {
+                return Some(Frame::MaxBytes {
+                    stream: Some(key),
+                    max,
+                });
+            }
+            if let Some(base) = stream.reorder_base() {
+                let max = base.wrapping_add(reorder);
+                if max.wrapping_sub(stream.max_index) >= reorder / 2 {
+                    stream.max_index = max;
+                    return Some(Frame::MaxIndex { stream: key, max });
+                }
+            }
+            self.dirty.remove(&key);
+        }
+
+        None
+    }
+
+    /// Schedules a limit to be advertised again, because the frame which
+    /// carried it was lost. Only the newest advertisement of a limit is sent
+    /// again.
+    pub fn lost(&mut self, frame: &Frame) {
+        let limit = match frame {
+            Frame::MaxBytes { stream: None, max }
+                if *max == self.window.advertised() =>
+            {
+                Limit::ConnectionBytes
+            }
+            Frame::MaxBytes {
+                stream: Some(key),
+                max,
+            } if self
+                .streams
+                .get(key)
+                .is_some_and(|s| *max == s.window.advertised()) =>
+            {
+                Limit::StreamBytes(*key)
+            }
+            Frame::MaxIndex { stream: key, max }
+                if self
+                    .streams
+                    .get(key)
+                    .is_some_and(|s| *max == s.max_index) =>
+            {
+                Limit::StreamIndex(*key)
+            }
+            _ => return,
+        };
+        self.lost.insert(limit);
+    }
+}
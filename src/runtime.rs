@@ -0,0 +1,285 @@
+//! Async runtimes which drive sessions.
+//!
+//! Sessions do no IO, so whatever drives them from a task needs a UDP socket,
+//! timers, and somewhere to spawn the task. `Runtime` abstracts over these so
+//! that drivers run on any executor. Adapters for Tokio, async-std and smol are
+//! behind the `tokio`, `async-std` and `smol` features.
+//!
+//! Sockets are polled rather than awaited, so that drivers may poll them from
+//! their own futures without boxing them. They send and receive batches of
+//! packets with the `batch` module.
+//!
+//! A server which outgrows one core may shard its connections across workers,
+//! each driving one of the sockets `bind_reuse_port` returns. A server takes
+//! IPv4 and IPv6 peers on the socket `bind_dual_stack` returns, or on the pair
+//! `bind_pair` returns where sockets cannot be dual-stack.
+
+#[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+use crate::batch;
+use crate::batch::{RecvMeta, Transmit};
+#[cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+use socket2::SockRef;
+use socket2::{Domain, Socket, Type};
+use std::{
+    fmt::Debug,
+    future::Future,
+    io::{self, IoSliceMut},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+/// A future a runtime spawns or wakes at a deadline.
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A UDP socket registered with a runtime's reactor.
+pub trait AsyncUdpSocket: Debug + Send + Sync + 'static {
+    /// Sends packets in order and returns how many were sent, or registers to
+    /// be woken when the socket can send.
+    fn poll_send(
+        &self,
+        ctx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>>;
+
+    /// Receives into `buffers` and returns how many were filled, with where
+    /// each came from in `meta`, or registers to be woken when packets arrive.
+    /// Each buffer should hold `batch::RECV_BUFFER_BYTES`.
+    fn poll_recv(
+        &self,
+        ctx: &mut Context,
+        buffers: &mut [IoSliceMut],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// The sockets, timers and executor of an async runtime.
+pub trait Runtime: Debug + Send + Sync + 'static {
+    type Socket: AsyncUdpSocket;
+
+    /// Prepares a bound socket for batched IO and registers it with the
+    /// runtime's reactor.
+    fn wrap_udp_socket(
+        &self,
+        socket: std::net::UdpSocket,
+    ) -> io::Result<Self::Socket>;
+
+    /// Returns a future which completes at `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture;
+
+    /// Runs a future in the background.
+    fn spawn(&self, future: BoxFuture);
+
+    /// Returns a socket bound to `address`.
+    fn bind_udp(&self, address: SocketAddr) -> io::Result<Self::Socket> {
+        self.wrap_udp_socket(std::net::UdpSocket::bind(address)?)
+    }
+}
+
+/// Returns a socket bound to `port` on every IPv4 and IPv6 address.
+pub fn bind_dual_stack(port: u16) -> io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket.into())
+}
+
+/// Returns an IPv4 socket and an IPv6 only socket bound to `port` on every
+/// address. If `port` is 0, both take the port the IPv4 socket was given.
+pub fn bind_pair(
+    port: u16,
+) -> io::Result<(std::net::UdpSocket, std::net::UdpSocket)> {
+    let ipv4 = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    let port = ipv4.local_addr()?.port();
+    let ipv6 = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+    ipv6.set_only_v6(true)?;
+    ipv6.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    Ok((ipv4, ipv6.into()))
+}
+
+/// Returns `count` sockets bound to `address` with `SO_REUSEPORT`. The kernel
+/// spreads packets among them by a hash of their source address, so each
+/// remote endpoint's packets reach the same socket for as long as all of them
+/// stay open.
+#[cfg(target_os = "linux")]
+pub fn bind_reuse_port(
+    address: SocketAddr,
+    count: usize,
+) -> io::Result<Vec<std::net::UdpSocket>> {
+    let mut address = address;
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket =
+            Socket::new(Domain::for_address(address), Type::DGRAM, None)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&address.into())?;
+        let socket = std::net::UdpSocket::from(socket);
+        // Later sockets join the port the first was given.
+        address = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+/// The Tokio runtime. Its methods must be called from within a Tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "tokio")]
+impl Runtime for TokioRuntime {
+    type Socket = tokio::net::UdpSocket;
+
+    fn wrap_udp_socket(
+        &self,
+        socket: std::net::UdpSocket,
+    ) -> io::Result<Self::Socket> {
+        batch::configure(SockRef::from(&socket));
+        socket.set_nonblocking(true)?;
+        tokio::net::UdpSocket::from_std(socket)
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+
+    fn spawn(&self, future: BoxFuture) {
+        tokio::spawn(future);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncUdpSocket for tokio::net::UdpSocket {
+    fn poll_send(
+        &self,
+        ctx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            std::task::ready!(self.poll_send_ready(ctx))?;
+            match self.try_io(tokio::io::Interest::WRITABLE, || {
+                batch::send(SockRef::from(self), transmits)
+            }) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    fn poll_recv(
+        &self,
+        ctx: &mut Context,
+        buffers: &mut [IoSliceMut],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            std::task::ready!(self.poll_recv_ready(ctx))?;
+            match self.try_io(tokio::io::Interest::READABLE, || {
+                batch::recv(SockRef::from(self), buffers, meta)
+            }) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+}
+
+/// The async-std runtime.
+#[cfg(feature = "async-std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std")]
+impl Runtime for AsyncStdRuntime {
+    type Socket = async_io::Async<std::net::UdpSocket>;
+
+    fn wrap_udp_socket(
+        &self,
+        socket: std::net::UdpSocket,
+    ) -> io::Result<Self::Socket> {
+        batch::configure(SockRef::from(&socket));
+        async_io::Async::new(socket)
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture {
+        Box::pin(async move {
+            async_io::Timer::at(deadline).await;
+        })
+    }
+
+    fn spawn(&self, future: BoxFuture) {
+        async_std::task::spawn(future);
+    }
+}
+
+/// The smol runtime.
+#[cfg(feature = "smol")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SmolRuntime;
+
+#[cfg(feature = "smol")]
+impl Runtime for SmolRuntime {
+    type Socket = async_io::Async<std::net::UdpSocket>;
+
+    fn wrap_udp_socket(
+        &self,
+        socket: std::net::UdpSocket,
+    ) -> io::Result<Self::Socket> {
+        batch::configure(SockRef::from(&socket));
+        async_io::Async::new(socket)
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture {
+        Box::pin(async move {
+            async_io::Timer::at(deadline).await;
+        })
+    }
+
+    fn spawn(&self, future: BoxFuture) {
+        smol::spawn(future).detach();
+    }
+}
+
+/// async-std and smol share the reactor of `async-io`.
+#[cfg(any(feature = "async-std", feature = "smol"))]
+impl AsyncUdpSocket for async_io::Async<std::net::UdpSocket> {
+    fn poll_send(
+        &self,
+        ctx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            std::task::ready!(self.poll_writable(ctx))?;
+            match batch::send(SockRef::from(self.get_ref()), transmits) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    fn poll_recv(
+        &self,
+        ctx: &mut Context,
+        buffers: &mut [IoSliceMut],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            std::task::ready!(self.poll_readable(ctx))?;
+            match batch::recv(SockRef::from(self.get_ref()), buffers, meta) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+}
@@ -0,0 +1,702 @@
+//! The sending side of a connection's streams.
+
+use crate::{
+    error::*,
+    flow::{ReceiveLimits, SendWindow},
+    frame::{DatagramFrame, Fragment, Frame},
+    serial,
+    stream::{reliable, StreamKey},
+};
+use nhanh::{Abandon, DeliveryMode, SendCmd, SendLimits};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// A datagram, or a fragment of one, waiting to be sent or acknowledged.
+#[derive(Debug, Clone)]
+pub struct Outgoing {
+    pub frame: DatagramFrame,
+    pub reliable: bool,
+    /// Whether this is the datagram's first fragment. The first fragment holds
+    /// the datagram's place in the count of buffered datagrams, and reserves
+    /// the remote endpoint's limits for all of it, so that a partially sent
+    /// datagram never waits on limits which only its arrival would raise.
+    first: bool,
+    /// The length of the whole datagram.
+    datagram_len: usize,
+    /// How many more times the fragment may be sent again, if that is
+    /// limited.
+    retransmissions_left: Option<u32>,
+    /// Shared by all of the datagram's fragments, so that they are abandoned
+    /// together.
+    abandon: Option<Abandon>,
+}
+
+impl Outgoing {
+    fn flow_controlled(&self) -> bool {
+        match self.frame.stream {
+            StreamKey::Ordered(_) => true,
+            StreamKey::Sequenced(_) => false,
+            StreamKey::Unordered => self.reliable,
+        }
+    }
+
+    fn is_abandoned(&self) -> bool {
+        self.abandon.as_ref().is_some_and(Abandon::is_abandoned)
+    }
+
+    fn message(&self) -> Option<u32> {
+        self.frame.fragment.map(|fragment| fragment.message)
+    }
+}
+
+/// The sending state of a stream. Streams have none until they are used, and
+/// it is reclaimed once they are idle.
+#[derive(Debug)]
+struct SendStream {
+    next_index: u32,
+    /// Bytes buffered of reliable datagrams.
+    bytes: usize,
+    window: SendWindow,
+    max_index: u32,
+    /// The newest index of a reliable datagram acknowledged, on sequenced
+    /// streams. Older reliable datagrams on the stream are not sent again
+    /// once a newer one arrived, because they would never surface. The newer
+    /// one is itself sent until it arrives.
+    acked: Option<u32>,
+    /// Fragments buffered or in flight, and `Skip` frames not yet
+    /// acknowledged. The stream is idle while there are none.
+    outstanding: usize,
+    /// When a datagram was last buffered on the stream.
+    last_used: Instant,
+}
+
+impl SendStream {
+    fn new(now: Instant, peer_limits: &ReceiveLimits) -> Self {
+        Self {
+            next_index: 0,
+            bytes: 0,
+            window: SendWindow::new(peer_limits.stream_bytes),
+            max_index: peer_limits.reorder_datagrams,
+            acked: None,
+            outstanding: 0,
+            last_used: now,
+        }
+    }
+}
+
+/// A stream whose state was reclaimed recently.
+#[derive(Debug)]
+struct Reclaimed {
+    at: Instant,
+    /// The number of the remote endpoint's packet which acknowledged the
+    /// `Reclaim`. Limits it advertised in older packets were for the
+    /// reclaimed state, and are ignored. Until the `Reclaim` is acknowledged,
+    /// datagrams on the stream wait, so that the remote endpoint never takes
+    /// them for the reclaimed state's.
+    acked_in: Option<u64>,
+}
+
+/// Buffers datagrams to send within the connection's `SendLimits`, and
+/// releases them within the remote endpoint's receive limits.
+#[derive(Debug)]
+pub struct Sender {
+    limits: SendLimits,
+    /// The most bytes of a datagram sent in one frame.
+    max_fragment: usize,
+    /// Datagrams not yet sent.
+    queue: VecDeque<Outgoing>,
+    /// Reliable datagrams to send again, because they were lost.
+    retransmit: VecDeque<Outgoing>,
+    /// `Skip` frames for abandoned datagrams the remote endpoint would wait
+    /// for, and `Reclaim` frames for idle streams.
+    control: VecDeque<Frame>,
+    bytes: usize,
+    datagrams: usize,
+    /// Bytes of datagrams not yet sent.
+    unsent_bytes: usize,
+    streams: HashMap<StreamKey, SendStream>,
+    reclaimed: HashMap<StreamKey, Reclaimed>,
+    next_message: u32,
+    peer_limits: ReceiveLimits,
+    window: SendWindow,
+}
+
+impl Sender {
+    pub fn new(
+        limits: SendLimits,
+        peer_limits: ReceiveLimits,
+        max_fragment: usize,
+    ) -> Self {
+        Self {
+            limits,
+            max_fragment,
+            queue: VecDeque::new(),
+            retransmit: VecDeque::new(),
+            control: VecDeque::new(),
+            bytes: 0,
+            datagrams: 0,
+            unsent_bytes: 0,
+            streams: HashMap::new(),
+            reclaimed: HashMap::new(),
+            next_message: 0,
+            peer_limits,
+            window: SendWindow::new(peer_limits.connection_bytes),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: SendLimits) {
+        self.limits = limits;
+    }
+
+    pub fn peer_limits(&self) -> &ReceiveLimits {
+        &self.peer_limits
+    }
+
+    /// Sets the remote endpoint's limits, once they are known.
+    pub fn set_peer_limits(&mut self, peer_limits: ReceiveLimits) {
+        self.peer_limits = peer_limits;
+        self.window = SendWindow::new(peer_limits.connection_bytes);
+        for stream in self.streams.values_mut() {
+            stream.window = SendWindow::new(peer_limits.stream_bytes);
+            stream.max_index = peer_limits.reorder_datagrams;
+        }
+    }
+
+    /// Returns whether a datagram can be buffered. Without a delivery mode,
+    /// only the connection's limits are checked.
+    pub fn has_room(&self, delivery_mode: Option<DeliveryMode>) -> bool {
+        if delivery_mode.is_some_and(|mode| !reliable(mode)) {
+            return true;
+        }
+
+        let connection_full = self.datagrams > 0
+            && (self.bytes >= self.limits.bytes
+                || self.datagrams >= self.limits.datagrams);
+        let stream_full = delivery_mode
+            .and_then(|mode| self.streams.get(&StreamKey::from(mode)))
+            .is_some_and(|stream| stream.bytes >= self.limits.stream_bytes);
+
+        !connection_full && !stream_full
+    }
+
+    /// Returns whether nothing is buffered.
+    pub fn is_empty(&self) -> bool {
+        self.datagrams == 0 && self.control.is_empty()
+    }
+
+    /// The number of datagrams, or fragments, waiting to be sent for the first
+    /// time.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// The bytes of datagrams waiting to be sent for the first time.
+    pub fn unsent_bytes(&self) -> usize {
+        self.unsent_bytes
+    }
+
+    /// Buffers a datagram. Reliable datagrams are always buffered, so callers
+    /// should check `has_room` first. Unreliable datagrams displace the oldest
+    /// buffered unreliable datagrams, or are dropped if there are none.
+    /// `compressed` is whether the datagram's data was compressed.
+    pub fn push(
+        &mut self,
+        now: Instant,
+        send_cmd: SendCmd,
+        compressed: bool,
+    ) -> Result<()> {
+        let key = StreamKey::from(send_cmd.delivery_mode);
+        let reliable = reliable(send_cmd.delivery_mode);
+        let len = send_cmd.data.len();
+        let retransmissions_left = send_cmd.max_retransmissions;
+        // Fragments which run out of retransmissions abandon the rest of
+        // their datagram through a handle of their own.
+        let abandon = match send_cmd.abandon {
+            Some(abandon) => Some(abandon),
+            None if retransmissions_left.is_some() => Some(Abandon::new()),
+            None => None,
+        };
+
+        let flow_controlled = match key {
+            StreamKey::Ordered(_) => true,
+            StreamKey::Sequenced(_) => false,
+            StreamKey::Unordered => reliable,
+        };
+        let max = self
+            .peer_limits
+            .stream_bytes
+            .min(self.peer_limits.connection_bytes);
+        if flow_controlled && len as u64 > max {
+            return Err(Error::TooLarge { len, max });
+        }
+        // Fragments are numbered in 16 bits.
+        let max_fragments = usize::from(u16::MAX);
+        if len.div_ceil(self.max_fragment) > max_fragments {
+            let max = (max_fragments * self.max_fragment) as u64;
+            return Err(Error::TooLarge { len, max });
+        }
+
+        if !reliable {
+            while !self.fits(len) {
+                if !self.drop_oldest_unreliable() {
+                    return Ok(());
+                }
+            }
+        }
+
+        let peer_limits = self.peer_limits;
+        let stream = self
+            .streams
+            .entry(key)
+            .or_insert_with(|| SendStream::new(now, &peer_limits));
+        stream.last_used = now;
+        let index = match key {
+            StreamKey::Unordered if !reliable => None,
+            _ => {
+                let index = stream.next_index;
+                stream.next_index = serial::next(index);
+                Some(index)
+            }
+        };
+        if reliable {
+            stream.bytes += len;
+        }
+        stream.outstanding += len.div_ceil(self.max_fragment).max(1);
+
+        self.bytes += len;
+        self.datagrams += 1;
+        self.unsent_bytes += len;
+
+        if len <= self.max_fragment {
+            self.queue.push_back(Outgoing {
+                frame: DatagramFrame {
+                    stream: key,
+                    index,
+                    fragment: None,
+                    compressed,
+                    data: send_cmd.data,
+                },
+                reliable,
+                first: true,
+                datagram_len: len,
+                retransmissions_left,
+                abandon,
+            });
+            return Ok(());
+        }
+
+        let message = self.next_message;
+        self.next_message = self.next_message.wrapping_add(1);
+        let parts = len.div_ceil(self.max_fragment);
+        for part in 0..parts {
+            // Fragments share the datagram's buffer.
+            let start = part * self.max_fragment;
+            let data = send_cmd
+                .data
+                .slice(start..len.min(start + self.max_fragment));
+            self.queue.push_back(Outgoing {
+                frame: DatagramFrame {
+                    stream: key,
+                    index,
+                    fragment: Some(Fragment {
+                        message,
+                        part: part as u16,
+                        parts: parts as u16,
+                    }),
+                    compressed,
+                    data,
+                },
+                reliable,
+                first: part == 0,
+                datagram_len: len,
+                retransmissions_left,
+                abandon: abandon.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        self.datagrams == 0
+            || (self.bytes + len <= self.limits.bytes
+                && self.datagrams < self.limits.datagrams)
+    }
+
+    /// Drops every fragment of the oldest queued unreliable datagram. Returns
+    /// `false` if there is none.
+    fn drop_oldest_unreliable(&mut self) -> bool {
+        let oldest = match self.queue.iter().position(|o| !o.reliable) {
+            Some(oldest) => oldest,
+            None => return false,
+        };
+        let outgoing = self.queue.remove(oldest).expect("oldest unreliable");
+        if let Some(message) = outgoing.message() {
+            self.drop_fragments(message);
+        }
+        self.unsent_bytes -= outgoing.frame.data.len();
+        self.release(&outgoing);
+        true
+    }
+
+    /// Drops the buffered fragments of a datagram.
+    fn drop_fragments(&mut self, message: u32) {
+        let (queued, rest): (VecDeque<_>, _) = self
+            .queue
+            .drain(..)
+            .partition(|outgoing| outgoing.message() == Some(message));
+        self.queue = rest;
+        let (lost, rest): (VecDeque<_>, _) = self
+            .retransmit
+            .drain(..)
+            .partition(|outgoing| outgoing.message() == Some(message));
+        self.retransmit = rest;
+
+        for part in &queued {
+            self.unsent_bytes -= part.frame.data.len();
+        }
+        for part in queued.iter().chain(&lost) {
+            self.release(part);
+        }
+    }
+
+    /// Drops a fragment and the rest of its datagram.
+    fn discard(&mut self, outgoing: &Outgoing, unsent: bool) {
+        if let Some(message) = outgoing.message() {
+            self.drop_fragments(message);
+        }
+        if unsent {
+            self.unsent_bytes -= outgoing.frame.data.len();
+        }
+        self.release(outgoing);
+    }
+
+    /// Returns whether a reliable datagram on a sequenced stream is older
+    /// than one which was acknowledged, so it would never surface.
+    fn superseded(&self, outgoing: &Outgoing) -> bool {
+        match (outgoing.frame.stream, outgoing.frame.index) {
+            (key @ StreamKey::Sequenced(_), Some(index)) => self
+                .streams
+                .get(&key)
+                .and_then(|stream| stream.acked)
+                .is_some_and(|acked| serial::lt(index, acked)),
+            _ => false,
+        }
+    }
+
+    /// Drops an abandoned datagram, and schedules a `Skip` for it if the
+    /// remote endpoint would otherwise wait for it. `unsent` is whether the
+    /// fragment came from the queue of datagrams not yet sent.
+    fn drop_abandoned(&mut self, outgoing: Outgoing, unsent: bool) {
+        if let Some(abandon) = &outgoing.abandon {
+            abandon.abandon();
+        }
+        self.discard(&outgoing, unsent);
+
+        let index = match outgoing.frame.index {
+            Some(index) if outgoing.flow_controlled() => index,
+            _ => return,
+        };
+        let stream = outgoing.frame.stream;
+        let scheduled = self.control.iter().any(|skip| {
+            matches!(*skip, Frame::Skip { stream: s, index: i, .. }
+                if s == stream && i == index)
+        });
+        if scheduled {
+            return;
+        }
+        if let Some(stream) = self.streams.get_mut(&stream) {
+            stream.outstanding += 1;
+        }
+        // Fragments are sent in order, so once any of the datagram was sent,
+        // its first fragment reserved the remote endpoint's limits.
+        let reserved = !(unsent && outgoing.first);
+        self.control.push_back(Frame::Skip {
+            stream,
+            index,
+            len: if reserved {
+                outgoing.datagram_len as u64
+            } else {
+                0
+            },
+        });
+    }
+
+    /// Returns the next datagram to send whose frame is at most `max_size`
+    /// bytes, within the remote endpoint's limits. Retransmissions go first,
+    /// and datagrams not yet sent are only returned if `fresh` is set.
+    pub fn next(&mut self, max_size: usize, fresh: bool) -> Option<Outgoing> {
+        while let Some(outgoing) = self.retransmit.front() {
+            if self.superseded(outgoing) {
+                let outgoing = self.retransmit.pop_front().expect("superseded");
+                self.discard(&outgoing, false);
+            } else if outgoing.is_abandoned() {
+                let outgoing = self.retransmit.pop_front().expect("abandoned");
+                self.drop_abandoned(outgoing, false);
+            } else {
+                break;
+            }
+        }
+        if let Some(outgoing) = self.retransmit.front() {
+            if outgoing.frame.size() > max_size {
+                return None;
+            }
+            return self.retransmit.pop_front();
+        }
+        if !fresh {
+            return None;
+        }
+
+        // Streams keep their order, so once a stream's datagram is held back,
+        // so are the rest of its datagrams.
+        let mut held: HashSet<StreamKey> = HashSet::new();
+        let mut position = None;
+        let mut i = 0;
+        while let Some(outgoing) = self.queue.get(i) {
+            if outgoing.is_abandoned() {
+                let outgoing = self.queue.remove(i).expect("abandoned");
+                self.drop_abandoned(outgoing, true);
+                // Dropping fragments may have shifted the queue.
+                i = 0;
+                held.clear();
+                continue;
+            }
+            let key = outgoing.frame.stream;
+            if held.contains(&key) {
+                i += 1;
+                continue;
+            }
+            if self.reclaiming(key) {
+                held.insert(key);
+                i += 1;
+                continue;
+            }
+            if outgoing.flow_controlled() && !self.permits(outgoing) {
+                held.insert(key);
+                i += 1;
+                continue;
+            }
+            if outgoing.frame.size() > max_size {
+                return None;
+            }
+            position = Some(i);
+            break;
+        }
+
+        let outgoing = self.queue.remove(position?).expect("outgoing");
+        self.unsent_bytes -= outgoing.frame.data.len();
+        if outgoing.flow_controlled() && outgoing.first {
+            let len = outgoing.datagram_len as u64;
+            self.window.send(len);
+            if let Some(stream) = self.streams.get_mut(&outgoing.frame.stream) {
+                stream.window.send(len);
+            }
+        }
+        if !outgoing.reliable {
+            self.release(&outgoing);
+        }
+        Some(outgoing)
+    }
+
+    /// Returns whether the remote endpoint's limits permit sending a flow
+    /// controlled datagram.
+    fn permits(&self, outgoing: &Outgoing) -> bool {
+        if !outgoing.first {
+            return true;
+        }
+        let len = outgoing.datagram_len as u64;
+        let stream = match self.streams.get(&outgoing.frame.stream) {
+            Some(stream) => stream,
+            None => return false,
+        };
+        let (available, max_index) =
+            (stream.window.available(), stream.max_index);
+        len <= self.window.available()
+            && len <= available
+            && outgoing
+                .frame
+                .index
+                .is_none_or(|index| serial::lt(index, max_index))
+    }
+
+    /// Returns the next `Skip` or `Reclaim` frame of at most `max_size`
+    /// bytes. `Skip` frames wait for the remote endpoint to permit their
+    /// index.
+    pub fn next_control(&mut self, max_size: usize) -> Option<Frame> {
+        let position = self.control.iter().position(|frame| {
+            let permitted = match *frame {
+                Frame::Skip { stream, index, .. } => self
+                    .streams
+                    .get(&stream)
+                    .is_some_and(|s| serial::lt(index, s.max_index)),
+                _ => true,
+            };
+            permitted && frame.size() <= max_size
+        })?;
+        self.control.remove(position)
+    }
+
+    /// Schedules a `Skip` or `Reclaim` frame to be sent again, because it was
+    /// lost.
+    pub fn control_lost(&mut self, frame: Frame) {
+        self.control.push_back(frame);
+    }
+
+    /// Handles the acknowledgement of a `Skip` or `Reclaim` frame, in the
+    /// remote endpoint's packet numbered `packet`.
+    pub fn control_acked(&mut self, frame: &Frame, packet: u64) {
+        match *frame {
+            Frame::Skip { stream, .. } => {
+                if let Some(stream) = self.streams.get_mut(&stream) {
+                    stream.outstanding = stream.outstanding.saturating_sub(1);
+                }
+            }
+            Frame::Reclaim { stream, .. } => {
+                if let Some(reclaimed) = self.reclaimed.get_mut(&stream) {
+                    let acked_in = reclaimed.acked_in.get_or_insert(packet);
+                    *acked_in = (*acked_in).min(packet);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns whether a stream's `Reclaim` is unacknowledged.
+    fn reclaiming(&self, key: StreamKey) -> bool {
+        self.reclaimed
+            .get(&key)
+            .is_some_and(|reclaimed| reclaimed.acked_in.is_none())
+    }
+
+    /// Reclaims the state of streams which have had nothing outstanding, and
+    /// no datagrams buffered, for `idle`, and forgets streams reclaimed more
+    /// than `idle` ago.
+    pub fn reclaim_idle(&mut self, now: Instant, idle: Duration) {
+        self.reclaimed.retain(|_, reclaimed| {
+            reclaimed.acked_in.is_none() || reclaimed.at + idle > now
+        });
+        let idle: Vec<StreamKey> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| {
+                stream.outstanding == 0 && stream.last_used + idle <= now
+            })
+            .map(|(&key, _)| key)
+            .collect();
+        for key in idle {
+            let stream = self.streams.remove(&key).expect("idle stream");
+            self.reclaimed.insert(
+                key,
+                Reclaimed {
+                    at: now,
+                    acked_in: None,
+                },
+            );
+            self.control.push_back(Frame::Reclaim {
+                stream: key,
+                end: stream.next_index,
+            });
+        }
+    }
+
+    /// Returns the state of a stream whose limits the remote endpoint raised
+    /// in its packet numbered `packet`, unless they were for reclaimed state.
+    fn raised(
+        &mut self,
+        key: StreamKey,
+        packet: u64,
+    ) -> Option<&mut SendStream> {
+        let stale = self.reclaimed.get(&key).is_some_and(|reclaimed| {
+            reclaimed.acked_in.is_none_or(|acked_in| packet < acked_in)
+        });
+        if stale {
+            return None;
+        }
+        self.streams.get_mut(&key)
+    }
+
+    pub fn raise_bytes(
+        &mut self,
+        stream: Option<StreamKey>,
+        max: u64,
+        packet: u64,
+    ) {
+        match stream {
+            Some(key) => {
+                if let Some(stream) = self.raised(key, packet) {
+                    stream.window.raise(max);
+                }
+            }
+            None => self.window.raise(max),
+        }
+    }
+
+    pub fn raise_index(&mut self, key: StreamKey, max: u32, packet: u64) {
+        if let Some(stream) = self.raised(key, packet) {
+            stream.max_index = serial::max(stream.max_index, max);
+        }
+    }
+
+    /// Releases a reliable datagram once it is acknowledged.
+    pub fn acked(&mut self, outgoing: &Outgoing) {
+        if let (key @ StreamKey::Sequenced(_), Some(index)) =
+            (outgoing.frame.stream, outgoing.frame.index)
+        {
+            if let Some(stream) = self.streams.get_mut(&key) {
+                let acked = stream.acked.get_or_insert(index);
+                *acked = serial::max(*acked, index);
+            }
+        }
+        self.release(outgoing);
+    }
+
+    /// Schedules a reliable datagram to be sent again, unless it was
+    /// abandoned, has been sent again as many times as it may be, or is
+    /// superseded on its sequenced stream. Returns whether it will be sent
+    /// again.
+    pub fn lost(&mut self, mut outgoing: Outgoing) -> bool {
+        if self.superseded(&outgoing) {
+            self.discard(&outgoing, false);
+            return false;
+        }
+        if outgoing.retransmissions_left == Some(0) || outgoing.is_abandoned() {
+            self.drop_abandoned(outgoing, false);
+            return false;
+        }
+        if let Some(left) = outgoing.retransmissions_left.as_mut() {
+            *left -= 1;
+        }
+        self.retransmit.push_back(outgoing);
+        true
+    }
+
+    /// Drops everything buffered.
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.retransmit.clear();
+        self.control.clear();
+        self.bytes = 0;
+        self.datagrams = 0;
+        self.unsent_bytes = 0;
+        for stream in self.streams.values_mut() {
+            stream.bytes = 0;
+            stream.outstanding = 0;
+        }
+    }
+
+    fn release(&mut self, outgoing: &Outgoing) {
+        let len = outgoing.frame.data.len();
+        self.bytes -= len;
+        if outgoing.first {
+            self.datagrams -= 1;
+        }
+        if let Some(stream) = self.streams.get_mut(&outgoing.frame.stream) {
+            if outgoing.reliable {
+                stream.bytes -= len;
+            }
+            stream.outstanding = stream.outstanding.saturating_sub(1);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! Serial number arithmetic for stream indices, as in RFC 1982.
+//!
+//! Stream indices are 32 bits, and wrap from `u32::MAX` to `0` on long-lived
+//! connections. They are compared by how far apart they are rather than by
+//! value: an index precedes those less than 2^31 ahead of it, counting past
+//! the wrap. The indices a flow controlled stream has in flight are never that
+//! far apart, because the receiver's reorder limit bounds how far past the
+//! oldest missing datagram its sender may go. Sequenced streams only compare a
+//! datagram with the newest one surfaced, which would take 2^31 consecutive
+//! losses to fall that far behind.
+
+/// Half of the index space. Indices this far apart are not ordered.
+const HALF: u32 = 1 << 31;
+
+/// Returns whether `a` comes before `b`.
+pub fn lt(a: u32, b: u32) -> bool {
+    let distance = b.wrapping_sub(a);
+    distance != 0 && distance < HALF
+}
+
+/// Returns whether `a` is `b`, or comes before it.
+pub fn le(a: u32, b: u32) -> bool {
+    a == b || lt(a, b)
+}
+
+/// Returns the later of `a` and `b`.
+pub fn max(a: u32, b: u32) -> u32 {
+    if lt(a, b) {
+        b
+    } else {
+        a
+    }
+}
+
+/// Returns the index after `a`.
+pub fn next(a: u32) -> u32 {
+    a.wrapping_add(1)
+}
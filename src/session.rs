@@ -0,0 +1,1077 @@
+//! The protocol state of one connection, apart from any socket or clock.
+//!
+//! A `Session` is driven by its owner: packets received from the remote
+//! endpoint are given to `handle`, packets to send are taken from
+//! `poll_transmit`, and `handle_timeout` is called once the instant from
+//! `poll_timeout` passes.
+
+use crate::{
+    compress::{self, CompressionConfig},
+    error::*,
+    fec::{Decoder, Encoder, FecConfig, REPAIR_OVERHEAD},
+    flow::ReceiveLimits,
+    frame::{
+        self, Frame, Hello, Packet, Reject, Repair, DATAGRAM_OVERHEAD,
+        DATA_OVERHEAD,
+    },
+    handshake::{Capabilities, Features, MIN_VERSION, VERSION},
+    recovery::{Acks, Recovery, Sent, SentPacket},
+    recv::Receiver,
+    send::Sender,
+    stream::StreamKey,
+};
+use nhanh::{
+    Bytes, Close, CloseReason, ConnectionEvent, ConnectionStats, Datagram,
+    DeliveryMode, SendCmd, SendLimits,
+};
+use std::{
+    cell::OnceCell,
+    collections::{HashSet, VecDeque},
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+/// How many probe timeouts a closing endpoint waits for its close to be
+/// acknowledged.
+const CLOSE_PTOS: u32 = 3;
+
+/// The smallest MTU, which leaves room for a byte of data in a packet with
+/// every overhead.
+pub const MIN_MTU: usize =
+    REPAIR_OVERHEAD + DATA_OVERHEAD + DATAGRAM_OVERHEAD + 1;
+
+/// The configuration of a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// Limits advertised to the remote endpoint.
+    pub receive_limits: ReceiveLimits,
+    /// What this endpoint offers the remote endpoint.
+    pub capabilities: Capabilities,
+    /// Features the connection is refused without.
+    pub required_features: Features,
+    pub send_limits: SendLimits,
+    /// The largest packet sent. Values below `MIN_MTU` are raised to it.
+    pub mtu: usize,
+    /// How long the remote endpoint may be silent before the connection
+    /// times out.
+    pub idle_timeout: Duration,
+    /// How long the connection may be quiet before a ping is sent to keep it
+    /// open.
+    pub keep_alive: Option<Duration>,
+    /// The longest a datagram waits to share a packet with others. Datagrams
+    /// are sent sooner once they fill a packet, or when another packet is
+    /// sent.
+    pub max_coalesce_delay: Duration,
+    /// Forward error correction to send, if the remote endpoint supports it.
+    /// See the `fec` module.
+    pub fec: Option<FecConfig>,
+    /// Compression of sent datagrams, if the remote endpoint supports it and
+    /// configured the same dictionary. See the `compress` module.
+    pub compression: Option<CompressionConfig>,
+    /// How long a stream may go unused before the state of it is reclaimed.
+    /// Reclaimed streams start again from their first index when next used.
+    pub stream_idle_timeout: Duration,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Config {
+    /// The dictionary datagrams are compressed with, in both directions.
+    fn dictionary(&self) -> &'static [u8] {
+        self.compression
+            .map_or(&[], |compression| compression.dictionary)
+    }
+
+    /// Returns what this endpoint and the sender of `hello` are able to do.
+    /// Compression needs both to have the same dictionary.
+    fn agreed(&self, hello: &Hello) -> Capabilities {
+        let mut capabilities =
+            self.capabilities.intersection(&hello.capabilities);
+        if hello.dictionary != compress::dictionary_id(self.dictionary()) {
+            capabilities.features =
+                capabilities.features.difference(Features::COMPRESSION);
+        }
+        capabilities
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            receive_limits: ReceiveLimits::default(),
+            capabilities: Capabilities::default(),
+            required_features: Features::empty(),
+            send_limits: SendLimits::default(),
+            mtu: 1200,
+            idle_timeout: Duration::from_secs(10),
+            keep_alive: Some(Duration::from_secs(2)),
+            max_coalesce_delay: Duration::from_secs(0),
+            fec: None,
+            compression: None,
+            stream_idle_timeout: Duration::from_secs(30),
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    /// The client is waiting for the server to accept.
+    Connecting {
+        attempt_at: Instant,
+        backoff: Duration,
+    },
+    Open,
+    /// The endpoints are incompatible, and the remote endpoint is owed a
+    /// `Reject`.
+    Rejecting(Reject),
+    /// The local endpoint is delivering its buffered datagrams before closing.
+    Lingering(CloseReason),
+    /// The local endpoint sent a close, and is waiting for it to be
+    /// acknowledged.
+    Closing {
+        deadline: Instant,
+    },
+    /// The remote endpoint closed the connection, and its close has not been
+    /// acknowledged yet.
+    Draining,
+    Closed,
+}
+
+/// A datagram to send on many connections. Its data is shared by them rather
+/// than copied, and compressed at most once for those which compress alike.
+/// Each connection still delivers it with reliability state of its own.
+#[derive(Debug, Clone)]
+pub struct SharedDatagram {
+    send_cmd: SendCmd,
+    /// The data compressed with the config of the first connection which
+    /// compressed it, if it shrank.
+    compressed: OnceCell<(CompressionConfig, Option<Bytes>)>,
+}
+
+impl SharedDatagram {
+    pub fn new(send_cmd: SendCmd) -> Self {
+        Self {
+            send_cmd,
+            compressed: OnceCell::new(),
+        }
+    }
+
+    pub fn send_cmd(&self) -> &SendCmd {
+        &self.send_cmd
+    }
+
+    /// Returns the data compressed with `config`, if it shrinks.
+    fn compress(&self, config: &CompressionConfig) -> Option<Bytes> {
+        let compress =
+            || compress::compress(config, &self.send_cmd.data).map(Bytes::from);
+        let (cached, compressed) =
+            self.compressed.get_or_init(|| (*config, compress()));
+        match cached == config {
+            true => compressed.clone(),
+            false => compress(),
+        }
+    }
+}
+
+/// One endpoint of a miknet connection.
+#[derive(Debug)]
+pub struct Session {
+    config: Config,
+    state: State,
+    sender: Sender,
+    receiver: Receiver,
+    recovery: Recovery,
+    acks: Acks,
+    server: bool,
+    /// Whether the server owes the client an `Accept`.
+    accept: bool,
+    /// The protocol version of the connection, or the one the client is
+    /// trying.
+    version: u32,
+    /// What both endpoints are able to do, once they know.
+    capabilities: Option<Capabilities>,
+    /// The most bytes in a packet, less room for forward error correction.
+    packet_size: usize,
+    fec_encoder: Option<Encoder>,
+    fec_decoder: Option<Decoder>,
+    /// A repair to send.
+    repair: Option<Repair>,
+    /// Streams whose packets forward error correction does not protect.
+    unprotected: HashSet<StreamKey>,
+    /// How sent datagrams are compressed, once the endpoints agree to.
+    compression: Option<CompressionConfig>,
+    /// Streams whose datagrams are not compressed.
+    uncompressed: HashSet<StreamKey>,
+    /// A close or abort frame to send.
+    close_frame: Option<Frame>,
+    probe: bool,
+    /// When buffered datagrams are due, if they do not fill a packet first.
+    coalesce_until: Option<Instant>,
+    /// Whether buffered datagrams are due.
+    due: bool,
+    last_received: Instant,
+    last_ack_eliciting: Instant,
+    /// When idle streams are next reclaimed.
+    sweep_at: Instant,
+    events: VecDeque<ConnectionEvent>,
+    remote_reason: Option<CloseReason>,
+    error: Option<Error>,
+    packets_sent: u64,
+    packets_received: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    retransmissions: u64,
+}
+
+impl Session {
+    /// Returns the client end of a new connection.
+    pub fn connect(now: Instant, config: Config) -> Self {
+        let mut session = Self::new(now, config, ReceiveLimits::default());
+        session.state = State::Connecting {
+            attempt_at: now,
+            backoff: session.recovery.rtt().pto(),
+        };
+        session
+    }
+
+    /// Returns the server end of a new connection, if `packet` opens one. If
+    /// the client is incompatible, the session is never connected, and only
+    /// sends the client a `Reject` before it closes.
+    pub fn accept(now: Instant, config: Config, packet: &[u8]) -> Option<Self> {
+        let decoded = Packet::decode(&Bytes::copy_from_slice(packet), None);
+        let incompatibility = match decoded {
+            Ok(Packet::Connect(hello)) => {
+                let capabilities = config.agreed(&hello);
+                let missing =
+                    config.required_features.difference(capabilities.features);
+                let mut session = Self::new(now, config, hello.limits);
+                session.received_connect(packet);
+                if !missing.is_empty() {
+                    session.reject(Incompatibility::Features(missing));
+                    return Some(session);
+                }
+                session.accept = true;
+                session.version = hello.version;
+                session.agree(capabilities);
+                session.events.push_back(ConnectionEvent::Connected);
+                return Some(session);
+            }
+            Err(Error::Malformed(DecodeError::UnsupportedVersion(version))) => {
+                Incompatibility::Version {
+                    min: version,
+                    max: version,
+                }
+            }
+            _ => return None,
+        };
+        let mut session = Self::new(now, config, ReceiveLimits::default());
+        session.received_connect(packet);
+        session.reject(incompatibility);
+        Some(session)
+    }
+
+    fn received_connect(&mut self, packet: &[u8]) {
+        self.server = true;
+        self.packets_received = 1;
+        self.bytes_received = packet.len() as u64;
+    }
+
+    fn new(
+        now: Instant,
+        mut config: Config,
+        peer_limits: ReceiveLimits,
+    ) -> Self {
+        config.mtu = config.mtu.max(MIN_MTU);
+        let packet_size = match config.fec {
+            Some(_) => config.mtu - REPAIR_OVERHEAD,
+            None => config.mtu,
+        };
+        let max_fragment = packet_size - DATA_OVERHEAD - DATAGRAM_OVERHEAD;
+        Self {
+            config,
+            state: State::Open,
+            sender: Sender::new(config.send_limits, peer_limits, max_fragment),
+            receiver: Receiver::new(config.receive_limits),
+            recovery: Recovery::new(config.mtu),
+            acks: Acks::default(),
+            server: false,
+            accept: false,
+            version: VERSION,
+            capabilities: None,
+            packet_size,
+            fec_encoder: None,
+            fec_decoder: None,
+            repair: None,
+            unprotected: HashSet::new(),
+            compression: None,
+            uncompressed: HashSet::new(),
+            close_frame: None,
+            probe: false,
+            coalesce_until: None,
+            due: false,
+            last_received: now,
+            last_ack_eliciting: now,
+            sweep_at: now + config.stream_idle_timeout / 2,
+            events: VecDeque::new(),
+            remote_reason: None,
+            error: None,
+            packets_sent: 0,
+            packets_received: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            retransmissions: 0,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Open | State::Lingering(_))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    /// What both endpoints are able to do, once the connection is open.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// The remote endpoint's reason for closing the connection, if it did.
+    pub fn close_reason(&self) -> Option<&CloseReason> {
+        self.remote_reason.as_ref()
+    }
+
+    /// Takes the error which closed the connection, if one did.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// Returns whether a datagram can be buffered without exceeding the send
+    /// limits. Without a delivery mode, only the connection's limits are
+    /// checked.
+    pub fn has_room(&self, delivery_mode: Option<DeliveryMode>) -> bool {
+        self.sender.has_room(delivery_mode)
+    }
+
+    pub fn set_send_limits(&mut self, limits: SendLimits) {
+        self.sender.set_limits(limits);
+    }
+
+    /// Buffers a datagram to send.
+    pub fn send(&mut self, now: Instant, send_cmd: SendCmd) -> Result<()> {
+        self.send_with(now, send_cmd, |config, data| {
+            compress::compress(config, data).map(Bytes::from)
+        })
+    }
+
+    /// Buffers a datagram which other connections send too.
+    pub fn send_shared(
+        &mut self,
+        now: Instant,
+        datagram: &SharedDatagram,
+    ) -> Result<()> {
+        self.send_with(now, datagram.send_cmd.clone(), |config, _| {
+            datagram.compress(config)
+        })
+    }
+
+    /// Buffers a datagram to send, compressed by `compress` if the connection
+    /// and its stream compress datagrams.
+    fn send_with(
+        &mut self,
+        now: Instant,
+        send_cmd: SendCmd,
+        compress: impl FnOnce(&CompressionConfig, &[u8]) -> Option<Bytes>,
+    ) -> Result<()> {
+        if let Some(stream) =
+            StreamKey::from(send_cmd.delivery_mode).stream_id()
+        {
+            let max = self.max_streams();
+            if stream.0 >= max {
+                return Err(Error::StreamLimit { stream, max });
+            }
+        }
+        match self.state {
+            State::Connecting { .. } | State::Open => {
+                if !self.due && self.coalesce_until.is_none() {
+                    self.coalesce_until =
+                        Some(now + self.config.max_coalesce_delay);
+                }
+                let compressed = self
+                    .compression(&send_cmd)
+                    .and_then(|config| compress(config, &send_cmd.data));
+                let (send_cmd, compressed) = match compressed {
+                    Some(data) => (SendCmd { data, ..send_cmd }, true),
+                    None => (send_cmd, false),
+                };
+                self.sender.push(now, send_cmd, compressed)
+            }
+            _ => Err(Error::Closed),
+        }
+    }
+
+    /// Returns how to compress a datagram's data, if the connection and its
+    /// stream compress datagrams. Datagrams longer than the remote endpoint
+    /// would decompress are sent as they are.
+    fn compression(&self, send_cmd: &SendCmd) -> Option<&CompressionConfig> {
+        let compression = self.compression.as_ref()?;
+        let stream = StreamKey::from(send_cmd.delivery_mode);
+        let max = self.sender.peer_limits().connection_bytes;
+        if self.uncompressed.contains(&stream)
+            || send_cmd.data.len() as u64 > max
+        {
+            return None;
+        }
+        Some(compression)
+    }
+
+    fn agree(&mut self, capabilities: Capabilities) {
+        self.capabilities = Some(capabilities);
+        if capabilities.features.contains(Features::FEC) {
+            self.fec_encoder = self.config.fec.map(Encoder::new);
+            self.fec_decoder = Some(Decoder::default());
+        }
+        if capabilities.features.contains(Features::COMPRESSION) {
+            self.compression = self.config.compression;
+            self.receiver.enable_compression(self.config.dictionary());
+        }
+    }
+
+    /// Turns forward error correction on or off for a stream's packets. All
+    /// streams are protected by default, if the connection sends forward
+    /// error correction.
+    pub fn set_fec(&mut self, delivery_mode: DeliveryMode, enabled: bool) {
+        let stream = StreamKey::from(delivery_mode);
+        if enabled {
+            self.unprotected.remove(&stream);
+        } else {
+            self.unprotected.insert(stream);
+        }
+    }
+
+    /// Turns compression on or off for a stream's datagrams, such as for
+    /// payloads which are already compressed. All streams are compressed by
+    /// default, if the connection compresses datagrams.
+    pub fn set_compression(
+        &mut self,
+        delivery_mode: DeliveryMode,
+        enabled: bool,
+    ) {
+        let stream = StreamKey::from(delivery_mode);
+        if enabled {
+            self.uncompressed.remove(&stream);
+        } else {
+            self.uncompressed.insert(stream);
+        }
+    }
+
+    /// The streams of each kind the connection may use. Until the remote
+    /// endpoint's capabilities are known, these are the ones offered to it.
+    fn max_streams(&self) -> u32 {
+        self.capabilities
+            .unwrap_or(self.config.capabilities)
+            .max_streams
+    }
+
+    /// Sends buffered datagrams without waiting out the coalescing delay.
+    pub fn flush(&mut self) {
+        if self.sender.queued() > 0 {
+            self.due = true;
+            self.coalesce_until = None;
+        }
+    }
+
+    pub fn poll_datagram(&mut self) -> Option<Datagram> {
+        match self.receiver.poll_datagram() {
+            Ok(datagram) => datagram,
+            Err(violation) => {
+                self.abort(violation);
+                None
+            }
+        }
+    }
+
+    pub fn poll_event(&mut self) -> Option<ConnectionEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let rtt = self.recovery.rtt();
+        ConnectionStats {
+            packets_sent: Some(self.packets_sent),
+            packets_received: Some(self.packets_received),
+            bytes_sent: Some(self.bytes_sent),
+            bytes_received: Some(self.bytes_received),
+            retransmissions: Some(self.retransmissions),
+            recovered: Some(self.acks.recovered()),
+            loss: Some(self.recovery.loss()),
+            smoothed_rtt: Some(rtt.smoothed()),
+            rtt_variance: Some(rtt.variance()),
+            bytes_in_flight: Some(self.recovery.bytes_in_flight() as u64),
+            send_queue_depth: Some(self.sender.queued() as u64),
+            congestion_window: Some(self.recovery.congestion_window() as u64),
+            ..ConnectionStats::default()
+        }
+    }
+
+    /// Closes the connection. If `close.linger` is set, buffered datagrams
+    /// are delivered first.
+    pub fn close(&mut self, now: Instant, close: &Close) {
+        match self.state {
+            State::Connecting { .. } => self.finish(),
+            State::Open => {
+                self.events.push_back(ConnectionEvent::Disconnecting);
+                if close.linger {
+                    self.state = State::Lingering(close.reason.clone());
+                } else {
+                    self.clear();
+                    self.begin_closing(now, close.reason.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn begin_closing(&mut self, now: Instant, reason: CloseReason) {
+        self.close_frame = Some(Frame::Close(reason));
+        self.state = State::Closing {
+            deadline: now + self.recovery.rtt().pto() * CLOSE_PTOS,
+        };
+    }
+
+    /// Closes the connection because the remote endpoint broke the protocol.
+    fn abort(&mut self, violation: Violation) {
+        self.clear();
+        self.close_frame = Some(Frame::Abort(violation));
+        self.error = Some(Error::Violation(violation));
+        self.state = State::Draining;
+    }
+
+    /// Refuses the connection because the endpoints are incompatible.
+    fn reject(&mut self, incompatibility: Incompatibility) {
+        let missing = match incompatibility {
+            Incompatibility::Features(missing) => missing,
+            Incompatibility::Version { .. } => Features::empty(),
+        };
+        self.clear();
+        self.error = Some(Error::Incompatible(incompatibility));
+        self.state = State::Rejecting(Reject {
+            min_version: MIN_VERSION,
+            max_version: VERSION,
+            missing,
+        });
+    }
+
+    /// Closes the connection because the remote endpoint refused it. A client
+    /// tries again in an older version if the server speaks one it does too.
+    fn rejected(&mut self, now: Instant, reject: Reject) {
+        let common = reject.max_version.min(VERSION);
+        if let State::Connecting { backoff, .. } = self.state {
+            if reject.missing.is_empty()
+                && common < self.version
+                && common >= reject.min_version.max(MIN_VERSION)
+            {
+                self.version = common;
+                self.state = State::Connecting {
+                    attempt_at: now,
+                    backoff,
+                };
+                return;
+            }
+        }
+
+        let incompatibility = if reject.missing.is_empty() {
+            Incompatibility::Version {
+                min: reject.min_version,
+                max: reject.max_version,
+            }
+        } else {
+            Incompatibility::Features(reject.missing)
+        };
+        self.error = Some(Error::Incompatible(incompatibility));
+        self.finish();
+    }
+
+    /// Drops everything buffered to send, and forgets what is in flight.
+    fn clear(&mut self) {
+        self.sender.clear();
+        self.recovery.forget_datagrams();
+    }
+
+    fn finish(&mut self) {
+        self.state = State::Closed;
+        self.clear();
+        self.events.push_back(ConnectionEvent::Disconnected(
+            self.remote_reason.clone(),
+        ));
+    }
+
+    /// Handles a packet received from the remote endpoint. Malformed packets
+    /// are ignored. Datagrams the packet carries share its bytes.
+    pub fn handle(&mut self, now: Instant, packet: Bytes) {
+        if self.is_closed() {
+            return;
+        }
+        self.packets_received += 1;
+        self.bytes_received += packet.len() as u64;
+        let bytes = packet;
+        let packet = match Packet::decode(&bytes, self.acks.largest()) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        self.last_received = now;
+
+        let (number, frames) = match (packet, &self.state) {
+            (Packet::Accept(hello), State::Connecting { .. }) => {
+                let capabilities = self.config.agreed(&hello);
+                let missing = self
+                    .config
+                    .required_features
+                    .difference(capabilities.features);
+                if !missing.is_empty() {
+                    self.reject(Incompatibility::Features(missing));
+                    return;
+                }
+                self.version = hello.version;
+                self.agree(capabilities);
+                self.sender.set_peer_limits(hello.limits);
+                self.state = State::Open;
+                self.events.push_back(ConnectionEvent::Connected);
+                return;
+            }
+            (Packet::Reject(reject), _) => {
+                self.rejected(now, reject);
+                return;
+            }
+            (Packet::Connect(_), _) => {
+                self.accept = self.server;
+                return;
+            }
+            // The server's accept was lost; it is sent again when the client
+            // retries.
+            (_, State::Connecting { .. }) | (Packet::Accept(_), _) => return,
+            (Packet::Data { number, frames }, _) => (number, frames),
+        };
+
+        // Packets rebuilt from repairs are handled as though received.
+        let payload = bytes.slice_ref(frame::payload(&bytes));
+        let mut packets = vec![(number, frames, payload, false)];
+        while let Some((number, frames, payload, rebuilt)) = packets.pop() {
+            let ack_eliciting = frames.iter().any(Frame::ack_eliciting);
+            if !self.acks.receive(now, number, ack_eliciting) {
+                continue;
+            }
+            if rebuilt {
+                self.acks.on_recovered();
+            }
+            if let Some(decoder) = self.fec_decoder.as_mut() {
+                decoder.on_received(number, &payload);
+            }
+
+            for frame in frames {
+                if let Frame::Repair(repair) = frame {
+                    let rebuilt = self
+                        .fec_decoder
+                        .as_mut()
+                        .and_then(|decoder| decoder.on_repair(&repair));
+                    let (number, payload) = match rebuilt {
+                        Some((number, payload)) => (number, payload.into()),
+                        None => continue,
+                    };
+                    if let Ok(frames) = frame::decode_frames(&payload) {
+                        packets.push((number, frames, payload, true));
+                    }
+                    continue;
+                }
+                if let Err(violation) = self.handle_frame(now, number, frame) {
+                    self.abort(violation);
+                    return;
+                }
+                if self.is_closed() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Handles a frame from the remote endpoint's packet numbered `number`.
+    fn handle_frame(
+        &mut self,
+        now: Instant,
+        number: u64,
+        frame: Frame,
+    ) -> std::result::Result<(), Violation> {
+        match frame {
+            Frame::Datagram(frame) => {
+                self.check_stream(frame.stream)?;
+                self.receiver.receive(frame, number)?
+            }
+            Frame::Skip { stream, index, len } => {
+                self.check_stream(stream)?;
+                self.receiver.skip(stream, index, len, number)?
+            }
+            Frame::Reclaim { stream, end } => {
+                self.check_stream(stream)?;
+                self.receiver.reclaim(now, stream, end, number)?
+            }
+            Frame::Ack(ack) => {
+                self.recovery.on_recovered(ack.recovered);
+                let (acked, lost) = self.recovery.on_ack(now, &ack);
+                self.on_acked(acked, number);
+                self.on_lost(lost);
+            }
+            Frame::MaxBytes { stream, max } => {
+                self.sender.raise_bytes(stream, max, number)
+            }
+            Frame::MaxIndex { stream, max } => {
+                self.sender.raise_index(stream, max, number)
+            }
+            Frame::Ping => {}
+            Frame::Close(reason) => {
+                self.remote_reason = Some(reason);
+                self.clear();
+                self.close_frame = None;
+                self.state = State::Draining;
+            }
+            Frame::Abort(violation) => {
+                self.error = Some(Error::ViolationReported(violation));
+                self.finish();
+            }
+            // Repairs are handled with the packet they arrive in.
+            Frame::Repair(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Checks that a stream the remote endpoint used is within those agreed
+    /// on.
+    fn check_stream(
+        &self,
+        stream: StreamKey,
+    ) -> std::result::Result<(), Violation> {
+        match stream.stream_id() {
+            Some(id) if id.0 >= self.max_streams() => {
+                Err(Violation::StreamLimitExceeded(stream))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles the acknowledgement of sent packets, in the remote endpoint's
+    /// packet numbered `number`.
+    fn on_acked(&mut self, acked: Vec<Sent>, number: u64) {
+        for sent in acked {
+            match sent {
+                Sent::Datagram(outgoing) => self.sender.acked(&outgoing),
+                Sent::Control(
+                    frame @ (Frame::Skip { .. } | Frame::Reclaim { .. }),
+                ) => self.sender.control_acked(&frame, number),
+                Sent::Control(Frame::Close(_)) => {
+                    if let State::Closing { .. } = self.state {
+                        self.finish();
+                    }
+                }
+                Sent::Control(_) => {}
+            }
+        }
+    }
+
+    fn on_lost(&mut self, lost: Vec<Sent>) {
+        for sent in lost {
+            match sent {
+                Sent::Datagram(outgoing) => {
+                    if self.sender.lost(outgoing) {
+                        self.retransmissions += 1;
+                    }
+                }
+                Sent::Control(frame @ Frame::Close(_)) => {
+                    if let State::Closing { .. } = self.state {
+                        self.close_frame = Some(frame);
+                    }
+                }
+                Sent::Control(
+                    frame @ (Frame::Skip { .. } | Frame::Reclaim { .. }),
+                ) => self.sender.control_lost(frame),
+                Sent::Control(frame) => self.receiver.lost(&frame),
+            }
+        }
+    }
+
+    /// Returns when `handle_timeout` should next be called.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let idle = self.last_received + self.config.idle_timeout;
+        let timeout = match self.state {
+            State::Closed => return None,
+            State::Connecting { attempt_at, .. } => {
+                return Some(attempt_at.min(idle))
+            }
+            State::Closing { deadline } => idle.min(deadline),
+            State::Open => match self.config.keep_alive {
+                Some(keep_alive) => {
+                    idle.min(self.last_ack_eliciting + keep_alive)
+                }
+                None => idle,
+            },
+            _ => idle,
+        };
+
+        let timeout = [
+            self.acks.poll_timeout(),
+            self.recovery.poll_timeout(),
+            self.coalesce_until,
+            Some(self.sweep_at),
+            self.fec_encoder.as_ref().and_then(Encoder::poll_timeout),
+        ]
+        .iter()
+        .flatten()
+        .fold(timeout, |timeout, &t| timeout.min(t));
+        Some(timeout)
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.is_closed() {
+            return;
+        }
+        if self.last_received + self.config.idle_timeout <= now {
+            self.error = Some(Error::TimedOut);
+            self.finish();
+            return;
+        }
+        if let State::Closing { deadline } = self.state {
+            if deadline <= now {
+                self.finish();
+                return;
+            }
+        }
+
+        if let Some(encoder) = self.fec_encoder.as_mut() {
+            self.repair = self.repair.take().or(encoder.handle_timeout(now));
+        }
+
+        if self.sweep_at <= now {
+            let idle = self.config.stream_idle_timeout;
+            self.sender.reclaim_idle(now, idle);
+            self.receiver.expire(now, idle);
+            self.sweep_at = now + idle / 2;
+        }
+
+        let (lost, probe) = self.recovery.on_timeout(now);
+        self.on_lost(lost);
+        self.probe |= probe;
+        if self.recovery.take_collapsed() {
+            self.events
+                .push_back(ConnectionEvent::CongestionWindowCollapsed);
+        }
+    }
+
+    /// Returns the next packet to send to the remote endpoint, if any.
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.state {
+            State::Closed => return None,
+            State::Connecting {
+                attempt_at,
+                backoff,
+            } => {
+                if attempt_at > now {
+                    return None;
+                }
+                self.state = State::Connecting {
+                    attempt_at: now + backoff,
+                    backoff: backoff * 2,
+                };
+                let packet = Packet::Connect(self.hello());
+                return Some(self.sent_bytes(packet.encode(None)));
+            }
+            State::Rejecting(reject) => {
+                self.finish();
+                let packet = Packet::Reject(reject);
+                return Some(self.sent_bytes(packet.encode(None)));
+            }
+            State::Lingering(ref reason)
+                if self.sender.is_empty() && !self.recovery.in_flight() =>
+            {
+                let reason = reason.clone();
+                self.begin_closing(now, reason);
+            }
+            _ => {}
+        }
+
+        if self.accept {
+            self.accept = false;
+            let packet = Packet::Accept(self.hello());
+            return Some(self.sent_bytes(packet.encode(None)));
+        }
+
+        // Repairs go out as soon as their groups close, in packets of their
+        // own.
+        if self.is_connected() && self.recovery.can_send() {
+            if let Some(repair) = self.repair.take() {
+                let frames = vec![Frame::Repair(repair)];
+                return Some(self.send_data(now, frames, vec![]));
+            }
+        }
+
+        let mut frames = vec![];
+        let mut contents = vec![];
+        let mut size = DATA_OVERHEAD;
+        let draining = matches!(self.state, State::Draining);
+
+        let mut acked = false;
+        if self.acks.due(now) || draining {
+            if let Some(ack) = self.acks.frame(now) {
+                let frame = Frame::Ack(ack);
+                size += frame.size();
+                frames.push(frame);
+                acked = true;
+            }
+        }
+
+        if let Some(frame) = self.close_frame.take() {
+            size += frame.size();
+            if let Frame::Close(_) = frame {
+                contents.push(Sent::Control(frame.clone()));
+            }
+            frames.push(frame);
+        }
+
+        if self.is_connected() {
+            while let Some(frame) = self.receiver.poll_update() {
+                if size + frame.size() > self.packet_size {
+                    self.receiver.lost(&frame);
+                    break;
+                }
+                size += frame.size();
+                contents.push(Sent::Control(frame.clone()));
+                frames.push(frame);
+            }
+            while let Some(frame) =
+                self.sender.next_control(self.packet_size - size)
+            {
+                size += frame.size();
+                contents.push(Sent::Control(frame.clone()));
+                frames.push(frame);
+            }
+
+            // Datagrams wait to fill a packet, unless one is being sent
+            // anyway.
+            if self.coalesce_until.is_some_and(|until| until <= now) {
+                self.due = true;
+                self.coalesce_until = None;
+            }
+            let fresh = self.due
+                || !frames.is_empty()
+                || matches!(self.state, State::Lingering(_))
+                || self.sender.unsent_bytes() + size >= self.packet_size;
+            if self.recovery.can_send() {
+                while let Some(outgoing) =
+                    self.sender.next(self.packet_size - size, fresh)
+                {
+                    size += outgoing.frame.size();
+                    let frame = Frame::Datagram(outgoing.frame.clone());
+                    if outgoing.reliable {
+                        contents.push(Sent::Datagram(outgoing));
+                    }
+                    frames.push(frame);
+                }
+            }
+            if self.sender.queued() == 0 {
+                self.due = false;
+                self.coalesce_until = None;
+            }
+        }
+
+        let keep_alive = match (&self.state, self.config.keep_alive) {
+            (State::Open, Some(keep_alive)) => {
+                self.last_ack_eliciting + keep_alive <= now
+            }
+            _ => false,
+        };
+        let ack_eliciting = frames.iter().any(Frame::ack_eliciting);
+        if (self.probe || keep_alive) && !ack_eliciting && !draining {
+            size += Frame::Ping.size();
+            frames.push(Frame::Ping);
+        }
+        self.probe = false;
+
+        if frames.is_empty() {
+            return None;
+        }
+        // Anything received is acknowledged by any packet sent.
+        if !acked {
+            if let Some(ack) = self.acks.frame(now) {
+                let frame = Frame::Ack(ack);
+                if size + frame.size() <= self.packet_size {
+                    size += frame.size();
+                    frames.insert(0, frame);
+                }
+            }
+        }
+
+        let bytes = self.send_data(now, frames, contents);
+        debug_assert!(bytes.len() <= size);
+        if draining {
+            self.finish();
+        }
+        Some(bytes)
+    }
+
+    /// Numbers and encodes a data packet, and records it as sent.
+    fn send_data(
+        &mut self,
+        now: Instant,
+        frames: Vec<Frame>,
+        contents: Vec<Sent>,
+    ) -> Vec<u8> {
+        let number = self.recovery.next_number();
+        let ack_eliciting = frames.iter().any(Frame::ack_eliciting);
+        let protected = frames.iter().any(|frame| match frame {
+            Frame::Datagram(datagram) => {
+                !self.unprotected.contains(&datagram.stream)
+            }
+            _ => false,
+        });
+        let bytes = Packet::Data { number, frames }
+            .encode(self.recovery.largest_acked());
+        if ack_eliciting {
+            self.last_ack_eliciting = now;
+        }
+        self.recovery.on_sent(
+            number,
+            SentPacket {
+                time: now,
+                size: bytes.len(),
+                ack_eliciting,
+                contents,
+            },
+        );
+
+        if let Some(encoder) = self.fec_encoder.as_mut() {
+            let repair = encoder.on_sent(
+                now,
+                self.recovery.rtt().smoothed(),
+                self.recovery.raw_loss(),
+                number,
+                frame::payload(&bytes),
+                protected,
+            );
+            self.repair = self.repair.take().or(repair);
+        }
+        self.sent_bytes(bytes)
+    }
+
+    fn hello(&self) -> Hello {
+        Hello {
+            version: self.version,
+            capabilities: self.config.capabilities,
+            limits: self.config.receive_limits,
+            dictionary: compress::dictionary_id(self.config.dictionary()),
+        }
+    }
+
+    fn sent_bytes(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        self.packets_sent += 1;
+        self.bytes_sent += bytes.len() as u64;
+        bytes
+    }
+}
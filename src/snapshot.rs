@@ -0,0 +1,386 @@
+//! Replication of world snapshots.
+//!
+//! A game server sends each client the state of its world every tick, and
+//! most of a snapshot is the same as the one before it. A `SnapshotSender`
+//! encodes each snapshot as a delta against its baseline, the newest snapshot
+//! the client acknowledged, and the client's `SnapshotReceiver` rebuilds it
+//! from the snapshots it has. Snapshots are sent on an `UnreliableSequenced`
+//! stream, so a lost one is never sent again: the next is encoded against the
+//! same baseline, until the client acknowledges a newer one. Clients
+//! acknowledge the newest snapshot they rebuilt on an `UnreliableSequenced`
+//! stream of their own, since only the newest acknowledgement matters.
+//! Senders which learn of deliveries another way, such as from responses,
+//! acknowledge ticks directly.
+//!
+//! A delta is the snapshot XORed with its baseline, with the runs of zero
+//! bytes, which did not change, skipped. Snapshots without a baseline, or
+//! whose baseline is older than the sender keeps, are sent whole.
+//!
+//! Clients render the world a little in the past, between two snapshots they
+//! hold, so that a late or lost snapshot does not stall it. An
+//! `InterpolationBuffer` holds snapshots until they play out, and returns the
+//! pair to blend between at an instant, a configurable delay behind the
+//! server.
+
+use crate::{
+    codec::{put_varint, Reader},
+    error::DecodeError,
+    serial,
+};
+use nhanh::Bytes;
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+/// The most snapshots either end keeps as baselines.
+const HISTORY: usize = 64;
+
+/// The longest snapshot a receiver rebuilds, so that a corrupt length does
+/// not allocate without bound.
+const MAX_LEN: usize = 1 << 24;
+
+/// Unchanged runs shorter than this are carried in the literal around them,
+/// which costs less than skipping them.
+const MIN_SKIP: usize = 3;
+
+/// Encodes snapshots of a world for one client.
+#[derive(Debug, Default)]
+pub struct SnapshotSender {
+    /// Snapshots sent since the baseline, oldest first.
+    history: VecDeque<(u32, Bytes)>,
+    baseline: Option<u32>,
+}
+
+impl SnapshotSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The newest tick the client acknowledged, which snapshots are encoded
+    /// against.
+    pub fn baseline(&self) -> Option<u32> {
+        self.baseline
+    }
+
+    /// Encodes the snapshot of `tick`, which must follow the tick of the last
+    /// snapshot encoded, against the baseline.
+    pub fn encode(&mut self, tick: u32, snapshot: Bytes) -> Bytes {
+        let baseline = self.baseline.and_then(|baseline| {
+            self.history
+                .iter()
+                .find(|(tick, _)| *tick == baseline)
+                .map(|(tick, snapshot)| (*tick, &snapshot[..]))
+        });
+        let mut message = vec![];
+        put_varint(&mut message, u64::from(tick));
+        match baseline {
+            Some((baseline, base)) => {
+                put_varint(
+                    &mut message,
+                    u64::from(tick.wrapping_sub(baseline)),
+                );
+                put_delta(&mut message, base, &snapshot);
+            }
+            None => {
+                put_varint(&mut message, 0);
+                put_delta(&mut message, &[], &snapshot);
+            }
+        }
+
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((tick, snapshot));
+        message.into()
+    }
+
+    /// Handles an acknowledgement from the client's `SnapshotReceiver::ack`.
+    pub fn handle_ack(&mut self, ack: &[u8]) -> Result<(), DecodeError> {
+        let tick = Reader::new(ack).varint_into()?;
+        self.acknowledge(tick);
+        Ok(())
+    }
+
+    /// Records that the client has the snapshot of `tick`. Acknowledgements
+    /// older than the baseline, or of snapshots no longer kept, are ignored.
+    pub fn acknowledge(&mut self, tick: u32) {
+        if self
+            .baseline
+            .is_some_and(|baseline| serial::le(tick, baseline))
+            || !self.history.iter().any(|(kept, _)| *kept == tick)
+        {
+            return;
+        }
+        self.baseline = Some(tick);
+        while self.history.front().is_some_and(|(kept, _)| *kept != tick) {
+            self.history.pop_front();
+        }
+    }
+}
+
+/// Rebuilds a world's snapshots from a `SnapshotSender`'s messages.
+#[derive(Debug, Default)]
+pub struct SnapshotReceiver {
+    /// Snapshots the sender may encode against, oldest first.
+    history: VecDeque<(u32, Bytes)>,
+}
+
+impl SnapshotReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuilds a snapshot from a message, and returns its tick and bytes.
+    pub fn receive(
+        &mut self,
+        message: &[u8],
+    ) -> Result<(u32, Bytes), DecodeError> {
+        let mut reader = Reader::new(message);
+        let tick: u32 = reader.varint_into()?;
+        let distance: u32 = reader.varint_into()?;
+        let snapshot: Bytes = match distance {
+            0 => delta(&[], &mut reader)?,
+            distance => {
+                let baseline = tick.wrapping_sub(distance);
+                let base = self
+                    .history
+                    .iter()
+                    .find(|(kept, _)| *kept == baseline)
+                    .map(|(_, base)| base)
+                    .ok_or(DecodeError::Invalid("snapshot baseline"))?;
+                let snapshot = delta(base, &mut reader)?;
+                // The sender never encodes against older snapshots again.
+                while self
+                    .history
+                    .front()
+                    .is_some_and(|(kept, _)| serial::lt(*kept, baseline))
+                {
+                    self.history.pop_front();
+                }
+                snapshot
+            }
+        }
+        .into();
+
+        let newest = self.history.back().map(|(newest, _)| *newest);
+        if newest.is_none_or(|newest| serial::lt(newest, tick)) {
+            if self.history.len() == HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back((tick, snapshot.clone()));
+        }
+        Ok((tick, snapshot))
+    }
+
+    /// Returns the acknowledgement of the newest snapshot received, to send
+    /// to the sender.
+    pub fn ack(&self) -> Option<Bytes> {
+        let (tick, _) = self.history.back()?;
+        let mut ack = vec![];
+        put_varint(&mut ack, u64::from(*tick));
+        Some(ack.into())
+    }
+}
+
+/// Appends the delta from `base` to `snapshot`: its length, then runs of
+/// unchanged bytes to skip, each followed by a literal of changed ones XORed
+/// with the base.
+fn put_delta(buf: &mut Vec<u8>, base: &[u8], snapshot: &[u8]) {
+    let changed = |i: usize| snapshot[i] != base.get(i).copied().unwrap_or(0);
+    put_varint(buf, snapshot.len() as u64);
+    let mut i = 0;
+    while i < snapshot.len() {
+        let start = i;
+        while i < snapshot.len() && !changed(i) {
+            i += 1;
+        }
+        if i == snapshot.len() {
+            break;
+        }
+        let skip = i - start;
+
+        let literal = i;
+        let mut unchanged = 0;
+        while i < snapshot.len() && unchanged < MIN_SKIP {
+            unchanged = if changed(i) { 0 } else { unchanged + 1 };
+            i += 1;
+        }
+        let end = i - unchanged;
+        i = end;
+
+        put_varint(buf, skip as u64);
+        put_varint(buf, (end - literal) as u64);
+        buf.extend(
+            (literal..end)
+                .map(|i| snapshot[i] ^ base.get(i).copied().unwrap_or(0)),
+        );
+    }
+}
+
+/// Reads a delta, and returns the snapshot it rebuilds from `base`.
+fn delta(base: &[u8], reader: &mut Reader) -> Result<Vec<u8>, DecodeError> {
+    let len: usize = reader.varint_into()?;
+    if len > MAX_LEN {
+        return Err(DecodeError::Invalid("snapshot length"));
+    }
+    let mut snapshot: Vec<u8> = (0..len)
+        .map(|i| base.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut i: usize = 0;
+    while !reader.is_empty() {
+        let skip: usize = reader.varint_into()?;
+        let literal_len: usize = reader.varint_into()?;
+        i = i
+            .checked_add(skip)
+            .ok_or(DecodeError::Invalid("snapshot delta"))?;
+        let literal = reader.bytes(literal_len)?;
+        let end = i
+            .checked_add(literal_len)
+            .filter(|end| *end <= len)
+            .ok_or(DecodeError::Invalid("snapshot delta"))?;
+        for (byte, change) in snapshot[i..end].iter_mut().zip(literal) {
+            *byte ^= change;
+        }
+        i = end;
+    }
+    Ok(snapshot)
+}
+
+/// How an `InterpolationBuffer` plays out snapshots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterpolationConfig {
+    /// The time between the server's ticks.
+    pub tick_interval: Duration,
+    /// How far behind the server snapshots are played out. A longer delay
+    /// rides out more jitter and loss, and shows the world later.
+    pub playout_delay: Duration,
+    /// The most snapshots held. The oldest are dropped beyond this.
+    pub capacity: usize,
+    #[doc(hidden)]
+    pub ___non_exhaustive: PhantomData<()>,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        Self {
+            tick_interval: Duration::from_secs(1) / 60,
+            playout_delay: Duration::from_millis(100),
+            capacity: 64,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// The snapshots to blend between to render an instant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interpolation<'a, T> {
+    pub from: &'a T,
+    pub to: &'a T,
+    /// How far from `from` to `to` the instant is, from 0 to 1.
+    pub alpha: f32,
+}
+
+/// Holds a client's snapshots until they play out.
+///
+/// The buffer maps ticks to instants by the earliest any snapshot arrived
+/// relative to its tick, which is the one delayed least on its way.
+#[derive(Debug)]
+pub struct InterpolationBuffer<T> {
+    config: InterpolationConfig,
+    /// Snapshots by tick, oldest first.
+    snapshots: VecDeque<(u32, T)>,
+    /// A tick, and the instant it is taken to have been sent.
+    anchor: Option<(u32, Instant)>,
+}
+
+impl<T> InterpolationBuffer<T> {
+    pub fn new(config: InterpolationConfig) -> Self {
+        Self {
+            config,
+            snapshots: VecDeque::new(),
+            anchor: None,
+        }
+    }
+
+    /// The snapshots held, oldest first.
+    pub fn snapshots(&self) -> impl Iterator<Item = (u32, &T)> + '_ {
+        self.snapshots
+            .iter()
+            .map(|(tick, snapshot)| (*tick, snapshot))
+    }
+
+    /// Returns the instant `tick` is taken to have been sent.
+    fn sent_at(&self, tick: u32) -> Option<Instant> {
+        let (anchor, at) = self.anchor?;
+        let ticks = tick.wrapping_sub(anchor) as i32;
+        let offset = self.config.tick_interval * ticks.unsigned_abs();
+        match ticks >= 0 {
+            true => at.checked_add(offset),
+            false => at.checked_sub(offset),
+        }
+    }
+
+    /// Adds the snapshot of `tick`, which arrived at `now`. Snapshots older
+    /// than those already played out are dropped.
+    pub fn push(&mut self, now: Instant, tick: u32, snapshot: T) {
+        if self.sent_at(tick).is_none_or(|sent_at| now < sent_at) {
+            self.anchor = Some((tick, now));
+        }
+
+        let position = self
+            .snapshots
+            .iter()
+            .position(|(held, _)| serial::le(tick, *held))
+            .unwrap_or(self.snapshots.len());
+        match self.snapshots.get(position) {
+            Some((held, _)) if *held == tick => return,
+            _ if position == 0 && self.played_out(tick) => return,
+            _ => self.snapshots.insert(position, (tick, snapshot)),
+        }
+        if self.snapshots.len() > self.config.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Returns whether a snapshot older than every one held was already
+    /// passed over.
+    fn played_out(&self, tick: u32) -> bool {
+        self.snapshots
+            .front()
+            .is_some_and(|(oldest, _)| serial::lt(tick, *oldest))
+    }
+
+    /// Returns the snapshots to blend between to render `now`, and forgets
+    /// those before them. Until a snapshot older than the playout point
+    /// arrives, this returns `None`. Past the newest snapshot, it holds it.
+    pub fn sample(&mut self, now: Instant) -> Option<Interpolation<'_, T>> {
+        let render = now.checked_sub(self.config.playout_delay)?;
+        let from = self.snapshots.iter().rposition(|(tick, _)| {
+            self.sent_at(*tick).is_some_and(|sent_at| sent_at <= render)
+        })?;
+        self.snapshots.drain(..from);
+
+        let (from_tick, from) = &self.snapshots[0];
+        let (to_tick, to) = match self.snapshots.get(1) {
+            Some((to_tick, to)) => (*to_tick, to),
+            None => {
+                return Some(Interpolation {
+                    from,
+                    to: from,
+                    alpha: 1.0,
+                })
+            }
+        };
+        let from_at = self.sent_at(*from_tick)?;
+        let to_at = self.sent_at(to_tick)?;
+        let alpha = render.duration_since(from_at).as_secs_f32()
+            / to_at.duration_since(from_at).as_secs_f32();
+        Some(Interpolation {
+            from,
+            to,
+            alpha: alpha.min(1.0),
+        })
+    }
+}
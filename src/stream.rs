@@ -0,0 +1,47 @@
+//! The streams of a connection.
+
+use nhanh::{DeliveryMode, StreamId};
+
+/// A stream on a connection.
+///
+/// Each kind of stream has its own id space. Reliable and unreliable datagrams
+/// share the sequenced and unordered streams.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum StreamKey {
+    Ordered(StreamId),
+    Sequenced(StreamId),
+    Unordered,
+}
+
+impl From<DeliveryMode> for StreamKey {
+    fn from(delivery_mode: DeliveryMode) -> Self {
+        match delivery_mode {
+            DeliveryMode::ReliableOrdered(id) => StreamKey::Ordered(id),
+            DeliveryMode::ReliableSequenced(id)
+            | DeliveryMode::UnreliableSequenced(id) => StreamKey::Sequenced(id),
+            DeliveryMode::ReliableUnordered
+            | DeliveryMode::UnreliableUnordered => StreamKey::Unordered,
+        }
+    }
+}
+
+impl StreamKey {
+    pub fn stream_id(self) -> Option<StreamId> {
+        match self {
+            StreamKey::Ordered(id) | StreamKey::Sequenced(id) => Some(id),
+            StreamKey::Unordered => None,
+        }
+    }
+}
+
+/// Returns whether datagrams sent in `delivery_mode` are retransmitted until
+/// acknowledged.
+pub fn reliable(delivery_mode: DeliveryMode) -> bool {
+    match delivery_mode {
+        DeliveryMode::ReliableOrdered(_)
+        | DeliveryMode::ReliableSequenced(_)
+        | DeliveryMode::ReliableUnordered => true,
+        DeliveryMode::UnreliableSequenced(_)
+        | DeliveryMode::UnreliableUnordered => false,
+    }
+}
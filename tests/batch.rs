@@ -0,0 +1,90 @@
+use miknet::batch::{self, RecvMeta, Transmit, BATCH_SIZE, RECV_BUFFER_BYTES};
+use socket2::SockRef;
+use std::{
+    io::IoSliceMut,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+fn bind() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").expect("bind");
+    batch::configure(SockRef::from(&socket));
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("timeout");
+    socket
+}
+
+/// Receives until `count` packets arrive.
+fn recv(socket: &UdpSocket, count: usize) -> Vec<(SocketAddr, Vec<u8>)> {
+    let mut buffer = vec![0; BATCH_SIZE * RECV_BUFFER_BYTES];
+    let mut packets = vec![];
+    while packets.len() < count {
+        let mut meta = [RecvMeta::default(); BATCH_SIZE];
+        let mut buffers: Vec<IoSliceMut> = buffer
+            .chunks_mut(RECV_BUFFER_BYTES)
+            .map(IoSliceMut::new)
+            .collect();
+        let received =
+            batch::recv(SockRef::from(socket), &mut buffers, &mut meta)
+                .expect("receive");
+        drop(buffers);
+        for (meta, buffer) in meta[..received]
+            .iter()
+            .zip(buffer.chunks(RECV_BUFFER_BYTES))
+        {
+            packets.extend(
+                meta.packets(buffer)
+                    .map(|packet| (meta.source, packet.to_vec())),
+            );
+        }
+    }
+    packets
+}
+
+#[test]
+fn batches_arrive_whole_and_in_order() {
+    let sender = bind();
+    let sender_addr = sender.local_addr().expect("address");
+    let receivers = [bind(), bind()];
+
+    // Runs of equal packets to one destination may be segmented, and each
+    // run ends with a shorter packet or a new destination.
+    let mut packets = vec![];
+    for i in 0..100u8 {
+        let receiver = usize::from(i / 40 % 2);
+        let len = if i % 10 == 9 { 300 } else { 1200 };
+        packets.push((receiver, vec![i; len]));
+    }
+    let destinations: Vec<SocketAddr> = receivers
+        .iter()
+        .map(|receiver| receiver.local_addr().expect("address"))
+        .collect();
+    let transmits: Vec<Transmit> = packets
+        .iter()
+        .map(|(receiver, contents)| Transmit {
+            destination: destinations[*receiver],
+            contents,
+        })
+        .collect();
+
+    let syscalls = batch::syscalls();
+    let mut sent = 0;
+    while sent < transmits.len() {
+        sent += batch::send(SockRef::from(&sender), &transmits[sent..])
+            .expect("send");
+    }
+    assert_eq!(sent, packets.len());
+
+    for (i, receiver) in receivers.iter().enumerate() {
+        let expected: Vec<_> = packets
+            .iter()
+            .filter(|(to, _)| *to == i)
+            .map(|(_, contents)| (sender_addr, contents.clone()))
+            .collect();
+        assert_eq!(recv(receiver, expected.len()), expected);
+    }
+    if cfg!(target_os = "linux") {
+        assert!(batch::syscalls() - syscalls < packets.len() as u64 / 4);
+    }
+}
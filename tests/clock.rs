@@ -0,0 +1,106 @@
+use miknet::{ClockConfig, ClockServer, ClockSync, DecodeError, ServerTime};
+use std::time::{Duration, Instant};
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+/// Asserts that an estimate bounds the server's time, no looser than
+/// `error`.
+fn assert_bounds(estimate: ServerTime, time: Duration, error: Duration) {
+    let low = estimate.time.saturating_sub(estimate.error);
+    let high = estimate.time + estimate.error;
+    assert!(low <= time && time <= high, "{:?} for {:?}", estimate, time);
+    assert!(estimate.error <= error, "{:?}", estimate);
+}
+
+#[test]
+fn exchanges_estimate_the_server_clock() {
+    let start = Instant::now();
+    // The server started a minute before the client.
+    let server = ClockServer::new(start);
+    let client_start = start + Duration::from_secs(60);
+    let mut client = ClockSync::new(client_start, ClockConfig::default());
+    assert_eq!(client.server_time_estimate(client_start), None);
+
+    let request = client.poll_request(client_start).expect("request");
+    assert_eq!(client.poll_request(client_start + ms(500)), None);
+    let response = server
+        .respond(client_start + ms(10), client_start + ms(12), &request)
+        .expect("respond");
+    client
+        .handle_response(client_start + ms(22), &response)
+        .expect("handle response");
+
+    assert_eq!(client.round_trip(), Some(ms(20)));
+    let now = client_start + ms(30);
+    let estimate = client.server_time_estimate(now).expect("estimate");
+    assert_bounds(estimate, server.time(now), ms(10) + ms(1));
+    assert_eq!(estimate.time, server.time(now));
+
+    assert!(client.poll_request(client_start + ms(1000)).is_some());
+}
+
+#[test]
+fn exchanges_delayed_in_queues_are_filtered() {
+    let start = Instant::now();
+    let server = ClockServer::new(start);
+    let mut client = ClockSync::new(start, ClockConfig::default());
+
+    for (second, up, down) in [(0, 10, 10), (1, 150, 10), (2, 12, 8)] {
+        let sent = start + Duration::from_secs(second);
+        let server_time = server.time(sent + ms(up));
+        client.sample(sent, server_time, server_time, sent + ms(up + down));
+    }
+    // The second exchange waited 140ms on the way up, which would put the
+    // server's clock 70ms ahead.
+    let now = start + Duration::from_secs(3);
+    let estimate = client.server_time_estimate(now).expect("estimate");
+    assert_bounds(estimate, server.time(now), ms(10));
+    assert_eq!(client.round_trip(), Some(ms(20)));
+}
+
+#[test]
+fn estimates_follow_drifting_clocks() {
+    let start = Instant::now();
+    let config = ClockConfig {
+        samples: 4,
+        ..ClockConfig::default()
+    };
+    let mut client = ClockSync::new(start, config);
+    // The server's clock runs a thousandth fast.
+    let server_time = |at: Duration| at + at / 1000;
+
+    for second in 0..20 {
+        let sent = Duration::from_secs(second);
+        let time = server_time(sent + ms(5));
+        client.sample(start + sent, time, time, start + sent + ms(10));
+    }
+    let now = Duration::from_secs(20);
+    let estimate = client.server_time_estimate(start + now).expect("estimate");
+    assert_bounds(estimate, server_time(now), ms(10));
+}
+
+#[test]
+fn hostile_responses_are_refused() {
+    let start = Instant::now();
+    let mut client = ClockSync::new(start, ClockConfig::default());
+    let now = start + ms(10);
+
+    // Sent at 0, received by the server at 2^63 microseconds, and answered
+    // at 1.
+    let mut response = vec![0x00];
+    response.extend([0x80; 9]);
+    response.extend([0x01, 0x01]);
+    assert_eq!(
+        client.handle_response(now, &response),
+        Err(DecodeError::Invalid("varint"))
+    );
+    assert_eq!(client.server_time_estimate(now), None);
+
+    // Exchanges made some other way saturate instead.
+    client.sample(start, Duration::MAX, Duration::ZERO, now);
+    client.sample(start, Duration::ZERO, Duration::MAX, now);
+    assert!(client.server_time_estimate(now).is_some());
+    assert!(client.round_trip().is_some());
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::{connect, deliver, ordered};
+use miknet::{
+    frame::{Frame, Packet},
+    Config, Session,
+};
+use nhanh::Bytes;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+fn frames(packet: &[u8]) -> Vec<Frame> {
+    match Packet::decode(&Bytes::copy_from_slice(packet), None).expect("decode")
+    {
+        Packet::Data { frames, .. } => frames,
+        packet => panic!("sent {:?}", packet),
+    }
+}
+
+fn datagrams(frames: &[Frame]) -> usize {
+    frames
+        .iter()
+        .filter(|frame| matches!(frame, Frame::Datagram(_)))
+        .count()
+}
+
+fn transmit_all(session: &mut Session, now: Instant) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| session.poll_transmit(now)).collect()
+}
+
+#[test]
+fn datagrams_of_many_streams_share_packets_with_acks() {
+    let now = Instant::now();
+    let config = Config {
+        mtu: 1000,
+        ..Config::default()
+    };
+    let (mut client, mut server) = connect(now, config, config);
+    server.send(now, ordered(0, vec![1; 10])).expect("send");
+    deliver(&mut server, &mut client, now);
+
+    for stream in 0..4 {
+        client
+            .send(now, ordered(stream, vec![2; 300]))
+            .expect("send");
+    }
+    let packets = transmit_all(&mut client, now);
+    assert_eq!(packets.len(), 2);
+    assert!(packets.iter().all(|packet| packet.len() <= 1000));
+
+    // The first packet is filled, and carries the acknowledgement.
+    let first = frames(&packets[0]);
+    assert!(matches!(first[0], Frame::Ack(_)), "{:?}", first);
+    assert_eq!(datagrams(&first), 3);
+    let streams: HashSet<_> = first
+        .iter()
+        .filter_map(|frame| match frame {
+            Frame::Datagram(datagram) => Some(datagram.stream),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(streams.len(), 3);
+    assert_eq!(datagrams(&frames(&packets[1])), 1);
+}
+
+#[test]
+fn datagrams_below_the_mtu_wait_out_the_coalesce_delay() {
+    let now = Instant::now();
+    let delay = Duration::from_millis(5);
+    let config = Config {
+        max_coalesce_delay: delay,
+        ..Config::default()
+    };
+    let (mut client, mut server) = connect(now, config, config);
+    deliver(&mut client, &mut server, now);
+
+    client.send(now, ordered(0, vec![1; 10])).expect("send");
+    client.send(now, ordered(1, vec![2; 10])).expect("send");
+    assert_eq!(client.poll_transmit(now), None);
+    assert!(client.poll_timeout().is_some_and(|t| t <= now + delay));
+    let later = now + Duration::from_millis(4);
+    assert_eq!(client.poll_transmit(later), None);
+
+    client.handle_timeout(now + delay);
+    let packets = transmit_all(&mut client, now + delay);
+    assert_eq!(packets.len(), 1);
+    assert_eq!(datagrams(&frames(&packets[0])), 2);
+
+    // Datagrams which fill a packet do not wait.
+    let now = now + delay;
+    client.send(now, ordered(0, vec![3; 2000])).expect("send");
+    let packets = transmit_all(&mut client, now);
+    assert!(!packets.is_empty());
+    assert!(packets.iter().all(|packet| packet.len() <= config.mtu));
+}
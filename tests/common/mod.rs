@@ -0,0 +1,66 @@
+//! Fixtures the integration tests share.
+
+#![allow(dead_code)]
+
+use miknet::{Config, Session};
+use nhanh::{
+    Bytes, Datagram, DeliveryMode, SendCmd, StreamId, StreamIndex,
+    StreamPosition,
+};
+use std::time::Instant;
+
+/// Opens a connection between a client and a server session.
+pub fn connect(
+    now: Instant,
+    client: Config,
+    server: Config,
+) -> (Session, Session) {
+    let mut client = Session::connect(now, client);
+    let connect = client.poll_transmit(now).expect("connect");
+    let mut server = Session::accept(now, server, &connect).expect("accept");
+    let accept = server.poll_transmit(now).expect("accept");
+    client.handle(now, accept.into());
+    assert!(client.is_connected());
+    (client, server)
+}
+
+pub fn ordered(stream: u32, data: impl Into<Bytes>) -> SendCmd {
+    SendCmd {
+        delivery_mode: DeliveryMode::ReliableOrdered(StreamId(stream)),
+        data: data.into(),
+        ..SendCmd::default()
+    }
+}
+
+/// Sends a datagram, and returns the packet which carries it.
+pub fn send(session: &mut Session, now: Instant, send_cmd: SendCmd) -> Vec<u8> {
+    session.send(now, send_cmd).expect("send");
+    session.poll_transmit(now).expect("packet")
+}
+
+/// Hands every packet one session has to send to the other.
+pub fn deliver(from: &mut Session, to: &mut Session, now: Instant) {
+    while let Some(packet) = from.poll_transmit(now) {
+        to.handle(now, packet.into());
+    }
+}
+
+/// Returns the stream, index and first byte of the datagrams a session
+/// surfaces.
+pub fn surfaced(session: &mut Session) -> Vec<(u32, u32, u8)> {
+    std::iter::from_fn(|| session.poll_datagram())
+        .map(|datagram| match datagram {
+            Datagram {
+                stream_position:
+                    Some(StreamPosition {
+                        stream_id,
+                        index:
+                            StreamIndex::Ordinal(index)
+                            | StreamIndex::Sequence(index),
+                    }),
+                data,
+            } => (stream_id.0, index, data[0]),
+            datagram => panic!("surfaced {:?}", datagram),
+        })
+        .collect()
+}
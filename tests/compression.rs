@@ -0,0 +1,148 @@
+mod common;
+
+use common::{connect, ordered};
+use miknet::{
+    frame::{DatagramFrame, Frame, Packet},
+    stream::StreamKey,
+    CompressionConfig, Config, Error, Features, Session, Violation,
+};
+use nhanh::{Bytes, DeliveryMode, SendCmd, StreamId};
+use std::time::Instant;
+
+const DICTIONARY: &[u8] = b"{\"entity\":0,\"position\":[0.0,0.0,0.0],\
+\"velocity\":[0.0,0.0,0.0],\"health\":100}";
+
+fn config(dictionary: &'static [u8]) -> Config {
+    Config {
+        compression: Some(CompressionConfig {
+            dictionary,
+            min_len: 16,
+            ..CompressionConfig::default()
+        }),
+        ..Config::default()
+    }
+}
+
+/// Sends a datagram, and returns the bytes of the packets which carried it.
+fn transmit(
+    client: &mut Session,
+    server: &mut Session,
+    now: Instant,
+    send_cmd: SendCmd,
+) -> usize {
+    client.send(now, send_cmd).expect("send");
+    client.flush();
+    let mut sent = 0;
+    while let Some(packet) = client.poll_transmit(now) {
+        sent += packet.len();
+        server.handle(now, packet.into());
+    }
+    sent
+}
+
+fn state(entity: u8) -> Vec<u8> {
+    format!(
+        "{{\"entity\":{},\"position\":[1.5,0.0,2.25],\
+         \"velocity\":[0.0,0.0,0.0],\"health\":100}}",
+        entity
+    )
+    .into_bytes()
+}
+
+/// Bytes which only shrink by repeating themselves.
+fn repetitive(len: usize) -> Vec<u8> {
+    let mut seed = 0x2545_f491u32;
+    let block: Vec<u8> = (0..3000)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        })
+        .collect();
+    block.iter().copied().cycle().take(len).collect()
+}
+
+#[test]
+fn datagrams_shrink_with_a_shared_dictionary() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, config(DICTIONARY), config(DICTIONARY));
+    let agreed = client.capabilities().expect("capabilities");
+    assert!(agreed.features.contains(Features::COMPRESSION));
+
+    let compressed =
+        transmit(&mut client, &mut server, now, ordered(0, state(7)));
+    client.set_compression(DeliveryMode::ReliableOrdered(StreamId(1)), false);
+    let uncompressed =
+        transmit(&mut client, &mut server, now, ordered(1, state(7)));
+    assert!(compressed + 20 < uncompressed);
+
+    let surfaced = server.poll_datagram().expect("datagram");
+    assert_eq!(surfaced.data, state(7));
+    let surfaced = server.poll_datagram().expect("datagram");
+    assert_eq!(surfaced.data, state(7));
+}
+
+#[test]
+fn datagrams_are_compressed_before_fragmentation() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, config(DICTIONARY), config(DICTIONARY));
+
+    let data = repetitive(30_000);
+    let sent =
+        transmit(&mut client, &mut server, now, ordered(0, data.clone()));
+    assert!(sent < 5000);
+    let surfaced = server.poll_datagram().expect("datagram");
+    assert_eq!(surfaced.data, data);
+}
+
+#[test]
+fn endpoints_with_different_dictionaries_do_not_compress() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, config(DICTIONARY), config(b"another dictionary"));
+    let agreed = client.capabilities().expect("capabilities");
+    assert!(!agreed.features.contains(Features::COMPRESSION));
+    assert_eq!(server.capabilities(), Some(agreed));
+
+    let data = repetitive(2000);
+    let sent =
+        transmit(&mut client, &mut server, now, ordered(0, data.clone()));
+    assert!(sent > 2000);
+    let surfaced = server.poll_datagram().expect("datagram");
+    assert_eq!(surfaced.data, data);
+}
+
+#[test]
+fn malformed_compression_aborts_when_the_datagram_surfaces() {
+    let now = Instant::now();
+    let (_, mut server) = connect(now, config(DICTIONARY), config(DICTIONARY));
+
+    // The datagram claims to decompress to 1000 bytes. It is buffered as it
+    // arrived, and found malformed only once it surfaces.
+    let stream = StreamKey::Ordered(StreamId(0));
+    let frame = Frame::Datagram(DatagramFrame {
+        stream,
+        index: Some(0),
+        fragment: None,
+        compressed: true,
+        data: Bytes::from_static(&[0xe8, 0x07, 0xff, 0xff, 0xff]),
+    });
+    let packet = Packet::Data {
+        number: 0,
+        frames: vec![frame],
+    };
+    server.handle(now, packet.encode(None).into());
+    assert!(server.is_connected());
+
+    assert_eq!(server.poll_datagram(), None);
+    assert!(!server.is_connected());
+    match server.take_error() {
+        Some(Error::Violation(violation)) => {
+            assert_eq!(violation, Violation::MalformedCompression(stream))
+        }
+        error => panic!("error {:?}", error),
+    }
+}
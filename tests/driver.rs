@@ -0,0 +1,79 @@
+#![cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+
+use futures::prelude::*;
+use miknet::{Connection, DriverConfig, Runtime, Server};
+use nhanh::{
+    Bytes, Close, ConnectionEvent, ConnectionExt, DeliveryMode,
+    EventThresholds, SendCmd, ServerEvent, StreamId,
+};
+use std::net::UdpSocket;
+
+/// Connects to a server driven by tasks of `runtime`, which echoes a
+/// datagram back, and closes the connection.
+async fn echo_over<R: Runtime + Clone>(runtime: R) {
+    let socket = UdpSocket::bind("127.0.0.1:0").expect("bind");
+    let server_addr = socket.local_addr().expect("address");
+    let mut server =
+        Server::serve(runtime.clone(), DriverConfig::default(), vec![socket])
+            .expect("serve");
+    let mut server_events =
+        nhanh::Server::events(&mut server, EventThresholds::default());
+
+    let mut client =
+        Connection::connect(runtime, DriverConfig::default(), server_addr)
+            .await
+            .expect("connect");
+    let mut accepted = server.next().await.expect("client").expect("Ok");
+    match server_events.next().await {
+        Some(ServerEvent {
+            event: ConnectionEvent::Connected,
+            ..
+        }) => {}
+        event => panic!("unexpected {:?}", event),
+    }
+
+    client
+        .send(SendCmd {
+            delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
+            data: Bytes::from_static(b"hello"),
+            ..SendCmd::default()
+        })
+        .await
+        .expect("send");
+    let datagram = accepted.next().await.expect("datagram").expect("Ok");
+    accepted
+        .send(SendCmd {
+            delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
+            data: datagram.data,
+            ..SendCmd::default()
+        })
+        .await
+        .expect("echo");
+    let echo = client.next().await.expect("echo").expect("Ok");
+    assert_eq!(echo.data, &b"hello"[..]);
+
+    client.close_with(Close::default()).await.expect("close");
+    assert!(accepted.next().await.is_none());
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn connections_echo_over_tokio() {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("runtime")
+        .block_on(echo_over(miknet::runtime::TokioRuntime));
+}
+
+#[cfg(feature = "async-std")]
+#[test]
+fn connections_echo_over_async_std() {
+    async_std::task::block_on(echo_over(miknet::runtime::AsyncStdRuntime));
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn connections_echo_over_smol() {
+    smol::block_on(echo_over(miknet::runtime::SmolRuntime));
+}
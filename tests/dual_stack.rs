@@ -0,0 +1,167 @@
+use miknet::{runtime, Config, GroupId, Host, HostEvent};
+use nhanh::{Bytes, Close, DeliveryMode, SendCmd, StreamId};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+const TICK: Duration = Duration::from_millis(10);
+
+/// Connects a client to `server` at `address`, sends a datagram, and returns
+/// the address the server saw the client at.
+fn exchange(server: &mut Host, address: SocketAddr) -> SocketAddr {
+    let mut client = Host::client(Config::default()).expect("client");
+    client.connect(address);
+
+    let mut peer = None;
+    let started = Instant::now();
+    loop {
+        assert!(started.elapsed() < Duration::from_secs(5), "no datagram");
+        let now = Instant::now();
+        match client.service(now, TICK).expect("service") {
+            Some(HostEvent::Connect(server)) => {
+                assert_eq!(server, address);
+                client
+                    .send(
+                        server,
+                        SendCmd {
+                            delivery_mode: DeliveryMode::ReliableOrdered(
+                                StreamId(0),
+                            ),
+                            data: Bytes::from_static(b"hello"),
+                            ..SendCmd::default()
+                        },
+                    )
+                    .expect("send");
+            }
+            Some(event) => panic!("unexpected {:?}", event),
+            None => {}
+        }
+        match server.service(now, TICK).expect("service") {
+            Some(HostEvent::Connect(client)) => peer = Some(client),
+            Some(HostEvent::Receive(client, datagram)) => {
+                assert_eq!(Some(client), peer);
+                assert_eq!(datagram.data, &b"hello"[..]);
+                return client;
+            }
+            Some(event) => panic!("unexpected {:?}", event),
+            None => {}
+        }
+    }
+}
+
+#[test]
+fn dual_stack_hosts_accept_ipv4_and_ipv6() {
+    let mut server = Host::bind_dual_stack(0, Config::default()).expect("bind");
+    let port = server.local_addr().expect("address").port();
+
+    let peer = exchange(&mut server, (Ipv4Addr::LOCALHOST, port).into());
+    assert!(peer.is_ipv4());
+    let peer = exchange(&mut server, (Ipv6Addr::LOCALHOST, port).into());
+    assert!(peer.is_ipv6());
+    assert_eq!(server.peers().count(), 2);
+}
+
+#[test]
+fn ipv4_mapped_addresses_name_ipv4_peers() {
+    let mut server =
+        Host::bind(("127.0.0.1", 0), Config::default()).expect("bind");
+    let port = server.local_addr().expect("address").port();
+    let mapped = (Ipv4Addr::LOCALHOST.to_ipv6_mapped(), port).into();
+    let mut client = Host::client(Config::default()).expect("client");
+    client.connect(mapped);
+
+    let started = Instant::now();
+    loop {
+        assert!(started.elapsed() < Duration::from_secs(5), "no connect");
+        let now = Instant::now();
+        server.service(now, TICK).expect("service");
+        match client.service(now, TICK).expect("service") {
+            Some(HostEvent::Connect(peer)) => {
+                assert_eq!(peer, (Ipv4Addr::LOCALHOST, port).into());
+                break;
+            }
+            Some(event) => panic!("unexpected {:?}", event),
+            None => {}
+        }
+    }
+
+    // The peer answers to its IPv4-mapped address too.
+    assert!(client.peer(mapped).is_some());
+    assert!(client.join(GroupId(0), mapped));
+    client.leave(GroupId(0), mapped);
+    assert_eq!(client.members(GroupId(0)).count(), 0);
+    client
+        .send(
+            mapped,
+            SendCmd {
+                delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
+                data: Bytes::from_static(b"hello"),
+                ..SendCmd::default()
+            },
+        )
+        .expect("send");
+    client.disconnect(mapped, &Close::default());
+    loop {
+        assert!(started.elapsed() < Duration::from_secs(5), "no disconnect");
+        let now = Instant::now();
+        server.service(now, TICK).expect("service");
+        match client.service(now, TICK).expect("service") {
+            Some(HostEvent::Disconnect { peer, .. }) => {
+                assert_eq!(peer, (Ipv4Addr::LOCALHOST, port).into());
+                break;
+            }
+            Some(event) => panic!("unexpected {:?}", event),
+            None => {}
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn connections_to_ipv4_mapped_addresses_open() {
+    use miknet::{Connection, DriverConfig, Server};
+
+    let runtime = runtime::TokioRuntime;
+    let connected = async {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind");
+        let port = socket.local_addr().expect("address").port();
+        let _server =
+            Server::serve(runtime, DriverConfig::default(), vec![socket])
+                .expect("serve");
+        let mapped = (Ipv4Addr::LOCALHOST.to_ipv6_mapped(), port).into();
+        let connect =
+            Connection::connect(runtime, DriverConfig::default(), mapped);
+        tokio::time::timeout(Duration::from_secs(5), connect)
+            .await
+            .expect("connected in time")
+            .expect("connect");
+    };
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("runtime")
+        .block_on(connected);
+}
+
+#[test]
+fn paired_sockets_share_a_port() {
+    let (ipv4, ipv6) = runtime::bind_pair(0).expect("bind");
+    let port = ipv4.local_addr().expect("address").port();
+    assert_eq!(ipv6.local_addr().expect("address").port(), port);
+
+    let client = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).expect("bind");
+    client
+        .send_to(b"v6", (Ipv6Addr::LOCALHOST, port))
+        .expect("send");
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind");
+    client
+        .send_to(b"v4", (Ipv4Addr::LOCALHOST, port))
+        .expect("send");
+
+    let mut buffer = [0; 2];
+    ipv6.recv_from(&mut buffer).expect("receive");
+    assert_eq!(&buffer, b"v6");
+    ipv4.recv_from(&mut buffer).expect("receive");
+    assert_eq!(&buffer, b"v4");
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::{connect, ordered, send};
+use miknet::{Config, FecConfig};
+use std::time::Instant;
+
+#[test]
+fn lost_packet_is_rebuilt_from_repair() {
+    let fec = FecConfig {
+        min_loss: 0.0,
+        max_group: 4,
+        ..FecConfig::default()
+    };
+    let now = Instant::now();
+    let (mut client, mut server) = connect(
+        now,
+        Config {
+            fec: Some(fec),
+            ..Config::default()
+        },
+        Config::default(),
+    );
+
+    let packets: Vec<Vec<u8>> = (0..4u8)
+        .map(|i| {
+            send(&mut client, now, ordered(0, vec![i; 10 + 30 * i as usize]))
+        })
+        .collect();
+    let repair = client.poll_transmit(now).expect("repair");
+
+    for (i, packet) in packets.into_iter().enumerate() {
+        if i != 1 {
+            server.handle(now, packet.into());
+        }
+    }
+    server.handle(now, repair.into());
+
+    let surfaced: Vec<Vec<u8>> = std::iter::from_fn(|| server.poll_datagram())
+        .map(|datagram| datagram.data.to_vec())
+        .collect();
+    let expected: Vec<Vec<u8>> =
+        (0..4u8).map(|i| vec![i; 10 + 30 * i as usize]).collect();
+    assert_eq!(surfaced, expected);
+    assert_eq!(server.stats().recovered, Some(1));
+
+    // The client learns the packet arrived, so nothing is sent again.
+    while let Some(packet) = server.poll_transmit(now) {
+        client.handle(now, packet.into());
+    }
+    assert_eq!(client.stats().retransmissions, Some(0));
+    assert_eq!(client.stats().bytes_in_flight, Some(0));
+}
+
+#[test]
+fn repairs_need_both_endpoints_to_support_fec() {
+    let now = Instant::now();
+    let client = Config {
+        fec: Some(FecConfig {
+            min_loss: 0.0,
+            max_group: 1,
+            ..FecConfig::default()
+        }),
+        capabilities: miknet::Capabilities {
+            features: miknet::Features::empty(),
+            ..miknet::Capabilities::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, _) = connect(now, client, Config::default());
+    send(&mut client, now, ordered(0, vec![1, 2, 3]));
+    assert_eq!(client.poll_transmit(now), None);
+}
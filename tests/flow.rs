@@ -0,0 +1,209 @@
+mod common;
+
+use common::{connect, deliver, ordered};
+use miknet::{
+    frame::{DatagramFrame, Frame, Packet},
+    session::MIN_MTU,
+    stream::StreamKey,
+    Config, Error, ReceiveLimits, Session, Violation,
+};
+use nhanh::{Bytes, Close, DeliveryMode, SendCmd, StreamId};
+use std::time::{Duration, Instant};
+
+fn limited(limits: ReceiveLimits) -> Config {
+    Config {
+        receive_limits: limits,
+        ..Config::default()
+    }
+}
+
+fn datagram(stream: u32, index: u32, len: usize) -> Frame {
+    Frame::Datagram(DatagramFrame {
+        stream: StreamKey::Ordered(StreamId(stream)),
+        index: Some(index),
+        fragment: None,
+        compressed: false,
+        data: Bytes::from(vec![index as u8; len]),
+    })
+}
+
+/// Has a peer which ignores the server's limits send it `frames`, and returns
+/// the server.
+fn misbehave(limits: ReceiveLimits, frames: Vec<Frame>) -> Session {
+    let now = Instant::now();
+    let (_, mut server) = connect(now, Config::default(), limited(limits));
+    let packet = Packet::Data { number: 0, frames };
+    server.handle(now, packet.encode(None).into());
+    server
+}
+
+/// Asserts that the server closed the connection for `violation`, told the
+/// peer why, and surfaced nothing past the limit.
+fn assert_aborted(server: &mut Session, violation: Violation) {
+    let now = Instant::now();
+    assert!(!server.is_connected());
+    match server.take_error() {
+        Some(Error::Violation(found)) => assert_eq!(found, violation),
+        error => panic!("error {:?}", error),
+    }
+    let packet = server.poll_transmit(now).expect("abort");
+    match Packet::decode(&packet.into(), None).expect("decode") {
+        Packet::Data { frames, .. } => {
+            assert!(frames.contains(&Frame::Abort(violation)), "{:?}", frames)
+        }
+        packet => panic!("sent {:?}", packet),
+    }
+
+    // The datagrams would surface, were they buffered.
+    let packet = Packet::Data {
+        number: 1,
+        frames: vec![datagram(0, 0, 1), datagram(1, 0, 1)],
+    };
+    server.handle(now, packet.encode(None).into());
+    assert_eq!(server.poll_datagram(), None);
+}
+
+#[test]
+fn peers_exceeding_a_stream_window_are_aborted() {
+    let limits = ReceiveLimits {
+        stream_bytes: 1000,
+        ..ReceiveLimits::default()
+    };
+    let mut server =
+        misbehave(limits, vec![datagram(0, 1, 600), datagram(0, 2, 600)]);
+    assert_aborted(
+        &mut server,
+        Violation::StreamWindowExceeded(StreamKey::Ordered(StreamId(0))),
+    );
+}
+
+#[test]
+fn peers_exceeding_the_connection_window_are_aborted() {
+    let limits = ReceiveLimits {
+        stream_bytes: 1000,
+        connection_bytes: 1000,
+        ..ReceiveLimits::default()
+    };
+    let mut server =
+        misbehave(limits, vec![datagram(0, 1, 600), datagram(1, 1, 600)]);
+    assert_aborted(&mut server, Violation::ConnectionWindowExceeded);
+}
+
+#[test]
+fn peers_sending_beyond_the_reorder_window_are_aborted() {
+    let limits = ReceiveLimits {
+        reorder_datagrams: 16,
+        ..ReceiveLimits::default()
+    };
+    let stream = StreamKey::Ordered(StreamId(0));
+    let mut server =
+        misbehave(limits, vec![datagram(0, 15, 1), datagram(0, 16, 1)]);
+    assert_aborted(&mut server, Violation::ReorderWindowExceeded(stream));
+
+    let skip = Frame::Skip {
+        stream,
+        index: 16,
+        len: 0,
+    };
+    let mut server = misbehave(limits, vec![skip]);
+    assert_aborted(&mut server, Violation::ReorderWindowExceeded(stream));
+}
+
+#[test]
+fn peers_within_their_limits_are_not_aborted() {
+    let limits = ReceiveLimits {
+        stream_bytes: 1000,
+        reorder_datagrams: 16,
+        ..ReceiveLimits::default()
+    };
+    let mut server =
+        misbehave(limits, vec![datagram(0, 15, 1), datagram(0, 1, 600)]);
+    assert!(server.is_connected());
+
+    let now = Instant::now();
+    let packet = Packet::Data {
+        number: 1,
+        frames: vec![datagram(0, 0, 1)],
+    };
+    server.handle(now, packet.encode(None).into());
+    let surfaced: Vec<usize> = std::iter::from_fn(|| server.poll_datagram())
+        .map(|datagram| datagram.data.len())
+        .collect();
+    assert_eq!(surfaced, vec![1, 600]);
+}
+
+#[test]
+fn mtus_too_small_for_data_are_raised() {
+    let now = Instant::now();
+    let config = Config {
+        mtu: 10,
+        ..Config::default()
+    };
+    let (mut client, mut server) = connect(now, config, config);
+    let data = Bytes::from(vec![7; 100]);
+    let send_cmd = SendCmd {
+        delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
+        data: data.clone(),
+        ..SendCmd::default()
+    };
+    client.send(now, send_cmd).expect("send");
+    client.flush();
+    while let Some(packet) = client.poll_transmit(now) {
+        assert!(packet.len() <= MIN_MTU, "{} bytes", packet.len());
+        server.handle(now, packet.into());
+    }
+    assert_eq!(server.poll_datagram().expect("datagram").data, data);
+}
+
+#[test]
+fn datagrams_with_too_many_fragments_are_too_large() {
+    let now = Instant::now();
+    let config = Config {
+        mtu: MIN_MTU,
+        ..Config::default()
+    };
+    let (mut client, mut server) = connect(now, config, config);
+    let send_cmd = SendCmd {
+        delivery_mode: DeliveryMode::UnreliableSequenced(StreamId(0)),
+        data: Bytes::from(vec![0; 4 << 20]),
+        ..SendCmd::default()
+    };
+    match client.send(now, send_cmd) {
+        Err(Error::TooLarge { len, .. }) => assert_eq!(len, 4 << 20),
+        result => panic!("sent {:?}", result),
+    }
+    deliver(&mut client, &mut server, now);
+    assert_eq!(server.poll_datagram(), None);
+}
+
+#[test]
+fn datagrams_in_flight_when_closing_are_forgotten() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, Config::default(), Config::default());
+    let mut send = |stream| {
+        client
+            .send(now, ordered(stream, vec![1; 100]))
+            .expect("send");
+        client.flush();
+        client.poll_transmit(now).expect("packet")
+    };
+    let acked = send(0);
+    let _lost = send(1);
+    client.close(
+        now,
+        &Close {
+            linger: false,
+            ..Close::default()
+        },
+    );
+
+    // The first packet is acknowledged after the close, and the second lost.
+    let later = now + Duration::from_millis(100);
+    server.handle(now, acked.into());
+    server.handle_timeout(later);
+    deliver(&mut server, &mut client, later);
+    client.handle_timeout(later + Duration::from_secs(1));
+    deliver(&mut client, &mut server, later + Duration::from_secs(1));
+    assert!(!client.is_connected());
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use common::{connect, ordered};
+use miknet::{
+    CompressionConfig, Config, GroupId, Host, HostEvent, SharedDatagram,
+};
+use nhanh::Bytes;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+const TICK: Duration = Duration::from_millis(10);
+
+/// Services every host for a while, and returns what each client received.
+fn received(server: &mut Host, clients: &mut [Host]) -> Vec<Vec<Bytes>> {
+    let mut received = vec![vec![]; clients.len()];
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(200) {
+        let now = Instant::now();
+        server
+            .service(now, Duration::from_secs(0))
+            .expect("service");
+        for (client, received) in clients.iter_mut().zip(&mut received) {
+            while let Some(event) = client.service(now, TICK).expect("service")
+            {
+                if let HostEvent::Receive(_, datagram) = event {
+                    received.push(datagram.data);
+                }
+            }
+        }
+    }
+    received
+}
+
+#[test]
+fn groups_receive_what_is_sent_to_them() {
+    let mut server =
+        Host::bind("127.0.0.1:0", Config::default()).expect("bind");
+    let server_addr = server.local_addr().expect("address");
+    let mut clients: Vec<Host> = (0..3)
+        .map(|_| Host::client(Config::default()).expect("client"))
+        .collect();
+    for client in &mut clients {
+        client.connect(server_addr);
+    }
+
+    let mut peers: Vec<SocketAddr> = vec![];
+    let started = Instant::now();
+    while peers.len() < clients.len() {
+        assert!(started.elapsed() < Duration::from_secs(5), "no connects");
+        let now = Instant::now();
+        if let Some(HostEvent::Connect(peer)) =
+            server.service(now, TICK).expect("service")
+        {
+            peers.push(peer);
+        }
+        for client in &mut clients {
+            client
+                .service(now, Duration::from_secs(0))
+                .expect("service");
+        }
+    }
+    let port = |client: &Host| client.local_addr().expect("address").port();
+    peers.sort_by_key(|peer| {
+        clients
+            .iter()
+            .position(|client| port(client) == peer.port())
+    });
+
+    let group = GroupId(1);
+    assert!(server.join(group, peers[0]));
+    assert!(server.join(group, peers[1]));
+    assert!(!server.join(group, "127.0.0.1:1".parse().expect("address")));
+    let mut members: Vec<SocketAddr> = server.members(group).collect();
+    members.sort_by_key(|peer| peers.iter().position(|p| p == peer));
+    assert_eq!(members, peers[..2]);
+
+    assert!(server
+        .send_group(group, ordered(0, &b"group"[..]))
+        .is_empty());
+    server.flush();
+    let group_received = received(&mut server, &mut clients);
+    assert_eq!(group_received[0], vec![&b"group"[..]]);
+    assert_eq!(group_received[1], vec![&b"group"[..]]);
+    assert!(group_received[2].is_empty());
+
+    server.leave(group, peers[0]);
+    assert_eq!(server.members(group).collect::<Vec<_>>(), peers[1..2]);
+    assert!(server.broadcast(ordered(0, &b"all"[..])).is_empty());
+    server.flush();
+    for received in received(&mut server, &mut clients) {
+        assert_eq!(received, vec![&b"all"[..]]);
+    }
+}
+
+fn compressed() -> Config {
+    Config {
+        compression: Some(CompressionConfig {
+            dictionary: b"position velocity health",
+            min_len: 16,
+            ..CompressionConfig::default()
+        }),
+        ..Config::default()
+    }
+}
+
+#[test]
+fn shared_datagrams_are_delivered_independently() {
+    let now = Instant::now();
+    let (mut first, mut first_server) =
+        connect(now, compressed(), compressed());
+    let (mut second, mut second_server) =
+        connect(now, compressed(), compressed());
+
+    let data: Vec<u8> = b"position velocity health ".repeat(20);
+    let datagram = SharedDatagram::new(ordered(0, data.clone()));
+    first_server.send_shared(now, &datagram).expect("send");
+    second_server.send_shared(now, &datagram).expect("send");
+    first_server.flush();
+    second_server.flush();
+
+    // The first client's packets are lost.
+    let mut sent = 0;
+    while let Some(packet) = first_server.poll_transmit(now) {
+        sent += packet.len();
+    }
+    assert!(sent < data.len() / 2);
+    while let Some(packet) = second_server.poll_transmit(now) {
+        second.handle(now, packet.into());
+    }
+    assert_eq!(second.poll_datagram().expect("datagram").data, data);
+
+    // The first server probes until it learns its packet was lost.
+    let mut later = now;
+    for _ in 0..3 {
+        later += Duration::from_secs(1);
+        while let Some(packet) = first.poll_transmit(later) {
+            first_server.handle(later, packet.into());
+        }
+        first_server.handle_timeout(later);
+        while let Some(packet) = first_server.poll_transmit(later) {
+            first.handle(later, packet.into());
+        }
+    }
+    assert_eq!(first.poll_datagram().expect("datagram").data, data);
+    assert_eq!(first_server.stats().retransmissions, Some(1));
+    assert_eq!(second_server.stats().retransmissions, Some(0));
+}
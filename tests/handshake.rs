@@ -0,0 +1,154 @@
+use miknet::{
+    frame::{Packet, Reject},
+    handshake::{MIN_VERSION, VERSION},
+    Capabilities, Config, Error, Features, Incompatibility, Session,
+};
+use nhanh::{DeliveryMode, SendCmd, StreamId};
+use std::time::Instant;
+
+/// Opens a connection between a client and a server, delivering every packet.
+fn handshake(client: Config, server: Config) -> (Session, Session) {
+    let now = Instant::now();
+    let mut client = Session::connect(now, client);
+    let connect = client.poll_transmit(now).expect("connect");
+    let mut server = Session::accept(now, server, &connect).expect("accept");
+    while let Some(packet) = server.poll_transmit(now) {
+        client.handle(now, packet.into());
+    }
+    while let Some(packet) = client.poll_transmit(now) {
+        server.handle(now, packet.into());
+    }
+    (client, server)
+}
+
+/// A feature this version does not implement.
+const UNKNOWN: Features = Features::from_bits(1);
+
+fn offering(features: Features, max_streams: u32) -> Config {
+    Config {
+        capabilities: Capabilities {
+            features,
+            max_streams,
+            ..Capabilities::default()
+        },
+        ..Config::default()
+    }
+}
+
+fn requiring(features: Features) -> Config {
+    Config {
+        required_features: features,
+        ..offering(features, 256)
+    }
+}
+
+#[test]
+fn endpoints_agree_on_common_capabilities() {
+    let (client, server) = handshake(
+        offering(Features::FEC | Features::COMPRESSION, 16),
+        offering(Features::FEC | UNKNOWN, 64),
+    );
+    assert!(client.is_connected());
+    assert!(server.is_connected());
+
+    let agreed = client.capabilities().expect("capabilities");
+    assert_eq!(agreed.features, Features::FEC);
+    assert_eq!(agreed.max_streams, 16);
+    assert_eq!(server.capabilities(), Some(agreed));
+}
+
+#[test]
+fn server_rejects_clients_lacking_required_features() {
+    let (mut client, mut server) = handshake(
+        offering(Features::COMPRESSION, 256),
+        requiring(Features::FEC),
+    );
+    for session in [&mut client, &mut server].iter_mut() {
+        assert!(session.is_closed());
+        match session.take_error() {
+            Some(Error::Incompatible(Incompatibility::Features(missing))) => {
+                assert_eq!(missing, Features::FEC)
+            }
+            error => panic!("closed with {:?}", error),
+        }
+    }
+}
+
+#[test]
+fn client_rejects_servers_lacking_required_features() {
+    let (mut client, mut server) = handshake(
+        requiring(Features::COMPRESSION),
+        offering(Features::FEC, 256),
+    );
+    for session in [&mut client, &mut server].iter_mut() {
+        assert!(session.is_closed());
+        match session.take_error() {
+            Some(Error::Incompatible(Incompatibility::Features(missing))) => {
+                assert_eq!(missing, Features::COMPRESSION)
+            }
+            error => panic!("closed with {:?}", error),
+        }
+    }
+}
+
+#[test]
+fn endpoints_requiring_unknown_features_reject_every_peer() {
+    let strict = requiring(Features::FEC | UNKNOWN);
+    for (client, server) in [(strict, strict), (strict, Config::default())] {
+        let (mut client, mut server) = handshake(client, server);
+        for session in [&mut client, &mut server].iter_mut() {
+            assert!(session.is_closed());
+            match session.take_error() {
+                Some(Error::Incompatible(Incompatibility::Features(
+                    missing,
+                ))) => assert_eq!(missing, UNKNOWN),
+                error => panic!("closed with {:?}", error),
+            }
+        }
+    }
+}
+
+#[test]
+fn server_rejects_unsupported_versions() {
+    let now = Instant::now();
+    let unsupported = VERSION as u8 + 1;
+    let mut server =
+        Session::accept(now, Config::default(), &[0x01, unsupported])
+            .expect("accept");
+    assert!(!server.is_connected());
+
+    let packet = server.poll_transmit(now).expect("reject");
+    assert_eq!(
+        Packet::decode(&packet.into(), None).expect("decode"),
+        Packet::Reject(Reject {
+            min_version: MIN_VERSION,
+            max_version: VERSION,
+            missing: Features::empty(),
+        })
+    );
+    assert!(server.is_closed());
+    match server.take_error() {
+        Some(Error::Incompatible(Incompatibility::Version { min, max })) => {
+            assert_eq!((min, max), (unsupported.into(), unsupported.into()))
+        }
+        error => panic!("closed with {:?}", error),
+    }
+}
+
+#[test]
+fn streams_beyond_the_agreed_limit_are_refused() {
+    let (mut client, _) =
+        handshake(offering(Features::empty(), 4), Config::default());
+    let send = |id| SendCmd {
+        delivery_mode: DeliveryMode::ReliableOrdered(StreamId(id)),
+        data: vec![1, 2, 3].into(),
+        ..SendCmd::default()
+    };
+    assert!(client.send(Instant::now(), send(3)).is_ok());
+    match client.send(Instant::now(), send(4)) {
+        Err(Error::StreamLimit { stream, max: 4 }) => {
+            assert_eq!(stream, StreamId(4))
+        }
+        result => panic!("sent with {:?}", result),
+    }
+}
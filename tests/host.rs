@@ -0,0 +1,198 @@
+use miknet::{Config, Error, GroupId, Host, HostEvent};
+use nhanh::{
+    Bytes, Close, CloseReason, DeliveryMode, SendCmd, SendLimits, StreamId,
+};
+use std::time::{Duration, Instant};
+
+const TICK: Duration = Duration::from_millis(10);
+
+/// Services both hosts until one of them has an event.
+fn next_event(server: &mut Host, client: &mut Host) -> (bool, HostEvent) {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(5) {
+        let now = Instant::now();
+        if let Some(event) = server.service(now, TICK).expect("service") {
+            return (true, event);
+        }
+        if let Some(event) = client.service(now, TICK).expect("service") {
+            return (false, event);
+        }
+    }
+    panic!("no event");
+}
+
+#[test]
+fn hosts_connect_exchange_datagrams_and_disconnect() {
+    let mut server =
+        Host::bind("127.0.0.1:0", Config::default()).expect("bind");
+    let server_addr = server.local_addr().expect("address");
+    let mut client = Host::client(Config::default()).expect("client");
+    client.connect(server_addr);
+
+    let mut connected = (None, None);
+    while connected.0.is_none() || connected.1.is_none() {
+        match next_event(&mut server, &mut client) {
+            (true, HostEvent::Connect(peer)) => connected.0 = Some(peer),
+            (false, HostEvent::Connect(peer)) => connected.1 = Some(peer),
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+    assert_eq!(connected.1, Some(server_addr));
+    let client_addr = connected.0.expect("client address");
+
+    client
+        .send(
+            server_addr,
+            SendCmd {
+                delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
+                data: Bytes::from_static(b"hello"),
+                ..SendCmd::default()
+            },
+        )
+        .expect("send");
+    client.flush();
+    match next_event(&mut server, &mut client) {
+        (true, HostEvent::Receive(peer, datagram)) => {
+            assert_eq!(peer, client_addr);
+            assert_eq!(datagram.data, &b"hello"[..]);
+        }
+        event => panic!("unexpected {:?}", event),
+    }
+
+    let close = Close {
+        reason: CloseReason {
+            code: 7,
+            ..CloseReason::default()
+        },
+        ..Close::default()
+    };
+    client.disconnect(server_addr, &close);
+    let mut disconnected = (false, false);
+    while !(disconnected.0 && disconnected.1) {
+        match next_event(&mut server, &mut client) {
+            (true, HostEvent::Disconnect { peer, reason, .. }) => {
+                assert_eq!(peer, client_addr);
+                assert_eq!(reason.map(|reason| reason.code), Some(7));
+                disconnected.0 = true;
+            }
+            (false, HostEvent::Disconnect { peer, .. }) => {
+                assert_eq!(peer, server_addr);
+                disconnected.1 = true;
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+    assert_eq!(server.peers().count(), 0);
+    assert_eq!(client.peers().count(), 0);
+}
+
+#[test]
+fn failed_connections_are_reported() {
+    // Nothing answers on the port of a socket which was just dropped.
+    let address = Host::bind("127.0.0.1:0", Config::default())
+        .and_then(|host| host.local_addr())
+        .expect("address");
+    let config = Config {
+        idle_timeout: Duration::from_millis(200),
+        ..Config::default()
+    };
+    let mut client = Host::client(config).expect("client");
+    client.connect(address);
+
+    let started = Instant::now();
+    loop {
+        assert!(started.elapsed() < Duration::from_secs(5), "no event");
+        match client.service(Instant::now(), TICK).expect("service") {
+            Some(HostEvent::Disconnect { peer, error, .. }) => {
+                assert_eq!(peer, address);
+                assert!(error.is_some());
+                break;
+            }
+            Some(event) => panic!("unexpected {:?}", event),
+            None => {}
+        }
+    }
+}
+
+#[test]
+fn unreachable_peers_do_not_hold_up_others() {
+    let mut server =
+        Host::bind("127.0.0.1:0", Config::default()).expect("bind");
+    let server_addr = server.local_addr().expect("address");
+    // An IPv4 socket cannot send to IPv6 addresses at all.
+    let mut client =
+        Host::bind("127.0.0.1:0", Config::default()).expect("bind");
+    client.connect("[::1]:9".parse().expect("address"));
+    client.connect(server_addr);
+
+    match next_event(&mut server, &mut client) {
+        (true, HostEvent::Connect(_)) => {}
+        event => panic!("unexpected {:?}", event),
+    }
+    match next_event(&mut server, &mut client) {
+        (false, HostEvent::Connect(peer)) => assert_eq!(peer, server_addr),
+        event => panic!("unexpected {:?}", event),
+    }
+}
+
+#[test]
+fn sends_beyond_the_send_limits_fail_until_acknowledged() {
+    let mut server =
+        Host::bind("127.0.0.1:0", Config::default()).expect("bind");
+    let server_addr = server.local_addr().expect("address");
+    let mut client = Host::client(Config::default()).expect("client");
+    client.connect(server_addr);
+    let mut connected = (false, false);
+    while !(connected.0 && connected.1) {
+        match next_event(&mut server, &mut client) {
+            (true, HostEvent::Connect(_)) => connected.0 = true,
+            (false, HostEvent::Connect(_)) => connected.1 = true,
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    let limits = SendLimits {
+        datagrams: 2,
+        ..SendLimits::default()
+    };
+    client
+        .peer(server_addr)
+        .expect("peer")
+        .set_send_limits(limits);
+    let delivery_mode = DeliveryMode::ReliableOrdered(StreamId(0));
+    let send_cmd = SendCmd {
+        delivery_mode,
+        data: Bytes::from_static(b"hello"),
+        ..SendCmd::default()
+    };
+    for _ in 0..2 {
+        assert!(client.has_room(server_addr, Some(delivery_mode)));
+        client.send(server_addr, send_cmd.clone()).expect("send");
+    }
+    assert!(!client.has_room(server_addr, Some(delivery_mode)));
+    match client.send(server_addr, send_cmd.clone()) {
+        Err(Error::Full) => {}
+        result => panic!("sent with {:?}", result),
+    }
+    assert!(client.join(GroupId(0), server_addr));
+    match client.send_group(GroupId(0), send_cmd.clone()).as_slice() {
+        [(peer, Error::Full)] => assert_eq!(*peer, server_addr),
+        failures => panic!("failed with {:?}", failures),
+    }
+
+    // The datagrams arrive, and their acknowledgement makes room.
+    for _ in 0..2 {
+        match next_event(&mut server, &mut client) {
+            (true, HostEvent::Receive(..)) => {}
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+    let started = Instant::now();
+    while !client.has_room(server_addr, Some(delivery_mode)) {
+        assert!(started.elapsed() < Duration::from_secs(5), "no room");
+        let now = Instant::now();
+        server.service(now, TICK).expect("service");
+        client.service(now, TICK).expect("service");
+    }
+    client.send(server_addr, send_cmd).expect("send");
+}
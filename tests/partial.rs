@@ -0,0 +1,93 @@
+mod common;
+
+use common::{connect, deliver, ordered, surfaced};
+use miknet::Config;
+use nhanh::{Abandon, SendCmd};
+use std::time::{Duration, Instant};
+
+#[test]
+fn abandoned_datagrams_are_skipped() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, Config::default(), Config::default());
+
+    let abandon = Abandon::new();
+    client.send(now, ordered(0, vec![0])).expect("send");
+    client
+        .send(
+            now,
+            SendCmd {
+                abandon: Some(abandon.clone()),
+                ..ordered(0, vec![1])
+            },
+        )
+        .expect("send");
+    client.send(now, ordered(0, vec![2])).expect("send");
+    abandon.abandon();
+
+    deliver(&mut client, &mut server, now);
+    assert_eq!(surfaced(&mut server), vec![(0, 0, 0), (0, 2, 2)]);
+}
+
+#[test]
+fn datagrams_are_abandoned_after_their_retransmissions() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, Config::default(), Config::default());
+
+    let abandon = Abandon::new();
+    client
+        .send(
+            now,
+            SendCmd {
+                max_retransmissions: Some(0),
+                abandon: Some(abandon.clone()),
+                ..ordered(0, vec![0])
+            },
+        )
+        .expect("send");
+    client.poll_transmit(now).expect("lost packet");
+    client.send(now, ordered(0, vec![1])).expect("send");
+    deliver(&mut client, &mut server, now);
+    assert_eq!(surfaced(&mut server), vec![]);
+
+    // The second packet's acknowledgement leads the client to declare the
+    // first lost, and it is not sent again.
+    let later = now + Duration::from_millis(50);
+    deliver(&mut server, &mut client, later);
+    client.handle_timeout(later + Duration::from_secs(1));
+    assert!(abandon.is_abandoned());
+
+    let later = later + Duration::from_secs(1);
+    deliver(&mut client, &mut server, later);
+    assert_eq!(surfaced(&mut server), vec![(0, 1, 1)]);
+    assert_eq!(client.stats().retransmissions, Some(0));
+}
+
+#[test]
+fn datagrams_within_their_retransmissions_are_delivered() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, Config::default(), Config::default());
+
+    client
+        .send(
+            now,
+            SendCmd {
+                max_retransmissions: Some(1),
+                ..ordered(0, vec![0])
+            },
+        )
+        .expect("send");
+    client.poll_transmit(now).expect("lost packet");
+    client.send(now, ordered(0, vec![1])).expect("send");
+    deliver(&mut client, &mut server, now);
+
+    let later = now + Duration::from_millis(50);
+    deliver(&mut server, &mut client, later);
+    let later = later + Duration::from_secs(1);
+    client.handle_timeout(later);
+    deliver(&mut client, &mut server, later);
+    assert_eq!(surfaced(&mut server), vec![(0, 0, 0), (0, 1, 1)]);
+    assert_eq!(client.stats().retransmissions, Some(1));
+}
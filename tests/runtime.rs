@@ -0,0 +1,97 @@
+#![cfg(any(feature = "async-std", feature = "smol", feature = "tokio"))]
+
+use miknet::{
+    batch::{RecvMeta, Transmit, RECV_BUFFER_BYTES},
+    AsyncUdpSocket, Config, Runtime, Session,
+};
+use std::{
+    future::poll_fn,
+    io::IoSliceMut,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Receives one batch of packets.
+async fn recv(socket: &impl AsyncUdpSocket) -> Vec<(SocketAddr, Vec<u8>)> {
+    let mut buffer = vec![0; RECV_BUFFER_BYTES];
+    let mut meta = [RecvMeta::default()];
+    let received = poll_fn(|ctx| {
+        socket.poll_recv(ctx, &mut [IoSliceMut::new(&mut buffer)], &mut meta)
+    })
+    .await
+    .expect("receive");
+    meta[..received]
+        .iter()
+        .flat_map(|meta| {
+            meta.packets(&buffer)
+                .map(move |packet| (meta.source, packet.to_vec()))
+        })
+        .collect()
+}
+
+async fn send(socket: &impl AsyncUdpSocket, packet: &[u8], to: SocketAddr) {
+    let transmit = Transmit {
+        destination: to,
+        contents: packet,
+    };
+    let sent = poll_fn(|ctx| socket.poll_send(ctx, &[transmit]))
+        .await
+        .expect("send");
+    assert_eq!(sent, 1);
+}
+
+/// Opens a connection between sessions over sockets of `runtime`, with the
+/// server's side spawned on it.
+async fn connect_over<R: Runtime>(runtime: R) {
+    let server = runtime
+        .bind_udp("127.0.0.1:0".parse().unwrap())
+        .expect("bind");
+    let server_addr = server.local_addr().expect("address");
+    runtime.spawn(Box::pin(async move {
+        let (client_addr, connect) = recv(&server).await.remove(0);
+        let now = Instant::now();
+        let mut session =
+            Session::accept(now, Config::default(), &connect).expect("accept");
+        while let Some(packet) = session.poll_transmit(now) {
+            send(&server, &packet, client_addr).await;
+        }
+    }));
+
+    let client = runtime
+        .bind_udp("127.0.0.1:0".parse().unwrap())
+        .expect("bind");
+    let now = Instant::now();
+    let mut session = Session::connect(now, Config::default());
+    let connect = session.poll_transmit(now).expect("connect");
+    send(&client, &connect, server_addr).await;
+    let (from, accept) = recv(&client).await.remove(0);
+    assert_eq!(from, server_addr);
+    session.handle(Instant::now(), accept.into());
+    assert!(session.is_connected());
+
+    let deadline = Instant::now() + Duration::from_millis(20);
+    runtime.sleep_until(deadline).await;
+    assert!(Instant::now() >= deadline);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn sessions_connect_over_tokio() {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("runtime")
+        .block_on(connect_over(miknet::runtime::TokioRuntime));
+}
+
+#[cfg(feature = "async-std")]
+#[test]
+fn sessions_connect_over_async_std() {
+    async_std::task::block_on(connect_over(miknet::runtime::AsyncStdRuntime));
+}
+
+#[cfg(feature = "smol")]
+#[test]
+fn sessions_connect_over_smol() {
+    smol::block_on(connect_over(miknet::runtime::SmolRuntime));
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::{connect, deliver, send, surfaced};
+use miknet::Config;
+use nhanh::{DeliveryMode, SendCmd, StreamId};
+use std::time::{Duration, Instant};
+
+fn sequenced(data: u8) -> SendCmd {
+    SendCmd {
+        delivery_mode: DeliveryMode::ReliableSequenced(StreamId(0)),
+        data: vec![data].into(),
+        ..SendCmd::default()
+    }
+}
+
+#[test]
+fn newer_datagrams_surface_and_older_ones_are_dropped() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, Config::default(), Config::default());
+
+    let first = send(&mut client, now, sequenced(0));
+    let second = send(&mut client, now, sequenced(1));
+    let third = send(&mut client, now, sequenced(2));
+    server.handle(now, second.into());
+    server.handle(now, first.into());
+    server.handle(now, third.into());
+    assert_eq!(surfaced(&mut server), vec![(0, 1, 1), (0, 2, 2)]);
+}
+
+#[test]
+fn superseded_datagrams_are_not_sent_again() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, Config::default(), Config::default());
+
+    send(&mut client, now, sequenced(0));
+    let second = send(&mut client, now, sequenced(1));
+    server.handle(now, second.into());
+    assert_eq!(surfaced(&mut server), vec![(0, 1, 1)]);
+
+    let later = now + Duration::from_millis(50);
+    deliver(&mut server, &mut client, later);
+    let later = later + Duration::from_secs(1);
+    client.handle_timeout(later);
+    deliver(&mut client, &mut server, later);
+    assert_eq!(surfaced(&mut server), vec![]);
+    assert_eq!(client.stats().retransmissions, Some(0));
+}
+
+#[test]
+fn the_newest_datagram_is_sent_until_it_arrives() {
+    let now = Instant::now();
+    let (mut client, mut server) =
+        connect(now, Config::default(), Config::default());
+
+    let first = send(&mut client, now, sequenced(0));
+    send(&mut client, now, sequenced(1));
+    server.handle(now, first.into());
+    assert_eq!(surfaced(&mut server), vec![(0, 0, 0)]);
+
+    // The client probes until it learns the second packet was lost.
+    let mut later = now;
+    for _ in 0..3 {
+        later += Duration::from_secs(1);
+        deliver(&mut server, &mut client, later);
+        client.handle_timeout(later);
+        deliver(&mut client, &mut server, later);
+    }
+    assert_eq!(surfaced(&mut server), vec![(0, 1, 1)]);
+    assert_eq!(client.stats().retransmissions, Some(1));
+}
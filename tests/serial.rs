@@ -0,0 +1,70 @@
+use miknet::{
+    frame::{DatagramFrame, Frame, Packet},
+    serial,
+    stream::StreamKey,
+};
+use nhanh::{Bytes, StreamId, StreamIndex};
+use proptest::prelude::*;
+
+proptest! {
+    #[test]
+    fn indices_ahead_by_less_than_half_follow(
+        a in any::<u32>(),
+        distance in 1..1u32 << 31,
+    ) {
+        let b = a.wrapping_add(distance);
+        prop_assert!(serial::lt(a, b));
+        prop_assert!(!serial::lt(b, a));
+        prop_assert_eq!(serial::max(a, b), b);
+        prop_assert_eq!(serial::max(b, a), b);
+        prop_assert!(StreamIndex::Sequence(b).follows(StreamIndex::Sequence(a)));
+        prop_assert!(!StreamIndex::Sequence(a).follows(StreamIndex::Sequence(b)));
+    }
+}
+
+#[test]
+fn indices_wrap_to_zero() {
+    assert_eq!(serial::next(u32::MAX), 0);
+    assert!(serial::lt(u32::MAX, 0));
+    assert!(serial::lt(u32::MAX - 5, 3));
+    assert!(!serial::lt(3, u32::MAX - 5));
+    assert!(serial::le(0, 0));
+    assert!(!serial::lt(0, 0));
+    assert_eq!(serial::max(u32::MAX, 1), 1);
+
+    assert_eq!(
+        StreamIndex::Ordinal(u32::MAX).next(),
+        StreamIndex::Ordinal(0)
+    );
+    assert!(StreamIndex::Ordinal(0).follows(StreamIndex::Ordinal(u32::MAX)));
+    assert!(!StreamIndex::Ordinal(1).follows(StreamIndex::Sequence(0)));
+}
+
+#[test]
+fn indices_half_the_space_apart_are_unordered() {
+    let half = 1 << 31;
+    assert!(!serial::lt(0, half));
+    assert!(!serial::lt(half, 0));
+    assert!(!StreamIndex::Sequence(half).follows(StreamIndex::Sequence(0)));
+}
+
+#[test]
+fn consecutive_datagrams_share_a_header_across_the_wrap() {
+    let datagram = |index, data: &[u8]| {
+        Frame::Datagram(DatagramFrame {
+            stream: StreamKey::Ordered(StreamId(0)),
+            index: Some(index),
+            fragment: None,
+            compressed: false,
+            data: Bytes::copy_from_slice(data),
+        })
+    };
+    let packet = Packet::Data {
+        number: 0,
+        frames: vec![datagram(u32::MAX, b"ab"), datagram(0, b"cd")],
+    };
+
+    let bytes = packet.encode(None);
+    assert_eq!(&bytes[bytes.len() - 3..], [0x90, b'c', b'd']);
+    assert_eq!(Packet::decode(&bytes.into(), None).unwrap(), packet);
+}
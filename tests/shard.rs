@@ -0,0 +1,49 @@
+#![cfg(target_os = "linux")]
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+#[test]
+fn each_peer_reaches_one_socket() {
+    let sockets = miknet::runtime::bind_reuse_port(
+        "127.0.0.1:0".parse().expect("address"),
+        4,
+    )
+    .expect("bind");
+    let address = sockets[0].local_addr().expect("address");
+    for socket in &sockets {
+        assert_eq!(socket.local_addr().expect("address"), address);
+        socket.set_nonblocking(true).expect("nonblocking");
+    }
+
+    let clients: Vec<UdpSocket> = (0..8)
+        .map(|_| UdpSocket::bind("127.0.0.1:0").expect("bind"))
+        .collect();
+    for round in 0..4u8 {
+        for client in &clients {
+            client.send_to(&[round], address).expect("send");
+        }
+    }
+
+    let mut shards: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+    let mut received = 0;
+    let started = Instant::now();
+    while received < clients.len() * 4 {
+        assert!(started.elapsed() < Duration::from_secs(5), "lost packets");
+        for (shard, socket) in sockets.iter().enumerate() {
+            let mut buffer = [0; 16];
+            while let Ok((_, source)) = socket.recv_from(&mut buffer) {
+                shards.entry(source).or_default().push(shard);
+                received += 1;
+            }
+        }
+    }
+
+    assert_eq!(shards.len(), clients.len());
+    for shards in shards.values() {
+        assert!(shards.iter().all(|&shard| shard == shards[0]));
+    }
+}
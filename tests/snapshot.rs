@@ -0,0 +1,134 @@
+use miknet::{
+    DecodeError, InterpolationBuffer, InterpolationConfig, SnapshotReceiver,
+    SnapshotSender,
+};
+use nhanh::Bytes;
+use std::time::{Duration, Instant};
+
+const ENTITIES: usize = 100;
+const ENTITY_LEN: usize = 16;
+
+/// A world of entities, of which a few move every tick.
+fn world(tick: u32) -> Bytes {
+    let mut world = vec![0; ENTITIES * ENTITY_LEN];
+    for (entity, state) in world.chunks_mut(ENTITY_LEN).enumerate() {
+        let moves = entity % 10 == 0;
+        let position = if moves { tick } else { 0 } + entity as u32;
+        state[..4].copy_from_slice(&(entity as u32).to_le_bytes());
+        state[4..8].copy_from_slice(&position.to_le_bytes());
+        state[8..].copy_from_slice(&[100; 8]);
+    }
+    world.into()
+}
+
+#[test]
+fn deltas_against_acknowledged_snapshots_rebuild_them() {
+    let mut sender = SnapshotSender::new();
+    let mut receiver = SnapshotReceiver::new();
+
+    let full = sender.encode(0, world(0));
+    assert_eq!(receiver.receive(&full), Ok((0, world(0))));
+    sender
+        .handle_ack(&receiver.ack().expect("ack"))
+        .expect("handle ack");
+    assert_eq!(sender.baseline(), Some(0));
+
+    for tick in 1..10 {
+        let delta = sender.encode(tick, world(tick));
+        assert!(delta.len() * 10 < full.len(), "{} bytes", delta.len());
+        assert_eq!(receiver.receive(&delta), Ok((tick, world(tick))));
+        sender
+            .handle_ack(&receiver.ack().expect("ack"))
+            .expect("handle ack");
+        assert_eq!(sender.baseline(), Some(tick));
+    }
+}
+
+#[test]
+fn lost_snapshots_leave_the_baseline() {
+    let mut sender = SnapshotSender::new();
+    let mut receiver = SnapshotReceiver::new();
+    receiver
+        .receive(&sender.encode(0, world(0)))
+        .expect("receive");
+    sender.acknowledge(0);
+
+    let _lost = sender.encode(1, world(1));
+    let late = sender.encode(2, world(2));
+    assert_eq!(receiver.receive(&late), Ok((2, world(2))));
+    assert_eq!(sender.baseline(), Some(0));
+
+    // Acknowledgements of old or unknown ticks do not move the baseline.
+    sender.acknowledge(7);
+    assert_eq!(sender.baseline(), Some(0));
+    sender.acknowledge(2);
+    sender.acknowledge(1);
+    assert_eq!(sender.baseline(), Some(2));
+
+    let next = sender.encode(3, world(3));
+    assert_eq!(receiver.receive(&next), Ok((3, world(3))));
+}
+
+#[test]
+fn snapshots_against_unknown_baselines_are_refused() {
+    let mut sender = SnapshotSender::new();
+    let _lost = sender.encode(0, world(0));
+    sender.acknowledge(0);
+
+    let mut receiver = SnapshotReceiver::new();
+    assert_eq!(
+        receiver.receive(&sender.encode(1, world(1))),
+        Err(DecodeError::Invalid("snapshot baseline"))
+    );
+    assert_eq!(receiver.ack(), None);
+}
+
+#[test]
+fn deltas_skipping_past_the_end_are_refused() {
+    // A whole snapshot of tick 1, one byte long, whose first run writes the
+    // byte and whose second skips `u64::MAX` bytes past it.
+    let mut message = vec![0x01, 0x00, 0x01, 0x00, 0x01, 0xaa];
+    message.extend([0xff; 9]);
+    message.extend([0x01, 0x00]);
+
+    let mut receiver = SnapshotReceiver::new();
+    assert_eq!(
+        receiver.receive(&message),
+        Err(DecodeError::Invalid("snapshot delta"))
+    );
+}
+
+#[test]
+fn snapshots_play_out_behind_the_delay() {
+    let tick = Duration::from_millis(10);
+    let config = InterpolationConfig {
+        tick_interval: tick,
+        playout_delay: Duration::from_millis(30),
+        ..InterpolationConfig::default()
+    };
+    let mut buffer = InterpolationBuffer::new(config);
+    let start = Instant::now();
+
+    buffer.push(start, 0, 0.0f32);
+    // Later arrivals are delayed on their way, and do not move the clock.
+    buffer.push(start + tick + Duration::from_millis(4), 1, 1.0);
+    buffer.push(start + tick * 2, 2, 2.0);
+    assert!(buffer.sample(start + Duration::from_millis(20)).is_none());
+
+    let sample = buffer.sample(start + Duration::from_millis(35)).unwrap();
+    assert_eq!((*sample.from, *sample.to), (0.0, 1.0));
+    assert!((sample.alpha - 0.5).abs() < 1e-3, "{}", sample.alpha);
+
+    let sample = buffer.sample(start + Duration::from_millis(47)).unwrap();
+    assert_eq!((*sample.from, *sample.to), (1.0, 2.0));
+    assert!((sample.alpha - 0.7).abs() < 1e-3, "{}", sample.alpha);
+    assert_eq!(buffer.snapshots().count(), 2);
+
+    // Past the newest snapshot, the buffer holds it.
+    let sample = buffer.sample(start + Duration::from_millis(90)).unwrap();
+    assert_eq!((*sample.from, *sample.to, sample.alpha), (2.0, 2.0, 1.0));
+
+    // Snapshots older than those played out are dropped.
+    buffer.push(start + tick * 3, 1, 1.0);
+    assert_eq!(buffer.snapshots().count(), 1);
+}
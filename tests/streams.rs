@@ -0,0 +1,84 @@
+mod common;
+
+use common::{connect, deliver, ordered, surfaced};
+use miknet::{Config, Session};
+use std::time::{Duration, Instant};
+
+fn config() -> Config {
+    Config {
+        stream_idle_timeout: Duration::from_secs(1),
+        ..Config::default()
+    }
+}
+
+/// Runs both endpoints' timers and exchanges their packets until `until`.
+fn run(
+    client: &mut Session,
+    server: &mut Session,
+    mut now: Instant,
+    until: Instant,
+) {
+    while now < until {
+        now += Duration::from_millis(100);
+        for session in [&mut *client, &mut *server] {
+            if session.poll_timeout().is_some_and(|timeout| timeout <= now) {
+                session.handle_timeout(now);
+            }
+        }
+        deliver(client, server, now);
+        deliver(server, client, now);
+    }
+}
+
+#[test]
+fn streams_far_beyond_a_byte_carry_datagrams() {
+    let now = Instant::now();
+    let (mut client, mut server) = connect(now, config(), config());
+
+    client.send(now, ordered(10_000, vec![1])).expect("send");
+    client.send(now, ordered(60_000, vec![2])).expect("send");
+    client.flush();
+    deliver(&mut client, &mut server, now);
+    assert_eq!(surfaced(&mut server), vec![(10_000, 0, 1), (60_000, 0, 2)]);
+}
+
+#[test]
+fn idle_streams_are_reclaimed_and_start_again() {
+    let now = Instant::now();
+    let (mut client, mut server) = connect(now, config(), config());
+
+    client.send(now, ordered(7, vec![0])).expect("send");
+    client.send(now, ordered(7, vec![1])).expect("send");
+    client.flush();
+    deliver(&mut client, &mut server, now);
+    assert_eq!(surfaced(&mut server), vec![(7, 0, 0), (7, 1, 1)]);
+
+    // Were the stream's state not reclaimed on both ends, the server would
+    // wait for index 2 and drop index 0.
+    let later = now + Duration::from_secs(3);
+    run(&mut client, &mut server, now, later);
+    client.send(later, ordered(7, vec![2])).expect("send");
+    client.flush();
+    deliver(&mut client, &mut server, later);
+    assert_eq!(surfaced(&mut server), vec![(7, 0, 2)]);
+}
+
+#[test]
+fn busy_streams_are_not_reclaimed() {
+    let now = Instant::now();
+    let (mut client, mut server) = connect(now, config(), config());
+
+    let mut now = now;
+    for data in 0..6 {
+        client.send(now, ordered(3, vec![data])).expect("send");
+        client.flush();
+        let later = now + Duration::from_millis(500);
+        run(&mut client, &mut server, now, later);
+        now = later;
+    }
+    let indices: Vec<u32> = surfaced(&mut server)
+        .into_iter()
+        .map(|(_, index, _)| index)
+        .collect();
+    assert_eq!(indices, (0..6).collect::<Vec<_>>());
+}
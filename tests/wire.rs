@@ -0,0 +1,342 @@
+use miknet::{
+    frame::{
+        Ack, DatagramFrame, Fragment, Frame, Hello, Packet, Reject, Repair,
+    },
+    handshake::VERSION,
+    stream::StreamKey,
+    Capabilities, DecodeError, Error, Features, ReceiveLimits, Violation,
+};
+use nhanh::{Bytes, CloseReason, StreamId};
+use proptest::{collection::vec, option, prelude::*};
+use std::time::Duration;
+
+fn stream() -> impl Strategy<Value = StreamKey> {
+    prop_oneof![
+        Just(StreamKey::Unordered),
+        any::<u32>().prop_map(|id| StreamKey::Ordered(StreamId(id))),
+        any::<u32>().prop_map(|id| StreamKey::Sequenced(StreamId(id))),
+    ]
+}
+
+fn limits() -> impl Strategy<Value = ReceiveLimits> {
+    (any::<u64>(), any::<u64>(), any::<u32>()).prop_map(
+        |(stream_bytes, connection_bytes, reorder_datagrams)| ReceiveLimits {
+            stream_bytes,
+            connection_bytes,
+            reorder_datagrams,
+            ..ReceiveLimits::default()
+        },
+    )
+}
+
+fn hello() -> impl Strategy<Value = Hello> {
+    (limits(), any::<u64>(), any::<u32>(), any::<u32>()).prop_map(
+        |(limits, features, max_streams, dictionary)| Hello {
+            version: VERSION,
+            capabilities: Capabilities {
+                features: Features::from_bits(features),
+                max_streams,
+                ..Capabilities::default()
+            },
+            limits,
+            dictionary,
+        },
+    )
+}
+
+fn reject() -> impl Strategy<Value = Reject> {
+    (any::<u32>(), any::<u32>(), any::<u64>()).prop_map(
+        |(min_version, max_version, missing)| Reject {
+            min_version,
+            max_version,
+            missing: Features::from_bits(missing),
+        },
+    )
+}
+
+fn datagram() -> impl Strategy<Value = DatagramFrame> {
+    let fragment = (any::<u32>(), any::<u16>(), any::<u16>()).prop_map(
+        |(message, part, parts)| Fragment {
+            message,
+            part,
+            parts,
+        },
+    );
+    (
+        stream(),
+        option::of(any::<u32>()),
+        option::of(fragment),
+        any::<bool>(),
+        vec(any::<u8>(), 0..64),
+    )
+        .prop_map(|(stream, index, fragment, compressed, data)| {
+            DatagramFrame {
+                stream,
+                index,
+                fragment,
+                compressed,
+                data: data.into(),
+            }
+        })
+}
+
+/// Runs of datagrams on one stream with consecutive indices, which is what
+/// the encoding abbreviates.
+fn datagram_run() -> impl Strategy<Value = Vec<Frame>> {
+    (stream(), any::<u32>(), vec(vec(any::<u8>(), 0..16), 1..8)).prop_map(
+        |(stream, first, data)| {
+            data.into_iter()
+                .zip(first..)
+                .map(|(data, index)| {
+                    Frame::Datagram(DatagramFrame {
+                        stream,
+                        index: Some(index),
+                        fragment: None,
+                        compressed: false,
+                        data: data.into(),
+                    })
+                })
+                .collect()
+        },
+    )
+}
+
+fn ack() -> impl Strategy<Value = Ack> {
+    (
+        any::<u64>(),
+        vec((0..1000u64, 0..1000u64), 1..8),
+        0..1_000_000u64,
+    )
+        .prop_map(|(largest, spans, delay)| {
+            let mut ranges = vec![];
+            let mut end = Some(largest);
+            for (gap, len) in spans {
+                let newest = match end {
+                    Some(newest) => newest,
+                    None => break,
+                };
+                let start = newest.saturating_sub(len);
+                ranges.push((start, newest));
+                end = start.checked_sub(gap + 2);
+            }
+            Ack {
+                ranges,
+                delay: Duration::from_micros(delay),
+                recovered: largest % 7,
+            }
+        })
+}
+
+fn violation() -> impl Strategy<Value = Violation> {
+    prop_oneof![
+        stream().prop_map(Violation::StreamWindowExceeded),
+        Just(Violation::ConnectionWindowExceeded),
+        stream().prop_map(Violation::ReorderWindowExceeded),
+        stream().prop_map(Violation::MissingIndex),
+        Just(Violation::InconsistentFragment),
+        Just(Violation::UnexpectedPacket),
+        stream().prop_map(Violation::StreamLimitExceeded),
+        stream().prop_map(Violation::PrematureReclaim),
+        stream().prop_map(Violation::MalformedCompression),
+    ]
+}
+
+fn frame() -> impl Strategy<Value = Frame> {
+    prop_oneof![
+        datagram().prop_map(Frame::Datagram),
+        ack().prop_map(Frame::Ack),
+        (option::of(stream()), any::<u64>())
+            .prop_map(|(stream, max)| Frame::MaxBytes { stream, max }),
+        (stream(), any::<u32>())
+            .prop_map(|(stream, max)| Frame::MaxIndex { stream, max }),
+        Just(Frame::Ping),
+        (any::<u32>(), ".{0,32}").prop_map(|(code, message)| {
+            Frame::Close(CloseReason { code, message })
+        }),
+        violation().prop_map(Frame::Abort),
+        (
+            any::<u64>(),
+            any::<u32>(),
+            any::<u64>(),
+            vec(any::<u8>(), 0..64)
+        )
+            .prop_map(|(first, count, len, data)| {
+                Frame::Repair(Repair {
+                    first,
+                    count,
+                    len,
+                    data,
+                })
+            }),
+        (stream(), any::<u32>(), any::<u64>()).prop_map(
+            |(stream, index, len)| Frame::Skip { stream, index, len }
+        ),
+        (stream(), any::<u32>())
+            .prop_map(|(stream, end)| Frame::Reclaim { stream, end }),
+    ]
+}
+
+fn frames() -> impl Strategy<Value = Vec<Frame>> {
+    vec(
+        prop_oneof![frame().prop_map(|frame| vec![frame]), datagram_run()],
+        0..8,
+    )
+    .prop_map(|runs| runs.into_iter().flatten().collect())
+}
+
+proptest! {
+    #[test]
+    fn handshakes_round_trip(
+        hello in hello(),
+        reject in reject(),
+        kind in 0..3,
+    ) {
+        let packet = match kind {
+            0 => Packet::Connect(hello),
+            1 => Packet::Accept(hello),
+            _ => Packet::Reject(reject),
+        };
+        let bytes = packet.encode(None).into();
+        let decoded = Packet::decode(&bytes, None).unwrap();
+        prop_assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn data_round_trips(
+        number in any::<u64>(),
+        unacked in 1..1u64 << 40,
+        reordered in any::<u64>(),
+        frames in frames(),
+    ) {
+        // The remote endpoint has received at least what it acknowledged, and
+        // at most as far past this packet as this packet is past what it
+        // acknowledged.
+        let largest_acked = number.checked_sub(unacked);
+        let unacked = largest_acked.map_or(number + 1, |_| unacked);
+        let floor = largest_acked.unwrap_or(0);
+        let ceiling = number.saturating_add(unacked - 1);
+        let largest_received = match largest_acked {
+            None if reordered % 2 == 0 => None,
+            _ => Some(floor + reordered % (ceiling - floor + 1)),
+        };
+
+        let packet = Packet::Data { number, frames };
+        let bytes = Bytes::from(packet.encode(largest_acked));
+        let decoded = Packet::decode(&bytes, largest_received).unwrap();
+        prop_assert_eq!(&decoded, &packet);
+
+        if let Packet::Data { frames, .. } = &packet {
+            let most = 9 + frames.iter().map(Frame::size).sum::<usize>();
+            prop_assert!(bytes.len() <= most);
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_decode_or_fail(
+        bytes in vec(any::<u8>(), 0..256),
+        largest_received in option::of(any::<u64>()),
+    ) {
+        if let Ok(packet) = Packet::decode(&bytes.into(), largest_received) {
+            let bytes = packet.encode(None).into();
+            let decoded = Packet::decode(&bytes, None).unwrap();
+            prop_assert_eq!(decoded, packet);
+        }
+    }
+
+    #[test]
+    fn corrupted_packets_decode_or_fail(
+        frames in frames(),
+        position in any::<prop::sample::Index>(),
+        byte in any::<u8>(),
+        cut in any::<bool>(),
+    ) {
+        let mut bytes = Packet::Data { number: 7, frames }.encode(Some(3));
+        let i = position.index(bytes.len());
+        if cut {
+            bytes.truncate(i);
+        } else {
+            bytes[i] = byte;
+        }
+        let _ = Packet::decode(&bytes.into(), Some(5));
+    }
+}
+
+#[test]
+fn consecutive_datagrams_share_a_header() {
+    let datagram = |index, data: &[u8]| {
+        Frame::Datagram(DatagramFrame {
+            stream: StreamKey::Ordered(StreamId(2)),
+            index: Some(index),
+            fragment: None,
+            compressed: false,
+            data: Bytes::copy_from_slice(data),
+        })
+    };
+    let packet = Packet::Data {
+        number: 300,
+        frames: vec![datagram(40, b"ab"), datagram(41, b"cd")],
+    };
+
+    let bytes = Bytes::from(packet.encode(Some(290)));
+    assert_eq!(
+        bytes[..],
+        [
+            0x08, 0x2c, // Data, number 300 in one byte
+            0xf4, 0x05, 0x28, 0x02, b'a', b'b', // stream 5, index 40
+            0x90, b'c', b'd', // same stream, next index, to the end
+        ]
+    );
+    let decoded = Packet::decode(&bytes, Some(298)).unwrap();
+    assert_eq!(decoded, packet);
+
+    // The datagrams' data is sliced from the packet, not copied.
+    if let Packet::Data { frames, .. } = decoded {
+        for frame in frames {
+            if let Frame::Datagram(datagram) = frame {
+                let data = datagram.data.as_ptr();
+                assert!(bytes.as_ptr_range().contains(&data));
+            }
+        }
+    }
+}
+
+#[test]
+fn packet_numbers_widen_with_unacknowledged_packets() {
+    let packet = |number| Packet::Data {
+        number,
+        frames: vec![Frame::Ping],
+    };
+    assert_eq!(packet(127).encode(Some(0)).len(), 3);
+    assert_eq!(packet(128).encode(Some(0)).len(), 4);
+    assert_eq!(packet(1 << 40).encode(None).len(), 8);
+    assert_eq!(packet(u64::MAX).encode(None).len(), 10);
+
+    // Numbers wrap past the truncated window in either direction.
+    let bytes = packet(0x1_0005).encode(Some(0xfff0));
+    assert_eq!(bytes.len(), 3);
+    assert_eq!(
+        Packet::decode(&bytes.into(), Some(0xfffe)).unwrap(),
+        packet(0x1_0005)
+    );
+    let bytes = packet(0xfff0).encode(Some(0xffe0));
+    assert_eq!(
+        Packet::decode(&bytes.into(), Some(0x1_0005)).unwrap(),
+        packet(0xfff0)
+    );
+}
+
+#[test]
+fn unsupported_versions_are_reported() {
+    let mut bytes = Packet::Connect(Hello {
+        version: VERSION,
+        capabilities: Capabilities::default(),
+        limits: ReceiveLimits::default(),
+        dictionary: 0,
+    })
+    .encode(None);
+    bytes[1] = 99;
+    match Packet::decode(&bytes.into(), None) {
+        Err(Error::Malformed(DecodeError::UnsupportedVersion(99))) => {}
+        decoded => panic!("decoded {:?}", decoded),
+    }
+}